@@ -0,0 +1,20 @@
+//! Bakes the git commit into the binary as GIT_SHA, for GET /api/ping.
+//! CI can set GIT_SHA itself when building outside a checkout.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+
+    let sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|o| o.status.success())?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=GIT_SHA={}", sha.unwrap_or_else(|| "unknown".into()));
+}
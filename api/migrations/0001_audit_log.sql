@@ -0,0 +1,14 @@
+-- Admin actions that changed something (see src/audit.rs).
+-- Apply with: npx wrangler d1 migrations apply newsletter --remote
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    at INTEGER NOT NULL,          -- milliseconds since the Unix epoch
+    request_id TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    credential TEXT NOT NULL,     -- fingerprint of the key or token used
+    detail TEXT                   -- start of the request body
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_at ON audit_log (at);
@@ -0,0 +1,16 @@
+-- Reader comments on posts (see src/comments.rs).
+CREATE TABLE IF NOT EXISTS comments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    slug TEXT NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT,                   -- optional, never returned by the API
+    body TEXT NOT NULL,           -- markdown as submitted
+    created_at INTEGER NOT NULL,  -- milliseconds since the Unix epoch
+    -- Spam signals, kept so false positives can be reviewed
+    ip_hash TEXT,
+    user_agent TEXT,
+    spam INTEGER NOT NULL DEFAULT 0,
+    spam_reason TEXT
+);
+
+CREATE INDEX IF NOT EXISTS comments_slug ON comments (slug, created_at);
@@ -0,0 +1,7 @@
+-- Comment moderation: pending -> approved | spam (see src/comments.rs).
+-- Comments already shown stay shown.
+ALTER TABLE comments ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
+UPDATE comments SET status = CASE WHEN spam = 1 THEN 'spam' ELSE 'approved' END;
+ALTER TABLE comments DROP COLUMN spam;
+
+CREATE INDEX IF NOT EXISTS comments_status ON comments (status, created_at);
@@ -0,0 +1,15 @@
+-- Guestbook entries (see src/guestbook.rs), moderated like comments:
+-- pending -> approved | spam.
+CREATE TABLE IF NOT EXISTS guestbook (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    email TEXT,                   -- optional, never returned by the API
+    body TEXT NOT NULL,           -- markdown as submitted
+    created_at INTEGER NOT NULL,  -- milliseconds since the Unix epoch
+    ip_hash TEXT,
+    user_agent TEXT,
+    status TEXT NOT NULL DEFAULT 'pending',
+    spam_reason TEXT
+);
+
+CREATE INDEX IF NOT EXISTS guestbook_status ON guestbook (status, created_at);
@@ -0,0 +1,9 @@
+-- Fediverse accounts following the blog's ActivityPub actor
+-- (see src/activitypub.rs).
+CREATE TABLE IF NOT EXISTS followers (
+    actor_id TEXT PRIMARY KEY,    -- the follower's actor URL
+    inbox TEXT NOT NULL,
+    shared_inbox TEXT,            -- their server's, if it has one
+    follow_id TEXT,               -- the Follow activity, to match its Undo
+    followed_at INTEGER NOT NULL  -- milliseconds since the Unix epoch
+);
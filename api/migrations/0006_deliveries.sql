@@ -0,0 +1,14 @@
+-- ActivityPub activities waiting to be delivered to followers' inboxes
+-- (see src/activitypub.rs). Rows go once delivered or given up on.
+CREATE TABLE IF NOT EXISTS activitypub_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    inbox TEXT NOT NULL,
+    activity TEXT NOT NULL,           -- JSON
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL, -- milliseconds since the Unix epoch
+    last_error TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS activitypub_deliveries_due
+    ON activitypub_deliveries (next_attempt_at);
@@ -0,0 +1,9 @@
+-- Bookmarked links (see src/bookmarks.rs), for the links page and roundups.
+CREATE TABLE IF NOT EXISTS bookmarks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    note TEXT,
+    tags TEXT NOT NULL DEFAULT '[]',  -- JSON array of lowercase tags
+    created_at INTEGER NOT NULL       -- milliseconds since the Unix epoch
+);
@@ -0,0 +1,8 @@
+-- Threaded comments and reply notifications (see src/comments.rs).
+ALTER TABLE comments ADD COLUMN parent_id INTEGER REFERENCES comments (id);
+-- Set when the commenter asked to be emailed about replies
+ALTER TABLE comments ADD COLUMN notify_replies INTEGER NOT NULL DEFAULT 0;
+-- Opens the opt-out link in those emails; only set with notify_replies
+ALTER TABLE comments ADD COLUMN opt_out_token TEXT;
+
+CREATE INDEX IF NOT EXISTS comments_opt_out_token ON comments (opt_out_token);
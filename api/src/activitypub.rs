@@ -0,0 +1,642 @@
+//! A minimal ActivityPub server, so the blog can be followed from Mastodon
+//! and the rest of the fediverse as `@blog@lindfors.no` (the host of
+//! SITE_URL):
+//!
+//! - GET /.well-known/webfinger resolves the handle to the actor,
+//! - GET /api/activitypub/actor is the actor document, with its public key,
+//! - GET /api/activitypub/outbox lists the sent issues as Articles, each also
+//!   at /api/activitypub/posts/{slug},
+//! - POST /api/activitypub/inbox takes Follow and Undo (of a Follow),
+//!   keeping followers in the `followers` D1 table
+//!   (`migrations/0005_followers.sql`).
+//!
+//! Inbox requests must carry an HTTP signature by the actor they're from
+//! (see [`httpsig`]), and follows are answered with a signed Accept. The key
+//! pair is ACTIVITYPUB_PUBLIC_KEY and the ACTIVITYPUB_PRIVATE_KEY secret; see
+//! wrangler.toml.
+//!
+//! Sending an issue queues its Create for each follower's server in the
+//! `activitypub_deliveries` table (`migrations/0006_deliveries.sql`), and the
+//! cron trigger delivers what's due, retrying failures with backoff.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Error, Fetch, Headers, Method, Request, Response, Result, RouteContext};
+
+use crate::httpsig::{self, SigningKey};
+use crate::logging::{self, RequestLog};
+use crate::sendlog::{self, SentIssue};
+use crate::{dates, fanout, flags, html, is_valid_slug, json_response, ApiResponse};
+
+/// The actor's name, the part before the @ in its handle.
+const USERNAME: &str = "blog";
+
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// The documents only change when an issue is sent.
+const CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Delivery attempts before giving up on an inbox.
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Wait before the first retry, doubled for each one after, so the last
+/// attempt is about ten hours after the first.
+const RETRY_BASE_MILLIS: u64 = 5 * 60 * 1000;
+
+/// Deliveries per cron run, well within the subrequest limit.
+const DELIVERIES_PER_RUN: u32 = 25;
+
+#[derive(Deserialize)]
+struct Activity {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    actor: String,
+    #[serde(default)]
+    object: Value,
+}
+
+#[derive(Deserialize)]
+struct Delivery {
+    id: u64,
+    inbox: String,
+    activity: String,
+    attempts: u32,
+}
+
+/// The parts of another server's actor document used here.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteActor {
+    id: String,
+    inbox: String,
+    #[serde(default)]
+    endpoints: Option<Endpoints>,
+    #[serde(default)]
+    public_key: Option<PublicKey>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoints {
+    #[serde(default)]
+    shared_inbox: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PublicKey {
+    id: String,
+    owner: String,
+    public_key_pem: String,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn not_configured() -> Result<Response> {
+    error(503, "ActivityPub is not configured")
+}
+
+fn activity_response(document: &Value) -> Result<Response> {
+    let mut resp = json_response(document, 200)?;
+    let headers = resp.headers_mut();
+    headers.set("Content-Type", ACTIVITY_JSON)?;
+    headers.set("Cache-Control", CACHE_CONTROL)?;
+    Ok(resp)
+}
+
+pub fn actor_url(site_url: &str) -> String {
+    format!("{}/api/activitypub/actor", site_url)
+}
+
+fn site_host(site_url: &str) -> String {
+    worker::Url::parse(site_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Whether `url` is https on `host`.
+fn https_on(url: &str, host: &str) -> bool {
+    worker::Url::parse(url).is_ok_and(|url| url.scheme() == "https" && url.host_str() == Some(host))
+}
+
+fn followers_url(site_url: &str) -> String {
+    format!("{}/api/activitypub/followers", site_url)
+}
+
+fn post_url(site_url: &str, slug: &str) -> String {
+    format!("{}/api/activitypub/posts/{}", site_url, slug)
+}
+
+/// The key the actor signs with. Fails if the private key isn't set.
+pub fn signing_key(env: &Env) -> Result<SigningKey> {
+    let site_url = env.var("SITE_URL")?.to_string();
+    Ok(SigningKey {
+        key_id: format!("{}#main-key", actor_url(&site_url)),
+        private_key_pem: env.secret("ACTIVITYPUB_PRIVATE_KEY")?.to_string(),
+    })
+}
+
+/// An issue as an ActivityStreams Article: the title, description and link,
+/// with its tags as hashtags.
+fn article(site_url: &str, issue: &SentIssue) -> Value {
+    let mut content = format!("<p><strong>{}</strong></p>", html::escape(&issue.title));
+    if !issue.description.is_empty() {
+        content.push_str(&format!("<p>{}</p>", html::escape(&issue.description)));
+    }
+    content.push_str(&format!(
+        "<p><a href=\"{url}\">{url}</a></p>",
+        url = html::escape(&issue.post_url)
+    ));
+    let tags: Vec<Value> = issue
+        .tags
+        .iter()
+        .map(|tag| {
+            json!({
+                "type": "Hashtag",
+                "name": format!("#{}", tag),
+                "href": format!("{}/tags/{}/", site_url, html::slugify(tag)),
+            })
+        })
+        .collect();
+    json!({
+        "id": post_url(site_url, &issue.slug),
+        "type": "Article",
+        "attributedTo": actor_url(site_url),
+        "name": issue.title,
+        // HTML, as ActivityStreams has it
+        "summary": html::escape(&issue.description),
+        "content": content,
+        "url": issue.post_url,
+        "published": dates::rfc3339(issue.sent_at),
+        "to": [PUBLIC],
+        "cc": [followers_url(site_url)],
+        "tag": tags,
+    })
+}
+
+/// The Create activity announcing an issue.
+pub fn create_activity(site_url: &str, issue: &SentIssue) -> Value {
+    json!({
+        "id": format!("{}#create", post_url(site_url, &issue.slug)),
+        "type": "Create",
+        "actor": actor_url(site_url),
+        "published": dates::rfc3339(issue.sent_at),
+        "to": [PUBLIC],
+        "cc": [followers_url(site_url)],
+        "object": article(site_url, issue),
+    })
+}
+
+/// GET /.well-known/webfinger?resource=acct:blog@{host} — the actor for the
+/// blog's handle (or its own URL).
+pub async fn handle_webfinger(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let host = site_host(&site_url);
+    let resource = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "resource")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    let handle = format!("acct:{}@{}", USERNAME, host);
+    let actor = actor_url(&site_url);
+    if !resource.eq_ignore_ascii_case(&handle) && resource != actor {
+        return error(404, "No such account");
+    }
+
+    let mut resp = json_response(
+        &json!({
+            "subject": handle,
+            "aliases": [actor, site_url],
+            "links": [
+                { "rel": "self", "type": ACTIVITY_JSON, "href": actor },
+                {
+                    "rel": "http://webfinger.net/rel/profile-page",
+                    "type": "text/html",
+                    "href": site_url,
+                },
+            ],
+        }),
+        200,
+    )?;
+    let headers = resp.headers_mut();
+    headers.set("Content-Type", "application/jrd+json")?;
+    headers.set("Cache-Control", CACHE_CONTROL)?;
+    Ok(resp)
+}
+
+/// GET /api/activitypub/actor — the blog's actor document.
+pub async fn handle_actor(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(public_key) = ctx.env.var("ACTIVITYPUB_PUBLIC_KEY") else {
+        return not_configured();
+    };
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let actor = actor_url(&site_url);
+    let host = site_host(&site_url);
+
+    activity_response(&json!({
+        "@context": [ACTIVITY_STREAMS, "https://w3id.org/security/v1"],
+        "id": actor,
+        "type": "Person",
+        "preferredUsername": USERNAME,
+        "name": host,
+        "summary": format!("<p>Posts and newsletter issues from <a href=\"{0}\">{0}</a>.</p>", site_url),
+        "url": site_url,
+        "inbox": format!("{}/api/activitypub/inbox", site_url),
+        "outbox": format!("{}/api/activitypub/outbox", site_url),
+        "followers": followers_url(&site_url),
+        "manuallyApprovesFollowers": false,
+        "discoverable": true,
+        "publicKey": {
+            "id": format!("{}#main-key", actor),
+            "owner": actor,
+            "publicKeyPem": public_key.to_string(),
+        },
+    }))
+}
+
+/// GET /api/activitypub/outbox — every sent issue as a Create, newest first.
+pub async fn handle_outbox(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let items: Vec<Value> = sendlog::load(&ctx.env)
+        .await
+        .iter()
+        .map(|issue| create_activity(&site_url, issue))
+        .collect();
+    activity_response(&json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": format!("{}/api/activitypub/outbox", site_url),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    }))
+}
+
+/// GET /api/activitypub/posts/{slug} — one sent issue's Article.
+pub async fn handle_post(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let issues = sendlog::load(&ctx.env).await;
+    let Some(issue) = issues.iter().find(|issue| issue.slug == slug) else {
+        return error(404, "No such post");
+    };
+    let mut document = article(&site_url, issue);
+    document["@context"] = ACTIVITY_STREAMS.into();
+    activity_response(&document)
+}
+
+/// GET /api/activitypub/followers — how many follow the blog; the followers
+/// themselves aren't listed.
+pub async fn handle_followers(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let count: u64 = ctx
+        .env
+        .d1("DB")?
+        .prepare("SELECT COUNT(*) AS count FROM followers")
+        .first(Some("count"))
+        .await?
+        .unwrap_or_default();
+    activity_response(&json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": followers_url(&site_url),
+        "type": "OrderedCollection",
+        "totalItems": count,
+    }))
+}
+
+/// Fetch an actor document, signed for servers that require it. `None` if
+/// it can't be had or isn't an actor at `url`.
+async fn fetch_actor(ctx: &RouteContext<RequestLog>, url: &str) -> Result<Option<RemoteActor>> {
+    if !url.starts_with("https://") {
+        return Ok(None);
+    }
+    let headers = Headers::new();
+    headers.set("Accept", ACTIVITY_JSON)?;
+    let req =
+        httpsig::signed_request(Method::Get, url, headers, None, &signing_key(&ctx.env)?).await?;
+
+    let started = logging::now_millis();
+    let mut resp = match Fetch::Request(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            let error = e.to_string();
+            ctx.data
+                .upstream("activitypub", "fetch_actor", started, None, Some(&error));
+            return Ok(None);
+        }
+    };
+    let status = resp.status_code();
+    ctx.data
+        .upstream("activitypub", "fetch_actor", started, Some(status), None);
+    if status != 200 {
+        return Ok(None);
+    }
+    Ok(resp
+        .json::<RemoteActor>()
+        .await
+        .ok()
+        .filter(|actor| actor.id == url))
+}
+
+/// The actor `actor_id`, if the request is signed with its key.
+async fn verified_actor(
+    req: &Request,
+    body: &[u8],
+    actor_id: &str,
+    ctx: &RouteContext<RequestLog>,
+) -> Result<Option<RemoteActor>> {
+    let Some(signature) = httpsig::parse(req) else {
+        return Ok(None);
+    };
+    let Some(actor) = fetch_actor(ctx, actor_id).await? else {
+        return Ok(None);
+    };
+    let Some(key) = actor
+        .public_key
+        .as_ref()
+        .filter(|key| key.id == signature.key_id && key.owner == actor.id)
+    else {
+        return Ok(None);
+    };
+    // A malformed key is the sender's problem, not a 500
+    let valid = httpsig::verify(req, body, &signature, &key.public_key_pem)
+        .await
+        .unwrap_or(false);
+    Ok(valid.then_some(actor))
+}
+
+/// POST a signed activity to an inbox; the response status.
+async fn deliver(env: &Env, inbox: &str, activity: &Value) -> Result<u16> {
+    let mut activity = activity.clone();
+    activity["@context"] = ACTIVITY_STREAMS.into();
+    let body = serde_json::to_string(&activity).map_err(|e| Error::RustError(e.to_string()))?;
+    let headers = Headers::new();
+    headers.set("Content-Type", ACTIVITY_JSON)?;
+    let req = httpsig::signed_request(
+        Method::Post,
+        inbox,
+        headers,
+        Some(&body),
+        &signing_key(env)?,
+    )
+    .await?;
+    Ok(Fetch::Request(req).send().await?.status_code())
+}
+
+fn random_hex() -> Result<String> {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn accepted() -> Result<Response> {
+    Ok(Response::empty()?.with_status(202))
+}
+
+/// POST /api/activitypub/inbox — follows and unfollows of the blog. Other
+/// activities are acknowledged and dropped.
+pub async fn handle_inbox(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let body = req.bytes().await?;
+    let Ok(activity) = serde_json::from_slice::<Activity>(&body) else {
+        return error(400, "Expected an activity with a type and an actor");
+    };
+    // Checked before the signature, which costs a fetch of the sender: the
+    // account Deletes servers broadcast are most of what arrives here
+    if activity.kind != "Follow" && activity.kind != "Undo" {
+        return accepted();
+    }
+    if ctx.env.secret("ACTIVITYPUB_PRIVATE_KEY").is_err() {
+        return not_configured();
+    }
+    let Some(follower) = verified_actor(&req, &body, &activity.actor, &ctx).await? else {
+        return error(401, "Missing or invalid HTTP signature");
+    };
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let db = ctx.env.d1("DB")?;
+    if activity.kind == "Undo" {
+        match &activity.object {
+            Value::String(follow_id) => {
+                db.prepare("DELETE FROM followers WHERE actor_id = ?1 AND follow_id = ?2")
+                    .bind(&[follower.id.as_str().into(), follow_id.as_str().into()])?
+                    .run()
+                    .await?;
+            }
+            object if object["type"] == "Follow" => {
+                db.prepare("DELETE FROM followers WHERE actor_id = ?1")
+                    .bind(&[follower.id.as_str().into()])?
+                    .run()
+                    .await?;
+            }
+            _ => {}
+        }
+        return accepted();
+    }
+
+    let actor = actor_url(&site_url);
+    if activity.object.as_str() != Some(actor.as_str()) {
+        return error(400, "Only the blog can be followed here");
+    }
+    let shared_inbox = follower.endpoints.and_then(|e| e.shared_inbox);
+    // Deliveries go to these, signed with the blog's key: only the
+    // follower's own server gets them
+    let host = site_host(&follower.id);
+    let inboxes_ok = https_on(&follower.inbox, &host)
+        && shared_inbox
+            .as_deref()
+            .is_none_or(|url| https_on(url, &host));
+    if host.is_empty() || !inboxes_ok {
+        return error(400, "The follower's inboxes must be https on its own host");
+    }
+    db.prepare(
+        "INSERT INTO followers (actor_id, inbox, shared_inbox, follow_id, followed_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5) \
+         ON CONFLICT (actor_id) DO UPDATE SET \
+         inbox = excluded.inbox, shared_inbox = excluded.shared_inbox, \
+         follow_id = excluded.follow_id",
+    )
+    .bind(&[
+        follower.id.as_str().into(),
+        follower.inbox.as_str().into(),
+        shared_inbox.map_or(JsValue::NULL, JsValue::from),
+        activity.id.as_deref().map_or(JsValue::NULL, JsValue::from),
+        JsValue::from_f64(logging::now_millis() as f64),
+    ])?
+    .run()
+    .await?;
+
+    let accept = json!({
+        "id": format!("{}#accepts/{}", actor, random_hex()?),
+        "type": "Accept",
+        "actor": actor,
+        "object": serde_json::from_slice::<Value>(&body).unwrap_or_default(),
+    });
+    let started = logging::now_millis();
+    let result = deliver(&ctx.env, &follower.inbox, &accept).await;
+    ctx.data
+        .upstream_status("activitypub", "accept", started, &result);
+    // The follower's server shows the follow as pending until the Accept
+    // arrives, so a failed one goes through the delivery queue
+    if !matches!(result, Ok(status) if status < 300) {
+        let retry_at = logging::now_millis() + RETRY_BASE_MILLIS;
+        queue(&ctx.env, &follower.inbox, &accept, 1, retry_at).await?;
+    }
+    ctx.data.event("activitypub_follow", true);
+    accepted()
+}
+
+async fn queue(
+    env: &Env,
+    inbox: &str,
+    activity: &Value,
+    attempts: u32,
+    next_attempt_at: u64,
+) -> Result<()> {
+    let activity = serde_json::to_string(activity).map_err(|e| Error::RustError(e.to_string()))?;
+    env.d1("DB")?
+        .prepare(
+            "INSERT INTO activitypub_deliveries \
+             (inbox, activity, attempts, next_attempt_at, created_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&[
+            inbox.into(),
+            activity.into(),
+            attempts.into(),
+            JsValue::from_f64(next_attempt_at as f64),
+            JsValue::from_f64(logging::now_millis() as f64),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Queue `activity` for delivery to every follower: to each server's shared
+/// inbox once, where it has one. The number of deliveries queued; none if
+/// ActivityPub isn't configured.
+pub async fn enqueue(env: &Env, activity: &Value) -> Result<usize> {
+    if env.secret("ACTIVITYPUB_PRIVATE_KEY").is_err() {
+        return Ok(0);
+    }
+    let activity = serde_json::to_string(activity).map_err(|e| Error::RustError(e.to_string()))?;
+    let queued = env
+        .d1("DB")?
+        .prepare(
+            "INSERT INTO activitypub_deliveries \
+             (inbox, activity, attempts, next_attempt_at, created_at) \
+             SELECT DISTINCT COALESCE(shared_inbox, inbox), ?1, 0, ?2, ?2 FROM followers",
+        )
+        .bind(&[
+            activity.into(),
+            JsValue::from_f64(logging::now_millis() as f64),
+        ])?
+        .run()
+        .await?;
+    Ok(queued.meta()?.and_then(|m| m.changes).unwrap_or_default())
+}
+
+/// Attempt the deliveries that are due, a few inboxes at a time. A success
+/// ends one, and so does a failure that won't change (a 4xx other than 408
+/// and 429) or the last attempt's; anything else is retried later. A 410
+/// also drops the followers behind the inbox. Nothing is attempted while the
+/// `activitypub_delivery` flag is off (see flags.rs).
+pub async fn deliver_due(env: &Env, log: &RequestLog) -> Result<()> {
+    if !flags::load(env).await.activitypub_delivery {
+        return Ok(());
+    }
+    let db = env.d1("DB")?;
+    let due: Vec<Delivery> = db
+        .prepare(
+            "SELECT id, inbox, activity, attempts FROM activitypub_deliveries \
+             WHERE next_attempt_at <= ?1 ORDER BY next_attempt_at LIMIT ?2",
+        )
+        .bind(&[
+            JsValue::from_f64(logging::now_millis() as f64),
+            DELIVERIES_PER_RUN.into(),
+        ])?
+        .all()
+        .await?
+        .results()?;
+
+    let results = fanout::map(&due, fanout::CONNECTIONS, |delivery| async move {
+        let activity: Value = serde_json::from_str(&delivery.activity).unwrap_or_default();
+        let started = logging::now_millis();
+        let result = deliver(env, &delivery.inbox, &activity).await;
+        log.upstream_status("activitypub", "deliver", started, &result);
+        result
+    })
+    .await;
+
+    for (delivery, result) in due.iter().zip(results) {
+        let id = JsValue::from_f64(delivery.id as f64);
+
+        let failure = match &result {
+            Ok(status) if *status < 300 => None,
+            Ok(status) => Some(format!("HTTP {}", status)),
+            Err(e) => Some(e.to_string()),
+        };
+        let Some(failure) = failure else {
+            db.prepare("DELETE FROM activitypub_deliveries WHERE id = ?1")
+                .bind(&[id])?
+                .run()
+                .await?;
+            continue;
+        };
+        if matches!(result, Ok(410)) {
+            db.prepare("DELETE FROM followers WHERE inbox = ?1 OR shared_inbox = ?1")
+                .bind(&[delivery.inbox.as_str().into()])?
+                .run()
+                .await?;
+        }
+
+        let attempts = delivery.attempts + 1;
+        let permanent = matches!(
+            result,
+            Ok(status) if (400..500).contains(&status) && status != 408 && status != 429
+        );
+        if permanent || attempts >= MAX_ATTEMPTS {
+            worker::console_warn!(
+                "Giving up delivering to {} after {} attempts: {}",
+                delivery.inbox,
+                attempts,
+                failure
+            );
+            db.prepare("DELETE FROM activitypub_deliveries WHERE id = ?1")
+                .bind(&[id])?
+                .run()
+                .await?;
+        } else {
+            let retry_at = logging::now_millis() + (RETRY_BASE_MILLIS << (attempts - 1));
+            db.prepare(
+                "UPDATE activitypub_deliveries \
+                 SET attempts = ?2, next_attempt_at = ?3, last_error = ?4 WHERE id = ?1",
+            )
+            .bind(&[
+                id,
+                attempts.into(),
+                JsValue::from_f64(retry_at as f64),
+                failure.into(),
+            ])?
+            .run()
+            .await?;
+        }
+    }
+    Ok(())
+}
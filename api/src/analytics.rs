@@ -0,0 +1,293 @@
+//! Site analytics without cookies or third parties. The site beacons
+//! POST /api/analytics/event on each page view (and for custom events), and
+//! each event becomes one data point in the ANALYTICS Analytics Engine
+//! dataset:
+//!
+//! ```text
+//! index1 / blob1  event: "pageview" or a custom name
+//! blob2           path, without query or fragment
+//! blob3           referring host, "" for direct visits and internal links
+//! blob4           screen class: "mobile", "tablet", "desktop" or "unknown"
+//! blob5           visitor: hash of IP, user agent and the day's salt
+//! blob6           country, from Cloudflare
+//! ```
+//!
+//! The visitor hash only tells visitors apart within a UTC day: its salt is
+//! random, kept in KV for the day and then expires, so hashes can't be linked
+//! across days or reversed by guessing IPs. Requests with `DNT: 1` or
+//! `Sec-GPC: 1` aren't recorded.
+//!
+//! GET /api/admin/analytics summarizes a date range through the SQL API, with
+//! the same CF_ACCOUNT_ID and CF_ANALYTICS_TOKEN as the API metrics.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::{
+    AnalyticsEngineDataPointBuilder, Env, Error, Request, Response, Result, RouteContext,
+};
+
+use crate::logging::{self, RequestLog};
+use crate::{client_fingerprint, dates, json_response, metrics, ApiResponse};
+
+/// Must match `dataset` of the ANALYTICS binding in wrangler.toml.
+const DATASET: &str = "site_analytics";
+
+const MAX_PATH_CHARS: usize = 256;
+const MAX_EVENT_CHARS: usize = 40;
+
+/// Slightly over a day, so a salt outlives the day it's for.
+const SALT_TTL_SECS: u64 = 2 * 24 * 60 * 60;
+
+const SCREEN_CLASSES: &[&str] = &["mobile", "tablet", "desktop"];
+
+/// Days summarized when no range is given.
+const DEFAULT_RANGE_DAYS: u64 = 30;
+
+/// Analytics Engine keeps data for three months.
+const MAX_RANGE_DAYS: u64 = 92;
+
+const DEFAULT_TOP: usize = 20;
+const MAX_TOP: usize = 100;
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Deserialize)]
+struct EventRequest {
+    #[serde(default = "pageview")]
+    event: String,
+    path: String,
+    #[serde(default)]
+    referrer: String,
+    #[serde(default)]
+    screen: String,
+}
+
+fn pageview() -> String {
+    "pageview".into()
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn is_valid_event(event: &str) -> bool {
+    !event.is_empty()
+        && event.len() <= MAX_EVENT_CHARS
+        && event
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// The path of a page URL or path, if it's one of the site's.
+fn page_path(path: &str) -> Option<&str> {
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    (path.starts_with('/') && path.chars().count() <= MAX_PATH_CHARS).then_some(path)
+}
+
+/// The host of an external referrer; empty for direct visits, internal
+/// links and anything unparseable.
+fn referrer_host(referrer: &str, site_host: Option<&str>) -> String {
+    let host = worker::Url::parse(referrer)
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|h| h.trim_start_matches("www.").to_string())
+        })
+        .unwrap_or_default();
+    if site_host.is_some_and(|site| site.trim_start_matches("www.") == host) {
+        return String::new();
+    }
+    host
+}
+
+/// Whether the browser asks not to be tracked.
+fn opted_out(req: &Request) -> bool {
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    header("DNT").as_deref() == Some("1") || header("Sec-GPC").as_deref() == Some("1")
+}
+
+/// Today's visitor-hash salt, created by the first event of the day.
+async fn daily_salt(env: &Env, today: &str) -> Result<String> {
+    let kv = env.kv("NEWSLETTER")?;
+    let key = format!("analytics:salt:{}", today);
+    if let Some(salt) = kv.get(&key).text().await? {
+        return Ok(salt);
+    }
+    // Isolates racing here each write their own salt and the last one wins;
+    // the losers' first visitors are counted twice
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    let salt: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    kv.put(&key, &salt)?
+        .expiration_ttl(SALT_TTL_SECS)
+        .execute()
+        .await?;
+    Ok(salt)
+}
+
+/// POST /api/analytics/event — record a page view or custom event:
+/// `{"event"?, "path", "referrer"?, "screen"?}`. Always 204 once valid.
+pub async fn handle_event(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(body) = req.json::<EventRequest>().await else {
+        return error(400, "Invalid request body — expected {\"path\": \"/...\"}");
+    };
+    if !is_valid_event(&body.event) {
+        return error(
+            400,
+            "event may only contain a-z, 0-9, '-' and '_' (at most 40)",
+        );
+    }
+    let Some(path) = page_path(&body.path) else {
+        return error(400, "path must be a site path starting with /");
+    };
+
+    if opted_out(&req) {
+        return Ok(Response::empty()?.with_status(204));
+    }
+    let Ok(dataset) = ctx.env.analytics_engine("ANALYTICS") else {
+        return Ok(Response::empty()?.with_status(204));
+    };
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let site_host = worker::Url::parse(&site_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    let referrer = referrer_host(&body.referrer, site_host.as_deref());
+    let screen = if SCREEN_CLASSES.contains(&body.screen.as_str()) {
+        body.screen.as_str()
+    } else {
+        "unknown"
+    };
+    let today = dates::iso_date(worker::Date::now().as_millis());
+    let salt = daily_salt(&ctx.env, &today).await?;
+    let visitor = client_fingerprint(&req, &format!("analytics:{}:{}", today, salt));
+    let country = req.cf().and_then(|cf| cf.country()).unwrap_or_default();
+
+    let point = AnalyticsEngineDataPointBuilder::new()
+        .indexes([body.event.as_str()])
+        .add_blob(body.event.as_str())
+        .add_blob(path)
+        .add_blob(referrer.as_str())
+        .add_blob(screen)
+        .add_blob(visitor.as_str())
+        .add_blob(country.as_str())
+        .build();
+    if let Err(e) = dataset.write_data_point(&point) {
+        worker::console_warn!("Failed to record analytics event: {}", e);
+    }
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// SQL filter for page views in `[from, until)` (Unix milliseconds).
+fn pageviews_in(from: u64, until: u64) -> String {
+    let timestamp = |millis: u64| {
+        let rfc = dates::rfc3339(millis);
+        format!("toDateTime('{} {}')", &rfc[..10], &rfc[11..19])
+    };
+    format!(
+        "blob1 = 'pageview' AND timestamp >= {} AND timestamp < {}",
+        timestamp(from),
+        timestamp(until)
+    )
+}
+
+/// GET /api/admin/analytics?from=YYYY-MM-DD&to=YYYY-MM-DD&limit=N — admin:
+/// page views and visitors per day, and the top pages and referrers, for the
+/// inclusive UTC date range (default: the last 30 days).
+///
+/// Visitor hashes change daily, so `visitors` over more than a day counts
+/// visitor-days.
+pub async fn handle_summary(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let url = req.url()?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    let today = worker::Date::now().as_millis() / MILLIS_PER_DAY * MILLIS_PER_DAY;
+    let to = match params.get("to") {
+        Some(to) => match dates::parse_iso_date(to) {
+            Some(to) => to,
+            None => return error(400, "to must be a YYYY-MM-DD date"),
+        },
+        None => today,
+    };
+    let from = match params.get("from") {
+        Some(from) => match dates::parse_iso_date(from) {
+            Some(from) => from,
+            None => return error(400, "from must be a YYYY-MM-DD date"),
+        },
+        None => to.saturating_sub((DEFAULT_RANGE_DAYS - 1) * MILLIS_PER_DAY),
+    };
+    if from > to {
+        return error(400, "from must not be after to");
+    }
+    if (to - from) / MILLIS_PER_DAY + 1 > MAX_RANGE_DAYS {
+        return error(400, "The range can be at most 92 days");
+    }
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_TOP)
+        .clamp(1, MAX_TOP);
+
+    let account_id = ctx.env.var("CF_ACCOUNT_ID")?.to_string();
+    let token = ctx.env.secret("CF_ANALYTICS_TOKEN")?.to_string();
+    let filter = pageviews_in(from, to + MILLIS_PER_DAY);
+    let queries = [
+        format!(
+            "SELECT toStartOfInterval(timestamp, INTERVAL '1' DAY) AS day, \
+             SUM(_sample_interval) AS pageviews, COUNT(DISTINCT blob5) AS visitors \
+             FROM {} WHERE {} GROUP BY day ORDER BY day FORMAT JSON",
+            DATASET, filter
+        ),
+        format!(
+            "SELECT blob2 AS path, SUM(_sample_interval) AS pageviews, \
+             COUNT(DISTINCT blob5) AS visitors \
+             FROM {} WHERE {} GROUP BY path ORDER BY pageviews DESC LIMIT {} FORMAT JSON",
+            DATASET, filter, limit
+        ),
+        format!(
+            "SELECT blob3 AS referrer, SUM(_sample_interval) AS visits \
+             FROM {} WHERE {} AND blob3 != '' \
+             GROUP BY referrer ORDER BY visits DESC LIMIT {} FORMAT JSON",
+            DATASET, filter, limit
+        ),
+    ];
+
+    let mut results = Vec::new();
+    for sql in &queries {
+        let started = logging::now_millis();
+        let rows = metrics::query(&account_id, &token, sql).await;
+        let error = rows.as_ref().err().map(|e| e.to_string());
+        ctx.data
+            .upstream("analytics_engine", "query", started, None, error.as_deref());
+        results.push(rows?);
+    }
+
+    #[derive(Serialize)]
+    struct SummaryResponse {
+        from: String,
+        to: String,
+        daily: Vec<Value>,
+        top_pages: Vec<Value>,
+        top_referrers: Vec<Value>,
+    }
+
+    let mut results = results.into_iter();
+    json_response(
+        &SummaryResponse {
+            from: dates::iso_date(from),
+            to: dates::iso_date(to),
+            daily: results.next().unwrap_or_default(),
+            top_pages: results.next().unwrap_or_default(),
+            top_referrers: results.next().unwrap_or_default(),
+        },
+        200,
+    )
+}
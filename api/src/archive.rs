@@ -0,0 +1,40 @@
+//! Hosted copies of sent issues, stored as `archive/{slug}.html` in the
+//! FILES R2 bucket (see files.rs) and served by `GET /api/archive/{slug}`.
+//! Issues archived before that were kept under `archive:{slug}` in the
+//! NEWSLETTER KV namespace, which is still read when the bucket has no copy.
+
+use worker::{Env, Result};
+
+use crate::files;
+
+fn archive_key(slug: &str) -> String {
+    format!("archive/{slug}.html")
+}
+
+fn legacy_key(slug: &str) -> String {
+    format!("archive:{slug}")
+}
+
+/// Public URL of the hosted copy for `slug`.
+pub fn archive_url(site_url: &str, slug: &str) -> String {
+    format!("{site_url}/api/archive/{slug}")
+}
+
+/// Store the exact rendered HTML of an issue, overwriting any earlier copy.
+pub async fn store(env: &Env, slug: &str, html: &str) -> Result<()> {
+    files::put(
+        env,
+        &archive_key(slug),
+        html.to_string(),
+        "text/html; charset=utf-8",
+    )
+    .await
+}
+
+/// Load the hosted copy for `slug`, if one was stored.
+pub async fn load(env: &Env, slug: &str) -> Result<Option<String>> {
+    if let Some(html) = files::get_text(env, &archive_key(slug)).await? {
+        return Ok(Some(html));
+    }
+    Ok(env.kv("NEWSLETTER")?.get(&legacy_key(slug)).text().await?)
+}
@@ -0,0 +1,137 @@
+//! Audit trail of admin actions, in the `audit_log` D1 table
+//! (`migrations/0001_audit_log.sql`). Every admin request that isn't a read
+//! is recorded with the credential fingerprint it used, whatever its outcome.
+
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Method, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{auth, json_response};
+
+/// How much of the request body to keep as the entry's detail.
+const DETAIL_CHARS: usize = 200;
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 500;
+
+/// An admin mutation, captured before its handler consumes the request.
+pub struct Pending {
+    method: String,
+    path: String,
+    credential: String,
+    detail: Option<String>,
+}
+
+impl Pending {
+    /// `None` for reads, which aren't audited.
+    pub async fn capture(req: &Request) -> Result<Option<Self>> {
+        if matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+            return Ok(None);
+        }
+        let detail = match uploaded(req)? {
+            Some(upload) => Some(upload),
+            None => {
+                let body = req.clone()?.text().await.unwrap_or_default();
+                (!body.is_empty()).then(|| body.chars().take(DETAIL_CHARS).collect())
+            }
+        };
+        Ok(Some(Pending {
+            method: req.method().to_string(),
+            // Not the query string, which may carry a legacy ?key=
+            path: req.path(),
+            credential: auth::credential_fingerprint(req)?,
+            detail,
+        }))
+    }
+
+    /// Write the entry. Failures are logged, never passed on: the action itself
+    /// has already happened.
+    pub async fn record(self, env: &Env, request_id: &str, status: u16) {
+        if let Err(e) = self.insert(env, request_id, status).await {
+            worker::console_error!("Failed to write audit entry for {}: {}", self.path, e);
+        }
+    }
+
+    async fn insert(&self, env: &Env, request_id: &str, status: u16) -> Result<()> {
+        let db = env.d1("DB")?;
+        db.prepare(
+            "INSERT INTO audit_log (at, request_id, method, path, status, credential, detail) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(&[
+            JsValue::from_f64(worker::Date::now().as_millis() as f64),
+            request_id.into(),
+            self.method.as_str().into(),
+            self.path.as_str().into(),
+            JsValue::from(status),
+            self.credential.as_str().into(),
+            self.detail.as_deref().map_or(JsValue::NULL, JsValue::from),
+        ])?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+/// For a body that isn't JSON or a form, such as an uploaded file, its type
+/// and declared length: the body itself is no use as text, and may be large.
+fn uploaded(req: &Request) -> Result<Option<String>> {
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    let media_type = media_type.to_ascii_lowercase();
+    if media_type.is_empty()
+        || media_type.ends_with("json")
+        || media_type == "application/x-www-form-urlencoded"
+    {
+        return Ok(None);
+    }
+    let length = req
+        .headers()
+        .get("Content-Length")?
+        .unwrap_or_else(|| "?".into());
+    Ok(Some(format!("{media_type}, {length} bytes")))
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuditEntry {
+    id: u64,
+    at: u64,
+    request_id: String,
+    method: String,
+    path: String,
+    status: u16,
+    credential: String,
+    detail: Option<String>,
+}
+
+/// GET /api/admin/audit?limit=N&before=ID — admin: audit entries, newest
+/// first. Pass the last entry's id as `before` for the next page.
+pub async fn handle_audit(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let params: std::collections::HashMap<String, String> =
+        req.url()?.query_pairs().into_owned().collect();
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .min(MAX_LIMIT);
+    let before = params
+        .get("before")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(i64::MAX as u64);
+
+    let db = ctx.env.d1("DB")?;
+    let entries: Vec<AuditEntry> = db
+        .prepare("SELECT * FROM audit_log WHERE id < ?1 ORDER BY id DESC LIMIT ?2")
+        .bind(&[JsValue::from_f64(before as f64), JsValue::from(limit)])?
+        .all()
+        .await?
+        .results()?;
+
+    #[derive(Serialize)]
+    struct AuditResponse {
+        entries: Vec<AuditEntry>,
+    }
+
+    json_response(&AuditResponse { entries }, 200)
+}
@@ -0,0 +1,316 @@
+//! Admin authentication shared by the admin endpoints.
+//!
+//! Requests carry ADMIN_KEY as `Authorization: Bearer <key>` (or the legacy
+//! `?key=`), and may additionally be signed:
+//!
+//! ```text
+//! X-Signature-Timestamp: <unix seconds>
+//! X-Signature: hex(HMAC-SHA256(ADMIN_KEY, METHOD \n PATH?QUERY \n TIMESTAMP \n BODY))
+//! ```
+//!
+//! A valid, recent signature authenticates on its own; a present but invalid
+//! or stale one is rejected even if the bearer key is right. With
+//! REQUIRE_SIGNED_ADMIN = "true", unsigned requests are rejected too.
+//! `scripts/send-newsletter.sh` shows how to sign with openssl.
+//!
+//! During a key rotation ADMIN_KEY_PREVIOUS is accepted too, for keys and
+//! signatures alike, and each use of it is logged.
+//!
+//! POST /api/admin/login exchanges the key for a short-lived session token,
+//! accepted as the bearer credential in its place (for browser clients that
+//! shouldn't hold the long-lived key).
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use worker::{Date, Env, Error, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{audit, json_response, ApiResponse};
+
+/// How far a signature timestamp may be from the worker's clock.
+const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+
+/// Lifetime of a session token from /api/admin/login.
+const SESSION_TTL_SECS: u64 = 60 * 60;
+
+/// Compare two byte strings in time independent of where they differ.
+/// (The length is not secret: it's fixed for a given key.)
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The admin credential presented with the request: `Authorization: Bearer
+/// <key>`, or the legacy `?key=` query parameter.
+fn presented_key(req: &Request) -> Result<Option<String>> {
+    if let Some(token) = bearer_token(req)? {
+        return Ok(Some(token));
+    }
+
+    // Deprecated: query strings end up in access logs and Referer headers
+    let key = query_key(req)?;
+    if key.is_some() {
+        worker::console_warn!(
+            "Admin key passed as ?key= on {}; use Authorization: Bearer",
+            req.path()
+        );
+    }
+    Ok(key)
+}
+
+fn bearer_token(req: &Request) -> Result<Option<String>> {
+    Ok(req
+        .headers()
+        .get("Authorization")?
+        .and_then(|header| header.strip_prefix("Bearer ").map(|t| t.trim().to_string())))
+}
+
+fn query_key(req: &Request) -> Result<Option<String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "key")
+        .map(|(_, v)| v.into_owned()))
+}
+
+/// Short, non-reversible name for the credential a request used, for the
+/// audit log: the start of its SHA-256, or "signature" for signed requests
+/// without a key.
+pub fn credential_fingerprint(req: &Request) -> Result<String> {
+    let presented = match bearer_token(req)? {
+        Some(token) => Some(token),
+        None => query_key(req)?,
+    };
+    Ok(match presented {
+        Some(key) => hex(&Sha256::digest(key.as_bytes())[..4]),
+        None => "signature".into(),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Outcome of checking the optional request signature.
+enum Signature {
+    Absent,
+    /// Signed with the admin key at this index of [`admin_keys`].
+    Valid(usize),
+    Invalid(&'static str),
+}
+
+/// Verify `X-Signature` over the method, path, timestamp and body. Reads the
+/// body from a clone, so the handler can still consume it.
+async fn check_signature(req: &Request, admin_keys: &[String]) -> Result<Signature> {
+    let headers = req.headers();
+    let (Some(signature), Some(timestamp)) = (
+        headers.get("X-Signature")?,
+        headers.get("X-Signature-Timestamp")?,
+    ) else {
+        return Ok(Signature::Absent);
+    };
+
+    let Ok(ts) = timestamp.trim().parse::<u64>() else {
+        return Ok(Signature::Invalid("malformed signature timestamp"));
+    };
+    let now = Date::now().as_millis() / 1000;
+    if now.abs_diff(ts) > MAX_SIGNATURE_AGE_SECS {
+        return Ok(Signature::Invalid("stale signature"));
+    }
+    let Some(signature) = decode_hex(signature.trim()) else {
+        return Ok(Signature::Invalid("malformed signature"));
+    };
+
+    let url = req.url()?;
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let body = req.clone()?.bytes().await?;
+
+    let preamble = format!("{}\n{}\n{}\n", req.method(), target, timestamp.trim());
+    for (index, admin_key) in admin_keys.iter().enumerate() {
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(admin_key.as_bytes()) else {
+            continue;
+        };
+        mac.update(preamble.as_bytes());
+        mac.update(&body);
+        if mac.verify_slice(&signature).is_ok() {
+            return Ok(Signature::Valid(index));
+        }
+    }
+    Ok(Signature::Invalid("bad signature"))
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// KV key for a session token. Only the token's hash is stored, so the
+/// namespace contents can't be replayed as credentials.
+fn session_key(token: &str) -> String {
+    format!("session:{}", hex(&Sha256::digest(token.as_bytes())))
+}
+
+async fn is_valid_session(env: &Env, token: &str) -> bool {
+    let Ok(kv) = env.kv("NEWSLETTER") else {
+        return false;
+    };
+    // Expiry is enforced by the KV TTL
+    matches!(kv.get(&session_key(token)).text().await, Ok(Some(_)))
+}
+
+/// Check the request's credentials; the error message if they don't pass.
+/// `allow_session` accepts a session token in place of ADMIN_KEY.
+async fn authenticate(
+    req: &Request,
+    env: &Env,
+    allow_session: bool,
+) -> Result<Option<&'static str>> {
+    let keys = admin_keys(env)?;
+    if keys.is_empty() {
+        return Ok(Some("Unauthorized"));
+    }
+    let require_signed = env
+        .var("REQUIRE_SIGNED_ADMIN")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false);
+
+    let presented = presented_key(req)?;
+    let matched = presented.as_deref().and_then(|key| {
+        keys.iter()
+            .position(|admin_key| constant_time_eq(key.as_bytes(), admin_key.as_bytes()))
+    });
+    let key_ok = match (matched, presented) {
+        (Some(index), _) => {
+            note_previous_key(req, index);
+            true
+        }
+        (None, Some(token)) if allow_session => is_valid_session(env, &token).await,
+        _ => false,
+    };
+
+    Ok(match check_signature(req, &keys).await? {
+        Signature::Invalid(reason) => {
+            worker::console_warn!("Rejected admin request to {}: {}", req.path(), reason);
+            Some("Invalid request signature")
+        }
+        Signature::Valid(index) => {
+            note_previous_key(req, index);
+            None
+        }
+        Signature::Absent if require_signed => Some("Signed request required"),
+        Signature::Absent if key_ok => None,
+        Signature::Absent => Some("Unauthorized"),
+    })
+}
+
+/// ADMIN_KEY, then ADMIN_KEY_PREVIOUS while a rotation is in progress: set
+/// the old key as ADMIN_KEY_PREVIOUS, the new one as ADMIN_KEY, update the
+/// scripts, then delete ADMIN_KEY_PREVIOUS.
+fn admin_keys(env: &Env) -> Result<Vec<String>> {
+    let current = env.secret("ADMIN_KEY")?.to_string();
+    if current.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut keys = vec![current];
+    if let Ok(previous) = env.secret("ADMIN_KEY_PREVIOUS") {
+        let previous = previous.to_string();
+        if !previous.is_empty() {
+            keys.push(previous);
+        }
+    }
+    Ok(keys)
+}
+
+/// Whether `key` is ADMIN_KEY (or ADMIN_KEY_PREVIOUS), for flows where the
+/// owner types it in once rather than sending it with each request, like
+/// IndieAuth's consent page.
+pub fn is_admin_key(env: &Env, key: &str) -> Result<bool> {
+    Ok(admin_keys(env)?
+        .iter()
+        .any(|admin_key| constant_time_eq(key.as_bytes(), admin_key.as_bytes())))
+}
+
+/// Log uses of ADMIN_KEY_PREVIOUS, so it's clear when nothing uses it any more.
+fn note_previous_key(req: &Request, index: usize) {
+    if index > 0 {
+        worker::console_warn!(
+            "Admin request to {} used ADMIN_KEY_PREVIOUS; finish rotating the key",
+            req.path()
+        );
+    }
+}
+
+fn unauthorized(error: &str) -> Result<Response> {
+    let mut resp = json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(error.into()),
+        },
+        401,
+    )?;
+    resp.headers_mut().set("WWW-Authenticate", "Bearer")?;
+    Ok(resp)
+}
+
+/// Guard for admin endpoints. Returns the 401 response to send back if the
+/// request isn't authenticated (by key, signature or session token), or
+/// `None` if it may proceed.
+pub async fn require_admin(req: &Request, env: &Env) -> Result<Option<Response>> {
+    match authenticate(req, env, true).await? {
+        Some(error) => unauthorized(error).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// POST /api/admin/login — exchange ADMIN_KEY (not a session token) for a
+/// session token valid for [`SESSION_TTL_SECS`].
+pub async fn handle_login(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    if let Some(error) = authenticate(&req, &ctx.env, false).await? {
+        return unauthorized(error);
+    }
+
+    let audit = audit::Pending::capture(&req).await?;
+
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    let token = hex(&bytes);
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let now = Date::now().as_millis();
+    kv.put(&session_key(&token), now)?
+        .expiration_ttl(SESSION_TTL_SECS)
+        .execute()
+        .await?;
+
+    #[derive(Serialize)]
+    struct LoginResponse {
+        success: bool,
+        token: String,
+        /// Milliseconds since the Unix epoch.
+        expires_at: u64,
+    }
+
+    if let Some(audit) = audit {
+        audit.record(&ctx.env, &ctx.data.id, 200).await;
+    }
+
+    json_response(
+        &LoginResponse {
+            success: true,
+            token,
+            expires_at: now + SESSION_TTL_SECS * 1000,
+        },
+        200,
+    )
+}
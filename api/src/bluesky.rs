@@ -0,0 +1,141 @@
+//! Announcing sent issues on Bluesky. With BLUESKY_HANDLE and the
+//! BLUESKY_APP_PASSWORD secret set, each send posts the issue's title and
+//! link, with a link card, to that account through the AT Protocol
+//! (`com.atproto.repo.createRecord`). BLUESKY_PDS is the account's server,
+//! bsky.social by default.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Error, Fetch, Headers, Method, Request, RequestInit, Result};
+
+use crate::dates;
+use crate::logging::{self, RequestLog};
+use crate::sendlog::SentIssue;
+
+const DEFAULT_PDS: &str = "https://bsky.social";
+
+/// Bluesky's post length limit, in graphemes; counted in chars here, which
+/// is never fewer.
+const MAX_POST_CHARS: usize = 300;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    access_jwt: String,
+    did: String,
+}
+
+#[derive(Deserialize)]
+struct CreatedRecord {
+    uri: String,
+}
+
+/// The post's text — the title, then the link — and the link's byte range
+/// in it, for the facet that makes it clickable.
+fn post_text(issue: &SentIssue) -> (String, usize, usize) {
+    let room = MAX_POST_CHARS.saturating_sub(issue.post_url.chars().count() + 2);
+    let mut title: String = issue.title.chars().take(room).collect();
+    if title.chars().count() < issue.title.chars().count() {
+        title.pop();
+        title.push('…');
+    }
+    let text = format!("{}\n\n{}", title, issue.post_url);
+    let (start, end) = (text.len() - issue.post_url.len(), text.len());
+    (text, start, end)
+}
+
+async fn xrpc<T: for<'de> Deserialize<'de>>(
+    log: &RequestLog,
+    pds: &str,
+    method: &str,
+    token: Option<&str>,
+    body: &Value,
+) -> Result<T> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    if let Some(token) = token {
+        headers.set("Authorization", &format!("Bearer {}", token))?;
+    }
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    init.with_headers(headers);
+    init.with_body(Some(JsValue::from_str(&body.to_string())));
+    let req = Request::new_with_init(&format!("{}/xrpc/{}", pds, method), &init)?;
+
+    let operation = method.rsplit('.').next().unwrap_or(method);
+    let started = logging::now_millis();
+    let mut resp = match Fetch::Request(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            log.upstream("bluesky", operation, started, None, Some(&e.to_string()));
+            return Err(e);
+        }
+    };
+    let status = resp.status_code();
+    log.upstream("bluesky", operation, started, Some(status), None);
+    if status != 200 {
+        let detail = resp.text().await.unwrap_or_default();
+        return Err(Error::RustError(format!(
+            "{} failed (status {}): {}",
+            method, status, detail
+        )));
+    }
+    resp.json().await
+}
+
+/// Post `issue` to the configured account: the record's `at://` URI, or
+/// `None` if Bluesky isn't configured.
+pub async fn announce(env: &Env, log: &RequestLog, issue: &SentIssue) -> Result<Option<String>> {
+    let (Ok(handle), Ok(password)) = (
+        env.var("BLUESKY_HANDLE"),
+        env.secret("BLUESKY_APP_PASSWORD"),
+    ) else {
+        return Ok(None);
+    };
+    let pds = env
+        .var("BLUESKY_PDS")
+        .map(|v| v.to_string().trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| DEFAULT_PDS.to_string());
+
+    let session: Session = xrpc(
+        log,
+        &pds,
+        "com.atproto.server.createSession",
+        None,
+        &json!({ "identifier": handle.to_string(), "password": password.to_string() }),
+    )
+    .await?;
+
+    let (text, link_start, link_end) = post_text(issue);
+    let record = json!({
+        "$type": "app.bsky.feed.post",
+        "text": text,
+        "createdAt": dates::rfc3339(issue.sent_at),
+        "facets": [{
+            "index": { "byteStart": link_start, "byteEnd": link_end },
+            "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": issue.post_url }],
+        }],
+        "embed": {
+            "$type": "app.bsky.embed.external",
+            "external": {
+                "uri": issue.post_url,
+                "title": issue.title,
+                "description": issue.description,
+            },
+        },
+    });
+    let created: CreatedRecord = xrpc(
+        log,
+        &pds,
+        "com.atproto.repo.createRecord",
+        Some(&session.access_jwt),
+        &json!({
+            "repo": session.did,
+            "collection": "app.bsky.feed.post",
+            "record": record,
+        }),
+    )
+    .await?;
+    Ok(Some(created.uri))
+}
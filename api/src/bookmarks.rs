@@ -0,0 +1,358 @@
+//! Bookmarks: links worth sharing, in the `bookmarks` D1 table
+//! (`migrations/0007_bookmarks.sql`), for the site's links page and link
+//! roundups. Added by the admin, with the title taken from the page itself
+//! when not given; listed publicly, newest first.
+
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use lol_html::{element, rewrite_str, text, RewriteStrSettings};
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::{Fetch, Headers, Method, Request, RequestInit, Response, Result, RouteContext, Url};
+
+use crate::logging::{self, RequestLog};
+use crate::{json_response, ApiResponse};
+
+const MAX_URL_CHARS: usize = 2048;
+const MAX_TITLE_CHARS: usize = 300;
+const MAX_NOTE_CHARS: usize = 1000;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 40;
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+/// The title is in the head, so the start of the page is enough.
+const TITLE_PROBE_BYTES: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct BookmarkRequest {
+    url: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    note: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StoredBookmark {
+    id: u64,
+    url: String,
+    title: String,
+    note: Option<String>,
+    /// JSON array.
+    tags: String,
+    created_at: u64,
+}
+
+#[derive(Serialize)]
+struct Bookmark {
+    id: u64,
+    url: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    tags: Vec<String>,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+}
+
+impl From<StoredBookmark> for Bookmark {
+    fn from(stored: StoredBookmark) -> Self {
+        Bookmark {
+            id: stored.id,
+            url: stored.url,
+            title: stored.title,
+            note: stored.note,
+            tags: serde_json::from_str(&stored.tags).unwrap_or_default(),
+            created_at: stored.created_at,
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// Lowercased, trimmed and deduplicated, blanks dropped.
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// Undo the entities titles commonly contain.
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// A page's `og:title`, or else its `<title>`, whitespace collapsed.
+fn page_title(html: &str) -> Option<String> {
+    let mut og_title = None;
+    let mut title = String::new();
+    let mut title_done = false;
+    let _ = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("meta[property='og:title'][content]", |el| {
+                    og_title = og_title.take().or(el.get_attribute("content"));
+                    Ok(())
+                }),
+                // The first title only: SVGs have their own
+                text!("title", |chunk| {
+                    if !title_done {
+                        title.push_str(chunk.as_str());
+                        title_done = chunk.last_in_text_node();
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    );
+    let title = og_title.unwrap_or(title);
+    let title = decode_entities(&title)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!title.is_empty()).then(|| title.chars().take(MAX_TITLE_CHARS).collect())
+}
+
+/// The title of the page at `url`, if it can be fetched and has one.
+async fn fetch_title(log: &RequestLog, url: &str) -> Option<String> {
+    let headers = Headers::new();
+    headers.set("Accept", "text/html").ok()?;
+    headers
+        .set("Range", &format!("bytes=0-{}", TITLE_PROBE_BYTES - 1))
+        .ok()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get);
+    init.with_headers(headers);
+    let req = Request::new_with_init(url, &init).ok()?;
+
+    let started = logging::now_millis();
+    let result = Fetch::Request(req).send().await;
+    let mut resp = match result {
+        Ok(resp) => resp,
+        Err(e) => {
+            let error = e.to_string();
+            log.upstream("bookmarks", "fetch_title", started, None, Some(&error));
+            return None;
+        }
+    };
+    log.upstream(
+        "bookmarks",
+        "fetch_title",
+        started,
+        Some(resp.status_code()),
+        None,
+    );
+    if !matches!(resp.status_code(), 200 | 206) {
+        return None;
+    }
+    // Servers ignoring the Range send the whole page: read only the start
+    let mut body = resp.stream().ok()?;
+    let mut head = Vec::new();
+    while head.len() < TITLE_PROBE_BYTES {
+        match body.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk.ok()?),
+            None => break,
+        }
+    }
+    head.truncate(TITLE_PROBE_BYTES);
+    page_title(&String::from_utf8_lossy(&head))
+}
+
+/// GET /api/bookmarks?limit=N&before=ID&tag=T — bookmarks newest first, 20
+/// at a time by default (at most 100). `next_before` is the `before` for the
+/// next page, when there is one.
+pub async fn handle_list(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let params: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+    let before = params
+        .get("before")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(i64::MAX as u64);
+    let tag = params
+        .get("tag")
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty());
+
+    let db = ctx.env.d1("DB")?;
+    // One extra row tells whether there's another page
+    let mut bookmarks: Vec<Bookmark> = db
+        .prepare(
+            "SELECT id, url, title, note, tags, created_at FROM bookmarks \
+             WHERE id < ?1 \
+             AND (?3 IS NULL OR EXISTS (SELECT 1 FROM json_each(bookmarks.tags) WHERE value = ?3)) \
+             ORDER BY id DESC LIMIT ?2",
+        )
+        .bind(&[
+            JsValue::from_f64(before as f64),
+            JsValue::from(limit + 1),
+            tag.map_or(JsValue::NULL, JsValue::from),
+        ])?
+        .all()
+        .await?
+        .results::<StoredBookmark>()?
+        .into_iter()
+        .map(Bookmark::from)
+        .collect();
+    let next_before = if bookmarks.len() > limit as usize {
+        bookmarks.truncate(limit as usize);
+        bookmarks.last().map(|b| b.id)
+    } else {
+        None
+    };
+
+    #[derive(Serialize)]
+    struct ListResponse {
+        bookmarks: Vec<Bookmark>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_before: Option<u64>,
+    }
+
+    let mut resp = json_response(
+        &ListResponse {
+            bookmarks,
+            next_before,
+        },
+        200,
+    )?;
+    resp.headers_mut()
+        .set("Cache-Control", "public, max-age=300")?;
+    Ok(resp)
+}
+
+/// POST /api/bookmarks — admin: bookmark a link:
+/// `{"url", "title"?, "note"?, "tags"?: [...]}`. Without a title, the page's
+/// own is used. 409 if the URL is already bookmarked.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(body) = req.json::<BookmarkRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"url\": \"https://...\", \"note\"?, \"tags\"?}",
+        );
+    };
+    let url = body.url.trim();
+    let valid_url = Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"));
+    if !valid_url || url.chars().count() > MAX_URL_CHARS {
+        return error(400, "url must be an http(s) URL of at most 2048 characters");
+    }
+    let note = body
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty());
+    if note.is_some_and(|n| n.chars().count() > MAX_NOTE_CHARS) {
+        return error(400, "note can be at most 1000 characters");
+    }
+    let tags = normalize_tags(&body.tags);
+    if tags.len() > MAX_TAGS || tags.iter().any(|t| t.chars().count() > MAX_TAG_CHARS) {
+        return error(400, "At most 10 tags, of at most 40 characters each");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let existing: Option<u64> = db
+        .prepare("SELECT id FROM bookmarks WHERE url = ?1")
+        .bind(&[url.into()])?
+        .first(Some("id"))
+        .await?;
+    if existing.is_some() {
+        return error(409, "That URL is already bookmarked");
+    }
+
+    let given_title = body
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| t.chars().take(MAX_TITLE_CHARS).collect());
+    let title = match given_title {
+        Some(title) => title,
+        None => fetch_title(&ctx.data, url)
+            .await
+            .unwrap_or_else(|| url.to_string()),
+    };
+    let created_at = logging::now_millis();
+    let tags_json = serde_json::to_string(&tags).unwrap_or_else(|_| "[]".into());
+    let inserted = db
+        .prepare(
+            "INSERT INTO bookmarks (url, title, note, tags, created_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&[
+            url.into(),
+            title.as_str().into(),
+            note.map_or(JsValue::NULL, JsValue::from),
+            tags_json.into(),
+            JsValue::from_f64(created_at as f64),
+        ])?
+        .run()
+        .await?;
+    let id = inserted
+        .meta()?
+        .and_then(|m| m.last_row_id)
+        .unwrap_or_default();
+
+    json_response(
+        &Bookmark {
+            id: id as u64,
+            url: url.to_string(),
+            title,
+            note: note.map(String::from),
+            tags,
+            created_at,
+        },
+        201,
+    )
+}
+
+/// DELETE /api/bookmarks/{id} — admin: remove a bookmark.
+pub async fn handle_delete(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Some(id) = ctx.param("id").and_then(|id| id.parse::<u64>().ok()) else {
+        return error(400, "Invalid bookmark id");
+    };
+    let deleted = ctx
+        .env
+        .d1("DB")?
+        .prepare("DELETE FROM bookmarks WHERE id = ?1")
+        .bind(&[JsValue::from_f64(id as f64)])?
+        .run()
+        .await?;
+    if deleted.meta()?.and_then(|m| m.changes).unwrap_or_default() == 0 {
+        return error(404, "No such bookmark");
+    }
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        200,
+    )
+}
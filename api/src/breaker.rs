@@ -0,0 +1,89 @@
+//! Circuit breaker for upstreams that go down as a whole (Stalwart): after
+//! [`FAILURE_THRESHOLD`] failures in a row, calls are refused for
+//! [`OPEN_MILLIS`] instead of each one waiting on a dead server, and the
+//! handlers answer 503 with Retry-After. Then one call is let through to
+//! try; a success closes the breaker, a failure keeps it open.
+//!
+//! The counts are per isolate, kept in memory: each isolate notices an
+//! outage on its own, which takes only a few requests, and nothing is
+//! shared that could itself be down.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use worker::{Response, Result};
+
+use crate::logging;
+use crate::{json_response, ApiResponse};
+
+const FAILURE_THRESHOLD: u32 = 3;
+
+const OPEN_MILLIS: u64 = 30 * 1000;
+
+#[derive(Default)]
+struct Breaker {
+    /// Failures since the last success.
+    failures: u32,
+    /// While open, when the next call may try. Milliseconds since the Unix
+    /// epoch.
+    retry_at: u64,
+}
+
+thread_local! {
+    static BREAKERS: RefCell<HashMap<&'static str, Breaker>> = RefCell::new(HashMap::new());
+}
+
+/// Whether a call to `service` may go ahead now; if not, seconds until one
+/// may. Check once per call, before making it: a call let through while
+/// the breaker is open is its trial, and holds off the others until it's
+/// recorded or the window passes again.
+pub fn check(service: &'static str) -> std::result::Result<(), u64> {
+    let now = logging::now_millis();
+    BREAKERS.with(|b| {
+        let mut breakers = b.borrow_mut();
+        let breaker = breakers.entry(service).or_default();
+        if breaker.failures < FAILURE_THRESHOLD {
+            return Ok(());
+        }
+        if now < breaker.retry_at {
+            return Err((breaker.retry_at - now).div_ceil(1000));
+        }
+        breaker.retry_at = now + OPEN_MILLIS;
+        Ok(())
+    })
+}
+
+/// Record how a call to `service` went. Only errors and 5xxs should count
+/// as failures; a 4xx is the server working.
+pub fn record(service: &'static str, ok: bool) {
+    let now = logging::now_millis();
+    BREAKERS.with(|b| {
+        let mut breakers = b.borrow_mut();
+        let breaker = breakers.entry(service).or_default();
+        if ok {
+            *breaker = Breaker::default();
+            return;
+        }
+        breaker.failures += 1;
+        if breaker.failures == FAILURE_THRESHOLD {
+            worker::console_warn!("{} keeps failing, pausing calls to it", service);
+        }
+        if breaker.failures >= FAILURE_THRESHOLD {
+            breaker.retry_at = now + OPEN_MILLIS;
+        }
+    })
+}
+
+/// 503 for a request refused because an upstream it needs is down.
+pub fn unavailable(retry_after_secs: u64) -> Result<Response> {
+    let mut resp = json_response(
+        &ApiResponse {
+            success: false,
+            error: Some("Temporarily unavailable, try again shortly".into()),
+        },
+        503,
+    )?;
+    resp.headers_mut()
+        .set("Retry-After", &retry_after_secs.to_string())?;
+    Ok(resp)
+}
@@ -0,0 +1,593 @@
+//! Reader comments, in the `comments` D1 table
+//! (`migrations/0002_comments.sql`).
+//!
+//! Comments are markdown, rendered with [`markdown::render_comment`] when
+//! read, so nothing a commenter writes reaches the page as HTML.
+//!
+//! Each comment is `pending` until moderated, then `approved` (shown) or
+//! `spam`. Submissions that look like spam start as `spam`, with the reason
+//! kept for review. New pending comments are announced by email to
+//! COMMENT_NOTIFY_EMAIL, if set.
+//!
+//! A comment can reply to another on the same post. Commenters who leave
+//! their email can ask to hear about replies: when a reply to their comment
+//! is approved, they're emailed a link to it, with a link to stop these
+//! emails (`/api/comment-replies/unsubscribe`). The guestbook (`guestbook.rs`) is moderated
+//! the same way, with the helpers here.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::wasm_bindgen::JsValue;
+use worker::{Error, FormEntry, Request, Response, Result, RouteContext};
+
+use crate::logging::{self, RequestLog};
+use crate::{
+    conditional, flags, html, is_valid_email, is_valid_slug, jmap_send_email, jobs, json_response,
+    markdown, ApiResponse,
+};
+
+const MAX_NAME_CHARS: usize = 80;
+const MAX_BODY_CHARS: usize = 5000;
+
+/// More links than this in one submission is almost always spam.
+const MAX_LINKS: usize = 3;
+
+/// Approving a comment shows up within a minute.
+const LIST_CACHE_CONTROL: &str = "public, max-age=60";
+
+#[derive(Deserialize)]
+struct CommentRequest {
+    name: String,
+    #[serde(default)]
+    email: Option<String>,
+    body: String,
+    /// The comment this replies to.
+    #[serde(default)]
+    parent_id: Option<u64>,
+    /// Email the commenter when someone replies; needs `email`.
+    #[serde(default)]
+    notify_replies: bool,
+    /// Honeypot: hidden in the form, so only bots fill it in.
+    #[serde(default)]
+    website: String,
+}
+
+#[derive(Deserialize)]
+struct StoredComment {
+    id: u64,
+    parent_id: Option<u64>,
+    name: String,
+    body: String,
+    created_at: u64,
+}
+
+/// A comment or guestbook entry as the moderation endpoints show it.
+#[derive(Serialize, Deserialize)]
+pub struct ModerationEntry {
+    id: u64,
+    /// The post commented on; guestbook entries have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    slug: Option<String>,
+    name: String,
+    email: Option<String>,
+    body: String,
+    created_at: u64,
+    status: String,
+    spam_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Comment {
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<u64>,
+    name: String,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    html: String,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// Why a submission looks like spam, if it does, from its honeypot field and
+/// body.
+pub fn spam_reason(honeypot: &str, body: &str) -> Option<&'static str> {
+    if !honeypot.is_empty() {
+        return Some("honeypot");
+    }
+    if body.matches("http://").count() + body.matches("https://").count() > MAX_LINKS {
+        return Some("too many links");
+    }
+    None
+}
+
+fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+pub fn hash_ip(ip: &str) -> String {
+    Sha256::digest(ip.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// GET /api/comments/{slug} — the post's comments, oldest first, as
+/// sanitized HTML.
+pub async fn handle_list(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let stored: Vec<StoredComment> = db
+        .prepare(
+            "SELECT id, parent_id, name, body, created_at FROM comments \
+             WHERE slug = ?1 AND status = 'approved' ORDER BY created_at",
+        )
+        .bind(&[slug.into()])?
+        .all()
+        .await?
+        .results()?;
+
+    #[derive(Serialize)]
+    struct ListResponse {
+        comments: Vec<Comment>,
+    }
+
+    let comments = stored
+        .into_iter()
+        .map(|c| Comment {
+            id: c.id,
+            parent_id: c.parent_id,
+            name: c.name,
+            created_at: c.created_at,
+            html: markdown::render_comment(&c.body),
+        })
+        .collect();
+    let resp = json_response(&ListResponse { comments }, 200)?;
+    conditional::respond(&req, resp, LIST_CACHE_CONTROL)
+}
+
+/// POST /api/comments/{slug} — add a comment: `{"name", "email"?, "body",
+/// "parent_id"?, "notify_replies"?}`.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+
+    let Ok(comment) = req.json::<CommentRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"name\": \"...\", \"body\": \"...\"}",
+        );
+    };
+    let name = comment.name.trim();
+    let body = comment.body.trim();
+    let email = comment
+        .email
+        .as_deref()
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty());
+
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return error(400, "Name must be 1–80 characters");
+    }
+    if body.is_empty() || body.chars().count() > MAX_BODY_CHARS {
+        return error(400, "Comment must be 1–5000 characters");
+    }
+    if email.as_deref().is_some_and(|e| !is_valid_email(e)) {
+        return error(400, "Invalid email address");
+    }
+    if comment.notify_replies && email.is_none() {
+        return error(400, "An email address is needed to hear about replies");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    if let Some(parent_id) = comment.parent_id {
+        // Only shown comments on the same post can be replied to
+        let parent: Option<u64> = db
+            .prepare(
+                "SELECT id FROM comments \
+                 WHERE id = ?1 AND slug = ?2 AND status = 'approved'",
+            )
+            .bind(&[JsValue::from_f64(parent_id as f64), slug.as_str().into()])?
+            .first(Some("id"))
+            .await?;
+        if parent.is_none() {
+            return error(400, "No such comment to reply to");
+        }
+    }
+    let opt_out_token = if comment.notify_replies {
+        Some(random_token()?)
+    } else {
+        None
+    };
+
+    let spam = spam_reason(&comment.website, &comment.body);
+    let headers = req.headers();
+    let ip_hash = headers.get("CF-Connecting-IP")?.map(|ip| hash_ip(&ip));
+    let user_agent = headers.get("User-Agent")?;
+    let optional = |v: Option<String>| v.map_or(JsValue::NULL, JsValue::from);
+
+    let inserted = db
+        .prepare(
+            "INSERT INTO comments \
+             (slug, name, email, body, created_at, ip_hash, user_agent, status, spam_reason, \
+              parent_id, notify_replies, opt_out_token) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )
+        .bind(&[
+            slug.as_str().into(),
+            name.into(),
+            optional(email),
+            body.into(),
+            JsValue::from_f64(worker::Date::now().as_millis() as f64),
+            optional(ip_hash),
+            optional(user_agent),
+            if spam.is_some() { "spam" } else { "pending" }.into(),
+            optional(spam.map(String::from)),
+            comment
+                .parent_id
+                .map_or(JsValue::NULL, |id| JsValue::from_f64(id as f64)),
+            JsValue::from(comment.notify_replies as u32),
+            optional(opt_out_token),
+        ])?
+        .run()
+        .await?;
+
+    match spam {
+        Some(reason) => worker::console_warn!("Comment on {} flagged as spam: {}", slug, reason),
+        None => {
+            let id = inserted
+                .meta()?
+                .and_then(|m| m.last_row_id)
+                .unwrap_or_default();
+            let intro = format!(
+                "<strong>{}</strong> commented on <strong>{}</strong>:",
+                html::escape(name),
+                html::escape(&slug)
+            );
+            notify(
+                &ctx,
+                &format!("New comment on {} from {}", slug, name),
+                &intro,
+                body,
+                &format!("/api/admin/comments/{}", id),
+            );
+        }
+    }
+    // Spam gets the same answer, so bots can't tell they were caught
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        202,
+    )
+}
+
+/// Email COMMENT_NOTIFY_EMAIL that a submission awaits moderation at
+/// `admin_path`: `intro` (HTML) over the rendered `body`. The email goes out
+/// in the background, and failures are only logged: the submission is
+/// saved either way.
+pub fn notify(
+    ctx: &RouteContext<RequestLog>,
+    subject: &str,
+    intro: &str,
+    body: &str,
+    admin_path: &str,
+) {
+    let Ok(to) = ctx.env.var("COMMENT_NOTIFY_EMAIL").map(|v| v.to_string()) else {
+        return;
+    };
+    let config = |name: &str| ctx.env.var(name).map(|v| v.to_string());
+    let (Ok(jmap_url), Ok(account_id), Ok(identity_id), Ok(credentials)) = (
+        config("JMAP_API_URL"),
+        config("JMAP_ACCOUNT_ID"),
+        config("JMAP_IDENTITY_ID"),
+        ctx.env.secret("JMAP_CREDENTIALS").map(|v| v.to_string()),
+    ) else {
+        worker::console_error!("Can't notify about {}: JMAP config missing", admin_path);
+        return;
+    };
+
+    let html = format!(
+        "<p>{}</p>\
+         <blockquote>{}</blockquote>\
+         <p>Approve with <code>POST {}/approve</code> \
+         or reject with <code>/reject</code>.</p>",
+        intro,
+        markdown::render_comment(body),
+        html::escape(admin_path)
+    );
+
+    let subject = subject.to_string();
+    let log = ctx.data.clone();
+    ctx.data.wait_until(async move {
+        let started = logging::now_millis();
+        let result = jmap_send_email(
+            &jmap_url,
+            &credentials,
+            &account_id,
+            &identity_id,
+            "postmaster@lindfors.no",
+            &to,
+            &subject,
+            &html,
+        )
+        .await;
+        log.upstream_status("jmap", "notify_comment", started, &result);
+    });
+}
+
+/// GET /api/admin/comments?status=pending|approved|spam — admin: comments in
+/// a moderation state (default pending), oldest first.
+pub async fn handle_moderation_list(
+    req: Request,
+    ctx: RouteContext<RequestLog>,
+) -> Result<Response> {
+    let Some(status) = requested_status(&req)? else {
+        return error(400, "status must be pending, approved or spam");
+    };
+    let comments = moderation_entries(&ctx, "comments", &status).await?;
+
+    #[derive(Serialize)]
+    struct ModerationResponse {
+        comments: Vec<ModerationEntry>,
+    }
+
+    json_response(&ModerationResponse { comments }, 200)
+}
+
+/// The `?status=` a moderation list asks for (default pending), or None if
+/// it isn't a moderation state.
+pub fn requested_status(req: &Request) -> Result<Option<String>> {
+    let status = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "status")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_else(|| "pending".into());
+    Ok(["pending", "approved", "spam"]
+        .contains(&status.as_str())
+        .then_some(status))
+}
+
+/// Entries of `table` (`comments` or `guestbook`) in a moderation state,
+/// oldest first.
+pub async fn moderation_entries(
+    ctx: &RouteContext<RequestLog>,
+    table: &'static str,
+    status: &str,
+) -> Result<Vec<ModerationEntry>> {
+    let slug = if table == "comments" { "slug, " } else { "" };
+    let db = ctx.env.d1("DB")?;
+    db.prepare(format!(
+        "SELECT id, {}name, email, body, created_at, status, spam_reason \
+         FROM {} WHERE status = ?1 ORDER BY created_at",
+        slug, table
+    ))
+    .bind(&[status.into()])?
+    .all()
+    .await?
+    .results()
+}
+
+/// POST /api/admin/comments/{id}/approve — admin: show a comment. The first
+/// time a reply is shown, the comment it replies to is told, if its author
+/// asked to be.
+pub async fn handle_approve(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let id = ctx.param("id").and_then(|id| id.parse::<u64>().ok());
+    let reply = match id {
+        Some(id) if flags::load(&ctx.env).await.reply_notifications => {
+            pending_reply(&ctx, id).await?
+        }
+        _ => None,
+    };
+    let resp = moderate(&ctx, "comments", "approved").await?;
+    if let Some(reply) = reply.filter(|_| resp.status_code() == 200) {
+        notify_reply(&ctx, &reply).await;
+    }
+    Ok(resp)
+}
+
+/// POST /api/admin/comments/{id}/reject — admin: hide a comment as spam.
+pub async fn handle_reject(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    moderate(&ctx, "comments", "spam").await
+}
+
+/// Set the status of the `table` row with the route's `{id}`.
+pub async fn moderate(
+    ctx: &RouteContext<RequestLog>,
+    table: &'static str,
+    status: &str,
+) -> Result<Response> {
+    let Some(id) = ctx.param("id").and_then(|id| id.parse::<u64>().ok()) else {
+        return error(400, "Invalid id");
+    };
+
+    let db = ctx.env.d1("DB")?;
+    let updated = db
+        .prepare(format!("UPDATE {} SET status = ?1 WHERE id = ?2", table))
+        .bind(&[status.into(), JsValue::from_f64(id as f64)])?
+        .run()
+        .await?;
+    if updated.meta()?.and_then(|m| m.changes).unwrap_or(0) == 0 {
+        return error(404, "Not found");
+    }
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        200,
+    )
+}
+
+/// An unapproved reply, with whom to tell about it.
+#[derive(Deserialize)]
+struct PendingReply {
+    id: u64,
+    slug: String,
+    name: String,
+    body: String,
+    parent_name: String,
+    parent_email: String,
+    opt_out_token: String,
+}
+
+/// Comment `id`, if it's a not yet approved reply to a comment whose author
+/// wants to hear about replies (from someone else).
+async fn pending_reply(ctx: &RouteContext<RequestLog>, id: u64) -> Result<Option<PendingReply>> {
+    let db = ctx.env.d1("DB")?;
+    db.prepare(
+        "SELECT c.id, c.slug, c.name, c.body, p.name AS parent_name, \
+         p.email AS parent_email, p.opt_out_token \
+         FROM comments c JOIN comments p ON p.id = c.parent_id \
+         WHERE c.id = ?1 AND c.status != 'approved' AND p.notify_replies = 1 \
+         AND p.email IS NOT NULL AND p.opt_out_token IS NOT NULL \
+         AND (c.email IS NULL OR c.email != p.email)",
+    )
+    .bind(&[JsValue::from_f64(id as f64)])?
+    .first(None)
+    .await
+}
+
+/// Queue an email to the author of the comment `reply` answers (see
+/// jobs.rs). Failures are only logged: the reply is approved either way.
+async fn notify_reply(ctx: &RouteContext<RequestLog>, reply: &PendingReply) {
+    let Ok(site_url) = ctx.env.var("SITE_URL").map(|v| v.to_string()) else {
+        worker::console_error!("Can't notify about reply {}: SITE_URL missing", reply.id);
+        return;
+    };
+
+    let thread = format!("{}/blog/{}/#comment-{}", site_url, reply.slug, reply.id);
+    let opt_out = format!(
+        "{}/api/comment-replies/unsubscribe?token={}",
+        site_url, reply.opt_out_token
+    );
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<body style="font-family: Georgia, serif; color: #1C3240; line-height: 1.6;">
+    <p>Hi {parent_name}, <strong>{name}</strong> replied to your comment on lindfors.no:</p>
+    <blockquote>{body}</blockquote>
+    <p><a href="{thread}">See the conversation</a></p>
+    <p style="font-size: 13px; color: #6B7B85;">You're getting this because you asked to hear about replies. <a href="{opt_out}">Stop these emails</a>.</p>
+</body>
+</html>"#,
+        parent_name = html::escape(&reply.parent_name),
+        name = html::escape(&reply.name),
+        body = markdown::render_comment(&reply.body),
+        thread = html::escape(&thread),
+        opt_out = html::escape(&opt_out),
+    );
+
+    let job = jobs::Job::Email {
+        to: reply.parent_email.clone(),
+        subject: format!("{} replied to your comment", reply.name),
+        html,
+        operation: "notify_reply".into(),
+    };
+    let result = jobs::enqueue(&ctx.env, &job).await;
+    if let Err(e) = &result {
+        worker::console_error!("Failed to queue notification of reply {}: {}", reply.id, e);
+    }
+    ctx.data.event("comment_reply_notify", result.is_ok());
+}
+
+/// GET /api/comment-replies/unsubscribe?token=... — confirm stopping reply
+/// emails. A page with a button rather than a link that acts, so mail
+/// scanners following links don't opt anyone out.
+pub async fn handle_opt_out_page(req: Request, _ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let token = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    Response::from_html(opt_out_page(&format!(
+        r#"<p>Stop getting emails about replies to your comments on lindfors.no?</p>
+    <form method="post">
+        <input type="hidden" name="token" value="{}">
+        <button type="submit">Stop reply emails</button>
+    </form>"#,
+        html::escape(&token)
+    )))
+}
+
+/// POST /api/comment-replies/unsubscribe — stop reply emails for every
+/// comment left with the token's email address.
+pub async fn handle_opt_out(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let token = match req.form_data().await {
+        Ok(form) => match form.get("token") {
+            Some(FormEntry::Field(token)) => token,
+            _ => String::new(),
+        },
+        Err(_) => String::new(),
+    };
+    if token.is_empty() {
+        return error(400, "A token is required");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let updated = db
+        .prepare(
+            "UPDATE comments SET notify_replies = 0, opt_out_token = NULL \
+             WHERE email = (SELECT email FROM comments WHERE opt_out_token = ?1)",
+        )
+        .bind(&[token.into()])?
+        .run()
+        .await?;
+    let changed = updated.meta()?.and_then(|m| m.changes).unwrap_or(0) > 0;
+    ctx.data.event("comment_reply_opt_out", changed);
+    if !changed {
+        return Ok(Response::from_html(opt_out_page(
+            "<p>This link has already been used, or isn't valid.</p>",
+        ))?
+        .with_status(404));
+    }
+    Response::from_html(opt_out_page(
+        "<p>Done. You won't get emails about replies to your comments any more.</p>",
+    ))
+}
+
+fn opt_out_page(content: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="robots" content="noindex">
+    <title>Reply emails - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 480px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        p {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+        button {{ padding: 10px 18px; background: #D4706A; color: #F0EAE0; border: none; border-radius: 6px; font-size: 14px; font-weight: 600; cursor: pointer; font-family: -apple-system, sans-serif; }}
+        button:hover {{ background: #B85A54; }}
+    </style>
+</head>
+<body>
+    <h1>Reply emails</h1>
+    {content}
+    <p style="margin-top: 32px;"><a href="https://lindfors.no">Back to lindfors.no</a></p>
+</body>
+</html>"#
+    )
+}
@@ -0,0 +1,48 @@
+//! Conditional GETs for public responses: [`respond`] tags a response with
+//! an ETag (a hash of its body) and Cache-Control, and answers 304 Not
+//! Modified, without the body, when the request's If-None-Match already
+//! has that ETag. The CDN and browsers revalidate instead of refetching.
+
+use sha2::{Digest, Sha256};
+use worker::{Request, Response, ResponseBody, Result};
+
+fn etag(body: &[u8]) -> String {
+    let digest = Sha256::digest(body);
+    let hex: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("\"{hex}\"")
+}
+
+/// Whether an If-None-Match header value lists `etag`. Comparison is weak,
+/// as RFC 9110 has it for If-None-Match: a `W/` prefix doesn't matter.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
+
+/// `resp` with `cache_control`, and for a 200 its ETag, or a 304 in its
+/// place if the client has it already. Streamed bodies aren't tagged.
+pub fn respond(req: &Request, mut resp: Response, cache_control: &str) -> Result<Response> {
+    resp.headers_mut().set("Cache-Control", cache_control)?;
+    if resp.status_code() != 200 {
+        return Ok(resp);
+    }
+    let ResponseBody::Body(body) = resp.body() else {
+        return Ok(resp);
+    };
+    let etag = etag(body);
+    resp.headers_mut().set("ETag", &etag)?;
+
+    let cached = req
+        .headers()
+        .get("If-None-Match")?
+        .is_some_and(|header| matches(&header, &etag));
+    if !cached {
+        return Ok(resp);
+    }
+    let headers = resp.headers().clone();
+    headers.delete("Content-Length")?;
+    Ok(Response::empty()?.with_status(304).with_headers(headers))
+}
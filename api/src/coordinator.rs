@@ -0,0 +1,227 @@
+//! One Durable Object per issue (the SEND_COORDINATOR binding, named by
+//! slug) coordinates its send. A send first claims the issue; while one is
+//! in flight a second claim is refused, so two admin requests racing each
+//! other can't both mail the list. The object also records how each
+//! recipient fared, for GET /api/admin/send-status/{slug}.
+//!
+//! A Durable Object handles one request's storage reads and writes at a
+//! time, so a claim's check and write can't interleave with another's. A
+//! claim left `pending` by a worker that died mid-send lapses after
+//! [`STALE_AFTER_MILLIS`].
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+// The Durable Object macro's generated code expects `wasm_bindgen` by name
+use worker::wasm_bindgen;
+use worker::{
+    durable_object, DurableObject, Env, Method, Request, RequestInit, Response, Result,
+    RouteContext, State, Stub,
+};
+
+use crate::logging::RequestLog;
+use crate::{is_valid_slug, json_response, ApiResponse};
+
+const STATE_KEY: &str = "send";
+
+const STALE_AFTER_MILLIS: u64 = 15 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Progress {
+    Pending,
+    Sent,
+    Failed,
+}
+
+/// An issue's latest send.
+#[derive(Serialize, Deserialize)]
+pub struct SendState {
+    /// `Pending` while sending, then how it ended.
+    pub status: Progress,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    pub recipients: BTreeMap<String, Progress>,
+}
+
+/// What a claim got.
+pub enum Claim {
+    Claimed,
+    /// Another send is in flight.
+    Busy(SendState),
+}
+
+#[derive(Deserialize)]
+struct ClaimRequest {
+    recipients: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ProgressRequest {
+    recipient: String,
+    ok: bool,
+}
+
+#[derive(Deserialize)]
+struct FinishRequest {
+    ok: bool,
+}
+
+fn outcome(ok: bool) -> Progress {
+    if ok {
+        Progress::Sent
+    } else {
+        Progress::Failed
+    }
+}
+
+#[durable_object(fetch)]
+pub struct SendCoordinator {
+    state: State,
+}
+
+impl SendCoordinator {
+    async fn load(&self) -> Result<Option<SendState>> {
+        self.state.storage().get(STATE_KEY).await
+    }
+
+    async fn save(&self, send: &SendState) -> Result<()> {
+        self.state.storage().put(STATE_KEY, send).await
+    }
+}
+
+impl DurableObject for SendCoordinator {
+    fn new(state: State, _env: Env) -> Self {
+        SendCoordinator { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let now = worker::Date::now().as_millis();
+        match (req.method(), req.path().as_str()) {
+            (Method::Get, "/status") => match self.load().await? {
+                Some(send) => Response::from_json(&send),
+                None => Response::error("Never sent", 404),
+            },
+            (Method::Post, "/claim") => {
+                let claim: ClaimRequest = req.json().await?;
+                if let Some(send) = self.load().await? {
+                    let in_flight = send.status == Progress::Pending
+                        && now.saturating_sub(send.started_at) < STALE_AFTER_MILLIS;
+                    if in_flight {
+                        return Ok(Response::from_json(&send)?.with_status(409));
+                    }
+                }
+                let send = SendState {
+                    status: Progress::Pending,
+                    started_at: now,
+                    finished_at: None,
+                    recipients: claim
+                        .recipients
+                        .into_iter()
+                        .map(|r| (r, Progress::Pending))
+                        .collect(),
+                };
+                self.save(&send).await?;
+                Response::from_json(&send)
+            }
+            (Method::Post, "/progress") => {
+                let progress: ProgressRequest = req.json().await?;
+                let Some(mut send) = self.load().await? else {
+                    return Response::error("Not claimed", 409);
+                };
+                send.recipients
+                    .insert(progress.recipient, outcome(progress.ok));
+                self.save(&send).await?;
+                Response::from_json(&send)
+            }
+            (Method::Post, "/finish") => {
+                let finish: FinishRequest = req.json().await?;
+                let Some(mut send) = self.load().await? else {
+                    return Response::error("Not claimed", 409);
+                };
+                send.status = outcome(finish.ok);
+                send.finished_at = Some(now);
+                self.save(&send).await?;
+                Response::from_json(&send)
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+fn stub(env: &Env, slug: &str) -> Result<Stub> {
+    env.durable_object("SEND_COORDINATOR")?.get_by_name(slug)
+}
+
+/// Ask `slug`'s coordinator; the host is a placeholder, only the path
+/// reaches the object.
+async fn call(
+    env: &Env,
+    slug: &str,
+    method: Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<Response> {
+    let mut init = RequestInit::new();
+    init.with_method(method);
+    if let Some(body) = body {
+        init.with_body(Some(body.to_string().into()));
+    }
+    let req = Request::new_with_init(&format!("https://coordinator{}", path), &init)?;
+    stub(env, slug)?.fetch_with_request(req).await
+}
+
+/// Claim `slug` for a send to `recipients`.
+pub async fn claim(env: &Env, slug: &str, recipients: &[&str]) -> Result<Claim> {
+    let body = serde_json::json!({ "recipients": recipients });
+    let mut resp = call(env, slug, Method::Post, "/claim", Some(body)).await?;
+    match resp.status_code() {
+        200 => Ok(Claim::Claimed),
+        409 => Ok(Claim::Busy(resp.json().await?)),
+        status => Err(worker::Error::RustError(format!(
+            "Send coordinator answered {}",
+            status
+        ))),
+    }
+}
+
+/// Record whether `slug`'s send reached `recipient`.
+pub async fn record(env: &Env, slug: &str, recipient: &str, ok: bool) -> Result<()> {
+    let body = serde_json::json!({ "recipient": recipient, "ok": ok });
+    call(env, slug, Method::Post, "/progress", Some(body)).await?;
+    Ok(())
+}
+
+/// End `slug`'s send, releasing the claim.
+pub async fn finish(env: &Env, slug: &str, ok: bool) -> Result<()> {
+    let body = serde_json::json!({ "ok": ok });
+    call(env, slug, Method::Post, "/finish", Some(body)).await?;
+    Ok(())
+}
+
+/// GET /api/admin/send-status/{slug} — admin: the issue's latest send.
+pub async fn handle_status(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return json_response(
+            &ApiResponse {
+                success: false,
+                error: Some("Invalid slug".into()),
+            },
+            400,
+        );
+    }
+    let mut resp = call(&ctx.env, &slug, Method::Get, "/status", None).await?;
+    if resp.status_code() != 200 {
+        return json_response(
+            &ApiResponse {
+                success: false,
+                error: Some(format!("{} has never been sent", slug)),
+            },
+            404,
+        );
+    }
+    json_response(&resp.json::<SendState>().await?, 200)
+}
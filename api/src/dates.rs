@@ -0,0 +1,83 @@
+//! Calendar dates from Unix timestamps, without pulling in a date crate.
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Days since the Unix epoch to (year, month, day), proleptic Gregorian.
+/// Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Inverse of [`civil_from_days`]: Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Milliseconds since the Unix epoch at the start (UTC) of a `YYYY-MM-DD`
+/// date, if it's a real date on or after 1970-01-01.
+pub fn parse_iso_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let millis = days * MILLIS_PER_DAY;
+    // Rejects out-of-range parts like 2024-02-30 and unpadded input
+    (iso_date(millis) == date).then_some(millis)
+}
+
+/// `YYYY-MM-DD` (UTC) for milliseconds since the Unix epoch.
+pub fn iso_date(millis: u64) -> String {
+    let (year, month, day) = civil_from_days((millis / MILLIS_PER_DAY) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// RFC 3339 timestamp (UTC, whole seconds) for milliseconds since the epoch.
+pub fn rfc3339(millis: u64) -> String {
+    let secs_of_day = (millis % MILLIS_PER_DAY) / 1000;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        iso_date(millis),
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// HTTP date (RFC 9110 IMF-fixdate), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// for milliseconds since the epoch.
+pub fn http_date(millis: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = (millis / MILLIS_PER_DAY) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let secs_of_day = (millis % MILLIS_PER_DAY) / 1000;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        // The epoch was a Thursday
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
@@ -0,0 +1,197 @@
+//! The DB (D1) schema, as the versioned migrations in `migrations/`, built
+//! into the worker so it can bring its own database up to date:
+//! POST /api/admin/migrate applies the pending ones, and GET shows which
+//! those are. Applied migrations are recorded in `schema_migrations`.
+//!
+//! Migrations applied earlier with `wrangler d1 migrations apply` (listed
+//! in its `d1_migrations` table) count as applied too, and once wrangler
+//! has made that table the worker records its migrations there as well, so
+//! either way works. A database only the worker has migrated has no such
+//! table, and wrangler would apply everything again: keep to the worker
+//! there.
+//! A new migration is a new `migrations/NNNN_*.sql` file plus its line in
+//! [`MIGRATIONS`]; applied files are never edited.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::{D1Database, Request, Response, Result, RouteContext};
+
+use crate::json_response;
+use crate::logging::{self, RequestLog};
+
+/// Every migration, oldest first, named as its file is.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "0001_audit_log.sql",
+        include_str!("../migrations/0001_audit_log.sql"),
+    ),
+    (
+        "0002_comments.sql",
+        include_str!("../migrations/0002_comments.sql"),
+    ),
+    (
+        "0003_comment_status.sql",
+        include_str!("../migrations/0003_comment_status.sql"),
+    ),
+    (
+        "0004_guestbook.sql",
+        include_str!("../migrations/0004_guestbook.sql"),
+    ),
+    (
+        "0005_followers.sql",
+        include_str!("../migrations/0005_followers.sql"),
+    ),
+    (
+        "0006_deliveries.sql",
+        include_str!("../migrations/0006_deliveries.sql"),
+    ),
+    (
+        "0007_bookmarks.sql",
+        include_str!("../migrations/0007_bookmarks.sql"),
+    ),
+    (
+        "0008_comment_replies.sql",
+        include_str!("../migrations/0008_comment_replies.sql"),
+    ),
+];
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (\
+     name TEXT PRIMARY KEY, \
+     applied_at INTEGER NOT NULL)";
+
+#[derive(Deserialize)]
+struct Applied {
+    name: String,
+}
+
+/// The statements of a migration file: `--` comments dropped, split at
+/// the `;`s. The files don't put either inside string literals.
+fn statements(sql: &str) -> Vec<String> {
+    let without_comments: String = sql
+        .lines()
+        .map(|line| line.split_once("--").map_or(line, |(code, _)| code))
+        .collect::<Vec<_>>()
+        .join("\n");
+    without_comments
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Names of the migrations already applied, by the worker or by wrangler,
+/// and whether wrangler's `d1_migrations` table exists.
+async fn applied(db: &D1Database) -> Result<(HashSet<String>, bool)> {
+    db.exec(CREATE_TABLE).await?;
+    let mut names: HashSet<String> = db
+        .prepare("SELECT name FROM schema_migrations")
+        .all()
+        .await?
+        .results::<Applied>()?
+        .into_iter()
+        .map(|a| a.name)
+        .collect();
+
+    let wrangler: Option<String> = db
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'd1_migrations'")
+        .first(Some("name"))
+        .await?;
+    if wrangler.is_some() {
+        let by_wrangler = db
+            .prepare("SELECT name FROM d1_migrations")
+            .all()
+            .await?
+            .results::<Applied>()?;
+        names.extend(by_wrangler.into_iter().map(|a| a.name));
+    }
+    Ok((names, wrangler.is_some()))
+}
+
+fn pending(applied: &HashSet<String>) -> Vec<&'static (&'static str, &'static str)> {
+    MIGRATIONS
+        .iter()
+        .filter(|(name, _)| !applied.contains(*name))
+        .collect()
+}
+
+#[derive(Serialize)]
+struct MigrationsResponse {
+    applied: Vec<&'static str>,
+    pending: Vec<&'static str>,
+}
+
+/// GET /api/admin/migrate — admin: which migrations are applied and which
+/// are pending.
+pub async fn handle_status(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let db = ctx.env.d1("DB")?;
+    let (applied, _) = applied(&db).await?;
+    let (done, pending): (Vec<_>, Vec<_>) = MIGRATIONS
+        .iter()
+        .map(|(name, _)| *name)
+        .partition(|name| applied.contains(*name));
+    json_response(
+        &MigrationsResponse {
+            applied: done,
+            pending,
+        },
+        200,
+    )
+}
+
+/// POST /api/admin/migrate — admin: apply the pending migrations in order;
+/// `applied` lists the ones this run applied. Each runs as one batch (a
+/// transaction) along with its record, so a failing one leaves nothing
+/// half-applied and stops the run.
+pub async fn handle_migrate(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let db = ctx.env.d1("DB")?;
+    let (applied, wrangler) = applied(&db).await?;
+
+    let pending = pending(&applied);
+    let mut done = Vec::new();
+    for (i, (name, sql)) in pending.iter().enumerate() {
+        let mut batch = statements(sql)
+            .iter()
+            .map(|statement| db.prepare(statement))
+            .collect::<Vec<_>>();
+        batch.push(
+            db.prepare("INSERT INTO schema_migrations (name, applied_at) VALUES (?1, ?2)")
+                .bind(&[
+                    (*name).into(),
+                    JsValue::from_f64(logging::now_millis() as f64),
+                ])?,
+        );
+        // So `wrangler d1 migrations apply` doesn't run it again
+        if wrangler {
+            batch.push(
+                db.prepare("INSERT OR IGNORE INTO d1_migrations (name) VALUES (?1)")
+                    .bind(&[(*name).into()])?,
+            );
+        }
+        if let Err(e) = db.batch(batch).await {
+            worker::console_error!("Migration {} failed: {}", name, e);
+            ctx.data.event("migration", false);
+            return json_response(
+                &serde_json::json!({
+                    "success": false,
+                    "error": format!("Migration {} failed: {}", name, e),
+                    "applied": done,
+                    "pending": pending[i..].iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                }),
+                500,
+            );
+        }
+        ctx.data.event("migration", true);
+        done.push(*name);
+    }
+
+    json_response(
+        &MigrationsResponse {
+            applied: done,
+            pending: Vec::new(),
+        },
+        200,
+    )
+}
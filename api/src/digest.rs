@@ -0,0 +1,286 @@
+//! Weekly admin digest: every Monday morning (the [`CRON`] trigger) the
+//! week's numbers are emailed to DIGEST_EMAIL, so the dashboard doesn't need
+//! checking. Unset DIGEST_EMAIL to stop it.
+//!
+//! Subscribes and unsubscribes come from the metrics events (see
+//! metrics.rs), so they need CF_ACCOUNT_ID and CF_ANALYTICS_TOKEN; without
+//! them the digest goes out without those two numbers. Broken links are
+//! the last link audit's (see linkaudit.rs).
+
+use worker::{Env, Error, Result};
+
+use crate::linkaudit::{self, BrokenLink};
+use crate::logging::{self, RequestLog};
+use crate::{dates, html, jmap_send_email, metrics, views};
+
+pub const CRON: &str = "0 7 * * 1";
+
+const SANS: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif";
+
+/// Posts listed under "Most read".
+const TOP_POSTS: usize = 5;
+
+const WEEK_MILLIS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// The week's numbers.
+struct Stats {
+    /// None when the metrics couldn't be read.
+    subscribes: Option<u64>,
+    unsubscribes: Option<u64>,
+    /// Slug and views over the last 7 days, most read first.
+    top_posts: Vec<(String, u64)>,
+    pending_comments: u64,
+    pending_guestbook: u64,
+    /// From the last link audit; None if none has run.
+    broken_links: Option<Vec<BrokenLink>>,
+}
+
+async fn pending(env: &Env, table: &str) -> Result<u64> {
+    let count: Option<u64> = env
+        .d1("DB")?
+        .prepare(format!(
+            "SELECT COUNT(*) AS count FROM {} WHERE status = 'pending'",
+            table
+        ))
+        .first(Some("count"))
+        .await?;
+    Ok(count.unwrap_or(0))
+}
+
+/// Successful subscribes and unsubscribes over the last 7 days.
+async fn subscriber_changes(env: &Env, log: &RequestLog) -> Result<(u64, u64)> {
+    let account_id = env.var("CF_ACCOUNT_ID")?.to_string();
+    let token = env.secret("CF_ANALYTICS_TOKEN")?.to_string();
+
+    let started = logging::now_millis();
+    let counts = metrics::event_counts(
+        &account_id,
+        &token,
+        &["subscribe", "unsubscribe"],
+        "'7' DAY",
+    )
+    .await;
+    let error = counts.as_ref().err().map(|e| e.to_string());
+    log.upstream(
+        "analytics_engine",
+        "event_counts",
+        started,
+        None,
+        error.as_deref(),
+    );
+    let counts = counts?;
+    let count = |name: &str| counts.get(name).copied().unwrap_or(0);
+    Ok((count("subscribe"), count("unsubscribe")))
+}
+
+async fn compile(env: &Env, log: &RequestLog) -> Result<Stats> {
+    let (subscribes, unsubscribes) = match subscriber_changes(env, log).await {
+        Ok((subscribes, unsubscribes)) => (Some(subscribes), Some(unsubscribes)),
+        Err(e) => {
+            worker::console_warn!("Digest without subscriber counts: {}", e);
+            (None, None)
+        }
+    };
+    Ok(Stats {
+        subscribes,
+        unsubscribes,
+        top_posts: views::top_last_7_days(env, TOP_POSTS).await?,
+        pending_comments: pending(env, "comments").await?,
+        pending_guestbook: pending(env, "guestbook").await?,
+        broken_links: linkaudit::last_report(env).await?.map(|r| r.broken),
+    })
+}
+
+/// Compile the week's stats and email them to DIGEST_EMAIL.
+pub async fn send_weekly(env: &Env, log: &RequestLog) -> Result<()> {
+    let Ok(to) = env.var("DIGEST_EMAIL").map(|v| v.to_string()) else {
+        return Ok(());
+    };
+    let stats = compile(env, log).await?;
+
+    let site_url = env.var("SITE_URL")?.to_string();
+    let now = logging::now_millis();
+    let from = dates::iso_date(now.saturating_sub(WEEK_MILLIS));
+    let until = dates::iso_date(now);
+    let html = DigestTemplate {
+        site_url: &site_url,
+        from: &from,
+        until: &until,
+        stats: &stats,
+    }
+    .render();
+
+    let jmap_url = env.var("JMAP_API_URL")?.to_string();
+    let credentials = env.secret("JMAP_CREDENTIALS")?.to_string();
+    let account_id = env.var("JMAP_ACCOUNT_ID")?.to_string();
+    let identity_id = env.var("JMAP_IDENTITY_ID")?.to_string();
+
+    let started = logging::now_millis();
+    let result = jmap_send_email(
+        &jmap_url,
+        &credentials,
+        &account_id,
+        &identity_id,
+        "postmaster@lindfors.no",
+        &to,
+        &format!("lindfors.no this week ({} to {})", from, until),
+        &html,
+    )
+    .await;
+    log.upstream_status("jmap", "send_digest", started, &result);
+    log.event("digest", matches!(result, Ok(200)));
+    match result? {
+        200 => Ok(()),
+        status => Err(Error::RustError(format!(
+            "Sending the digest failed (status {})",
+            status
+        ))),
+    }
+}
+
+/// The digest email.
+struct DigestTemplate<'a> {
+    site_url: &'a str,
+    /// First and last day covered, `YYYY-MM-DD`.
+    from: &'a str,
+    until: &'a str,
+    stats: &'a Stats,
+}
+
+impl DigestTemplate<'_> {
+    fn render(&self) -> String {
+        let partials = [
+            self.heading(),
+            self.subscribers(),
+            self.top_posts(),
+            self.moderation(),
+            self.broken_links(),
+        ];
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>lindfors.no this week</title>
+</head>
+<body style="margin: 0; padding: 0; background-color: #F0EAE0; font-family: Georgia, 'Times New Roman', serif;">
+    <div style="max-width: 600px; margin: 0 auto; padding: 32px 24px; background-color: #ffffff;">
+        {partials}
+    </div>
+</body>
+</html>"#,
+            partials = partials.join("\n        "),
+        )
+    }
+
+    fn heading(&self) -> String {
+        format!(
+            r#"<div style="border-bottom: 2px solid #2A8F82; padding-bottom: 16px; margin-bottom: 24px;">
+            <a href="{site_url}" style="color: #1C3240; text-decoration: none; font-family: {SANS}; font-size: 14px; font-weight: 600;">lindfors.no</a>
+        </div>
+        <h1 style="font-family: {SANS}; font-size: 24px; color: #1C3240; margin: 0 0 4px 0;">This week</h1>
+        <p style="color: #5A7078; font-family: {SANS}; font-size: 13px; margin: 0 0 24px 0;">{from} to {until}</p>"#,
+            site_url = self.site_url,
+            from = self.from,
+            until = self.until,
+        )
+    }
+
+    fn section_title(title: &str) -> String {
+        format!(
+            r#"<h2 style="font-family: {SANS}; font-size: 16px; color: #1C3240; margin: 24px 0 8px 0;">{title}</h2>"#
+        )
+    }
+
+    fn subscribers(&self) -> String {
+        let count = |n: Option<u64>| n.map_or("&ndash;".to_string(), |n| n.to_string());
+        format!(
+            r#"{title}
+        <p style="color: #1C3240; line-height: 1.6; margin: 0;">{subscribes} new, {unsubscribes} unsubscribed</p>"#,
+            title = Self::section_title("Subscribers"),
+            subscribes = count(self.stats.subscribes),
+            unsubscribes = count(self.stats.unsubscribes),
+        )
+    }
+
+    fn top_posts(&self) -> String {
+        let list = if self.stats.top_posts.is_empty() {
+            r#"<p style="color: #5A7078; margin: 0;">No views this week.</p>"#.to_string()
+        } else {
+            let items: String = self
+                .stats
+                .top_posts
+                .iter()
+                .map(|(slug, views)| {
+                    format!(
+                        r#"<li style="margin: 0 0 4px 0;"><a href="{}/blog/{}/" style="color: #D4706A;">{}</a> &middot; {} views</li>"#,
+                        self.site_url,
+                        html::escape(slug),
+                        html::escape(slug),
+                        views
+                    )
+                })
+                .collect();
+            format!(
+                r#"<ol style="color: #1C3240; line-height: 1.6; margin: 0; padding-left: 20px;">{}</ol>"#,
+                items
+            )
+        };
+        format!("{}\n        {}", Self::section_title("Most read"), list)
+    }
+
+    fn moderation(&self) -> String {
+        let stats = self.stats;
+        let waiting = if stats.pending_comments + stats.pending_guestbook == 0 {
+            "Nothing waiting.".to_string()
+        } else {
+            format!(
+                "{} comments and {} guestbook entries waiting \
+                 (<code>GET /api/admin/comments</code>, <code>/api/admin/guestbook</code>).",
+                stats.pending_comments, stats.pending_guestbook
+            )
+        };
+        format!(
+            r#"{title}
+        <p style="color: #1C3240; line-height: 1.6; margin: 0;">{waiting}</p>"#,
+            title = Self::section_title("Moderation"),
+        )
+    }
+
+    fn broken_links(&self) -> String {
+        let content = match &self.stats.broken_links {
+            None => r#"<p style="color: #5A7078; margin: 0;">No link audit has run yet.</p>"#
+                .to_string(),
+            Some(broken) if broken.is_empty() => {
+                r#"<p style="color: #1C3240; margin: 0;">None found.</p>"#.to_string()
+            }
+            Some(broken) => {
+                let items: String = broken
+                    .iter()
+                    .map(|link| {
+                        let problem = match (link.status, &link.error) {
+                            (Some(status), _) => status.to_string(),
+                            (None, Some(error)) => html::escape(error),
+                            (None, None) => String::new(),
+                        };
+                        format!(
+                            r#"<li style="margin: 0 0 4px 0;"><a href="{url}" style="color: #D4706A; word-break: break-all;">{url}</a> &middot; {problem} &middot; in {found_in}</li>"#,
+                            url = html::escape(&link.url),
+                            found_in = html::escape(&link.found_in.join(", ")),
+                        )
+                    })
+                    .collect();
+                format!(
+                    r#"<ul style="color: #1C3240; font-size: 14px; line-height: 1.6; margin: 0; padding-left: 20px;">{}</ul>"#,
+                    items
+                )
+            }
+        };
+        format!(
+            "{}\n        {}",
+            Self::section_title("Broken links"),
+            content
+        )
+    }
+}
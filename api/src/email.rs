@@ -0,0 +1,482 @@
+//! Newsletter email template, assembled from partials.
+//!
+//! Each feature owns its own fragment (preheader, masthead, hero, footer, ...)
+//! and [`EmailTemplate::render`] stitches them into the document shell.
+
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+use crate::html;
+use crate::sendlog::SentIssue;
+
+/// Bump when the template's markup changes, so cached renders (see
+/// `rendercache`) aren't reused by a build that wasn't made from a checkout.
+pub const TEMPLATE_VERSION: u32 = 1;
+
+const SANS: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif";
+
+/// Zero-width filler after the preheader so clients don't pull body text
+/// (the "lindfors.no" header link) into the inbox preview.
+const PREHEADER_PADDING: &str = "&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;&zwnj;&nbsp;";
+
+// ---------------------------------------------------------------------------
+// Configuration
+// ---------------------------------------------------------------------------
+
+/// Email footer content, stored as JSON under `config:footer` in the
+/// NEWSLETTER KV namespace (falling back to the FOOTER_CONFIG var).
+#[derive(Serialize, Deserialize, Default)]
+pub struct FooterConfig {
+    #[serde(default)]
+    tagline: Option<String>,
+    /// Physical mailing address (required by CAN-SPAM).
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    social: Vec<SocialLink>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SocialLink {
+    label: String,
+    url: String,
+}
+
+/// Load the footer config from KV, then the FOOTER_CONFIG var, so it can be
+/// edited without redeploying. Missing or malformed config yields an empty footer.
+pub async fn load_footer_config(env: &Env) -> FooterConfig {
+    if let Ok(kv) = env.kv("NEWSLETTER") {
+        if let Ok(Some(config)) = kv.get("config:footer").json::<FooterConfig>().await {
+            return config;
+        }
+    }
+    env.var("FOOTER_CONFIG")
+        .ok()
+        .and_then(|v| serde_json::from_str(&v.to_string()).ok())
+        .unwrap_or_default()
+}
+
+/// Language of the email boilerplate, chosen by the `lang:` frontmatter key.
+#[derive(Clone, Copy, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    No,
+}
+
+/// Localized template boilerplate.
+struct TemplateStrings {
+    html_lang: &'static str,
+    read_full_post: &'static str,
+    read_full_post_hint: &'static str,
+    subscribed_before: &'static str,
+    subscribed_after: &'static str,
+    visit_site: &'static str,
+    unsubscribe: &'static str,
+    previously: &'static str,
+    min_read: &'static str,
+    view_in_browser: &'static str,
+    view_equation: &'static str,
+    contents: &'static str,
+    continue_reading: &'static str,
+    /// Note, tip, important, warning, caution.
+    callout_titles: [&'static str; 5],
+}
+
+impl Lang {
+    /// Parse a frontmatter `lang:` value; anything unrecognized is English.
+    pub fn from_code(code: &str) -> Self {
+        match code.trim().to_lowercase().as_str() {
+            "no" | "nb" | "nn" | "nb-no" | "nn-no" | "norsk" | "norwegian" => Lang::No,
+            _ => Lang::En,
+        }
+    }
+
+    /// Link text for equations the email can't display.
+    pub fn view_equation(self) -> &'static str {
+        self.strings().view_equation
+    }
+
+    /// Heading of the table of contents.
+    pub fn contents(self) -> &'static str {
+        self.strings().contents
+    }
+
+    /// Label of the button ending a truncated body.
+    pub fn continue_reading(self) -> &'static str {
+        self.strings().continue_reading
+    }
+
+    /// Titles of `> [!NOTE]`-style callouts: note, tip, important, warning, caution.
+    pub fn callout_titles(self) -> [&'static str; 5] {
+        self.strings().callout_titles
+    }
+
+    fn strings(self) -> TemplateStrings {
+        match self {
+            Lang::En => TemplateStrings {
+                html_lang: "en",
+                read_full_post: "Read the full post on the site",
+                read_full_post_hint: "For math equations, citations, and interactive features",
+                subscribed_before: "You received this because you subscribed to the",
+                subscribed_after: "newsletter.",
+                visit_site: "Visit site",
+                unsubscribe: "Unsubscribe",
+                previously: "Previously on the newsletter",
+                min_read: "min read",
+                view_in_browser: "View this email in your browser",
+                view_equation: "View equation on the site",
+                contents: "In this issue",
+                continue_reading: "Continue reading on the site",
+                callout_titles: ["Note", "Tip", "Important", "Warning", "Caution"],
+            },
+            Lang::No => TemplateStrings {
+                html_lang: "nb",
+                read_full_post: "Les hele innlegget på nettsiden",
+                read_full_post_hint: "For matematikk, kildehenvisninger og interaktive elementer",
+                subscribed_before: "Du mottar denne e-posten fordi du abonnerer på nyhetsbrevet fra",
+                subscribed_after: "",
+                visit_site: "Besøk nettsiden",
+                unsubscribe: "Meld deg av",
+                previously: "Tidligere i nyhetsbrevet",
+                min_read: "min lesetid",
+                view_in_browser: "Se denne e-posten i nettleseren",
+                view_equation: "Se formelen på nettsiden",
+                contents: "I denne utgaven",
+                continue_reading: "Les videre på nettsiden",
+                callout_titles: ["Merk", "Tips", "Viktig", "Advarsel", "Forsiktig"],
+            },
+        }
+    }
+}
+
+/// Outer structure of the email, chosen by the `layout:` frontmatter key.
+#[derive(Clone, Copy)]
+pub enum Layout {
+    /// Plain div with max-width. Fine everywhere except desktop Outlook,
+    /// which ignores max-width and stretches the column to the window.
+    Fluid,
+    /// Fluid layout wrapped in a fixed-width, MSO-conditional table shell
+    /// so Word-based Outlook renders the 600px column too.
+    Table,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Self {
+        match name.trim().to_lowercase().as_str() {
+            "fluid" | "div" => Layout::Fluid,
+            _ => Layout::Table,
+        }
+    }
+
+    fn open(self) -> &'static str {
+        match self {
+            Layout::Fluid => "",
+            Layout::Table => {
+                r#"<table role="presentation" class="email-bg" width="100%" cellpadding="0" cellspacing="0" border="0" style="background-color: #F0EAE0;"><tr><td align="center" style="padding: 0;">
+    <!--[if mso]><table role="presentation" width="600" align="center" cellpadding="0" cellspacing="0" border="0" style="background-color: #ffffff;"><tr><td style="padding: 0;"><![endif]-->"#
+            }
+        }
+    }
+
+    fn close(self) -> &'static str {
+        match self {
+            Layout::Fluid => "",
+            Layout::Table => {
+                r#"<!--[if mso]></td></tr></table><![endif]-->
+    </td></tr></table>"#
+            }
+        }
+    }
+}
+
+/// Reading time in minutes, matching Zola's `reading_time` (200 words/minute,
+/// rounded up, at least one minute).
+pub fn read_time_minutes(md: &str) -> usize {
+    md.split_whitespace().count().div_ceil(200).max(1)
+}
+
+// ---------------------------------------------------------------------------
+// Template
+// ---------------------------------------------------------------------------
+
+/// Everything the email template needs for one issue.
+pub struct EmailTemplate<'a> {
+    pub title: &'a str,
+    pub description: &'a str,
+    pub date: &'a str,
+    pub post_url: &'a str,
+    pub rendered_body: &'a str,
+    pub site_url: &'a str,
+    pub footer: &'a FooterConfig,
+    pub lang: Lang,
+    /// Hidden inbox preview text shown next to the subject line.
+    pub preheader: &'a str,
+    /// Absolute URL of the `cover:`/`image:` hero image, if any.
+    pub hero_url: Option<&'a str>,
+    pub hero_alt: &'a str,
+    pub layout: Layout,
+    /// Recently sent issues for the "Previously" footer list.
+    pub previously: &'a [&'a SentIssue],
+    pub read_minutes: usize,
+    /// Hosted archive copy of this exact email.
+    pub browser_url: &'a str,
+    pub tags: &'a [String],
+}
+
+impl EmailTemplate<'_> {
+    /// Assemble the full email document from its partials.
+    pub fn render(&self) -> String {
+        let s = self.lang.strings();
+        let partials = [
+            self.masthead(&s),
+            self.title_block(&s),
+            self.body(),
+            self.read_more(&s),
+            self.footer(&s),
+        ];
+
+        format!(
+            r#"<!DOCTYPE html>
+<html lang="{html_lang}" xmlns="http://www.w3.org/1999/xhtml" xmlns:v="urn:schemas-microsoft-com:vml" xmlns:o="urn:schemas-microsoft-com:office:office">
+{head}
+<body class="email-bg" style="margin: 0; padding: 0; background-color: #F0EAE0; font-family: Georgia, 'Times New Roman', serif;">
+    {preheader}
+    {layout_open}
+    <div class="email-card" style="max-width: 600px; margin: 0 auto; padding: 32px 24px; background-color: #ffffff;">
+        {partials}
+    </div>
+    {layout_close}
+</body>
+</html>"#,
+            html_lang = s.html_lang,
+            head = self.head(),
+            preheader = self.preheader(),
+            layout_open = self.layout.open(),
+            partials = partials.join("\n        "),
+            layout_close = self.layout.close(),
+        )
+    }
+
+    /// `<head>` with the dark-mode and Outlook overrides.
+    fn head(&self) -> String {
+        format!(
+            r#"<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="color-scheme" content="light dark">
+    <meta name="supported-color-schemes" content="light dark">
+    <title>{title}</title>
+    <style>
+        :root {{ color-scheme: light dark; supported-color-schemes: light dark; }}
+        @media (prefers-color-scheme: dark) {{
+            .email-bg {{ background-color: #0E1A20 !important; }}
+            .email-card {{ background-color: #152730 !important; }}
+            .email-text {{ color: #E8F0F0 !important; }}
+            .email-muted {{ color: #8BA5A8 !important; }}
+            .email-link {{ color: #F2A07B !important; }}
+            .email-rule {{ border-color: #4DD4AC !important; }}
+            .email-callout {{ background-color: #1A2830 !important; }}
+        }}
+        /* Outlook.com dark mode rewrites colors and tags the body with data-ogsc/data-ogsb */
+        [data-ogsb] .email-bg {{ background-color: #0E1A20 !important; }}
+        [data-ogsb] .email-card {{ background-color: #152730 !important; }}
+        [data-ogsb] .email-callout {{ background-color: #1A2830 !important; }}
+        [data-ogsc] .email-text {{ color: #E8F0F0 !important; }}
+        [data-ogsc] .email-muted {{ color: #8BA5A8 !important; }}
+        [data-ogsc] .email-link {{ color: #F2A07B !important; }}
+    </style>
+    <!--[if mso]>
+    <noscript><xml><o:OfficeDocumentSettings><o:PixelsPerInch>96</o:PixelsPerInch></o:OfficeDocumentSettings></xml></noscript>
+    <style>table, td, div, p, a, h1 {{ font-family: Georgia, 'Times New Roman', serif; }}</style>
+    <![endif]-->
+</head>"#,
+            title = self.title,
+        )
+    }
+
+    /// Hidden inbox preview text.
+    fn preheader(&self) -> String {
+        format!(
+            r#"<div style="display: none; font-size: 1px; line-height: 1px; max-height: 0; max-width: 0; opacity: 0; overflow: hidden; mso-hide: all;">{}{}</div>"#,
+            html::escape(self.preheader),
+            PREHEADER_PADDING
+        )
+    }
+
+    /// View-in-browser link and the site name rule.
+    fn masthead(&self, s: &TemplateStrings) -> String {
+        format!(
+            r#"<p class="email-muted" style="color: #5A7078; font-family: {SANS}; font-size: 12px; text-align: center; margin: 0 0 16px 0;"><a href="{browser_url}" class="email-muted" style="color: #5A7078;">{view_in_browser}</a></p>
+        <div class="email-rule" style="border-bottom: 2px solid #2A8F82; padding-bottom: 16px; margin-bottom: 24px;">
+            <a href="{site_url}" class="email-text" style="color: #1C3240; text-decoration: none; font-family: {SANS}; font-size: 14px; font-weight: 600;">lindfors.no</a>
+        </div>"#,
+            browser_url = html::escape(self.browser_url),
+            view_in_browser = s.view_in_browser,
+            site_url = self.site_url,
+        )
+    }
+
+    /// Title, description, hero image, and the date/read-time line.
+    fn title_block(&self, s: &TemplateStrings) -> String {
+        format!(
+            r#"<h1 class="email-text" style="font-family: {SANS}; font-size: 28px; color: #1C3240; margin: 0 0 8px 0; line-height: 1.2;">{title}</h1>
+        {description}
+        {hero}
+        <p class="email-muted" style="color: #5A7078; font-size: 14px; margin: 0 0 24px 0;">{date}<span class="email-callout" style="display: inline-block; margin-left: 8px; padding: 2px 8px; background-color: #F0EAE0; border-radius: 10px; font-family: {SANS}; font-size: 12px;">&asymp; {read_minutes} {min_read}</span></p>
+        {tags}"#,
+            title = self.title,
+            description = self.description_line(),
+            hero = self.hero(),
+            date = self.date,
+            read_minutes = self.read_minutes,
+            min_read = s.min_read,
+            tags = self.tag_badges(),
+        )
+    }
+
+    fn description_line(&self) -> String {
+        if self.description.trim().is_empty() {
+            return String::new();
+        }
+        format!(
+            r#"<p class="email-muted" style="color: #5A7078; font-size: 18px; margin: 0 0 16px 0; line-height: 1.5;">{}</p>"#,
+            html::escape(self.description)
+        )
+    }
+
+    /// Tags as small badges linking to the site's tag pages.
+    fn tag_badges(&self) -> String {
+        if self.tags.is_empty() {
+            return String::new();
+        }
+        let badges: String = self
+            .tags
+            .iter()
+            .map(|tag| {
+                format!(
+                    r#"<a href="{site_url}/tags/{slug}/" class="email-callout email-link" style="display: inline-block; margin: 0 6px 6px 0; padding: 2px 10px; background-color: #F0EAE0; border-radius: 10px; color: #2A8F82; font-family: {SANS}; font-size: 12px; text-decoration: none;">#{tag}</a>"#,
+                    site_url = self.site_url,
+                    slug = html::escape(&html::slugify(tag)),
+                    tag = html::escape(tag),
+                )
+            })
+            .collect();
+        format!(r#"<p style="margin: -16px 0 24px 0;">{badges}</p>"#)
+    }
+
+    /// Hero image below the title/description, constrained to the 600px column.
+    fn hero(&self) -> String {
+        let Some(url) = self.hero_url else {
+            return String::new();
+        };
+        format!(
+            r#"<img src="{}" alt="{}" width="552" style="display: block; width: 100%; max-width: 552px; height: auto; border: 0; border-radius: 6px; margin: 0 0 16px 0;">"#,
+            html::escape(url),
+            html::escape(self.hero_alt)
+        )
+    }
+
+    fn body(&self) -> String {
+        format!(
+            r#"<div class="email-text" style="color: #1C3240; font-size: 17px; line-height: 1.75;">
+            {}
+        </div>"#,
+            self.rendered_body
+        )
+    }
+
+    /// "Read the full post" callout.
+    fn read_more(&self, s: &TemplateStrings) -> String {
+        format!(
+            r#"<div class="email-callout" style="margin-top: 24px; padding: 12px 16px; background-color: #F0EAE0; border-radius: 6px;">
+            <a href="{post_url}" class="email-link" style="color: #D4706A; font-family: {SANS}; font-size: 14px; font-weight: 500;">{read_full_post} &rarr;</a>
+            <span class="email-muted" style="color: #5A7078; font-size: 13px; display: block; margin-top: 4px;">{read_full_post_hint}</span>
+        </div>"#,
+            post_url = self.post_url,
+            read_full_post = s.read_full_post,
+            read_full_post_hint = s.read_full_post_hint,
+        )
+    }
+
+    /// Footer: previously-sent issues, subscription boilerplate, configurable extras.
+    fn footer(&self, s: &TemplateStrings) -> String {
+        format!(
+            r#"<div class="email-rule" style="border-top: 2px solid #2A8F82; margin-top: 32px; padding-top: 16px;">
+            {previously}
+            <p class="email-muted" style="color: #5A7078; font-size: 13px; margin: 0 0 8px 0;">{subscribed_before} <a href="{site_url}" class="email-link" style="color: #D4706A;">lindfors.no</a> {subscribed_after}</p>
+            <a href="{site_url}" class="email-link" style="color: #D4706A; font-size: 13px;">{visit_site}</a> &middot;
+            <a href="{site_url}/api/unsubscribe" class="email-link" style="color: #D4706A; font-size: 13px;">{unsubscribe}</a>
+            {extras}
+        </div>"#,
+            previously = self.previously(s),
+            subscribed_before = s.subscribed_before,
+            subscribed_after = s.subscribed_after,
+            site_url = self.site_url,
+            visit_site = s.visit_site,
+            unsubscribe = s.unsubscribe,
+            extras = self.footer_extras(),
+        )
+    }
+
+    /// "Previously on the newsletter" link list.
+    fn previously(&self, s: &TemplateStrings) -> String {
+        if self.previously.is_empty() {
+            return String::new();
+        }
+        let items: String = self
+            .previously
+            .iter()
+            .map(|issue| {
+                format!(
+                    r#"<li style="margin: 0 0 4px 0;"><a href="{}" class="email-link" style="color: #D4706A;">{}</a></li>"#,
+                    html::escape(&issue.post_url),
+                    html::escape(&issue.title)
+                )
+            })
+            .collect();
+        format!(
+            r#"<div style="margin: 0 0 16px 0;">
+            <p class="email-text" style="font-family: {SANS}; color: #1C3240; font-size: 13px; font-weight: 600; margin: 0 0 6px 0;">{}</p>
+            <ul class="email-muted" style="color: #5A7078; font-size: 13px; margin: 0; padding-left: 18px;">{}</ul>
+        </div>"#,
+            s.previously, items
+        )
+    }
+
+    /// The configurable part of the footer (tagline, social links, address).
+    fn footer_extras(&self) -> String {
+        let config = self.footer;
+        let mut out = String::new();
+        if let Some(tagline) = &config.tagline {
+            out.push_str(&format!(
+                r#"<p class="email-muted" style="color: #5A7078; font-size: 13px; font-style: italic; margin: 0 0 8px 0;">{}</p>"#,
+                html::escape(tagline)
+            ));
+        }
+        if !config.social.is_empty() {
+            let links: Vec<String> = config
+                .social
+                .iter()
+                .map(|link| {
+                    format!(
+                        r#"<a href="{}" class="email-link" style="color: #D4706A; font-size: 13px;">{}</a>"#,
+                        html::escape(&link.url),
+                        html::escape(&link.label)
+                    )
+                })
+                .collect();
+            out.push_str(&format!(
+                r#"<p style="margin: 12px 0 0 0;">{}</p>"#,
+                links.join(" &middot;\n")
+            ));
+        }
+        if let Some(address) = &config.address {
+            out.push_str(&format!(
+                r#"<p class="email-muted" style="color: #5A7078; font-size: 12px; margin: 12px 0 0 0;">{}</p>"#,
+                html::escape(address)
+            ));
+        }
+        out
+    }
+}
@@ -0,0 +1,26 @@
+//! Running a batch of upstream calls side by side rather than one after
+//! another, but no more than a few at once: Workers allow six connections
+//! open at a time per invocation and queue the rest, and a cap also keeps a
+//! long list from hammering one host.
+
+use std::future::Future;
+
+use futures_util::stream::{self, StreamExt};
+
+/// Simultaneous open connections a Worker invocation gets.
+pub const CONNECTIONS: usize = 6;
+
+/// `f` applied to each of `items`, at most `limit` of the futures running at
+/// a time. The results come back in the order of `items`.
+pub async fn map<I, F, Fut>(items: I, limit: usize, f: F) -> Vec<Fut::Output>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future,
+{
+    stream::iter(items)
+        .map(f)
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
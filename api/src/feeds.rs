@@ -0,0 +1,158 @@
+//! Atom and JSON feeds of sent issues, built from the send log, for readers
+//! who'd rather follow the newsletter than subscribe. Entries link to the
+//! hosted copy of each issue.
+
+use serde::Serialize;
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::html::escape;
+use crate::logging::RequestLog;
+use crate::sendlog::SentIssue;
+use crate::{archive, conditional, dates, json_response, sendlog};
+
+const FEED_TITLE: &str = "Emil Lindfors — Newsletter";
+const AUTHOR: &str = "Emil Lindfors";
+
+/// Issues in a feed, newest first.
+const FEED_ENTRIES: usize = 50;
+
+/// Feeds change only when an issue is sent.
+const CACHE_CONTROL: &str = "public, max-age=600";
+
+fn feed_url(site_url: &str, file: &str) -> String {
+    format!("{}/api/newsletter/{}", site_url, file)
+}
+
+fn atom_entry(site_url: &str, issue: &SentIssue) -> String {
+    let link = archive::archive_url(site_url, &issue.slug);
+    let sent = dates::rfc3339(issue.sent_at);
+    let categories: String = issue
+        .tags
+        .iter()
+        .chain(&issue.categories)
+        .map(|term| format!("\n    <category term=\"{}\"/>", escape(term)))
+        .collect();
+    format!(
+        r#"  <entry>
+    <title>{title}</title>
+    <id>{link}</id>
+    <link rel="alternate" type="text/html" href="{link}"/>
+    <link rel="related" type="text/html" href="{post_url}"/>
+    <published>{sent}</published>
+    <updated>{sent}</updated>
+    <summary>{summary}</summary>{categories}
+  </entry>
+"#,
+        title = escape(&issue.title),
+        link = escape(&link),
+        post_url = escape(&issue.post_url),
+        sent = sent,
+        summary = escape(&issue.description),
+        categories = categories,
+    )
+}
+
+/// GET /api/newsletter/feed.xml — Atom feed of sent issues.
+pub async fn handle_atom(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let issues = sendlog::load(&ctx.env).await;
+    let issues = &issues[..issues.len().min(FEED_ENTRIES)];
+
+    let updated = issues.iter().map(|i| i.sent_at).max().unwrap_or(0);
+    let self_url = feed_url(&site_url, "feed.xml");
+    let entries: String = issues.iter().map(|i| atom_entry(&site_url, i)).collect();
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{title}</title>
+  <id>{self_url}</id>
+  <link rel="self" type="application/atom+xml" href="{self_url}"/>
+  <link rel="alternate" type="text/html" href="{site_url}/"/>
+  <updated>{updated}</updated>
+  <author><name>{author}</name></author>
+{entries}</feed>
+"#,
+        title = escape(FEED_TITLE),
+        self_url = escape(&self_url),
+        site_url = escape(&site_url),
+        updated = dates::rfc3339(updated),
+        author = escape(AUTHOR),
+        entries = entries,
+    );
+
+    let mut resp = Response::ok(xml)?;
+    resp.headers_mut()
+        .set("Content-Type", "application/atom+xml; charset=utf-8")?;
+    conditional::respond(&req, resp, CACHE_CONTROL)
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'static str,
+    home_page_url: String,
+    feed_url: String,
+    authors: [JsonFeedAuthor; 1],
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor {
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a> {
+    id: String,
+    url: String,
+    /// The blog post the issue is based on.
+    external_url: &'a str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    summary: &'a str,
+    date_published: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<&'a str>,
+}
+
+/// GET /api/newsletter/feed.json — JSON Feed 1.1 of sent issues, with the
+/// same entries as the Atom feed.
+pub async fn handle_json(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let issues = sendlog::load(&ctx.env).await;
+
+    let items = issues
+        .iter()
+        .take(FEED_ENTRIES)
+        .map(|issue| {
+            let link = archive::archive_url(&site_url, &issue.slug);
+            JsonFeedItem {
+                id: link.clone(),
+                url: link,
+                external_url: &issue.post_url,
+                title: &issue.title,
+                summary: &issue.description,
+                date_published: dates::rfc3339(issue.sent_at),
+                tags: issue
+                    .tags
+                    .iter()
+                    .chain(&issue.categories)
+                    .map(String::as_str)
+                    .collect(),
+            }
+        })
+        .collect();
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: FEED_TITLE,
+        home_page_url: format!("{}/", site_url),
+        feed_url: feed_url(&site_url, "feed.json"),
+        authors: [JsonFeedAuthor { name: AUTHOR }],
+        items,
+    };
+
+    let mut resp = json_response(&feed, 200)?;
+    resp.headers_mut()
+        .set("Content-Type", "application/feed+json; charset=utf-8")?;
+    conditional::respond(&req, resp, CACHE_CONTROL)
+}
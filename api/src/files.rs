@@ -0,0 +1,197 @@
+//! The FILES R2 bucket: sent issues' archived HTML (`archive/{slug}.html`,
+//! see archive.rs) and uploaded attachments (`uploads/{name}`).
+//!
+//! Attachments aren't public: PUT /api/admin/uploads/{name} stores one and
+//! answers with a link that works for [`LINK_TTL_SECS`],
+//!
+//! ```text
+//! /api/uploads/{name}?expires=<unix seconds>&sig=hex(HMAC-SHA256(FILES_SIGNING_KEY, NAME \n EXPIRES))
+//! ```
+//!
+//! and GET /api/uploads/{name} serves it only with a valid, unexpired one.
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use worker::{Bucket, Date, Env, HttpMetadata, Object, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{auth, json_response, ApiResponse};
+
+/// How long the link an upload answers with works.
+const LINK_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Cloudflare's request body limit on the smaller plans.
+pub const MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+fn bucket(env: &Env) -> Result<Bucket> {
+    env.bucket("FILES")
+}
+
+/// Store `data` under `key`, replacing any earlier object.
+pub async fn put(
+    env: &Env,
+    key: &str,
+    data: impl Into<worker::Data>,
+    content_type: &str,
+) -> Result<()> {
+    bucket(env)?
+        .put(key, data)
+        .http_metadata(HttpMetadata {
+            content_type: Some(content_type.to_string()),
+            ..HttpMetadata::default()
+        })
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// The object under `key`, if there is one.
+pub async fn get(env: &Env, key: &str) -> Result<Option<Object>> {
+    bucket(env)?.get(key).execute().await
+}
+
+/// The text of the object under `key`, if there is one.
+pub async fn get_text(env: &Env, key: &str) -> Result<Option<String>> {
+    let Some(object) = get(env, key).await? else {
+        return Ok(None);
+    };
+    match object.body() {
+        Some(body) => Ok(Some(body.text().await?)),
+        None => Ok(None),
+    }
+}
+
+fn upload_key(name: &str) -> String {
+    format!("uploads/{name}")
+}
+
+/// Upload names are one path segment, so they map to keys as they are.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn signature(key: &str, name: &str, expires: u64) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(format!("{name}\n{expires}").as_bytes());
+    Some(
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+    )
+}
+
+/// A link to upload `name` that works until `expires` (Unix seconds).
+pub fn signed_url(env: &Env, site_url: &str, name: &str, expires: u64) -> Result<String> {
+    let key = env.secret("FILES_SIGNING_KEY")?.to_string();
+    let sig = signature(&key, name, expires)
+        .ok_or_else(|| worker::Error::RustError("Unusable FILES_SIGNING_KEY".into()))?;
+    Ok(format!(
+        "{site_url}/api/uploads/{name}?expires={expires}&sig={sig}"
+    ))
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    success: bool,
+    name: String,
+    size: usize,
+    url: String,
+    /// Unix seconds.
+    expires: u64,
+}
+
+/// PUT /api/admin/uploads/{name} — admin: store the body as attachment
+/// `name`, with the request's Content-Type, replacing any earlier one.
+pub async fn handle_upload(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let name = ctx.param("name").cloned().unwrap_or_default();
+    if !is_valid_name(&name) {
+        return error(400, "Invalid name");
+    }
+    let content_type = req
+        .headers()
+        .get("Content-Type")?
+        .unwrap_or_else(|| "application/octet-stream".into());
+    let body = req.bytes().await?;
+    if body.is_empty() {
+        return error(400, "Empty upload");
+    }
+    if body.len() > MAX_UPLOAD_BYTES {
+        return error(413, "Upload too large");
+    }
+
+    let size = body.len();
+    put(&ctx.env, &upload_key(&name), body, &content_type).await?;
+    ctx.data.event("upload", true);
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let expires = Date::now().as_millis() / 1000 + LINK_TTL_SECS;
+    let url = signed_url(&ctx.env, &site_url, &name, expires)?;
+    json_response(
+        &UploadResponse {
+            success: true,
+            name,
+            size,
+            url,
+            expires,
+        },
+        200,
+    )
+}
+
+/// GET /api/uploads/{name}?expires=...&sig=... — an attachment, by a signed
+/// link that hasn't expired.
+pub async fn handle_download(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let name = ctx.param("name").cloned().unwrap_or_default();
+    let url = req.url()?;
+    let param = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    let expires = param("expires").and_then(|e| e.parse::<u64>().ok());
+    let (Some(expires), Some(sig)) = (expires, param("sig")) else {
+        return error(403, "Missing signature");
+    };
+    let Ok(key) = ctx.env.secret("FILES_SIGNING_KEY") else {
+        return error(503, "Uploads are not configured");
+    };
+    let valid = signature(&key.to_string(), &name, expires)
+        .is_some_and(|expected| auth::constant_time_eq(expected.as_bytes(), sig.as_bytes()));
+    if !valid {
+        return error(403, "Invalid signature");
+    }
+    if Date::now().as_millis() / 1000 > expires {
+        return error(410, "Link expired");
+    }
+
+    let Some(object) = get(&ctx.env, &upload_key(&name)).await? else {
+        return error(404, "Not found");
+    };
+    let Some(body) = object.body() else {
+        return error(404, "Not found");
+    };
+    let mut resp = Response::from_body(body.response_body()?)?;
+    let headers = resp.headers_mut();
+    object.write_http_metadata(headers.clone())?;
+    headers.set("ETag", &object.http_etag())?;
+    headers.set("Cache-Control", "private, max-age=3600")?;
+    Ok(resp)
+}
@@ -0,0 +1,129 @@
+//! Feature flags, kept as one JSON document under `feature_flags` in the
+//! NEWSLETTER KV namespace so the riskier features can be switched off
+//! without a deploy: GET /api/admin/flags shows them, PUT sets some, e.g.
+//! `{"activitypub_delivery": false}`. Flags missing from the document are
+//! on.
+//!
+//! Each isolate keeps what it read for [`CACHE_TTL_MILLIS`], so a change
+//! reaches every isolate within that; the one taking the PUT sees it at once.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::logging::{self, RequestLog};
+use crate::{json_response, ApiResponse};
+
+const FLAGS_KEY: &str = "feature_flags";
+
+const CACHE_TTL_MILLIS: u64 = 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Flags {
+    /// Deliver queued ActivityPub activities to followers' inboxes (the
+    /// cron in activitypub.rs). While off they stay queued.
+    pub activitypub_delivery: bool,
+    /// Announce sent issues on Bluesky and Mastodon.
+    pub cross_posts: bool,
+    /// Email commenters about approved replies.
+    pub reply_notifications: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            activitypub_delivery: true,
+            cross_posts: true,
+            reply_notifications: true,
+        }
+    }
+}
+
+thread_local! {
+    /// When the flags were read, and what they were.
+    static CACHE: RefCell<Option<(u64, Flags)>> = const { RefCell::new(None) };
+}
+
+fn cache(flags: &Flags) {
+    CACHE.with(|c| *c.borrow_mut() = Some((logging::now_millis(), flags.clone())));
+}
+
+async fn read(env: &Env) -> Result<Flags> {
+    Ok(env
+        .kv("NEWSLETTER")?
+        .get(FLAGS_KEY)
+        .json()
+        .await?
+        .unwrap_or_default())
+}
+
+/// The current flags. If they can't be read, the defaults, which this
+/// isolate then sticks to until the cache would have expired anyway.
+pub async fn load(env: &Env) -> Flags {
+    let now = logging::now_millis();
+    let cached = CACHE.with(|c| {
+        c.borrow()
+            .as_ref()
+            .filter(|(read_at, _)| now.saturating_sub(*read_at) < CACHE_TTL_MILLIS)
+            .map(|(_, flags)| flags.clone())
+    });
+    if let Some(flags) = cached {
+        return flags;
+    }
+
+    let flags = read(env).await.unwrap_or_else(|e| {
+        worker::console_error!("Failed to read feature flags: {}", e);
+        Flags::default()
+    });
+    cache(&flags);
+    flags
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// GET /api/admin/flags — admin: the feature flags, as this isolate has them.
+pub async fn handle_get(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    json_response(&load(&ctx.env).await, 200)
+}
+
+/// PUT /api/admin/flags — admin: set the flags in `{"name": bool, ...}`,
+/// leaving the others as they are. Answers with all of them.
+pub async fn handle_put(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(changes) = req.json::<BTreeMap<String, bool>>().await else {
+        return error(400, "Expected {\"flag\": true|false, ...}");
+    };
+
+    // Read from KV rather than the cache, so this builds on the latest
+    let mut flags = match serde_json::to_value(read(&ctx.env).await?) {
+        Ok(serde_json::Value::Object(flags)) => flags,
+        _ => return error(500, "Failed to encode feature flags"),
+    };
+    for (name, on) in changes {
+        if !flags.contains_key(&name) {
+            return error(400, &format!("Unknown flag {}", name));
+        }
+        flags.insert(name, on.into());
+    }
+    let Ok(flags) = serde_json::from_value::<Flags>(flags.into()) else {
+        return error(500, "Failed to encode feature flags");
+    };
+
+    ctx.env
+        .kv("NEWSLETTER")?
+        .put(FLAGS_KEY, &flags)?
+        .execute()
+        .await?;
+    cache(&flags);
+    json_response(&flags, 200)
+}
@@ -0,0 +1,303 @@
+//! Newsletter frontmatter at the top of the markdown file: YAML between `---`
+//! delimiter lines, or TOML between `+++` lines (Zola's format). Both are
+//! normalized into the same [`Frontmatter`].
+
+use serde::{Deserialize, Deserializer};
+
+/// Metadata the send pipeline reads from an issue's frontmatter.
+/// Unknown keys are ignored.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Frontmatter {
+    #[serde(deserialize_with = "scalar")]
+    pub title: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub date: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub description: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub url: Option<String>,
+    /// Preferred over `url` as the post's canonical address.
+    #[serde(deserialize_with = "scalar")]
+    pub canonical: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub lang: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub preheader: Option<String>,
+    #[serde(deserialize_with = "scalar", alias = "featured_image")]
+    pub cover: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub image: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub cover_alt: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub image_alt: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub layout: Option<String>,
+    /// Unfinished issue; sending requires `force`.
+    pub draft: bool,
+    pub stack_wide_tables: bool,
+    pub toc: bool,
+    /// Overrides the SMART_PUNCTUATION default when set.
+    pub smart_punctuation: Option<bool>,
+    /// Email only roughly the first N words, then link to the post.
+    pub email_truncate: Option<usize>,
+    #[serde(deserialize_with = "string_list")]
+    pub tags: Vec<String>,
+    #[serde(deserialize_with = "string_list")]
+    pub categories: Vec<String>,
+    pub poll: Option<Poll>,
+    /// `false` skips announcing the issue on Mastodon.
+    pub mastodon: Option<bool>,
+}
+
+/// A `poll:` block: a question answered with buttons in the email (see
+/// `polls.rs`).
+#[derive(Deserialize)]
+pub struct Poll {
+    /// Defaults to the issue's slug.
+    #[serde(default, deserialize_with = "scalar")]
+    pub id: Option<String>,
+    pub question: String,
+    #[serde(deserialize_with = "string_list")]
+    pub options: Vec<String>,
+}
+
+/// Accept any YAML scalar as a string, so unquoted values like
+/// `date: 2024-01-20` or `title: 1984` don't fail the whole document.
+fn scalar<'de, D: Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    Ok(match serde_yaml::Value::deserialize(de)? {
+        serde_yaml::Value::String(s) => Some(s),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    })
+}
+
+/// Accept a list of scalars, or a single scalar as a one-item list
+/// (`tags: rust` as well as `tags: [rust, wasm]`).
+fn string_list<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<String>, D::Error> {
+    fn to_string(value: serde_yaml::Value) -> Option<String> {
+        match value {
+            serde_yaml::Value::String(s) => Some(s),
+            serde_yaml::Value::Number(n) => Some(n.to_string()),
+            serde_yaml::Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+    Ok(match serde_yaml::Value::deserialize(de)? {
+        serde_yaml::Value::Sequence(items) => items.into_iter().filter_map(to_string).collect(),
+        other => to_string(other).into_iter().collect(),
+    })
+}
+
+/// Split a markdown file into its frontmatter block and body. The opening
+/// and closing delimiters must be `delim` alone on a line.
+fn split<'a>(md: &'a str, delim: &str) -> Option<(&'a str, &'a str)> {
+    let rest = md.trim_start().strip_prefix(delim)?;
+    let rest = rest.strip_prefix('\r').unwrap_or(rest).strip_prefix('\n')?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == delim {
+            let front = &rest[..offset];
+            let body = rest[offset + line.len()..].trim_start_matches(['\r', '\n']);
+            return Some((front, body));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Parse frontmatter from a markdown file. Returns the metadata and the body
+/// after the frontmatter; a file without frontmatter yields defaults and the
+/// whole input as body.
+pub fn parse(md: &str) -> Result<(Frontmatter, &str), String> {
+    let (value, body) = if let Some((front, body)) = split(md, "---") {
+        (parse_yaml(front)?, body)
+    } else if let Some((front, body)) = split(md, "+++") {
+        (parse_toml(front)?, body)
+    } else {
+        return Ok((Frontmatter::default(), md));
+    };
+
+    if value.is_null() {
+        return Ok((Frontmatter::default(), body));
+    }
+    let meta = serde_yaml::from_value(value).map_err(|e| format!("invalid frontmatter: {e}"))?;
+    Ok((meta, body))
+}
+
+fn parse_yaml(front: &str) -> Result<serde_yaml::Value, String> {
+    serde_yaml::from_str(front).map_err(|e| format!("invalid YAML frontmatter: {e}"))
+}
+
+/// Parse TOML frontmatter into the same value tree as YAML. Zola keeps custom
+/// keys under `[extra]` and tags under `[taxonomies]`; those are hoisted to
+/// the top level (without overriding top-level keys) so both formats read
+/// the same.
+fn parse_toml(front: &str) -> Result<serde_yaml::Value, String> {
+    let table: toml::Table =
+        toml::from_str(front).map_err(|e| format!("invalid TOML frontmatter: {e}"))?;
+    let mut value = toml_to_yaml(toml::Value::Table(table));
+
+    if let serde_yaml::Value::Mapping(map) = &mut value {
+        for section in ["extra", "taxonomies"] {
+            if let Some(serde_yaml::Value::Mapping(nested)) = map.remove(section) {
+                for (k, v) in nested {
+                    if !map.contains_key(&k) {
+                        map.insert(k, v);
+                    }
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+fn toml_to_yaml(value: toml::Value) -> serde_yaml::Value {
+    use serde_yaml::Value as Y;
+    match value {
+        toml::Value::String(s) => Y::String(s),
+        toml::Value::Integer(i) => Y::Number(i.into()),
+        toml::Value::Float(f) => Y::Number(f.into()),
+        toml::Value::Boolean(b) => Y::Bool(b),
+        // Dates stay strings, as unquoted YAML dates do
+        toml::Value::Datetime(dt) => Y::String(dt.to_string()),
+        toml::Value::Array(items) => Y::Sequence(items.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(table) => Y::Mapping(
+            table
+                .into_iter()
+                .map(|(k, v)| (Y::String(k), toml_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_newsletter_files() {
+        let md = include_str!("../../static/newsletter/aquaculture-innovation.md");
+        let (meta, body) = parse(md).unwrap();
+        assert_eq!(
+            meta.title.as_deref(),
+            Some("Innovation Narratives in Norwegian Aquaculture")
+        );
+        assert_eq!(meta.date.as_deref(), Some("2024-01-20"));
+        let description = meta.description.unwrap();
+        assert!(description.starts_with("Exploring the diverse"));
+        assert_eq!(
+            meta.url.as_deref(),
+            Some("https://lindfors.no/blog/aquaculture-innovation/")
+        );
+        assert!(!body.starts_with("---"));
+        assert!(!body.is_empty());
+    }
+
+    #[test]
+    fn keeps_colons_and_quotes_in_values() {
+        let md = "---\n\
+                  title: \"Rust: a \\\"real\\\" language\"\n\
+                  description: 'It''s: fine'\n\
+                  preheader: Doors open at 10:30\n\
+                  url: https://lindfors.no/blog/rust/\n\
+                  ---\n\
+                  Body";
+        let (meta, body) = parse(md).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Rust: a \"real\" language"));
+        assert_eq!(meta.description.as_deref(), Some("It's: fine"));
+        assert_eq!(meta.preheader.as_deref(), Some("Doors open at 10:30"));
+        assert_eq!(meta.url.as_deref(), Some("https://lindfors.no/blog/rust/"));
+        assert_eq!(body, "Body");
+    }
+
+    #[test]
+    fn reads_unquoted_scalars_as_strings() {
+        let md = "---\ntitle: 1984\ndate: 2024-01-20\ndraft: true\n---\n";
+        let (meta, _) = parse(md).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("1984"));
+        assert_eq!(meta.date.as_deref(), Some("2024-01-20"));
+        assert!(meta.draft);
+    }
+
+    #[test]
+    fn reads_lists_in_every_shape() {
+        let md = "---\ntags: [rust, wasm]\ncategories:\n  - notes\n  - 2024\n---\n";
+        let (meta, _) = parse(md).unwrap();
+        assert_eq!(meta.tags, ["rust", "wasm"]);
+        assert_eq!(meta.categories, ["notes", "2024"]);
+
+        let (meta, _) = parse("---\ntags: rust\n---\n").unwrap();
+        assert_eq!(meta.tags, ["rust"]);
+    }
+
+    #[test]
+    fn reads_nested_maps() {
+        let md = "---\n\
+                  poll:\n  \
+                    id: tools\n  \
+                    question: \"Which one: A or B?\"\n  \
+                    options:\n    \
+                      - A\n    \
+                      - B\n\
+                  ---\n";
+        let (meta, _) = parse(md).unwrap();
+        let poll = meta.poll.unwrap();
+        assert_eq!(poll.id.as_deref(), Some("tools"));
+        assert_eq!(poll.question, "Which one: A or B?");
+        assert_eq!(poll.options, ["A", "B"]);
+    }
+
+    #[test]
+    fn reads_multiline_strings() {
+        let md = "---\n\
+                  description: |\n  \
+                    First line.\n  \
+                    Second: line.\n\
+                  preheader: >\n  \
+                    Folded\n  \
+                    together\n\
+                  ---\n";
+        let (meta, _) = parse(md).unwrap();
+        assert_eq!(
+            meta.description.as_deref(),
+            Some("First line.\nSecond: line.\n")
+        );
+        assert_eq!(meta.preheader.as_deref(), Some("Folded together\n"));
+    }
+
+    #[test]
+    fn hoists_zola_sections_from_toml() {
+        let md = "+++\n\
+                  title = \"Zola: issue\"\n\
+                  date = 2024-01-20\n\
+                  [taxonomies]\n\
+                  tags = [\"rust\"]\n\
+                  [extra]\n\
+                  preheader = \"Hi\"\n\
+                  title = \"Not this one\"\n\
+                  +++\n\
+                  Body";
+        let (meta, body) = parse(md).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Zola: issue"));
+        assert_eq!(meta.date.as_deref(), Some("2024-01-20"));
+        assert_eq!(meta.tags, ["rust"]);
+        assert_eq!(meta.preheader.as_deref(), Some("Hi"));
+        assert_eq!(body, "Body");
+    }
+
+    #[test]
+    fn passes_through_files_without_frontmatter() {
+        let (meta, body) = parse("# Just markdown\n---\n").unwrap();
+        assert!(meta.title.is_none());
+        assert_eq!(body, "# Just markdown\n---\n");
+    }
+
+    #[test]
+    fn rejects_malformed_yaml() {
+        assert!(parse("---\ntitle: [unclosed\n---\n").is_err());
+    }
+}
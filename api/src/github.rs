@@ -0,0 +1,199 @@
+//! Committing to the site's repository through the GitHub API, for content
+//! created through the worker (see micropub.rs). A push to the branch is
+//! what triggers the Cloudflare Pages build, so committed posts go live a
+//! minute or two later.
+//!
+//! GITHUB_REPO is `owner/name`, GITHUB_BRANCH the branch Pages builds (main
+//! by default), and the GITHUB_TOKEN secret a fine-grained token for that
+//! repository with Contents: read and write.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Error, Fetch, Headers, Method, Request, RequestInit, Result};
+
+use crate::logging::{self, RequestLog};
+
+const API_URL: &str = "https://api.github.com";
+const DEFAULT_BRANCH: &str = "main";
+
+/// GitHub rejects requests without one.
+const USER_AGENT: &str = "newsletter-api";
+
+#[derive(Deserialize)]
+struct Ref {
+    object: Sha,
+}
+
+#[derive(Deserialize)]
+struct Commit {
+    tree: Sha,
+}
+
+#[derive(Deserialize)]
+struct Sha {
+    sha: String,
+}
+
+pub struct Repo {
+    name: String,
+    branch: String,
+    token: String,
+}
+
+impl Repo {
+    /// The configured repository, or `None` if GITHUB_REPO or GITHUB_TOKEN
+    /// isn't set.
+    pub fn from_env(env: &Env) -> Option<Repo> {
+        let name = env.var("GITHUB_REPO").ok()?.to_string();
+        let token = env.secret("GITHUB_TOKEN").ok()?.to_string();
+        let branch = env
+            .var("GITHUB_BRANCH")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| DEFAULT_BRANCH.to_string());
+        Some(Repo {
+            name,
+            branch,
+            token,
+        })
+    }
+
+    /// Call `{API_URL}/repos/{repo}{path}`: the status and the JSON body
+    /// (`Null` if there isn't one).
+    async fn call(
+        &self,
+        log: &RequestLog,
+        operation: &str,
+        method: Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<(u16, Value)> {
+        let headers = Headers::new();
+        headers.set("Accept", "application/vnd.github+json")?;
+        headers.set("Authorization", &format!("Bearer {}", self.token))?;
+        headers.set("User-Agent", USER_AGENT)?;
+        headers.set("X-GitHub-Api-Version", "2022-11-28")?;
+        let mut init = RequestInit::new();
+        init.with_method(method);
+        if let Some(body) = body {
+            headers.set("Content-Type", "application/json")?;
+            init.with_body(Some(JsValue::from_str(&body.to_string())));
+        }
+        init.with_headers(headers);
+        let url = format!("{}/repos/{}{}", API_URL, self.name, path);
+        let req = Request::new_with_init(&url, &init)?;
+
+        let started = logging::now_millis();
+        let mut resp = match Fetch::Request(req).send().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                log.upstream("github", operation, started, None, Some(&e.to_string()));
+                return Err(e);
+            }
+        };
+        let status = resp.status_code();
+        log.upstream("github", operation, started, Some(status), None);
+        let body = resp.json().await.unwrap_or(Value::Null);
+        Ok((status, body))
+    }
+
+    /// Like [`Repo::call`], but a non-2xx status is an error.
+    async fn call_ok<T: for<'de> Deserialize<'de>>(
+        &self,
+        log: &RequestLog,
+        operation: &str,
+        method: Method,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<T> {
+        let (status, body) = self.call(log, operation, method, path, body).await?;
+        if !(200..300).contains(&status) {
+            return Err(Error::RustError(format!(
+                "GitHub {} failed (status {}): {}",
+                operation, status, body
+            )));
+        }
+        serde_json::from_value(body).map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    /// Whether `path` exists on the branch.
+    pub async fn exists(&self, log: &RequestLog, path: &str) -> Result<bool> {
+        let (status, body) = self
+            .call(
+                log,
+                "get_contents",
+                Method::Get,
+                &format!("/contents/{}?ref={}", path, self.branch),
+                None,
+            )
+            .await?;
+        match status {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => Err(Error::RustError(format!(
+                "GitHub get_contents failed (status {}): {}",
+                status, body
+            ))),
+        }
+    }
+
+    /// Commit `files` (path and text content) to the branch as one commit on
+    /// top of its head: the new commit's SHA. Fails, rather than
+    /// overwriting, if the branch moves in the meantime.
+    pub async fn commit(
+        &self,
+        log: &RequestLog,
+        message: &str,
+        files: &[(String, String)],
+    ) -> Result<String> {
+        let ref_path = format!("/git/refs/heads/{}", self.branch);
+        let head: Ref = self
+            .call_ok(log, "get_ref", Method::Get, &ref_path, None)
+            .await?;
+        let parent = head.object.sha;
+        let commit: Commit = self
+            .call_ok(
+                log,
+                "get_commit",
+                Method::Get,
+                &format!("/git/commits/{}", parent),
+                None,
+            )
+            .await?;
+
+        let entries: Vec<Value> = files
+            .iter()
+            .map(|(path, content)| {
+                json!({ "path": path, "mode": "100644", "type": "blob", "content": content })
+            })
+            .collect();
+        let tree: Sha = self
+            .call_ok(
+                log,
+                "create_tree",
+                Method::Post,
+                "/git/trees",
+                Some(&json!({ "base_tree": commit.tree.sha, "tree": entries })),
+            )
+            .await?;
+        let created: Sha = self
+            .call_ok(
+                log,
+                "create_commit",
+                Method::Post,
+                "/git/commits",
+                Some(&json!({ "message": message, "tree": tree.sha, "parents": [parent] })),
+            )
+            .await?;
+        let _: Value = self
+            .call_ok(
+                log,
+                "update_ref",
+                Method::Patch,
+                &ref_path,
+                Some(&json!({ "sha": created.sha, "force": false })),
+            )
+            .await?;
+        Ok(created.sha)
+    }
+}
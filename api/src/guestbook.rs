@@ -0,0 +1,312 @@
+//! The guestbook, in the `guestbook` D1 table
+//! (`migrations/0004_guestbook.sql`). Entries are markdown like comments and
+//! go through the same moderation (see [`comments`]): pending until approved,
+//! spam signals flagged on arrival, and the moderator emailed about new ones.
+//!
+//! GET /api/guestbook is a page of its own for browsers and JSON for
+//! everything else, by the Accept header.
+
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::comments::{self, hash_ip, spam_reason};
+use crate::logging::RequestLog;
+use crate::{conditional, dates, html, is_valid_email, json_response, markdown, ApiResponse};
+
+const MAX_NAME_CHARS: usize = 80;
+const MAX_BODY_CHARS: usize = 2000;
+
+/// Entries shown, newest first.
+const MAX_SHOWN: u32 = 200;
+
+#[derive(Deserialize)]
+struct EntryRequest {
+    name: String,
+    #[serde(default)]
+    email: Option<String>,
+    body: String,
+    /// Honeypot, as for comments.
+    #[serde(default)]
+    website: String,
+}
+
+#[derive(Deserialize)]
+struct StoredEntry {
+    id: u64,
+    name: String,
+    body: String,
+    created_at: u64,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    id: u64,
+    name: String,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    html: String,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn wants_html(req: &Request) -> bool {
+    req.headers()
+        .get("Accept")
+        .ok()
+        .flatten()
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// GET /api/guestbook — approved entries, newest first: an HTML page for
+/// browsers, `{"entries": [...]}` otherwise.
+pub async fn handle_list(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let db = ctx.env.d1("DB")?;
+    let stored: Vec<StoredEntry> = db
+        .prepare(
+            "SELECT id, name, body, created_at FROM guestbook \
+             WHERE status = 'approved' ORDER BY created_at DESC LIMIT ?1",
+        )
+        .bind(&[MAX_SHOWN.into()])?
+        .all()
+        .await?
+        .results()?;
+    let entries: Vec<Entry> = stored
+        .into_iter()
+        .map(|e| Entry {
+            id: e.id,
+            name: e.name,
+            created_at: e.created_at,
+            html: markdown::render_comment(&e.body),
+        })
+        .collect();
+
+    if wants_html(&req) {
+        let site_url = ctx.env.var("SITE_URL")?.to_string();
+        let mut resp = Response::from_html(guestbook_page(&site_url, &entries))?;
+        resp.headers_mut().set("Vary", "Accept")?;
+        return Ok(resp);
+    }
+
+    #[derive(Serialize)]
+    struct ListResponse {
+        entries: Vec<Entry>,
+    }
+
+    let mut resp = json_response(&ListResponse { entries }, 200)?;
+    // The HTML page has the same URL
+    resp.headers_mut().set("Vary", "Accept")?;
+    conditional::respond(&req, resp, "public, max-age=60")
+}
+
+/// POST /api/guestbook — sign the guestbook: `{"name", "email"?, "body"}`.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(entry) = req.json::<EntryRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"name\": \"...\", \"body\": \"...\"}",
+        );
+    };
+    let name = entry.name.trim();
+    let body = entry.body.trim();
+    let email = entry
+        .email
+        .as_deref()
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty());
+
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return error(400, "Name must be 1–80 characters");
+    }
+    if body.is_empty() || body.chars().count() > MAX_BODY_CHARS {
+        return error(400, "Message must be 1–2000 characters");
+    }
+    if email.as_deref().is_some_and(|e| !is_valid_email(e)) {
+        return error(400, "Invalid email address");
+    }
+
+    let spam = spam_reason(&entry.website, body);
+    let headers = req.headers();
+    let ip_hash = headers.get("CF-Connecting-IP")?.map(|ip| hash_ip(&ip));
+    let user_agent = headers.get("User-Agent")?;
+    let optional = |v: Option<String>| v.map_or(JsValue::NULL, JsValue::from);
+
+    let db = ctx.env.d1("DB")?;
+    let inserted = db
+        .prepare(
+            "INSERT INTO guestbook \
+             (name, email, body, created_at, ip_hash, user_agent, status, spam_reason) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )
+        .bind(&[
+            name.into(),
+            optional(email),
+            body.into(),
+            JsValue::from_f64(worker::Date::now().as_millis() as f64),
+            optional(ip_hash),
+            optional(user_agent),
+            if spam.is_some() { "spam" } else { "pending" }.into(),
+            optional(spam.map(String::from)),
+        ])?
+        .run()
+        .await?;
+
+    match spam {
+        Some(reason) => worker::console_warn!("Guestbook entry flagged as spam: {}", reason),
+        None => {
+            let id = inserted
+                .meta()?
+                .and_then(|m| m.last_row_id)
+                .unwrap_or_default();
+            let intro = format!(
+                "<strong>{}</strong> signed the guestbook:",
+                html::escape(name)
+            );
+            comments::notify(
+                &ctx,
+                &format!("New guestbook entry from {}", name),
+                &intro,
+                body,
+                &format!("/api/admin/guestbook/{}", id),
+            );
+        }
+    }
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        202,
+    )
+}
+
+/// GET /api/admin/guestbook?status=pending|approved|spam — admin: entries in
+/// a moderation state (default pending), oldest first.
+pub async fn handle_moderation_list(
+    req: Request,
+    ctx: RouteContext<RequestLog>,
+) -> Result<Response> {
+    let Some(status) = comments::requested_status(&req)? else {
+        return error(400, "status must be pending, approved or spam");
+    };
+    let entries = comments::moderation_entries(&ctx, "guestbook", &status).await?;
+
+    #[derive(Serialize)]
+    struct ModerationResponse {
+        entries: Vec<comments::ModerationEntry>,
+    }
+
+    json_response(&ModerationResponse { entries }, 200)
+}
+
+/// POST /api/admin/guestbook/{id}/approve — admin: show an entry.
+pub async fn handle_approve(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    comments::moderate(&ctx, "guestbook", "approved").await
+}
+
+/// POST /api/admin/guestbook/{id}/reject — admin: hide an entry as spam.
+pub async fn handle_reject(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    comments::moderate(&ctx, "guestbook", "spam").await
+}
+
+fn guestbook_page(site_url: &str, entries: &[Entry]) -> String {
+    let items: String = if entries.is_empty() {
+        "    <p>Nobody has signed yet. Be the first!</p>\n".into()
+    } else {
+        entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "    <article>\n        <header><strong>{}</strong> \
+                     <time datetime=\"{date}\">{date}</time></header>\n        {}\n    </article>\n",
+                    html::escape(&e.name),
+                    e.html,
+                    date = dates::iso_date(e.created_at),
+                )
+            })
+            .collect()
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Guestbook - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 560px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        p {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+        article {{ padding: 16px 0; border-top: 1px dashed #E4DED5; }}
+        article header {{ font-family: -apple-system, sans-serif; font-size: 14px; }}
+        time {{ color: #6B7B85; margin-left: 8px; }}
+        form {{ display: grid; gap: 8px; margin-top: 16px; }}
+        input, textarea {{ padding: 10px 14px; border: 1px solid #E4DED5; border-radius: 6px; font-size: 16px; font-family: -apple-system, sans-serif; }}
+        textarea {{ min-height: 96px; }}
+        .hp {{ position: absolute; left: -9999px; }}
+        button {{ justify-self: start; padding: 10px 18px; background: #D4706A; color: #F0EAE0; border: none; border-radius: 6px; font-size: 14px; font-weight: 600; cursor: pointer; font-family: -apple-system, sans-serif; }}
+        button:hover {{ background: #B85A54; }}
+        .msg {{ margin-top: 16px; padding: 12px; border-radius: 6px; font-size: 14px; font-family: -apple-system, sans-serif; }}
+        .msg.ok {{ background: #e8f5e9; color: #2e7d32; }}
+        .msg.err {{ background: #fce4ec; color: #c62828; }}
+    </style>
+</head>
+<body>
+    <h1>Guestbook</h1>
+    <p>Passing through? Leave a note. Entries show up once I've read them.</p>
+    <form id="sign-form">
+        <input type="text" name="name" placeholder="Your name" maxlength="80" required>
+        <input type="email" name="email" placeholder="Email (optional, never shown)">
+        <input type="text" name="website" class="hp" tabindex="-1" autocomplete="off" aria-hidden="true">
+        <textarea name="body" placeholder="Your message (markdown works)" maxlength="2000" required></textarea>
+        <button type="submit">Sign the guestbook</button>
+    </form>
+    <div id="msg"></div>
+{items}    <p style="margin-top: 32px;"><a href="{site_url}">Back to lindfors.no</a></p>
+    <script>
+    document.getElementById('sign-form').addEventListener('submit', function(e) {{
+        e.preventDefault();
+        var form = this;
+        var btn = form.querySelector('button');
+        var msg = document.getElementById('msg');
+        var field = function(name) {{ return form.querySelector('[name="' + name + '"]').value; }};
+        btn.disabled = true;
+        btn.textContent = 'Sending...';
+        fetch('/api/guestbook', {{
+            method: 'POST',
+            headers: {{ 'Content-Type': 'application/json' }},
+            body: JSON.stringify({{ name: field('name'), email: field('email'), body: field('body'), website: field('website') }})
+        }}).then(function(r) {{ return r.json(); }}).then(function(data) {{
+            if (data.success) {{
+                msg.className = 'msg ok';
+                msg.textContent = 'Thanks for signing! Your entry will appear once approved.';
+                form.reset();
+            }} else {{
+                msg.className = 'msg err';
+                msg.textContent = data.error || 'Something went wrong.';
+            }}
+        }}).catch(function() {{
+            msg.className = 'msg err';
+            msg.textContent = 'Something went wrong. Please try again.';
+        }}).finally(function() {{
+            btn.disabled = false;
+            btn.textContent = 'Sign the guestbook';
+        }});
+    }});
+    </script>
+</body>
+</html>"#,
+        items = items,
+        site_url = html::escape(site_url),
+    )
+}
@@ -0,0 +1,74 @@
+//! GET /api/ping — liveness and config check for monitoring and post-deploy
+//! verification. Reports which build is running where, and whether every
+//! required var, secret and binding is present, without calling upstreams.
+
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::json_response;
+use crate::logging::{self, RequestLog};
+
+const REQUIRED_VARS: &[&str] = &[
+    "SITE_URL",
+    "STALWART_API_URL",
+    "STALWART_LIST_ID",
+    "JMAP_API_URL",
+    "JMAP_ACCOUNT_ID",
+    "JMAP_IDENTITY_ID",
+];
+
+const REQUIRED_SECRETS: &[&str] = &["STALWART_API_KEY", "ADMIN_KEY", "JMAP_CREDENTIALS"];
+
+const REQUIRED_KV: &[&str] = &["NEWSLETTER"];
+
+const REQUIRED_D1: &[&str] = &["DB"];
+
+/// When this isolate served its first request.
+static ISOLATE_STARTED: OnceLock<u64> = OnceLock::new();
+
+#[derive(Serialize)]
+struct PingResponse {
+    ok: bool,
+    version: &'static str,
+    /// Cloudflare data center that served the request.
+    colo: Option<String>,
+    /// Age of the isolate serving the request; workers have no process uptime.
+    isolate_uptime_secs: u64,
+    /// Names of required config that is missing.
+    missing: Vec<&'static str>,
+}
+
+fn missing_config(env: &Env) -> Vec<&'static str> {
+    let vars = REQUIRED_VARS
+        .iter()
+        .filter(|name| env.var(name).map_or(true, |v| v.to_string().is_empty()));
+    let secrets = REQUIRED_SECRETS
+        .iter()
+        .filter(|name| env.secret(name).map_or(true, |v| v.to_string().is_empty()));
+    let kv = REQUIRED_KV.iter().filter(|name| env.kv(name).is_err());
+    let d1 = REQUIRED_D1.iter().filter(|name| env.d1(name).is_err());
+    vars.chain(secrets).chain(kv).chain(d1).copied().collect()
+}
+
+/// 200 when fully configured, 503 (with what's missing) otherwise.
+pub async fn handle_ping(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let now = logging::now_millis();
+    let started = *ISOLATE_STARTED.get_or_init(|| now);
+    let missing = missing_config(&ctx.env);
+    let ok = missing.is_empty();
+
+    let mut resp = json_response(
+        &PingResponse {
+            ok,
+            version: env!("GIT_SHA"),
+            colo: req.cf().map(|cf| cf.colo()),
+            isolate_uptime_secs: now.saturating_sub(started) / 1000,
+            missing,
+        },
+        if ok { 200 } else { 503 },
+    )?;
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(resp)
+}
@@ -0,0 +1,239 @@
+//! Post-render HTML transforms applied to the markdown output before it is
+//! placed into the email body.
+
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+
+const SANS: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif";
+const MONO: &str = "'SF Mono', Menlo, Monaco, Consolas, monospace";
+
+/// Escape text for use in HTML content and double-quoted attributes.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Zola-style slug: lowercase, with runs of non-alphanumerics as `-`.
+/// Used for tag page URLs and heading anchors.
+pub fn slugify(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Resolve a possibly relative `href` against `base` (the post's URL), the way
+/// a browser would. Unparseable input is returned unchanged.
+pub fn resolve_url(base: &str, href: &str) -> String {
+    match worker::Url::parse(base).and_then(|b| b.join(href)) {
+        Ok(url) => url.to_string(),
+        Err(_) => href.to_string(),
+    }
+}
+
+/// Resolve relative `href`/`src` attributes against `base` (the post's URL),
+/// so site-relative links and images like `./figure1.webp` work in email.
+/// In-document `#fragment` links are left alone.
+pub fn absolutize_urls(html: &str, base: &str) -> String {
+    fn rewrite(el: &mut Element, attr: &str, base: &str) {
+        if let Some(value) = el.get_attribute(attr) {
+            let value = value.trim();
+            if !value.is_empty() && !value.starts_with('#') {
+                let _ = el.set_attribute(attr, &resolve_url(base, value));
+            }
+        }
+    }
+
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("a[href]", |el| {
+                    rewrite(el, "href", base);
+                    Ok(())
+                }),
+                element!("img[src], source[src], video[src], audio[src]", |el| {
+                    rewrite(el, "src", base);
+                    Ok(())
+                }),
+                element!("video[poster]", |el| {
+                    rewrite(el, "poster", base);
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )
+    .unwrap_or_else(|_| html.to_string())
+}
+
+/// Tag links to `host`'s pages with `utm_source=newsletter&utm_medium=email`
+/// and `utm_campaign={campaign}`, so the site's analytics can tell visits
+/// from the email apart. API links (unsubscribe, votes, the archive) and
+/// links already carrying UTM parameters are left alone.
+pub fn add_utm(html: &str, host: &str, campaign: &str) -> String {
+    fn tagged(href: &str, host: &str, campaign: &str) -> Option<String> {
+        let url = worker::Url::parse(href).ok()?;
+        let ours = matches!(url.scheme(), "http" | "https") && url.host_str() == Some(host);
+        if !ours
+            || url.path().starts_with("/api/")
+            || url.query().is_some_and(|q| q.contains("utm_"))
+        {
+            return None;
+        }
+        // Appended to the attribute as written (entities and all):
+        // re-serializing the URL would re-encode it
+        let (link, fragment) = match href.split_once('#') {
+            Some((link, fragment)) => (link, Some(fragment)),
+            None => (href, None),
+        };
+        let separator = if link.contains('?') { "&amp;" } else { "?" };
+        let mut out = format!(
+            "{link}{separator}utm_source=newsletter&amp;utm_medium=email&amp;utm_campaign={campaign}"
+        );
+        if let Some(fragment) = fragment {
+            out.push('#');
+            out.push_str(fragment);
+        }
+        Some(out)
+    }
+
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("a[href]", |el| {
+                if let Some(href) = el.get_attribute("href") {
+                    if let Some(href) = tagged(href.trim(), host, campaign) {
+                        let _ = el.set_attribute("href", &href);
+                    }
+                }
+                Ok(())
+            })],
+            ..RewriteStrSettings::new()
+        },
+    )
+    .unwrap_or_else(|_| html.to_string())
+}
+
+/// Wrap tables and code blocks in horizontally scrollable, width-constrained
+/// containers so wide content can't push the 600px layout out on mobile.
+/// Clients without overflow support still clamp the container to the column.
+pub fn wrap_wide_content(html: &str) -> String {
+    fn wrap(el: &mut Element) {
+        el.before(
+            r#"<div class="email-scroll" style="width: 100%; max-width: 100%; overflow-x: auto; -webkit-overflow-scrolling: touch; margin: 0 0 16px 0;">"#,
+            ContentType::Html,
+        );
+        el.after("</div>", ContentType::Html);
+    }
+
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("table", |el| {
+                    wrap(el);
+                    // The wrapper carries the bottom margin
+                    let _ = el.set_attribute("style", "margin: 0;");
+                    Ok(())
+                }),
+                element!("pre", |el| {
+                    wrap(el);
+                    // Keep long lines on one line inside the scroller instead of wrapping
+                    let _ = el.set_attribute("style", "white-space: pre; overflow-x: auto; margin: 0;");
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )
+    .unwrap_or_else(|_| html.to_string())
+}
+
+/// Inline styles per selector, plus the dark-mode class from the template's
+/// `<style>` block. Email clients strip `<style>`, so anything rendered by
+/// pulldown-cmark needs its styling on the element itself.
+///
+/// Handlers for the same element run in registration order and each one
+/// prepends, so more specific selectors (`pre > code`) go before `code`.
+fn inline_rules() -> Vec<(&'static str, String, &'static str)> {
+    vec![
+        ("div.footnote-definition p", "margin: 0 0 8px 0;".into(), ""),
+        ("p", "margin: 0 0 16px 0;".into(), ""),
+        ("h2", format!("font-family: {SANS}; font-size: 22px; color: #1C3240; margin: 32px 0 12px 0; line-height: 1.3;"), "email-text"),
+        ("h3", format!("font-family: {SANS}; font-size: 18px; color: #1C3240; margin: 24px 0 8px 0; line-height: 1.3;"), "email-text"),
+        ("h4", format!("font-family: {SANS}; font-size: 16px; color: #1C3240; margin: 20px 0 8px 0;"), "email-text"),
+        ("sup.footnote-reference a", "text-decoration: none; font-weight: 600;".into(), ""),
+        // Buttons carry their own colors, which dark mode mustn't recolor
+        ("a:not(.email-button)", "color: #D4706A;".into(), "email-link"),
+        ("ul", "margin: 0 0 16px 0; padding-left: 24px;".into(), ""),
+        ("ol", "margin: 0 0 16px 0; padding-left: 24px;".into(), ""),
+        ("li", "margin: 0 0 6px 0;".into(), ""),
+        ("blockquote", "margin: 0 0 16px 0; padding: 4px 0 4px 16px; border-left: 3px solid #2A8F82; color: #5A7078; font-style: italic;".into(), "email-muted"),
+        ("pre > code", "font-family: inherit; font-size: inherit; background-color: transparent; padding: 0;".into(), ""),
+        ("code", format!("font-family: {MONO}; font-size: 14px; background-color: #F0EBE3; padding: 2px 4px; border-radius: 3px;"), "email-callout"),
+        ("pre", format!("font-family: {MONO}; font-size: 14px; line-height: 1.5; background-color: #F0EBE3; padding: 12px 16px; border-radius: 6px; margin: 0 0 16px 0; white-space: pre-wrap; word-wrap: break-word;"), "email-callout"),
+        ("table", "border-collapse: collapse; width: 100%; margin: 0 0 16px 0; font-size: 15px;".into(), ""),
+        ("th", format!("font-family: {SANS}; text-align: left; padding: 8px 10px; border-bottom: 2px solid #2A8F82;"), "email-rule"),
+        ("td", "padding: 8px 10px; border-bottom: 1px solid #E4DED5; vertical-align: top;".into(), ""),
+        ("div.footnotes hr", "margin: 32px 0 16px 0;".into(), ""),
+        ("hr", "border: none; border-top: 1px solid #E4DED5; margin: 32px 0;".into(), ""),
+        ("sup.footnote-reference", "font-size: 12px; line-height: 0; vertical-align: super;".into(), ""),
+        ("div.footnote-definition", "font-size: 14px; line-height: 1.5; color: #5A7078; margin: 0 0 8px 0;".into(), "email-muted"),
+        ("sup.footnote-definition-label", format!("float: left; margin-right: 6px; font-family: {SANS}; font-size: 12px; line-height: 1.8;"), ""),
+        ("figure", "margin: 0 0 16px 0;".into(), ""),
+        ("figcaption", format!("font-family: {SANS}; font-size: 13px; color: #5A7078; text-align: center; margin: 6px 0 0 0;"), "email-muted"),
+        ("img", "max-width: 100%; height: auto; border-radius: 6px;".into(), ""),
+    ]
+}
+
+/// Prepend `style` to the element's style attribute so author-supplied inline
+/// styles keep precedence, and add `class` for the dark-mode overrides.
+fn merge_style(el: &mut Element, style: &str, class: &str) {
+    let merged = match el.get_attribute("style") {
+        Some(existing) if !existing.trim().is_empty() => format!("{style} {existing}"),
+        _ => style.to_string(),
+    };
+    let _ = el.set_attribute("style", &merged);
+
+    if !class.is_empty() {
+        let classes = match el.get_attribute("class") {
+            Some(existing) if !existing.trim().is_empty() => format!("{existing} {class}"),
+            _ => class.to_string(),
+        };
+        let _ = el.set_attribute("class", &classes);
+    }
+}
+
+/// Apply per-element inline styles matching the email template palette.
+pub fn inline_styles(html: &str) -> String {
+    let handlers = inline_rules()
+        .into_iter()
+        .map(|(selector, style, class)| {
+            element!(selector, move |el| {
+                merge_style(el, &style, class);
+                Ok(())
+            })
+        })
+        .collect();
+
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: handlers,
+            ..RewriteStrSettings::new()
+        },
+    )
+    .unwrap_or_else(|_| html.to_string())
+}
@@ -0,0 +1,259 @@
+//! HTTP Signatures as the fediverse uses them (draft-cavage, `rsa-sha256`):
+//! signing the worker's ActivityPub requests with the actor's key and
+//! verifying the ones other servers send to the inbox.
+//!
+//! There's no RSA in the crate, so keys are imported into and used through
+//! the runtime's WebCrypto. Keys are PEM: PKCS#8 for the private key, SPKI
+//! for public ones, as `openssl genpkey` and servers' actor documents have
+//! them.
+
+use sha2::{Digest, Sha256};
+use web_sys::{CryptoKey, SubtleCrypto, WorkerGlobalScope};
+use worker::js_sys::{self, Array, Object, Reflect, Uint8Array};
+use worker::wasm_bindgen::{JsCast, JsValue};
+use worker::wasm_bindgen_futures::JsFuture;
+use worker::{Error, Headers, Method, Request, RequestInit, Result, Url};
+
+use crate::{dates, logging};
+
+/// `rsa-sha256` in WebCrypto terms.
+const ALGORITHM: &str = "RSASSA-PKCS1-v1_5";
+
+/// How far a signed request's Date may be from now, as Mastodon allows.
+const MAX_CLOCK_SKEW_MILLIS: f64 = 12.0 * 60.0 * 60.0 * 1000.0;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// What requests are signed with: the key's ID (its URL in the actor
+/// document) and the private key.
+pub struct SigningKey {
+    pub key_id: String,
+    pub private_key_pem: String,
+}
+
+/// A parsed `Signature` header.
+pub struct Signature {
+    pub key_id: String,
+    headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+/// Standard base64, padded.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut n, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        n = n << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// The DER inside a PEM block. Tolerates `\n` escapes, for keys pasted into
+/// a one-line secret.
+fn pem_der(pem: &str) -> Option<Vec<u8>> {
+    let pem = pem.replace("\\n", "\n");
+    let base64: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+        .collect();
+    base64_decode(&base64).filter(|der| !der.is_empty())
+}
+
+/// The `Digest` header value for a body.
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", base64_encode(&Sha256::digest(body)))
+}
+
+fn subtle() -> Result<SubtleCrypto> {
+    let global: WorkerGlobalScope = js_sys::global().unchecked_into();
+    Ok(global.crypto()?.subtle())
+}
+
+async fn import_key(format: &str, pem: &str, usage: &str) -> Result<CryptoKey> {
+    let der = pem_der(pem).ok_or_else(|| Error::RustError("Malformed PEM key".into()))?;
+    let algorithm = Object::new();
+    Reflect::set(&algorithm, &"name".into(), &ALGORITHM.into())?;
+    Reflect::set(&algorithm, &"hash".into(), &"SHA-256".into())?;
+    let promise = subtle()?.import_key_with_object(
+        format,
+        &Uint8Array::from(der.as_slice()),
+        &algorithm,
+        false,
+        &Array::of1(&usage.into()),
+    )?;
+    Ok(JsFuture::from(promise).await?.unchecked_into())
+}
+
+async fn sign(private_key_pem: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let key = import_key("pkcs8", private_key_pem, "sign").await?;
+    let promise = subtle()?.sign_with_str_and_u8_array(ALGORITHM, &key, data)?;
+    Ok(Uint8Array::new(&JsFuture::from(promise).await?).to_vec())
+}
+
+async fn verify_signature(public_key_pem: &str, signature: &[u8], data: &[u8]) -> Result<bool> {
+    let key = import_key("spki", public_key_pem, "verify").await?;
+    let promise =
+        subtle()?.verify_with_str_and_u8_array_and_u8_array(ALGORITHM, &key, signature, data)?;
+    Ok(JsFuture::from(promise).await?.as_bool().unwrap_or(false))
+}
+
+fn request_target(method: &Method, url: &Url) -> String {
+    let mut target = format!("{} {}", method.to_string().to_lowercase(), url.path());
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    target
+}
+
+/// A request to `url` with `headers`, plus Date and (with a body) Digest,
+/// signed with `key` over the request target, Host, Date and Digest.
+pub async fn signed_request(
+    method: Method,
+    url: &str,
+    headers: Headers,
+    body: Option<&str>,
+    key: &SigningKey,
+) -> Result<Request> {
+    let parsed = Url::parse(url).map_err(|e| Error::RustError(e.to_string()))?;
+    let host = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(Error::RustError(format!("No host in {}", url))),
+    };
+    let date = dates::http_date(logging::now_millis());
+    headers.set("Date", &date)?;
+
+    let mut signed = vec![
+        ("(request-target)", request_target(&method, &parsed)),
+        ("host", host),
+        ("date", date),
+    ];
+    if let Some(body) = body {
+        let digest = digest(body.as_bytes());
+        headers.set("Digest", &digest)?;
+        signed.push(("digest", digest));
+    }
+    let signing_string: Vec<String> = signed
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    let signature = sign(&key.private_key_pem, signing_string.join("\n").as_bytes()).await?;
+    let names: Vec<&str> = signed.iter().map(|(name, _)| *name).collect();
+    headers.set(
+        "Signature",
+        &format!(
+            "keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+            key.key_id,
+            names.join(" "),
+            base64_encode(&signature)
+        ),
+    )?;
+
+    let mut init = RequestInit::new();
+    init.with_method(method);
+    init.with_headers(headers);
+    if let Some(body) = body {
+        init.with_body(Some(JsValue::from_str(body)));
+    }
+    Request::new_with_init(url, &init)
+}
+
+/// The request's `Signature` header, if it has a well-formed one.
+pub fn parse(req: &Request) -> Option<Signature> {
+    let header = req.headers().get("Signature").ok()??;
+    let (mut key_id, mut headers, mut signature) = (None, None, None);
+    for param in header.split(',') {
+        let (name, value) = param.trim().split_once('=')?;
+        let value = value.trim_matches('"');
+        match name {
+            "keyId" => key_id = Some(value.to_string()),
+            "headers" => headers = Some(value.split(' ').map(str::to_lowercase).collect()),
+            "signature" => signature = base64_decode(value),
+            _ => {}
+        }
+    }
+    Some(Signature {
+        key_id: key_id?,
+        headers: headers?,
+        signature: signature?,
+    })
+}
+
+/// Whether `signature` is `public_key_pem`'s over `req` (whose body is
+/// `body`): it must cover the request target, Host, Date and, with a body,
+/// a Digest that matches, and the Date must be within 12 hours of now.
+pub async fn verify(
+    req: &Request,
+    body: &[u8],
+    signature: &Signature,
+    public_key_pem: &str,
+) -> Result<bool> {
+    let mut required = vec!["(request-target)", "host", "date"];
+    if !body.is_empty() {
+        required.push("digest");
+    }
+    if !required
+        .iter()
+        .all(|name| signature.headers.iter().any(|h| h == name))
+    {
+        return Ok(false);
+    }
+
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    if !body.is_empty() {
+        let expected = digest(body);
+        let digests = header("Digest").unwrap_or_default();
+        if !digests.split(',').any(|d| d.trim() == expected) {
+            return Ok(false);
+        }
+    }
+    let date = js_sys::Date::parse(&header("Date").unwrap_or_default());
+    if date.is_nan() || (logging::now_millis() as f64 - date).abs() > MAX_CLOCK_SKEW_MILLIS {
+        return Ok(false);
+    }
+
+    let url = req.url()?;
+    let mut signing_string = Vec::new();
+    for name in &signature.headers {
+        let value = match name.as_str() {
+            "(request-target)" => request_target(&req.method(), &url),
+            name => match header(name) {
+                Some(value) => value,
+                None => return Ok(false),
+            },
+        };
+        signing_string.push(format!("{}: {}", name, value));
+    }
+    verify_signature(
+        public_key_pem,
+        &signature.signature,
+        signing_string.join("\n").as_bytes(),
+    )
+    .await
+}
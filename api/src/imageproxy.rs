@@ -0,0 +1,153 @@
+//! GET /api/img — resize an image from the site on the fly, for one-off
+//! sizes the offline `tools/img-optim` pass didn't produce. The resizing is
+//! Cloudflare's (Image Transformations must be enabled on the zone):
+//! without it the original comes back unchanged.
+//!
+//! Only images on SITE_URL's origin are fetched, and results are kept in
+//! the edge cache, so each size of each image is only made about once a
+//! day per data center. A source replaced under the same URL shows within
+//! [`RESIZED_CACHE_CONTROL`]'s max-age.
+
+use std::collections::HashMap;
+
+use serde_json::json;
+use worker::js_sys::{Reflect, JSON};
+use worker::wasm_bindgen::JsValue;
+use worker::{web_sys, Cache, Fetch, Request, Response, Result, RouteContext, Url};
+
+use crate::logging::{self, RequestLog};
+use crate::{json_response, ApiResponse};
+
+const MAX_WIDTH: u32 = 2400;
+const DEFAULT_QUALITY: u32 = 80;
+
+/// For resized images. Bounded, since a source can be replaced in place.
+const RESIZED_CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// For originals passed through unresized (Image Transformations off, or
+/// the source isn't something it resizes): soon retried.
+const PASSTHROUGH_CACHE_CONTROL: &str = "public, max-age=300";
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// `src` (absolute or site-relative) as a URL on the site's origin, if it
+/// is one. The API itself is left out, so this can't fetch itself.
+fn source_url(site_url: &str, src: &str) -> Option<Url> {
+    let site = Url::parse(site_url).ok()?;
+    let url = site.join(src).ok()?;
+    (url.origin() == site.origin() && !url.path().starts_with("/api/")).then_some(url)
+}
+
+/// A request for `url`, resized by Cloudflare to at most `width` wide as
+/// WebP. Built through `web_sys` because worker's `ResizeConfig` doesn't
+/// serialize a numeric quality the way the runtime expects.
+fn resize_request(url: &Url, width: Option<u32>, quality: u32) -> Result<Request> {
+    let mut image = json!({ "fit": "scale-down", "format": "webp", "quality": quality });
+    if let Some(width) = width {
+        image["width"] = width.into();
+    }
+    let cf = JSON::parse(&json!({ "image": image }).to_string())?;
+    let init = web_sys::RequestInit::new();
+    Reflect::set(&init, &JsValue::from("cf"), &cf)?;
+    Ok(web_sys::Request::new_with_str_and_init(url.as_str(), &init)?.into())
+}
+
+fn image_response(bytes: Vec<u8>, content_type: &str, cache_control: &str) -> Result<Response> {
+    let mut resp = Response::from_bytes(bytes)?;
+    resp.headers_mut().set("Content-Type", content_type)?;
+    resp.headers_mut().set("Cache-Control", cache_control)?;
+    Ok(resp)
+}
+
+/// GET /api/img?src=/blog/post/figure.png&w=800&q=75 — the image at `src`,
+/// at most `w` pixels wide (never enlarged), as WebP at quality `q` (1–100,
+/// default 80).
+pub async fn handle_resize(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let params: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let Some(src) = params.get("src").and_then(|src| source_url(&site_url, src)) else {
+        return error(400, "src must be an image on this site");
+    };
+    let width = match params.get("w").map(|w| w.parse::<u32>()) {
+        None => None,
+        Some(Ok(w)) if (1..=MAX_WIDTH).contains(&w) => Some(w),
+        Some(_) => return error(400, "w must be a width from 1 to 2400"),
+    };
+    let quality = match params.get("q").map(|q| q.parse::<u32>()) {
+        None => DEFAULT_QUALITY,
+        Some(Ok(q)) if (1..=100).contains(&q) => q,
+        Some(_) => return error(400, "q must be a quality from 1 to 100"),
+    };
+
+    // Keyed on the normalized parameters, so equivalent URLs share an entry
+    let mut cache_key = Url::parse(&format!("{}/api/img", site_url))?;
+    cache_key
+        .query_pairs_mut()
+        .append_pair("src", src.as_str())
+        .append_pair("w", &width.map(|w| w.to_string()).unwrap_or_default())
+        .append_pair("q", &quality.to_string());
+    let cache = Cache::default();
+    if let Some(mut cached) = cache.get(cache_key.as_str(), false).await? {
+        // Rebuilt, as cached responses' headers can't be added to
+        let content_type = cached.headers().get("Content-Type")?.unwrap_or_default();
+        let cache_control = cached
+            .headers()
+            .get("Cache-Control")?
+            .unwrap_or_else(|| PASSTHROUGH_CACHE_CONTROL.into());
+        return image_response(cached.bytes().await?, &content_type, &cache_control);
+    }
+
+    let started = logging::now_millis();
+    let fetched = Fetch::Request(resize_request(&src, width, quality)?)
+        .send()
+        .await;
+    let status = fetched.as_ref().map(|resp| resp.status_code());
+    ctx.data
+        .upstream_status("site", "fetch_image", started, &status);
+    let mut upstream = fetched?;
+    if upstream.status_code() != 200 {
+        return error(
+            if upstream.status_code() == 404 {
+                404
+            } else {
+                502
+            },
+            &format!(
+                "Fetching {} failed (status {})",
+                src,
+                upstream.status_code()
+            ),
+        );
+    }
+    let content_type = upstream.headers().get("Content-Type")?.unwrap_or_default();
+    if !content_type.starts_with("image/") {
+        return error(415, "src isn't an image");
+    }
+
+    // Cloudflare reports on what it resized, with `err=` when it couldn't
+    let resized = upstream
+        .headers()
+        .get("Cf-Resized")?
+        .is_some_and(|report| !report.contains("err="));
+    let cache_control = if resized {
+        RESIZED_CACHE_CONTROL
+    } else {
+        PASSTHROUGH_CACHE_CONTROL
+    };
+    let mut resp = image_response(upstream.bytes().await?, &content_type, cache_control)?;
+    let cached = resp.cloned()?;
+    ctx.data.wait_until(async move {
+        if let Err(e) = cache.put(cache_key.as_str(), cached).await {
+            worker::console_warn!("Caching the resized image failed: {}", e);
+        }
+    });
+    Ok(resp)
+}
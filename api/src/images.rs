@@ -0,0 +1,160 @@
+//! Image attribute pass for the email body: explicit `width`/`height` so
+//! clients reserve space (and Outlook doesn't render images at natural size),
+//! and removal of browser-only loading hints.
+
+use std::collections::HashMap;
+
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use worker::{Fetch, Headers, Method, Request, RequestInit};
+
+use crate::fanout;
+
+/// Width of the email's content column; wider images are scaled down to it.
+const MAX_WIDTH: u32 = 552;
+
+/// Enough for the header of any PNG/GIF/WebP, and of JPEGs without huge EXIF.
+const PROBE_BYTES: usize = 64 * 1024;
+
+/// Don't hold up a send probing an image-heavy post.
+const MAX_PROBES: usize = 20;
+
+/// Set `width`/`height` on body images that lack them, probing each image's
+/// header over HTTP (a few at a time), and strip `loading`/`decoding`.
+/// Images that can't be probed are left as they are.
+pub async fn add_dimensions(html: &str) -> String {
+    let sources: Vec<String> = sources_without_size(html)
+        .into_iter()
+        .take(MAX_PROBES)
+        .collect();
+    let probed = fanout::map(&sources, fanout::CONNECTIONS, |src| probe(src)).await;
+    let sizes: HashMap<String, (u32, u32)> = sources
+        .iter()
+        .zip(probed)
+        .filter_map(|(src, size)| Some((src.clone(), size?)))
+        .collect();
+    apply(html, &sizes)
+}
+
+/// Absolute `src`s of images without an explicit width, in document order.
+fn sources_without_size(html: &str) -> Vec<String> {
+    let mut sources = Vec::new();
+    let _ = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("img[src]:not([width])", |el| {
+                if let Some(src) = el.get_attribute("src") {
+                    let absolute = src.starts_with("https://") || src.starts_with("http://");
+                    if absolute && !sources.contains(&src) {
+                        sources.push(src);
+                    }
+                }
+                Ok(())
+            })],
+            ..RewriteStrSettings::new()
+        },
+    );
+    sources
+}
+
+fn apply(html: &str, sizes: &HashMap<String, (u32, u32)>) -> String {
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!("img", |el| {
+                // Meaningless in email, and some clients drop lazy images entirely
+                el.remove_attribute("loading");
+                el.remove_attribute("decoding");
+                el.remove_attribute("fetchpriority");
+
+                let size = el
+                    .get_attribute("src")
+                    .and_then(|src| sizes.get(&src).copied());
+                if let (Some((width, height)), false) = (size, el.has_attribute("width")) {
+                    let (width, height) = fit(width, height);
+                    let _ = el.set_attribute("width", &width.to_string());
+                    let _ = el.set_attribute("height", &height.to_string());
+                }
+                Ok(())
+            })],
+            ..RewriteStrSettings::new()
+        },
+    )
+    .unwrap_or_else(|_| html.to_string())
+}
+
+/// Scale to the content column, keeping the aspect ratio.
+fn fit(width: u32, height: u32) -> (u32, u32) {
+    if width <= MAX_WIDTH {
+        return (width, height);
+    }
+    let scaled = (u64::from(height) * u64::from(MAX_WIDTH) / u64::from(width)) as u32;
+    (MAX_WIDTH, scaled.max(1))
+}
+
+/// Fetch the start of an image and read its dimensions from the header.
+async fn probe(url: &str) -> Option<(u32, u32)> {
+    let headers = Headers::new();
+    headers
+        .set("Range", &format!("bytes=0-{}", PROBE_BYTES - 1))
+        .ok()?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get);
+    init.with_headers(headers);
+
+    let req = Request::new_with_init(url, &init).ok()?;
+    let mut resp = Fetch::Request(req).send().await.ok()?;
+    if !matches!(resp.status_code(), 200 | 206) {
+        return None;
+    }
+    let bytes = resp.bytes().await.ok()?;
+    dimensions(&bytes[..bytes.len().min(PROBE_BYTES)])
+}
+
+/// Pixel dimensions from a PNG, GIF, JPEG or WebP header.
+fn dimensions(b: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u32::from(u16::from_be_bytes([*b.get(i)?, *b.get(i + 1)?])));
+    let le16 = |i: usize| Some(u32::from(u16::from_le_bytes([*b.get(i)?, *b.get(i + 1)?])));
+    let le24 = |i: usize| {
+        Some(u32::from(*b.get(i)?) | u32::from(*b.get(i + 1)?) << 8 | u32::from(*b.get(i + 2)?) << 16)
+    };
+    let be32 = |i: usize| Some(u32::from_be_bytes(b.get(i..i + 4)?.try_into().ok()?));
+
+    if b.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+    if b.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+    if b.starts_with(b"RIFF") && b.get(8..12) == Some(b"WEBP") {
+        return match b.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(b.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        };
+    }
+    if b.starts_with(&[0xFF, 0xD8]) {
+        let mut i = 2;
+        while i + 9 < b.len() {
+            if b[i] != 0xFF {
+                i += 1;
+                continue;
+            }
+            let marker = b[i + 1];
+            if marker == 0xFF || marker == 0x01 || (0xD0..=0xD8).contains(&marker) {
+                i += if marker == 0xFF { 1 } else { 2 };
+                continue;
+            }
+            // Start-of-frame markers, excluding DHT, JPG and DAC
+            if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    None
+}
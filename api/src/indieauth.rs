@@ -0,0 +1,502 @@
+//! IndieAuth (<https://indieauth.spec.indieweb.org/>): signing in to
+//! IndieWeb apps, such as Micropub clients, as https://lindfors.no/, and
+//! issuing them access tokens, so they never hold ADMIN_KEY.
+//!
+//! Clients discover the endpoints from the `<link rel="indieauth-metadata">`
+//! on the site's pages. The authorization endpoint shows a consent page,
+//! where ADMIN_KEY approves the request once; the client then redeems the
+//! code it gets (PKCE, S256 only) at the token endpoint for a token with the
+//! approved scopes. Codes and tokens are kept in the NEWSLETTER KV namespace
+//! as hashes under `indieauth:code:` (for ten minutes) and `indieauth:token:`
+//! (until revoked).
+//!
+//! Clients are only checked as far as `redirect_uri` being on `client_id`'s
+//! origin; client metadata isn't fetched.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{Env, Error, FormData, FormEntry, Request, Response, Result, RouteContext, Url};
+
+use crate::logging::RequestLog;
+use crate::{auth, html, httpsig, json_response, sendlog};
+
+const CODE_TTL_SECS: u64 = 10 * 60;
+
+/// What tokens can be granted for: `create` and `draft` (drafts only) for
+/// Micropub, `profile` for the profile.
+const SCOPES: [&str; 3] = ["create", "draft", "profile"];
+
+/// An approved authorization request, waiting for its code to be redeemed.
+#[derive(Serialize, Deserialize)]
+struct Grant {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    /// Space-separated.
+    scope: String,
+}
+
+/// An issued access token.
+#[derive(Serialize, Deserialize)]
+pub struct Token {
+    pub client_id: String,
+    /// Space-separated.
+    pub scope: String,
+    /// Milliseconds since the Unix epoch.
+    pub issued_at: u64,
+}
+
+impl Token {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split(' ').any(|s| s == scope)
+    }
+}
+
+/// A checked authorization request.
+struct AuthRequest {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+    scope: String,
+}
+
+/// OAuth's error shape.
+fn error(status: u16, code: &str, description: &str) -> Result<Response> {
+    json_response(
+        &json!({ "error": code, "error_description": description }),
+        status,
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The KV key for a code or token: only its hash is stored.
+fn kv_key(kind: &str, secret: &str) -> String {
+    format!(
+        "indieauth:{}:{}",
+        kind,
+        hex(&Sha256::digest(secret.as_bytes()))
+    )
+}
+
+fn random_secret() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(hex(&bytes))
+}
+
+/// PKCE S256: unpadded base64url of the verifier's SHA-256.
+fn code_challenge(verifier: &str) -> String {
+    httpsig::base64_encode(&Sha256::digest(verifier.as_bytes()))
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+/// The profile URL tokens are issued for: the site's home page.
+fn me(env: &Env) -> Result<String> {
+    Ok(format!(
+        "{}/",
+        env.var("SITE_URL")?.to_string().trim_end_matches('/')
+    ))
+}
+
+pub fn bearer_token(req: &Request) -> Result<Option<String>> {
+    Ok(req
+        .headers()
+        .get("Authorization")?
+        .and_then(|h| h.strip_prefix("Bearer ").map(|t| t.trim().to_string()))
+        .filter(|t| !t.is_empty()))
+}
+
+/// The token `token` is, if it was issued and hasn't been revoked.
+pub async fn verify(env: &Env, token: &str) -> Result<Option<Token>> {
+    env.kv("NEWSLETTER")?
+        .get(&kv_key("token", token))
+        .json()
+        .await
+        .map_err(Error::from)
+}
+
+fn field(form: &FormData, name: &str) -> String {
+    form.get_field(name).unwrap_or_default()
+}
+
+/// Check the authorization request's parameters: the message to show if
+/// they don't make a valid request.
+fn auth_request(
+    env: &Env,
+    params: &HashMap<String, String>,
+) -> Result<std::result::Result<AuthRequest, &'static str>> {
+    let param = |name: &str| params.get(name).map(|v| v.trim().to_string());
+    if param("response_type").is_some_and(|t| t != "code") {
+        return Ok(Err("response_type must be code"));
+    }
+    let (client_id, redirect_uri) = (param("client_id"), param("redirect_uri"));
+    let Some(client) = client_id.as_deref().and_then(|c| Url::parse(c).ok()) else {
+        return Ok(Err("client_id must be a URL"));
+    };
+    if !matches!(client.scheme(), "http" | "https") {
+        return Ok(Err("client_id must be a URL"));
+    }
+    let Some(redirect) = redirect_uri.as_deref().and_then(|r| Url::parse(r).ok()) else {
+        return Ok(Err("redirect_uri must be a URL"));
+    };
+    if redirect.origin() != client.origin() {
+        return Ok(Err("redirect_uri must be on client_id's origin"));
+    }
+    let Some(state) = param("state").filter(|s| !s.is_empty()) else {
+        return Ok(Err("state is required"));
+    };
+    let Some(code_challenge) = param("code_challenge").filter(|c| !c.is_empty()) else {
+        return Ok(Err("code_challenge is required (PKCE)"));
+    };
+    if param("code_challenge_method").as_deref() != Some("S256") {
+        return Ok(Err("code_challenge_method must be S256"));
+    }
+    if let Some(requested_me) = param("me") {
+        if requested_me.trim_end_matches('/') != me(env)?.trim_end_matches('/') {
+            return Ok(Err("This server only signs in as its own site"));
+        }
+    }
+    let scope = param("scope").unwrap_or_default();
+    let scope: Vec<&str> = scope
+        .split_whitespace()
+        .filter(|s| SCOPES.contains(s))
+        .collect();
+    Ok(Ok(AuthRequest {
+        // As given, since redeeming them compares them as strings
+        client_id: client_id.unwrap_or_default(),
+        redirect_uri: redirect_uri.unwrap_or_default(),
+        state,
+        code_challenge,
+        scope: scope.join(" "),
+    }))
+}
+
+/// GET /api/indieauth/metadata — the server's metadata, for discovery.
+pub async fn handle_metadata(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let me = me(&ctx.env)?;
+    let endpoint = |name: &str| format!("{}api/indieauth/{}", me, name);
+    json_response(
+        &json!({
+            "issuer": me,
+            "authorization_endpoint": endpoint("auth"),
+            "token_endpoint": endpoint("token"),
+            "revocation_endpoint": endpoint("revoke"),
+            "revocation_endpoint_auth_methods_supported": ["none"],
+            "scopes_supported": SCOPES,
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "code_challenge_methods_supported": ["S256"],
+            "authorization_response_iss_parameter_supported": true,
+        }),
+        200,
+    )
+}
+
+/// GET /api/indieauth/auth — the consent page for an authorization request.
+pub async fn handle_authorize_page(
+    req: Request,
+    ctx: RouteContext<RequestLog>,
+) -> Result<Response> {
+    let params: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+    let page = match auth_request(&ctx.env, &params)? {
+        Ok(request) => consent_page(&request, None),
+        Err(message) => return html_page(400, &error_page(message)),
+    };
+    html_page(200, &page)
+}
+
+/// POST /api/indieauth/auth — either the consent form, approved with
+/// ADMIN_KEY (redirects back to the client with a code), or a client
+/// redeeming a code for the profile only (`grant_type=authorization_code`).
+pub async fn handle_authorize(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(form) = req.form_data().await else {
+        return error(400, "invalid_request", "Expected a form-encoded body");
+    };
+    if form.has("grant_type") {
+        return match redeem(&ctx.env, &form).await? {
+            Ok(_) => json_response(&json!({ "me": me(&ctx.env)? }), 200),
+            Err((code, description)) => error(400, code, description),
+        };
+    }
+
+    let params: HashMap<String, String> = [
+        "response_type",
+        "client_id",
+        "redirect_uri",
+        "state",
+        "code_challenge",
+        "code_challenge_method",
+        "scope",
+    ]
+    .iter()
+    .map(|name| (name.to_string(), field(&form, name)))
+    .collect();
+    let request = match auth_request(&ctx.env, &params)? {
+        Ok(request) => request,
+        Err(message) => return html_page(400, &error_page(message)),
+    };
+    if !auth::is_admin_key(&ctx.env, &field(&form, "key"))? {
+        return html_page(401, &consent_page(&request, Some("Wrong key")));
+    }
+
+    // Only what was left ticked on the consent page
+    let approved: Vec<String> = form
+        .get_all("approve")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|entry| match entry {
+            FormEntry::Field(scope) => Some(scope),
+            FormEntry::File(_) => None,
+        })
+        .collect();
+    let scope: Vec<&str> = request
+        .scope
+        .split_whitespace()
+        .filter(|s| approved.iter().any(|a| a == s))
+        .collect();
+    let code = random_secret()?;
+    let grant = Grant {
+        client_id: request.client_id,
+        redirect_uri: request.redirect_uri.clone(),
+        code_challenge: request.code_challenge,
+        scope: scope.join(" "),
+    };
+    ctx.env
+        .kv("NEWSLETTER")?
+        .put(&kv_key("code", &code), &grant)?
+        .expiration_ttl(CODE_TTL_SECS)
+        .execute()
+        .await?;
+
+    let mut redirect =
+        Url::parse(&request.redirect_uri).map_err(|e| Error::RustError(e.to_string()))?;
+    redirect
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &request.state)
+        .append_pair("iss", &me(&ctx.env)?);
+    Response::redirect_with_status(redirect, 302)
+}
+
+/// Redeem the form's authorization code (once): its grant, or the OAuth
+/// error if it doesn't check out.
+async fn redeem(
+    env: &Env,
+    form: &FormData,
+) -> Result<std::result::Result<Grant, (&'static str, &'static str)>> {
+    if field(form, "grant_type") != "authorization_code" {
+        return Ok(Err((
+            "unsupported_grant_type",
+            "grant_type must be authorization_code",
+        )));
+    }
+    let code = field(form, "code");
+    if code.is_empty() {
+        return Ok(Err(("invalid_request", "code is required")));
+    }
+    let kv = env.kv("NEWSLETTER")?;
+    let key = kv_key("code", &code);
+    let Some(grant) = kv.get(&key).json::<Grant>().await? else {
+        return Ok(Err(("invalid_grant", "Unknown or expired code")));
+    };
+    kv.delete(&key).await?;
+
+    if field(form, "client_id") != grant.client_id
+        || field(form, "redirect_uri") != grant.redirect_uri
+    {
+        return Ok(Err((
+            "invalid_grant",
+            "client_id and redirect_uri must match the authorization request",
+        )));
+    }
+    if code_challenge(&field(form, "code_verifier")) != grant.code_challenge {
+        return Ok(Err(("invalid_grant", "code_verifier doesn't match")));
+    }
+    Ok(Ok(grant))
+}
+
+/// POST /api/indieauth/token — redeem an authorization code for an access
+/// token.
+pub async fn handle_token(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(form) = req.form_data().await else {
+        return error(400, "invalid_request", "Expected a form-encoded body");
+    };
+    let grant = match redeem(&ctx.env, &form).await? {
+        Ok(grant) => grant,
+        Err((code, description)) => return error(400, code, description),
+    };
+    if grant.scope.is_empty() {
+        return error(
+            400,
+            "invalid_grant",
+            "No scope was approved, so there's no token to issue",
+        );
+    }
+
+    let access_token = random_secret()?;
+    let token = Token {
+        client_id: grant.client_id,
+        scope: grant.scope,
+        issued_at: sendlog::now_millis(),
+    };
+    ctx.env
+        .kv("NEWSLETTER")?
+        .put(&kv_key("token", &access_token), &token)?
+        .execute()
+        .await?;
+
+    let mut resp = json_response(
+        &json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "scope": token.scope,
+            "me": me(&ctx.env)?,
+        }),
+        200,
+    )?;
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(resp)
+}
+
+/// GET /api/indieauth/token — what the bearer token is, for clients that
+/// verify tokens the older way.
+pub async fn handle_token_info(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let token = match bearer_token(&req)? {
+        Some(token) => verify(&ctx.env, &token).await?,
+        None => None,
+    };
+    let Some(token) = token else {
+        return error(401, "invalid_token", "Unknown or revoked token");
+    };
+    json_response(
+        &json!({
+            "me": me(&ctx.env)?,
+            "client_id": token.client_id,
+            "scope": token.scope,
+        }),
+        200,
+    )
+}
+
+/// POST /api/indieauth/revoke — revoke the form's `token`. Always 200, as
+/// revoking an unknown token is not an error.
+pub async fn handle_revoke(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(form) = req.form_data().await else {
+        return error(400, "invalid_request", "Expected a form-encoded body");
+    };
+    let token = field(&form, "token");
+    if !token.is_empty() {
+        ctx.env
+            .kv("NEWSLETTER")?
+            .delete(&kv_key("token", &token))
+            .await?;
+    }
+    Ok(Response::empty()?.with_status(200))
+}
+
+fn html_page(status: u16, page: &str) -> Result<Response> {
+    let mut resp = Response::from_html(page)?.with_status(status);
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    // Nothing may frame the consent page
+    resp.headers_mut().set("X-Frame-Options", "DENY")?;
+    Ok(resp)
+}
+
+fn page(body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="robots" content="noindex">
+    <title>Sign in - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 480px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        p, li {{ line-height: 1.6; }}
+        label {{ font-family: -apple-system, sans-serif; font-size: 14px; }}
+        input[type="password"] {{ width: 100%; box-sizing: border-box; padding: 10px 14px; margin: 8px 0 16px; border: 1px solid #E4DED5; border-radius: 6px; font-size: 16px; }}
+        button {{ padding: 10px 18px; background: #D4706A; color: #F0EAE0; border: none; border-radius: 6px; font-size: 14px; font-weight: 600; cursor: pointer; font-family: -apple-system, sans-serif; }}
+        button:hover {{ background: #B85A54; }}
+        ul {{ list-style: none; padding: 0; }}
+        .msg {{ margin-top: 16px; padding: 12px; border-radius: 6px; font-size: 14px; font-family: -apple-system, sans-serif; background: #fce4ec; color: #c62828; }}
+    </style>
+</head>
+<body>
+{body}
+</body>
+</html>"#
+    )
+}
+
+fn error_page(message: &str) -> String {
+    page(&format!(
+        "    <h1>Can't sign in</h1>\n    <p>{}</p>",
+        html::escape(message)
+    ))
+}
+
+fn consent_page(request: &AuthRequest, message: Option<&str>) -> String {
+    let client = Url::parse(&request.client_id)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_else(|| request.client_id.clone());
+    let scopes: String = request
+        .scope
+        .split_whitespace()
+        .map(|scope| {
+            format!(
+                "        <li><label><input type=\"checkbox\" name=\"approve\" value=\"{0}\" checked> {0}</label></li>\n",
+                html::escape(scope)
+            )
+        })
+        .collect();
+    let scopes = if scopes.is_empty() {
+        "    <p>It asks only to confirm who you are.</p>\n".to_string()
+    } else {
+        format!("    <p>It asks for:</p>\n    <ul>\n{}    </ul>\n", scopes)
+    };
+    let hidden: String = [
+        ("response_type", "code"),
+        ("client_id", request.client_id.as_str()),
+        ("redirect_uri", request.redirect_uri.as_str()),
+        ("state", request.state.as_str()),
+        ("code_challenge", request.code_challenge.as_str()),
+        ("code_challenge_method", "S256"),
+        ("scope", request.scope.as_str()),
+    ]
+    .iter()
+    .map(|(name, value)| {
+        format!(
+            "        <input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+            name,
+            html::escape(value)
+        )
+    })
+    .collect();
+    let message = message
+        .map(|m| format!("    <div class=\"msg\">{}</div>\n", html::escape(m)))
+        .unwrap_or_default();
+    page(&format!(
+        r#"    <h1>Sign in to {client}</h1>
+    <p><strong>{client}</strong> wants to sign in as this site and will be sent back to <code>{redirect}</code>.</p>
+{scopes}    <form method="post">
+{hidden}        <label for="key">Admin key</label>
+        <input type="password" id="key" name="key" required autofocus>
+        <button type="submit">Approve</button>
+    </form>
+{message}"#,
+        client = html::escape(&client),
+        redirect = html::escape(&request.redirect_uri),
+    ))
+}
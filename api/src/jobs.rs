@@ -0,0 +1,143 @@
+//! Background jobs, through the JOBS queue. Request handlers [`enqueue`]
+//! work that shouldn't hold up their response or be lost when it fails: a
+//! single email, a cross-post. The same worker consumes the queue
+//! (`#[event(queue)]` in lib.rs): a job that fails is retried after
+//! [`RETRY_DELAY_SECS`], up to the consumer's `max_retries`, then moves to
+//! the dead-letter queue. Dead jobs are kept under `jobs:dead:{message id}`
+//! in the NEWSLETTER KV namespace for a look, and not run again.
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, MessageBatch, MessageExt, QueueRetryOptionsBuilder, Result};
+
+use crate::logging::{self, RequestLog};
+use crate::sendlog::SentIssue;
+use crate::{jmap_send_email, mastodon};
+
+/// Must match `dead_letter_queue` of the JOBS consumer in wrangler.toml.
+const DEAD_LETTER_QUEUE: &str = "newsletter-jobs-dead";
+
+/// Give the failing service a few minutes before trying again.
+const RETRY_DELAY_SECS: u32 = 300;
+
+/// How long dead jobs are kept.
+const DEAD_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    /// One email, from postmaster@, through JMAP. `operation` names it in
+    /// the upstream log (e.g. `notify_reply`).
+    Email {
+        to: String,
+        subject: String,
+        html: String,
+        operation: String,
+    },
+    /// Announce a sent issue on Mastodon.
+    Mastodon { issue: SentIssue },
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::Email { .. } => "email",
+            Job::Mastodon { .. } => "mastodon",
+        }
+    }
+}
+
+/// Queue `job` to run in the background.
+pub async fn enqueue(env: &Env, job: &Job) -> Result<()> {
+    env.queue("JOBS")?.send(job).await
+}
+
+async fn send_email(
+    env: &Env,
+    log: &RequestLog,
+    to: &str,
+    subject: &str,
+    html: &str,
+    operation: &str,
+) -> Result<()> {
+    let jmap_url = env.var("JMAP_API_URL")?.to_string();
+    let credentials = env.secret("JMAP_CREDENTIALS")?.to_string();
+    let account_id = env.var("JMAP_ACCOUNT_ID")?.to_string();
+    let identity_id = env.var("JMAP_IDENTITY_ID")?.to_string();
+
+    let started = logging::now_millis();
+    let result = jmap_send_email(
+        &jmap_url,
+        &credentials,
+        &account_id,
+        &identity_id,
+        "postmaster@lindfors.no",
+        to,
+        subject,
+        html,
+    )
+    .await;
+    log.upstream_status("jmap", operation, started, &result);
+    match result? {
+        200 => Ok(()),
+        status => Err(worker::Error::RustError(format!(
+            "JMAP request failed (status {})",
+            status
+        ))),
+    }
+}
+
+async fn run(env: &Env, log: &RequestLog, job: &Job) -> Result<()> {
+    match job {
+        Job::Email {
+            to,
+            subject,
+            html,
+            operation,
+        } => send_email(env, log, to, subject, html, operation).await,
+        Job::Mastodon { issue } => mastodon::announce(env, log, issue).await,
+    }
+}
+
+/// Run a batch of jobs, each acknowledged or retried on its own; jobs from
+/// the dead-letter queue are only recorded.
+pub async fn consume(batch: MessageBatch<Job>, env: &Env) -> Result<()> {
+    let log = RequestLog::scheduled(env);
+    let dead = batch.queue() == DEAD_LETTER_QUEUE;
+    let kv = env.kv("NEWSLETTER")?;
+
+    for message in batch.messages()? {
+        let job = message.body();
+        if dead {
+            worker::console_error!("{} job {} gave up after retries", job.kind(), message.id());
+            log.event("job_dead", false);
+            kv.put(&format!("jobs:dead:{}", message.id()), job)?
+                .expiration_ttl(DEAD_TTL_SECS)
+                .execute()
+                .await?;
+            message.ack();
+            continue;
+        }
+
+        match run(env, &log, job).await {
+            Ok(()) => {
+                log.event(&format!("job_{}", job.kind()), true);
+                message.ack();
+            }
+            Err(e) => {
+                worker::console_warn!(
+                    "{} job {} failed, retrying: {}",
+                    job.kind(),
+                    message.id(),
+                    e
+                );
+                log.event(&format!("job_{}", job.kind()), false);
+                message.retry_with_options(
+                    &QueueRetryOptionsBuilder::new()
+                        .with_delay_seconds(RETRY_DELAY_SECS)
+                        .build(),
+                );
+            }
+        }
+    }
+    Ok(())
+}
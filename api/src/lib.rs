@@ -1,6 +1,63 @@
 use serde::{Deserialize, Serialize};
 use worker::*;
 
+use logging::RequestLog;
+use middleware::{admin, Routes};
+use ratelimit::limited;
+
+mod activitypub;
+mod analytics;
+mod archive;
+mod audit;
+mod auth;
+mod bluesky;
+mod bookmarks;
+mod breaker;
+mod comments;
+mod conditional;
+mod coordinator;
+mod dates;
+mod db;
+mod digest;
+mod email;
+mod fanout;
+mod feeds;
+mod files;
+mod flags;
+mod frontmatter;
+mod github;
+mod guestbook;
+mod health;
+mod html;
+mod httpsig;
+mod imageproxy;
+mod images;
+mod indieauth;
+mod jobs;
+mod linkaudit;
+mod logging;
+mod markdown;
+mod mastodon;
+mod metrics;
+mod micropub;
+mod middleware;
+mod now;
+mod polls;
+mod premium;
+mod ratelimit;
+mod reactions;
+mod redirects;
+mod related;
+mod rendercache;
+mod search;
+mod sendlog;
+mod shortcodes;
+mod shortlinks;
+mod sitemap;
+mod sourcecache;
+mod supporters;
+mod views;
+
 // ---------------------------------------------------------------------------
 // Types
 // ---------------------------------------------------------------------------
@@ -14,6 +71,9 @@ struct SubscribeRequest {
 struct SendNewsletterRequest {
     slug: String,
     subject: Option<String>,
+    /// Send even if the issue is marked `draft: true`.
+    #[serde(default)]
+    force: bool,
 }
 
 #[derive(Serialize)]
@@ -49,27 +109,9 @@ struct StalwartGetResponse {
 // Helpers
 // ---------------------------------------------------------------------------
 
-fn cors_headers(req: &Request) -> Result<Headers> {
-    let origin = req.headers().get("Origin")?.unwrap_or_default();
-    let allowed = if origin.contains("lindfors.no") {
-        origin
-    } else {
-        "https://lindfors.no".to_string()
-    };
-
-    let headers = Headers::new();
-    headers.set("Access-Control-Allow-Origin", &allowed)?;
-    headers.set("Access-Control-Allow-Methods", "POST, GET, OPTIONS")?;
-    headers.set("Access-Control-Allow-Headers", "Content-Type")?;
-    Ok(headers)
-}
-
-fn json_response(data: &ApiResponse, status: u16, headers: Headers) -> Result<Response> {
+fn json_response<T: Serialize>(data: &T, status: u16) -> Result<Response> {
     let body = serde_json::to_string(data).map_err(|e| Error::RustError(e.to_string()))?;
     let mut resp = Response::ok(body)?;
-    for (key, val) in headers.entries() {
-        resp.headers_mut().set(&key, &val)?;
-    }
     resp.headers_mut().set("Content-Type", "application/json")?;
     Ok(resp.with_status(status))
 }
@@ -83,6 +125,36 @@ fn is_valid_email(email: &str) -> bool {
         && email.len() >= 5
 }
 
+/// Anonymous, stable name for the client behind a request within `scope`: a
+/// hash of its IP and user agent, so counts can be de-duplicated without
+/// storing either.
+fn client_fingerprint(req: &Request, scope: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let header = |name: &str| req.headers().get(name).ok().flatten().unwrap_or_default();
+    let digest = Sha256::digest(
+        format!(
+            "{}\n{}\n{}",
+            scope,
+            header("CF-Connecting-IP"),
+            header("User-Agent")
+        )
+        .as_bytes(),
+    );
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Slugs are only lowercase alphanumerics and hyphens.
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Name of the Stalwart Management API's circuit breaker (see breaker.rs).
+/// Callers check it before calling; the calls record how they went.
+const STALWART: &str = "stalwart";
+
 /// Call the Stalwart Management API.
 async fn stalwart_patch(
     api_url: &str,
@@ -103,8 +175,10 @@ async fn stalwart_patch(
     init.with_body(Some(wasm_bindgen::JsValue::from_str(&body)));
 
     let req = Request::new_with_init(&url, &init)?;
-    let resp = Fetch::Request(req).send().await?;
-    Ok(resp.status_code())
+    let result = Fetch::Request(req).send().await;
+    let status = result.map(|resp| resp.status_code());
+    breaker::record(STALWART, matches!(status, Ok(status) if status < 500));
+    status
 }
 
 /// Fetch the current external members of a Stalwart mailing list.
@@ -123,7 +197,12 @@ async fn stalwart_get_members(
     init.with_headers(headers);
 
     let req = Request::new_with_init(&url, &init)?;
-    let mut resp = Fetch::Request(req).send().await?;
+    let result = Fetch::Request(req).send().await;
+    breaker::record(
+        STALWART,
+        matches!(&result, Ok(resp) if resp.status_code() < 500),
+    );
+    let mut resp = result?;
 
     if resp.status_code() != 200 {
         return Err(Error::RustError(format!(
@@ -136,98 +215,211 @@ async fn stalwart_get_members(
     Ok(principal.data.external_members)
 }
 
-/// Parse YAML-ish frontmatter from a markdown file (between --- delimiters).
-/// Returns (key-value pairs, body after frontmatter).
-fn parse_frontmatter(md: &str) -> (std::collections::HashMap<String, String>, &str) {
-    let mut meta = std::collections::HashMap::new();
-    let trimmed = md.trim_start();
+/// How many past issues the "Previously" footer lists (PREVIOUSLY_COUNT, default 3).
+fn previously_count(env: &Env) -> usize {
+    env.var("PREVIOUSLY_COUNT")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(3)
+}
+
+/// Rendering settings from the environment, shared by every issue.
+#[derive(Serialize)]
+struct RenderConfig {
+    /// Math image service URL template (MATH_IMAGE_URL), if configured.
+    math_image_url: Option<String>,
+    /// Default for the `smart_punctuation:` frontmatter key (SMART_PUNCTUATION).
+    smart_punctuation: bool,
+}
 
-    if !trimmed.starts_with("---") {
-        return (meta, md);
+impl RenderConfig {
+    fn from_env(env: &Env) -> Self {
+        RenderConfig {
+            math_image_url: env.var("MATH_IMAGE_URL").ok().map(|v| v.to_string()),
+            smart_punctuation: env
+                .var("SMART_PUNCTUATION")
+                .map(|v| v.to_string() == "true")
+                .unwrap_or(false),
+        }
     }
+}
 
-    let after_first = &trimmed[3..].trim_start_matches('\r');
-    let after_first = after_first.strip_prefix('\n').unwrap_or(after_first);
+/// Length of the description derived from the body when frontmatter has none.
+const EXCERPT_CHARS: usize = 160;
+
+/// A newsletter issue rendered into its final email HTML.
+#[derive(Serialize, Deserialize)]
+struct RenderedIssue {
+    title: String,
+    /// Plain text, escaped where it goes into HTML.
+    description: String,
+    date: String,
+    post_url: String,
+    tags: Vec<String>,
+    categories: Vec<String>,
+    /// Problems worth recording in the send log (e.g. a rejected canonical URL).
+    warnings: Vec<String>,
+    html: String,
+}
 
-    if let Some(end) = after_first.find("\n---") {
-        let front = &after_first[..end];
-        let body_start = end + 4; // skip \n---
-        let body = after_first[body_start..].trim_start_matches(['\r', '\n']);
+/// The post's canonical URL: `canonical:` or `url:` from frontmatter if it is
+/// an absolute https URL on SITE_URL's host (or a subdomain of it), otherwise
+/// the slug-derived blog URL plus a warning explaining why.
+fn canonical_post_url(
+    meta: &frontmatter::Frontmatter,
+    slug: &str,
+    site_url: &str,
+) -> (String, Option<String>) {
+    let fallback = format!("{}/blog/{}/", site_url, slug);
+    let Some(candidate) = meta
+        .canonical
+        .as_deref()
+        .or(meta.url.as_deref())
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+    else {
+        return (fallback, None);
+    };
 
-        for line in front.lines() {
-            if let Some((k, v)) = line.split_once(':') {
-                let key = k.trim().to_string();
-                let val = v.trim().trim_matches('"').to_string();
-                meta.insert(key, val);
-            }
+    let site_host = worker::Url::parse(site_url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let problem = match worker::Url::parse(candidate) {
+        Err(_) => Some("is not an absolute URL"),
+        Ok(url) if url.scheme() != "https" => Some("is not https"),
+        Ok(url) => {
+            let host = url.host_str().unwrap_or_default();
+            let allowed = host == site_host || host.ends_with(&format!(".{site_host}"));
+            (!allowed).then_some("is not on the site's domain")
         }
+    };
 
-        (meta, body)
-    } else {
-        (meta, md)
+    match problem {
+        None => (candidate.to_string(), None),
+        Some(problem) => (
+            fallback.clone(),
+            Some(format!(
+                "Canonical URL {candidate} {problem}; used {fallback} instead"
+            )),
+        ),
     }
 }
 
-/// Render markdown to HTML using pulldown-cmark.
-fn render_markdown(md: &str) -> String {
-    use pulldown_cmark::{html, Options, Parser};
-    let opts = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
-    let parser = Parser::new_ext(md, opts);
-    let mut html_output = String::new();
-    html::push_html(&mut html_output, parser);
-    html_output
-}
-
-/// Wrap rendered HTML content in the email template.
-fn email_template(
-    title: &str,
-    description: &str,
-    date: &str,
-    post_url: &str,
-    rendered_body: &str,
+/// Render an issue's frontmatter and markdown body into the email template.
+async fn render_issue(
+    slug: &str,
+    meta: &frontmatter::Frontmatter,
+    md_body: &str,
     site_url: &str,
-) -> String {
-    format!(
-        r#"<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>{title}</title>
-</head>
-<body style="margin: 0; padding: 0; background-color: #F0EAE0; font-family: Georgia, 'Times New Roman', serif;">
-    <div style="max-width: 600px; margin: 0 auto; padding: 32px 24px; background-color: #ffffff;">
-        <div style="border-bottom: 2px solid #2A8F82; padding-bottom: 16px; margin-bottom: 24px;">
-            <a href="{site_url}" style="color: #1C3240; text-decoration: none; font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; font-size: 14px; font-weight: 600;">lindfors.no</a>
-        </div>
-        <h1 style="font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; font-size: 28px; color: #1C3240; margin: 0 0 8px 0; line-height: 1.2;">{title}</h1>
-        <p style="color: #5A7078; font-size: 18px; margin: 0 0 16px 0; line-height: 1.5;">{description}</p>
-        <p style="color: #5A7078; font-size: 14px; margin: 0 0 24px 0;">{date}</p>
-        <div style="color: #1C3240; font-size: 17px; line-height: 1.75;">
-            {rendered_body}
-        </div>
-        <div style="margin-top: 24px; padding: 12px 16px; background-color: #F0EAE0; border-radius: 6px;">
-            <a href="{post_url}" style="color: #D4706A; font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; font-size: 14px; font-weight: 500;">Read the full post on the site &rarr;</a>
-            <span style="color: #5A7078; font-size: 13px; display: block; margin-top: 4px;">For math equations, citations, and interactive features</span>
-        </div>
-        <div style="border-top: 2px solid #2A8F82; margin-top: 32px; padding-top: 16px;">
-            <p style="color: #5A7078; font-size: 13px; margin: 0 0 8px 0;">You received this because you subscribed to the <a href="{site_url}" style="color: #D4706A;">lindfors.no</a> newsletter.</p>
-            <a href="{site_url}" style="color: #D4706A; font-size: 13px;">Visit site</a> &middot;
-            <a href="{site_url}/api/unsubscribe" style="color: #D4706A; font-size: 13px;">Unsubscribe</a>
-        </div>
-    </div>
-</body>
-</html>"#,
-        title = title,
-        description = description,
-        date = date,
-        post_url = post_url,
-        rendered_body = rendered_body,
-        site_url = site_url,
-    )
+    footer: &email::FooterConfig,
+    previously: &[&sendlog::SentIssue],
+    config: &RenderConfig,
+) -> RenderedIssue {
+    let title = markdown::emojify(meta.title.as_deref().unwrap_or(slug));
+    let date = meta.date.clone().unwrap_or_default();
+    let (post_url, url_warning) = canonical_post_url(meta, slug, site_url);
+    if let Some(warning) = &url_warning {
+        console_warn!("{}: {}", slug, warning);
+    }
+
+    let md_body = shortcodes::expand(md_body, &post_url);
+    let description = meta
+        .description
+        .as_deref()
+        .filter(|d| !d.trim().is_empty())
+        .map(markdown::emojify)
+        .unwrap_or_else(|| markdown::excerpt(&md_body, EXCERPT_CHARS));
+    let preheader = meta
+        .preheader
+        .clone()
+        .unwrap_or_else(|| description.clone());
+
+    let hero_url = meta
+        .cover
+        .as_ref()
+        .or(meta.image.as_ref())
+        .filter(|v| !v.is_empty())
+        .map(|v| html::resolve_url(&post_url, v));
+    let hero_alt = meta
+        .cover_alt
+        .as_ref()
+        .or(meta.image_alt.as_ref())
+        .cloned()
+        .unwrap_or_else(|| title.clone());
+
+    let browser_url = archive::archive_url(site_url, slug);
+    let lang = meta
+        .lang
+        .as_deref()
+        .map(email::Lang::from_code)
+        .unwrap_or(email::Lang::En);
+    let render_opts = markdown::RenderOptions {
+        stack_wide_tables: meta.stack_wide_tables,
+        math_image_url: config.math_image_url.clone(),
+        smart_punctuation: meta.smart_punctuation.unwrap_or(config.smart_punctuation),
+        post_url: post_url.clone(),
+        toc: meta.toc,
+        archive_url: browser_url.clone(),
+        truncate_words: meta.email_truncate.filter(|&n| n > 0),
+        lang,
+    };
+    let mut rendered = markdown::render_markdown(&md_body, &render_opts);
+    let mut warnings: Vec<String> = url_warning.into_iter().collect();
+    if let Some(poll) = &meta.poll {
+        match polls::poll_id(poll, slug) {
+            Ok(id) => rendered.push_str(&polls::render_block(poll, &id, site_url)),
+            Err(warning) => {
+                console_warn!("{}: {}", slug, warning);
+                warnings.push(warning);
+            }
+        }
+    }
+    let rendered = html::absolutize_urls(&rendered, &post_url);
+    let rendered = images::add_dimensions(&rendered).await;
+    let rendered_body = html::inline_styles(&html::wrap_wide_content(&rendered));
+    let html = email::EmailTemplate {
+        title: &title,
+        description: &description,
+        date: &date,
+        post_url: &post_url,
+        rendered_body: &rendered_body,
+        site_url,
+        footer,
+        lang,
+        preheader: &preheader,
+        hero_url: hero_url.as_deref(),
+        hero_alt: &hero_alt,
+        layout: meta
+            .layout
+            .as_deref()
+            .map(email::Layout::from_name)
+            .unwrap_or(email::Layout::Table),
+        previously,
+        read_minutes: email::read_time_minutes(&md_body),
+        browser_url: &browser_url,
+        tags: &meta.tags,
+    }
+    .render();
+    let html = match worker::Url::parse(&post_url) {
+        Ok(url) => html::add_utm(&html, url.host_str().unwrap_or_default(), slug),
+        Err(_) => html,
+    };
+
+    RenderedIssue {
+        title,
+        description,
+        date,
+        post_url,
+        tags: meta.tags.clone(),
+        categories: meta.categories.clone(),
+        warnings,
+        html,
+    }
 }
 
 /// Send an email via Stalwart's JMAP API using Email/set + EmailSubmission/set.
+#[allow(clippy::too_many_arguments)]
 async fn jmap_send_email(
     base_url: &str,
     credentials: &str,
@@ -318,23 +510,188 @@ async fn jmap_send_email(
 // ---------------------------------------------------------------------------
 
 #[event(fetch, respond_with_errors)]
-pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    Router::new()
-        .post_async("/api/subscribe", handle_subscribe)
-        .get_async("/api/unsubscribe", handle_unsubscribe_page)
-        .post_async("/api/unsubscribe", handle_unsubscribe_post)
-        .get_async("/api/subscribers", handle_subscribers)
-        .post_async("/api/send-newsletter", handle_send_newsletter)
-        .options("/api/subscribe", handle_preflight)
-        .options("/api/unsubscribe", handle_preflight)
-        .run(req, env)
-        .await
+pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    let log = RequestLog::new(&req, &env, ctx);
+
+    // The unversioned paths are v1, kept for the static site's embedded forms
+    // and already-sent emails
+    let routes = Routes::new(log.clone());
+    let routes = v1_routes(routes, "/api/v1");
+    let routes = v1_routes(routes, "/api");
+    let routes = routes
+        .get("/s/:code", shortlinks::handle_redirect)
+        .get("/sitemap-newsletter.xml", sitemap::handle_sitemap)
+        .get("/.well-known/webfinger", activitypub::handle_webfinger)
+        .fallback(redirects::handle_fallback);
+
+    middleware::run(req, env, log, routes).await
 }
 
-/// POST /api/subscribe — add email to the Stalwart mailing list.
-async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let headers = cors_headers(&req)?;
+/// Cron triggers (`[triggers]` in wrangler.toml): send the weekly digest and
+/// run the link audit on their crons, otherwise deliver queued ActivityPub
+/// activities.
+#[event(scheduled)]
+pub async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let log = RequestLog::scheduled(&env);
+    let cron = event.cron();
+    let result = match cron.as_str() {
+        digest::CRON => digest::send_weekly(&env, &log).await,
+        linkaudit::CRON => linkaudit::run(&env, &log).await,
+        _ => activitypub::deliver_due(&env, &log).await,
+    };
+    let error = result.err().map(|e| e.to_string());
+    log.finish_scheduled(&cron, error.as_deref());
+}
 
+/// Queue consumer for the JOBS queue and its dead-letter queue (see jobs.rs).
+#[event(queue)]
+pub async fn queue(batch: MessageBatch<jobs::Job>, env: Env, _ctx: Context) -> Result<()> {
+    jobs::consume(batch, &env).await
+}
+
+/// Register the v1 API under `prefix`; route docs below give the unversioned
+/// path. Preflight requests are answered by the middleware.
+fn v1_routes<'a>(routes: Routes<'a>, prefix: &str) -> Routes<'a> {
+    let path = |route: &str| format!("{}{}", prefix, route);
+    routes
+        .post(&path("/subscribe"), |req, ctx| limited(req, ctx, handle_subscribe))
+        .get(&path("/unsubscribe"), handle_unsubscribe_page)
+        .post(&path("/unsubscribe"), |req, ctx| {
+            limited(req, ctx, handle_unsubscribe_post)
+        })
+        .get(&path("/subscribers"), |req, ctx| admin(req, ctx, handle_subscribers))
+        .get(&path("/subscribers/export"), |req, ctx| {
+            admin(req, ctx, handle_subscribers_export)
+        })
+        .post(&path("/send-newsletter"), |req, ctx| {
+            admin(req, ctx, handle_send_newsletter)
+        })
+        .get(&path("/admin/send-status/:slug"), |req, ctx| {
+            admin(req, ctx, coordinator::handle_status)
+        })
+        .get(&path("/admin/supporters"), |req, ctx| {
+            admin(req, ctx, supporters::handle_list)
+        })
+        .post_raw(
+            &path("/webhooks/stripe"),
+            supporters::MAX_WEBHOOK_BYTES,
+            supporters::handle_stripe_webhook,
+        )
+        .get(&path("/premium"), premium::handle_index)
+        .get(&path("/premium/:slug"), premium::handle_post)
+        .post(&path("/admin/login"), auth::handle_login)
+        .get(&path("/admin/template-preview"), |req, ctx| {
+            admin(req, ctx, handle_template_preview)
+        })
+        .get(&path("/admin/metrics"), |req, ctx| admin(req, ctx, metrics::handle_metrics))
+        .get(&path("/admin/link-audit"), |req, ctx| {
+            admin(req, ctx, linkaudit::handle_report)
+        })
+        .get(&path("/admin/flags"), |req, ctx| admin(req, ctx, flags::handle_get))
+        .put(&path("/admin/flags"), |req, ctx| admin(req, ctx, flags::handle_put))
+        .get(&path("/admin/audit"), |req, ctx| admin(req, ctx, audit::handle_audit))
+        .get(&path("/admin/migrate"), |req, ctx| admin(req, ctx, db::handle_status))
+        .post(&path("/admin/migrate"), |req, ctx| admin(req, ctx, db::handle_migrate))
+        .post(&path("/analytics/event"), analytics::handle_event)
+        .put_raw(&path("/admin/uploads/:name"), files::MAX_UPLOAD_BYTES, |req, ctx| {
+            admin(req, ctx, files::handle_upload)
+        })
+        .get(&path("/uploads/:name"), files::handle_download)
+        .get(&path("/archive"), handle_archive_index)
+        .get(&path("/archive/:slug"), handle_archive_issue)
+        .get(&path("/ping"), health::handle_ping)
+        .get(&path("/newsletter/feed.xml"), feeds::handle_atom)
+        .get(&path("/newsletter/feed.json"), feeds::handle_json)
+        .get(&path("/comment-replies/unsubscribe"), comments::handle_opt_out_page)
+        .post(&path("/comment-replies/unsubscribe"), |req, ctx| {
+            limited(req, ctx, comments::handle_opt_out)
+        })
+        .get(&path("/comments/:slug"), comments::handle_list)
+        .post(&path("/comments/:slug"), |req, ctx| {
+            limited(req, ctx, comments::handle_create)
+        })
+        .get(&path("/reactions/:slug"), reactions::handle_counts)
+        .post(&path("/reactions/:slug"), |req, ctx| {
+            limited(req, ctx, reactions::handle_react)
+        })
+        .get(&path("/guestbook"), guestbook::handle_list)
+        .post(&path("/guestbook"), |req, ctx| {
+            limited(req, ctx, guestbook::handle_create)
+        })
+        .get(&path("/poll/:id/vote/:option"), polls::handle_vote_page)
+        .post(&path("/poll/:id/vote/:option"), |req, ctx| {
+            limited(req, ctx, polls::handle_vote)
+        })
+        .get(&path("/related/:slug"), related::handle_related)
+        .get(&path("/img"), imageproxy::handle_resize)
+        .get(&path("/now"), now::handle_get)
+        .get(&path("/bookmarks"), bookmarks::handle_list)
+        .post(&path("/bookmarks"), |req, ctx| admin(req, ctx, bookmarks::handle_create))
+        .delete(&path("/bookmarks/:id"), |req, ctx| {
+            admin(req, ctx, bookmarks::handle_delete)
+        })
+        .put(&path("/now"), |req, ctx| admin(req, ctx, now::handle_put))
+        .get(&path("/indieauth/metadata"), indieauth::handle_metadata)
+        .get(&path("/indieauth/auth"), indieauth::handle_authorize_page)
+        .post(&path("/indieauth/auth"), |req, ctx| {
+            limited(req, ctx, indieauth::handle_authorize)
+        })
+        .get(&path("/indieauth/token"), indieauth::handle_token_info)
+        .post(&path("/indieauth/token"), indieauth::handle_token)
+        .post(&path("/indieauth/revoke"), indieauth::handle_revoke)
+        .get(&path("/micropub"), micropub::handle_query)
+        .post(&path("/micropub"), micropub::handle_create)
+        .get(&path("/activitypub/actor"), activitypub::handle_actor)
+        .get(&path("/activitypub/outbox"), activitypub::handle_outbox)
+        .get(&path("/activitypub/followers"), activitypub::handle_followers)
+        .get(&path("/activitypub/posts/:slug"), activitypub::handle_post)
+        .post(&path("/activitypub/inbox"), activitypub::handle_inbox)
+        .get(&path("/search"), search::handle_search)
+        .get(&path("/views/:slug"), views::handle_count)
+        .post(&path("/views/:slug"), views::handle_view)
+        .get(&path("/admin/views"), |req, ctx| admin(req, ctx, views::handle_popular))
+        .get(&path("/admin/analytics"), |req, ctx| {
+            admin(req, ctx, analytics::handle_summary)
+        })
+        .get(&path("/admin/comments"), |req, ctx| {
+            admin(req, ctx, comments::handle_moderation_list)
+        })
+        .post(&path("/admin/comments/:id/approve"), |req, ctx| {
+            admin(req, ctx, comments::handle_approve)
+        })
+        .post(&path("/admin/comments/:id/reject"), |req, ctx| {
+            admin(req, ctx, comments::handle_reject)
+        })
+        .get(&path("/admin/guestbook"), |req, ctx| {
+            admin(req, ctx, guestbook::handle_moderation_list)
+        })
+        .post(&path("/admin/guestbook/:id/approve"), |req, ctx| {
+            admin(req, ctx, guestbook::handle_approve)
+        })
+        .post(&path("/admin/guestbook/:id/reject"), |req, ctx| {
+            admin(req, ctx, guestbook::handle_reject)
+        })
+        .get(&path("/admin/polls"), |req, ctx| admin(req, ctx, polls::handle_results))
+        .get(&path("/admin/links"), |req, ctx| admin(req, ctx, shortlinks::handle_list))
+        .post(&path("/admin/links"), |req, ctx| {
+            admin(req, ctx, shortlinks::handle_create)
+        })
+        .delete(&path("/admin/links/:code"), |req, ctx| {
+            admin(req, ctx, shortlinks::handle_delete)
+        })
+        .get(&path("/admin/redirects"), |req, ctx| {
+            admin(req, ctx, redirects::handle_list)
+        })
+        .put(&path("/admin/redirects"), |req, ctx| {
+            admin(req, ctx, redirects::handle_put)
+        })
+        .delete(&path("/admin/redirects"), |req, ctx| {
+            admin(req, ctx, redirects::handle_delete)
+        })
+}
+
+/// POST /api/subscribe — add email to the Stalwart mailing list.
+async fn handle_subscribe(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
     let body: SubscribeRequest = match req.json().await {
         Ok(b) => b,
         Err(_) => {
@@ -344,7 +701,6 @@ async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Res
                     error: Some("Invalid request body".into()),
                 },
                 400,
-                headers,
             );
         }
     };
@@ -358,7 +714,6 @@ async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Res
                 error: Some("Invalid email address".into()),
             },
             400,
-            headers,
         );
     }
 
@@ -366,15 +721,23 @@ async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Res
     let api_key = ctx.env.secret("STALWART_API_KEY")?.to_string();
     let list_id = ctx.env.var("STALWART_LIST_ID")?.to_string();
 
+    if let Err(retry_after) = breaker::check(STALWART) {
+        return breaker::unavailable(retry_after);
+    }
     let ops = [StalwartPatchOp {
         action: "addItem",
         field: "externalMembers",
         value: email,
     }];
 
-    match stalwart_patch(&api_url, &api_key, &list_id, &ops).await {
+    let started = logging::now_millis();
+    let result = stalwart_patch(&api_url, &api_key, &list_id, &ops).await;
+    ctx.data.upstream_status("stalwart", "add_member", started, &result);
+    ctx.data.event("subscribe", matches!(result, Ok(status) if status < 300));
+
+    match result {
         Ok(status) if status < 300 => {
-            json_response(&ApiResponse { success: true, error: None }, 200, headers)
+            json_response(&ApiResponse { success: true, error: None }, 200)
         }
         Ok(status) => json_response(
             &ApiResponse {
@@ -382,7 +745,6 @@ async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Res
                 error: Some(format!("Upstream error ({})", status)),
             },
             502,
-            headers,
         ),
         Err(_) => json_response(
             &ApiResponse {
@@ -390,20 +752,17 @@ async fn handle_subscribe(mut req: Request, ctx: RouteContext<()>) -> Result<Res
                 error: Some("Subscription failed".into()),
             },
             500,
-            headers,
         ),
     }
 }
 
 /// GET /api/unsubscribe — show the unsubscribe form.
-async fn handle_unsubscribe_page(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    Response::from_html(&unsubscribe_form_page())
+async fn handle_unsubscribe_page(_req: Request, _ctx: RouteContext<RequestLog>) -> Result<Response> {
+    Response::from_html(unsubscribe_form_page())
 }
 
 /// POST /api/unsubscribe — remove email from the Stalwart mailing list.
-async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let headers = cors_headers(&req)?;
-
+async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
     let body: SubscribeRequest = match req.json().await {
         Ok(b) => b,
         Err(_) => {
@@ -413,7 +772,6 @@ async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<()>) -> Res
                     error: Some("Invalid request body".into()),
                 },
                 400,
-                headers,
             );
         }
     };
@@ -427,7 +785,6 @@ async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<()>) -> Res
                 error: Some("Invalid email address".into()),
             },
             400,
-            headers,
         );
     }
 
@@ -435,15 +792,23 @@ async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<()>) -> Res
     let api_key = ctx.env.secret("STALWART_API_KEY")?.to_string();
     let list_id = ctx.env.var("STALWART_LIST_ID")?.to_string();
 
+    if let Err(retry_after) = breaker::check(STALWART) {
+        return breaker::unavailable(retry_after);
+    }
     let ops = [StalwartPatchOp {
         action: "removeItem",
         field: "externalMembers",
         value: email,
     }];
 
-    match stalwart_patch(&api_url, &api_key, &list_id, &ops).await {
+    let started = logging::now_millis();
+    let result = stalwart_patch(&api_url, &api_key, &list_id, &ops).await;
+    ctx.data.upstream_status("stalwart", "remove_member", started, &result);
+    ctx.data.event("unsubscribe", matches!(result, Ok(status) if status < 300));
+
+    match result {
         Ok(status) if status < 300 => {
-            json_response(&ApiResponse { success: true, error: None }, 200, headers)
+            json_response(&ApiResponse { success: true, error: None }, 200)
         }
         Ok(_) | Err(_) => json_response(
             &ApiResponse {
@@ -451,74 +816,136 @@ async fn handle_unsubscribe_post(mut req: Request, ctx: RouteContext<()>) -> Res
                 error: Some("Unsubscribe failed".into()),
             },
             500,
-            headers,
         ),
     }
 }
 
-/// GET /api/subscribers?key=... — admin: list current subscribers from Stalwart.
-async fn handle_subscribers(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let url = req.url()?;
-    let params: std::collections::HashMap<String, String> =
-        url.query_pairs().into_owned().collect();
+/// Members per chunk of a streamed subscriber list.
+const MEMBERS_PER_CHUNK: usize = 500;
 
-    let key = params.get("key").cloned().unwrap_or_default();
-    let admin_key = ctx.env.secret("ADMIN_KEY")?.to_string();
+#[derive(Clone, Copy, PartialEq)]
+enum MemberFormat {
+    /// `{"total": n, "members": [...]}`
+    Json,
+    /// An `email` column
+    Csv,
+}
 
-    if key != admin_key {
-        return json_response(
-            &ApiResponse {
-                success: false,
-                error: Some("Unauthorized".into()),
-            },
-            401,
-            cors_headers(&req)?,
-        );
+/// `value` as a CSV field, quoted if it has to be.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
     }
+}
 
+/// A streamed response listing `members`, serialized a chunk at a time as
+/// the client reads it, so the serialized body is never held in full.
+/// `members` itself is: Stalwart returns a list's members in one response,
+/// with no paging to stream from.
+fn members_response(members: Vec<String>, format: MemberFormat) -> Result<Response> {
+    use futures_util::future::ready;
+    use futures_util::stream::{self, StreamExt};
+
+    let (head, tail) = match format {
+        MemberFormat::Json => (format!("{{\"total\":{},\"members\":[", members.len()), "]}"),
+        MemberFormat::Csv => ("email\n".to_string(), ""),
+    };
+    let rows = stream::iter(members)
+        .chunks(MEMBERS_PER_CHUNK)
+        .enumerate()
+        .map(move |(i, chunk)| {
+            let mut out = String::new();
+            for (j, member) in chunk.iter().enumerate() {
+                match format {
+                    MemberFormat::Json => {
+                        if i > 0 || j > 0 {
+                            out.push(',');
+                        }
+                        out.push_str(&serde_json::to_string(member).unwrap_or_default());
+                    }
+                    MemberFormat::Csv => {
+                        out.push_str(&csv_field(member));
+                        out.push('\n');
+                    }
+                }
+            }
+            out
+        });
+    let body = stream::once(ready(head))
+        .chain(rows)
+        .chain(stream::once(ready(tail.to_string())))
+        .map(Ok::<String, Error>);
+
+    let mut resp = Response::from_stream(body)?;
+    let content_type = match format {
+        MemberFormat::Json => "application/json",
+        MemberFormat::Csv => "text/csv; charset=utf-8",
+    };
+    resp.headers_mut().set("Content-Type", content_type)?;
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(resp)
+}
+
+async fn subscriber_members(ctx: &RouteContext<RequestLog>) -> Result<Vec<String>> {
     let api_url = ctx.env.var("STALWART_API_URL")?.to_string();
     let api_key = ctx.env.secret("STALWART_API_KEY")?.to_string();
     let list_id = ctx.env.var("STALWART_LIST_ID")?.to_string();
 
-    let members = stalwart_get_members(&api_url, &api_key, &list_id).await?;
+    let started = logging::now_millis();
+    let result = stalwart_get_members(&api_url, &api_key, &list_id).await;
+    let error = result.as_ref().err().map(|e| e.to_string());
+    ctx.data.upstream("stalwart", "list_members", started, None, error.as_deref());
+    result
+}
 
-    #[derive(Serialize)]
-    struct ListResponse {
-        total: usize,
-        members: Vec<String>,
+/// GET /api/subscribers — admin: list current subscribers from Stalwart, as
+/// `{"total", "members"}`.
+async fn handle_subscribers(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    if let Err(retry_after) = breaker::check(STALWART) {
+        return breaker::unavailable(retry_after);
     }
+    let members = subscriber_members(&ctx).await?;
+    members_response(members, MemberFormat::Json)
+}
 
-    let data = ListResponse {
-        total: members.len(),
-        members,
+/// GET /api/subscribers/export?format=csv|json — admin: the current
+/// subscribers as a file to download, CSV unless asked for JSON.
+async fn handle_subscribers_export(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let format = match req.url()?.query_pairs().find(|(k, _)| k == "format") {
+        None => MemberFormat::Csv,
+        Some((_, f)) if f == "csv" => MemberFormat::Csv,
+        Some((_, f)) if f == "json" => MemberFormat::Json,
+        Some(_) => {
+            return json_response(
+                &ApiResponse {
+                    success: false,
+                    error: Some("format must be csv or json".into()),
+                },
+                400,
+            );
+        }
     };
+    if let Err(retry_after) = breaker::check(STALWART) {
+        return breaker::unavailable(retry_after);
+    }
+    let members = subscriber_members(&ctx).await?;
 
-    let body = serde_json::to_string(&data).map_err(|e| Error::RustError(e.to_string()))?;
-    let mut resp = Response::ok(body)?;
-    resp.headers_mut().set("Content-Type", "application/json")?;
+    let mut resp = members_response(members, format)?;
+    let filename = match format {
+        MemberFormat::Json => "subscribers.json",
+        MemberFormat::Csv => "subscribers.csv",
+    };
+    resp.headers_mut().set(
+        "Content-Disposition",
+        &format!("attachment; filename=\"{}\"", filename),
+    )?;
     Ok(resp)
 }
 
-/// POST /api/send-newsletter?key=... — admin: send a newsletter to the mailing list via JMAP.
-async fn handle_send_newsletter(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let url = req.url()?;
-    let params: std::collections::HashMap<String, String> =
-        url.query_pairs().into_owned().collect();
-
-    let key = params.get("key").cloned().unwrap_or_default();
-    let admin_key = ctx.env.secret("ADMIN_KEY")?.to_string();
-
-    if key != admin_key {
-        return json_response(
-            &ApiResponse {
-                success: false,
-                error: Some("Unauthorized".into()),
-            },
-            401,
-            cors_headers(&req)?,
-        );
-    }
-
+/// POST /api/send-newsletter — admin: send a newsletter to the mailing list via JMAP.
+async fn handle_send_newsletter(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
     let body: SendNewsletterRequest = match req.json().await {
         Ok(b) => b,
         Err(_) => {
@@ -528,20 +955,17 @@ async fn handle_send_newsletter(mut req: Request, ctx: RouteContext<()>) -> Resu
                     error: Some("Invalid request body — expected {\"slug\": \"...\"}".into()),
                 },
                 400,
-                cors_headers(&req)?,
             );
         }
     };
 
-    // Validate slug: only lowercase alphanumeric and hyphens
-    if body.slug.is_empty() || !body.slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+    if !is_valid_slug(&body.slug) {
         return json_response(
             &ApiResponse {
                 success: false,
                 error: Some("Invalid slug — only lowercase letters, digits, and hyphens allowed".into()),
             },
             400,
-            cors_headers(&req)?,
         );
     }
 
@@ -549,76 +973,256 @@ async fn handle_send_newsletter(mut req: Request, ctx: RouteContext<()>) -> Resu
     let site_url = ctx.env.var("SITE_URL")?.to_string();
     let newsletter_url = format!("{}/newsletter/{}.md", site_url, body.slug);
 
-    let fetch_req = Request::new(&newsletter_url, Method::Get)?;
-    let mut fetch_resp = Fetch::Request(fetch_req).send().await?;
+    let source = match sourcecache::fetch(&ctx.env, &ctx.data, &body.slug, &newsletter_url).await? {
+        Ok(source) => source,
+        Err(status) => {
+            return json_response(
+                &ApiResponse {
+                    success: false,
+                    error: Some(format!(
+                        "Newsletter not found at {} (status {})",
+                        newsletter_url, status
+                    )),
+                },
+                404,
+            );
+        }
+    };
+    let md_source = source.markdown;
+    let (meta, md_body) = match frontmatter::parse(&md_source) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return json_response(
+                &ApiResponse {
+                    success: false,
+                    error: Some(format!("Newsletter {} has {}", body.slug, e)),
+                },
+                422,
+            );
+        }
+    };
 
-    if fetch_resp.status_code() != 200 {
+    if meta.draft && !body.force {
         return json_response(
             &ApiResponse {
                 success: false,
                 error: Some(format!(
-                    "Newsletter not found at {} (status {})",
-                    newsletter_url,
-                    fetch_resp.status_code()
+                    "Newsletter {} is a draft (draft: true); pass \"force\": true to send it anyway",
+                    body.slug
                 )),
             },
-            404,
-            cors_headers(&req)?,
+            409,
         );
     }
 
-    let md_source = fetch_resp.text().await?;
-    let (meta, md_body) = parse_frontmatter(&md_source);
-
-    let title = meta.get("title").cloned().unwrap_or_else(|| body.slug.clone());
-    let description = meta.get("description").cloned().unwrap_or_default();
-    let date = meta.get("date").cloned().unwrap_or_default();
-    let post_url = meta
-        .get("url")
-        .cloned()
-        .unwrap_or_else(|| format!("{}/blog/{}/", site_url, body.slug));
-
-    let rendered_body = render_markdown(md_body);
-    let html = email_template(&title, &description, &date, &post_url, &rendered_body, &site_url);
+    let footer = email::load_footer_config(&ctx.env).await;
+    let log = sendlog::load(&ctx.env).await;
+    let previously = sendlog::recent(&log, &body.slug, previously_count(&ctx.env));
+    let config = RenderConfig::from_env(&ctx.env);
+    let render_hash = rendercache::inputs_hash(&md_source, &site_url, &footer, &previously, &config);
+    let issue = match rendercache::load(&ctx.env, &body.slug, &render_hash).await {
+        Some(issue) => issue,
+        None => {
+            let issue = render_issue(
+                &body.slug,
+                &meta,
+                md_body,
+                &site_url,
+                &footer,
+                &previously,
+                &config,
+            )
+            .await;
+            if let Err(e) = rendercache::store(&ctx.env, &body.slug, &render_hash, &issue).await {
+                console_warn!("Failed to cache the render of {}: {}", body.slug, e);
+            }
+            issue
+        }
+    };
 
-    let subject = body.subject.unwrap_or(title);
+    let subject = body.subject.clone().unwrap_or_else(|| issue.title.clone());
+    let poll = meta
+        .poll
+        .as_ref()
+        .and_then(|poll| Some((poll, polls::poll_id(poll, &body.slug).ok()?)));
+
+    // An issue with a poll goes to each subscriber on their own, with voting
+    // links signed for them (see polls.rs); any other goes once to the list
+    let signing = match &poll {
+        Some((_, id)) if polls::has_links(&issue.html) => {
+            let Ok(key) = ctx.env.secret("POLL_SIGNING_KEY") else {
+                return json_response(
+                    &ApiResponse {
+                        success: false,
+                        error: Some("POLL_SIGNING_KEY isn't set, and the issue has a poll".into()),
+                    },
+                    500,
+                );
+            };
+            Some((key.to_string(), id.clone()))
+        }
+        _ => None,
+    };
+    let recipients = match signing {
+        Some(_) => {
+            if let Err(retry_after) = breaker::check(STALWART) {
+                return breaker::unavailable(retry_after);
+            }
+            subscriber_members(&ctx).await?
+        }
+        None => vec!["newsletter@lindfors.no".to_string()],
+    };
+    let recipient_refs: Vec<&str> = recipients.iter().map(String::as_str).collect();
 
-    // Read JMAP config
+    // Read JMAP config before claiming, so a missing one leaves no claim behind
     let jmap_url = ctx.env.var("JMAP_API_URL")?.to_string();
     let credentials = ctx.env.secret("JMAP_CREDENTIALS")?.to_string();
     let account_id = ctx.env.var("JMAP_ACCOUNT_ID")?.to_string();
     let identity_id = ctx.env.var("JMAP_IDENTITY_ID")?.to_string();
 
-    let from = "postmaster@lindfors.no";
-    let to = "newsletter@lindfors.no";
-
-    match jmap_send_email(
-        &jmap_url,
-        &credentials,
-        &account_id,
-        &identity_id,
-        from,
-        &to,
-        &subject,
-        &html,
-    )
-    .await
+    // Only one send of an issue at a time
+    if let coordinator::Claim::Busy(send) =
+        coordinator::claim(&ctx.env, &body.slug, &recipient_refs).await?
     {
-        Ok(status) if status == 200 => json_response(
+        return json_response(
             &ApiResponse {
-                success: true,
-                error: None,
+                success: false,
+                error: Some(format!(
+                    "Newsletter {} is already being sent (since {})",
+                    body.slug,
+                    dates::rfc3339(send.started_at)
+                )),
             },
-            200,
-            cors_headers(&req)?,
-        ),
+            409,
+        );
+    }
+
+    if let Some((poll, id)) = &poll {
+        if let Err(e) = polls::register(&ctx.env, id, poll).await {
+            release_claim(&ctx.env, &body.slug).await;
+            return json_response(
+                &ApiResponse {
+                    success: false,
+                    error: Some(format!("Failed to register poll {}: {}", id, e)),
+                },
+                500,
+            );
+        }
+    }
+
+    let from = "postmaster@lindfors.no";
+
+    let (issue_html, subject, signing) = (&issue.html, &subject, &signing);
+    let (jmap_url, credentials) = (&jmap_url, &credentials);
+    let (account_id, identity_id) = (&account_id, &identity_id);
+    let (env, log, slug) = (&ctx.env, &ctx.data, &body.slug);
+    let results = fanout::map(&recipients, fanout::CONNECTIONS, |to| async move {
+        let html = match signing {
+            Some((key, id)) => polls::sign_links(issue_html, key, id, to)
+                .ok_or_else(|| Error::RustError("Unusable POLL_SIGNING_KEY".into()))?,
+            None => issue_html.clone(),
+        };
+        let started = logging::now_millis();
+        let result = jmap_send_email(
+            jmap_url,
+            credentials,
+            account_id,
+            identity_id,
+            from,
+            to,
+            subject,
+            &html,
+        )
+        .await;
+        log.upstream_status("jmap", "send_email", started, &result);
+        if let Err(e) = coordinator::record(env, slug, to, matches!(result, Ok(200))).await {
+            console_error!("Failed to record the send of {} to {}: {}", slug, to, e);
+        }
+        result
+    })
+    .await;
+    // Hosted once someone has the email with its view-in-browser link, so a
+    // failed send doesn't publish the issue (or replace an earlier copy)
+    if results.iter().any(|result| matches!(result, Ok(200))) {
+        let html = polls::unsigned(&issue.html);
+        if let Err(e) = archive::store(&ctx.env, &body.slug, &html).await {
+            console_error!("Failed to store the archive copy of {}: {}", body.slug, e);
+        }
+    }
+    let sent = results.iter().all(|result| matches!(result, Ok(200)));
+    ctx.data.event("send", sent);
+    if let Err(e) = coordinator::finish(&ctx.env, &body.slug, sent).await {
+        console_error!("Failed to record the send of {}: {}", body.slug, e);
+    }
+    // The first failure, if any, stands for the send
+    let result = results
+        .into_iter()
+        .find(|result| !matches!(result, Ok(200)))
+        .unwrap_or(Ok(200));
+
+    match result {
+        Ok(200) => {
+            let sent = sendlog::SentIssue {
+                slug: body.slug.clone(),
+                title: issue.title,
+                description: issue.description,
+                date: issue.date,
+                post_url: issue.post_url,
+                sent_at: sendlog::now_millis(),
+                tags: issue.tags,
+                categories: issue.categories,
+                warnings: issue.warnings,
+                bluesky_uri: None,
+                content_hash: Some(source.hash),
+            };
+            let activity = activitypub::create_activity(&site_url, &sent);
+            if let Err(e) = sendlog::record(&ctx.env, sent.clone()).await {
+                console_error!("Failed to record send log entry for {}: {}", body.slug, e);
+            }
+            if let Err(e) = activitypub::enqueue(&ctx.env, &activity).await {
+                console_error!("Failed to queue {} for fediverse followers: {}", body.slug, e);
+            }
+            let cross_posts = flags::load(&ctx.env).await.cross_posts;
+            if cross_posts {
+                // The send log entry gets the post's URI once there is one
+                let (env, log, mut sent) = (ctx.env.clone(), ctx.data.clone(), sent.clone());
+                ctx.data.wait_until(async move {
+                    match bluesky::announce(&env, &log, &sent).await {
+                        Ok(None) => {}
+                        Ok(uri) => {
+                            sent.bluesky_uri = uri;
+                            if let Err(e) = sendlog::record(&env, sent.clone()).await {
+                                console_error!(
+                                    "Failed to record {}'s Bluesky post: {}",
+                                    sent.slug,
+                                    e
+                                );
+                            }
+                        }
+                        Err(e) => console_error!("Failed to post {} to Bluesky: {}", sent.slug, e),
+                    }
+                });
+            }
+            if cross_posts && meta.mastodon != Some(false) {
+                let job = jobs::Job::Mastodon { issue: sent };
+                if let Err(e) = jobs::enqueue(&ctx.env, &job).await {
+                    console_error!("Failed to queue {} for Mastodon: {}", body.slug, e);
+                }
+            }
+            json_response(
+                &ApiResponse {
+                    success: true,
+                    error: None,
+                },
+                200,
+            )
+        }
         Ok(status) => json_response(
             &ApiResponse {
                 success: false,
                 error: Some(format!("JMAP request failed (status {})", status)),
             },
             502,
-            cors_headers(&req)?,
         ),
         Err(e) => json_response(
             &ApiResponse {
@@ -626,25 +1230,243 @@ async fn handle_send_newsletter(mut req: Request, ctx: RouteContext<()>) -> Resu
                 error: Some(format!("Failed to send: {}", e)),
             },
             500,
-            cors_headers(&req)?,
         ),
     }
 }
 
-fn handle_preflight(req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    let headers = cors_headers(&req)?;
-    let mut resp = Response::empty()?.with_status(204);
-    for (key, val) in headers.entries() {
-        resp.headers_mut().set(&key, &val)?;
+/// Give up `slug`'s claim on a send that failed before reaching JMAP.
+async fn release_claim(env: &Env, slug: &str) {
+    if let Err(e) = coordinator::finish(env, slug, false).await {
+        console_error!("Failed to release the send claim on {}: {}", slug, e);
+    }
+}
+
+/// GET /api/admin/template-preview?theme=...&lang=... — admin: render the
+/// email template with sample content, for iterating on the template without a send.
+async fn handle_template_preview(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<String, String> =
+        url.query_pairs().into_owned().collect();
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let (mut meta, md_body) = frontmatter::parse(SAMPLE_NEWSLETTER).map_err(Error::RustError)?;
+    if let Some(theme) = params.get("theme") {
+        meta.layout = Some(theme.clone());
+    }
+    if let Some(lang) = params.get("lang") {
+        meta.lang = Some(lang.clone());
+    }
+
+    let footer = email::load_footer_config(&ctx.env).await;
+    let log = sendlog::load(&ctx.env).await;
+    let previously = sendlog::recent(&log, "template-preview", previously_count(&ctx.env));
+    let issue = render_issue(
+        "template-preview",
+        &meta,
+        md_body,
+        &site_url,
+        &footer,
+        &previously,
+        &RenderConfig::from_env(&ctx.env),
+    )
+    .await;
+    Response::from_html(polls::unsigned(&issue.html))
+}
+
+/// GET /api/archive — public index of sent issues, newest first.
+async fn handle_archive_index(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let issues = sendlog::load(&ctx.env).await;
+    let resp = Response::from_html(archive_index_page(&site_url, &issues))?;
+    conditional::respond(&req, resp, "public, max-age=600")
+}
+
+/// GET /api/archive/{slug} — hosted copy of a sent issue (the view-in-browser link).
+async fn handle_archive_issue(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    let stored = if is_valid_slug(&slug) {
+        archive::load(&ctx.env, &slug).await?
+    } else {
+        None
+    };
+
+    match stored {
+        // Only a resend changes it
+        Some(html) => {
+            conditional::respond(&req, Response::from_html(html)?, "public, max-age=3600")
+        }
+        None => json_response(
+            &ApiResponse {
+                success: false,
+                error: Some("Issue not found".into()),
+            },
+            404,
+        ),
     }
-    resp.headers_mut().set("Access-Control-Max-Age", "86400")?;
-    Ok(resp)
 }
 
 // ---------------------------------------------------------------------------
 // HTML pages
 // ---------------------------------------------------------------------------
 
+/// Sample issue for the template preview, exercising every element the
+/// markdown renderer and inliner produce.
+const SAMPLE_NEWSLETTER: &str = r#"---
+title: "Sensor rigs, salmon, and a very small Rust binary"
+date: "2024-06-01"
+description: "Notes from a season of field work: what broke, what held up, and what I'd build differently."
+url: "https://lindfors.no/blog/spectral-imaging-and-embedded-rust/"
+cover: "hero.webp"
+toc: true
+---
+
+Field seasons have a way of sorting good ideas from ones that only *looked* good on the whiteboard. This issue covers the **hardware**, the firmware, and a few [links worth reading](https://lindfors.no/blog/).
+
+## What held up
+
+1. The enclosure, after the second redesign
+2. Embedded Rust on the sensor board
+3. Logging everything to `postcard`-encoded files[^postcard]
+
+> The best sensor is the one that is still running when you come back to check on it.
+
+> [!TIP]
+> Pot the connectors before the first deployment, not after the first failure.
+
+## The numbers
+
+| Site | Days online | Frames captured |
+|------|-------------|-----------------|
+| Frøya | 41 | 12,408 |
+| Hitra | 37 | 10,992 |
+
+Frame rate dropped as $1/t$ with exposure time, so the daily total is
+
+$$N = \sum_{d=1}^{D} \frac{3600 \, h_d}{t_d}$$
+
+```rust
+fn main() {
+    println!("hello from the fjord");
+}
+```
+
+---
+
+That's it for this time. Reply to this email if you have questions — I read everything.
+
+[^postcard]: A compact, `no_std`-friendly serde format — about a third of the size of the JSON logs.
+"#;
+
+fn archive_index_page(site_url: &str, issues: &[sendlog::SentIssue]) -> String {
+    let items: String = if issues.is_empty() {
+        "    <p>No issues yet — subscribe below to get the first one.</p>\n".into()
+    } else {
+        let items: String = issues
+            .iter()
+            .map(|issue| {
+                let description = if issue.description.is_empty() {
+                    String::new()
+                } else {
+                    format!("\n            <p>{}</p>", html::escape(&issue.description))
+                };
+                format!(
+                    r#"        <li>
+            <time datetime="{date}">{date}</time>
+            <h2><a href="{archive_url}">{title}</a></h2>{description}
+            <a class="post" href="{post_url}">Read the post on the blog</a>
+        </li>
+"#,
+                    date = dates::iso_date(issue.sent_at),
+                    archive_url = html::escape(&archive::archive_url(site_url, &issue.slug)),
+                    title = html::escape(&issue.title),
+                    description = description,
+                    post_url = html::escape(&issue.post_url),
+                )
+            })
+            .collect();
+        format!("    <ol class=\"issues\">\n{}    </ol>\n", items)
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Newsletter archive - lindfors.no</title>
+    <meta name="description" content="Every issue of the lindfors.no newsletter.">
+    <link rel="canonical" href="{site_url}/api/archive">
+    <link rel="alternate" type="application/atom+xml" title="Newsletter" href="/api/newsletter/feed.xml">
+    <link rel="alternate" type="application/feed+json" title="Newsletter" href="/api/newsletter/feed.json">
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 640px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        h2 {{ font-family: -apple-system, sans-serif; font-size: 1.15rem; margin: 4px 0 8px; }}
+        p {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+        h2 a {{ color: #1C3240; text-decoration: none; }}
+        h2 a:hover {{ color: #D4706A; }}
+        .issues {{ list-style: none; padding: 0; margin: 32px 0; }}
+        .issues li {{ padding: 20px 0; border-top: 1px solid #E4DED5; }}
+        .issues p {{ margin: 0 0 8px; }}
+        time, .post {{ font-family: -apple-system, sans-serif; font-size: 14px; }}
+        time {{ color: #6B7B85; }}
+        form {{ display: flex; gap: 8px; margin-top: 16px; }}
+        input[type="email"] {{ flex: 1; padding: 10px 14px; border: 1px solid #E4DED5; border-radius: 6px; font-size: 16px; font-family: -apple-system, sans-serif; }}
+        button {{ padding: 10px 18px; background: #D4706A; color: #F0EAE0; border: none; border-radius: 6px; font-size: 14px; font-weight: 600; cursor: pointer; font-family: -apple-system, sans-serif; }}
+        button:hover {{ background: #B85A54; }}
+        .msg {{ margin-top: 16px; padding: 12px; border-radius: 6px; font-size: 14px; font-family: -apple-system, sans-serif; }}
+        .msg.ok {{ background: #e8f5e9; color: #2e7d32; }}
+        .msg.err {{ background: #fce4ec; color: #c62828; }}
+    </style>
+</head>
+<body>
+    <h1>Newsletter archive</h1>
+    <p>Every issue of the lindfors.no newsletter. Also as <a href="/api/newsletter/feed.xml">Atom</a> and <a href="/api/newsletter/feed.json">JSON</a> feeds.</p>
+{items}    <h1>Subscribe</h1>
+    <p>New issues straight to your inbox. Unsubscribe any time.</p>
+    <form id="sub-form">
+        <input type="email" name="email" placeholder="your@email.com" required>
+        <button type="submit">Subscribe</button>
+    </form>
+    <div id="msg"></div>
+    <p style="margin-top: 32px;"><a href="{site_url}">Back to lindfors.no</a></p>
+    <script>
+    document.getElementById('sub-form').addEventListener('submit', function(e) {{
+        e.preventDefault();
+        var email = this.querySelector('input').value;
+        var btn = this.querySelector('button');
+        var msg = document.getElementById('msg');
+        btn.disabled = true;
+        btn.textContent = 'Sending...';
+        fetch('/api/subscribe', {{
+            method: 'POST',
+            headers: {{ 'Content-Type': 'application/json' }},
+            body: JSON.stringify({{ email: email }})
+        }}).then(function(r) {{ return r.json(); }}).then(function(data) {{
+            if (data.success) {{
+                msg.className = 'msg ok';
+                msg.textContent = 'Subscribed — see you in your inbox.';
+            }} else {{
+                msg.className = 'msg err';
+                msg.textContent = data.error || 'Something went wrong.';
+            }}
+        }}).catch(function() {{
+            msg.className = 'msg err';
+            msg.textContent = 'Something went wrong. Please try again.';
+        }}).finally(function() {{
+            btn.disabled = false;
+            btn.textContent = 'Subscribe';
+        }});
+    }});
+    </script>
+</body>
+</html>"#,
+        site_url = html::escape(site_url),
+        items = items,
+    )
+}
+
 fn unsubscribe_form_page() -> String {
     r#"<!DOCTYPE html>
 <html lang="en">
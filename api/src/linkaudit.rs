@@ -0,0 +1,222 @@
+//! Broken-link audit: on its cron (once a week, before the digest) the links
+//! in the LINK_AUDIT_ISSUES most recent issues, both their archived emails
+//! and the posts on the site, are re-checked. The last report is kept under
+//! `link_audit` in the NEWSLETTER KV namespace, listed in the weekly digest
+//! (see digest.rs), and shown by GET /api/admin/link-audit.
+//!
+//! A run checks at most [`MAX_CHECKS`] links. With more than that, each run
+//! picks up where the last one stopped, and the report keeps the broken
+//! links found earlier that this run didn't get to.
+
+use std::collections::BTreeMap;
+
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use serde::{Deserialize, Serialize};
+use worker::{
+    Env, Fetch, Headers, Method, Request, RequestInit, Response, Result, RouteContext, Url,
+};
+
+use crate::logging::{self, RequestLog};
+use crate::{archive, fanout, html, json_response, sendlog};
+
+pub const CRON: &str = "0 6 * * 1";
+
+const REPORT_KEY: &str = "link_audit";
+
+const DEFAULT_ISSUES: usize = 10;
+
+/// Links checked per run at most, to stay within the subrequest limit.
+const MAX_CHECKS: usize = 200;
+
+/// The last audit's findings.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Report {
+    /// Milliseconds since the Unix epoch.
+    pub checked_at: u64,
+    pub checked: usize,
+    pub broken: Vec<BrokenLink>,
+    /// Where in the links, sorted by URL, the next run starts.
+    #[serde(default)]
+    pub next_offset: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BrokenLink {
+    pub url: String,
+    /// The status it answered with, if it answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Slugs of the issues linking to it.
+    pub found_in: Vec<String>,
+}
+
+/// The http(s) links `selector` matches in `html`, resolved against `base`,
+/// leaving out the worker's own (archive, unsubscribe, ...) pages on `site`.
+fn links(html: &str, selector: &str, base: &str, site: &Url) -> Vec<String> {
+    let mut found = Vec::new();
+    let _ = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!(selector, |el| {
+                if let Some(href) = el.get_attribute("href") {
+                    found.push(href);
+                }
+                Ok(())
+            })],
+            ..RewriteStrSettings::new()
+        },
+    );
+    found
+        .into_iter()
+        .filter(|href| !href.trim().starts_with('#'))
+        .filter_map(|href| Url::parse(&html::resolve_url(base, href.trim())).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .filter(|url| !(url.origin() == site.origin() && url.path().starts_with("/api/")))
+        .map(|mut url| {
+            url.set_fragment(None);
+            url.to_string()
+        })
+        .collect()
+}
+
+/// The HTML of the post at `url`, if the site serves it.
+async fn fetch_post(log: &RequestLog, url: &str) -> Option<String> {
+    let started = logging::now_millis();
+    let result = Fetch::Url(Url::parse(url).ok()?).send().await;
+    let status = result.as_ref().map(|resp| resp.status_code());
+    log.upstream_status("site", "fetch_post", started, &status);
+    let mut resp = result.ok()?;
+    if resp.status_code() != 200 {
+        return None;
+    }
+    resp.text().await.ok()
+}
+
+async fn request(url: &str, method: Method) -> Result<u16> {
+    let headers = Headers::new();
+    headers.set("User-Agent", "lindfors.no link checker")?;
+    if method == Method::Get {
+        headers.set("Range", "bytes=0-0")?;
+    }
+    let mut init = RequestInit::new();
+    init.with_method(method);
+    init.with_headers(headers);
+    let req = Request::new_with_init(url, &init)?;
+    Ok(Fetch::Request(req).send().await?.status_code())
+}
+
+/// The status or error `url` fails with; neither if it still works. Servers
+/// that don't do HEAD are asked with GET.
+async fn check(log: &RequestLog, url: &str) -> (Option<u16>, Option<String>) {
+    let started = logging::now_millis();
+    let mut result = request(url, Method::Head).await;
+    if matches!(result, Ok(403 | 405 | 501)) {
+        result = request(url, Method::Get).await;
+    }
+    log.upstream_status("links", "check", started, &result);
+    match result {
+        // Rate limited isn't gone
+        Ok(status) if status < 400 || status == 429 => (None, None),
+        Ok(status) => (Some(status), None),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+/// Re-check the recent issues' links and store the report.
+pub async fn run(env: &Env, log: &RequestLog) -> Result<()> {
+    let count = env
+        .var("LINK_AUDIT_ISSUES")
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_ISSUES);
+    let site_url = env.var("SITE_URL")?.to_string();
+    let site = Url::parse(&site_url)?;
+
+    // Each link, with the issues it's in
+    let mut found_in: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for issue in sendlog::load(env).await.iter().take(count) {
+        let mut issue_links = Vec::new();
+        if let Some(email) = archive::load(env, &issue.slug).await? {
+            issue_links.extend(links(&email, "a[href]", &issue.post_url, &site));
+        }
+        // Only the post itself, not the site's navigation around it
+        if let Some(post) = fetch_post(log, &issue.post_url).await {
+            issue_links.extend(links(
+                &post,
+                ".post-content a[href]",
+                &issue.post_url,
+                &site,
+            ));
+        }
+        for link in issue_links {
+            let slugs = found_in.entry(link).or_default();
+            if !slugs.contains(&issue.slug) {
+                slugs.push(issue.slug.clone());
+            }
+        }
+    }
+
+    let previous = last_report(env).await.ok().flatten().unwrap_or_default();
+    let mut to_check: Vec<(String, Vec<String>)> = found_in.into_iter().collect();
+    let total = to_check.len();
+    let mut next_offset = 0;
+    if total > MAX_CHECKS {
+        let start = previous.next_offset % total;
+        worker::console_warn!(
+            "Link audit: checking {} of {} links, from {}",
+            MAX_CHECKS,
+            total,
+            start
+        );
+        to_check.rotate_left(start);
+        next_offset = (start + MAX_CHECKS) % total;
+    }
+    let unchecked = to_check.split_off(total.min(MAX_CHECKS));
+    let mut report = Report {
+        checked_at: logging::now_millis(),
+        next_offset,
+        ..Report::default()
+    };
+    let checked = fanout::map(to_check, fanout::CONNECTIONS, |(url, slugs)| async move {
+        let (status, error) = check(log, &url).await;
+        BrokenLink {
+            url,
+            status,
+            error,
+            found_in: slugs,
+        }
+    })
+    .await;
+    report.checked = checked.len();
+    report.broken = checked
+        .into_iter()
+        .filter(|link| link.status.is_some() || link.error.is_some())
+        .collect();
+    // Still linked to, and not checked since
+    let unchecked: BTreeMap<String, Vec<String>> = unchecked.into_iter().collect();
+    for mut link in previous.broken {
+        if let Some(slugs) = unchecked.get(&link.url) {
+            link.found_in = slugs.clone();
+            report.broken.push(link);
+        }
+    }
+
+    log.event("link_audit", report.broken.is_empty());
+    env.kv("NEWSLETTER")?
+        .put(REPORT_KEY, &report)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// The last audit's report, if one has run.
+pub async fn last_report(env: &Env) -> Result<Option<Report>> {
+    Ok(env.kv("NEWSLETTER")?.get(REPORT_KEY).json().await?)
+}
+
+/// GET /api/admin/link-audit — admin: the last broken-link audit.
+pub async fn handle_report(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    json_response(&last_report(&ctx.env).await?.unwrap_or_default(), 200)
+}
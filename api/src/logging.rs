@@ -0,0 +1,189 @@
+//! Structured request logging. Every request gets an ID (Cloudflare's
+//! `cf-ray` when present) that is returned as `X-Request-Id` and tagged on one
+//! JSON log line for the request and one per upstream call it makes, so a
+//! `wrangler tail` can be filtered down to a single request. The same
+//! outcomes are recorded as metrics (see `metrics.rs`).
+//!
+//! The log also carries the fetch event's [`Context`], so handlers can leave
+//! work running after their response with [`RequestLog::wait_until`].
+
+use std::fmt::Display;
+use std::future::Future;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+use worker::{console_log, Context, Date, Env, Request, Response, Result};
+
+use crate::metrics::{self, Metrics};
+
+/// Per-request logging context, carried as the router's data.
+#[derive(Clone)]
+pub struct RequestLog {
+    pub id: String,
+    started: u64,
+    metrics: Metrics,
+    context: Option<Rc<Context>>,
+}
+
+impl RequestLog {
+    pub fn new(req: &Request, env: &Env, context: Context) -> Self {
+        let id = req
+            .headers()
+            .get("cf-ray")
+            .ok()
+            .flatten()
+            .filter(|ray| !ray.is_empty())
+            .unwrap_or_else(random_id);
+        RequestLog {
+            id,
+            started: Date::now().as_millis(),
+            metrics: Metrics::new(env),
+            context: Some(Rc::new(context)),
+        }
+    }
+
+    /// Context for a cron run, which has no request to take an ID from.
+    pub fn scheduled(env: &Env) -> Self {
+        RequestLog {
+            id: random_id(),
+            started: Date::now().as_millis(),
+            metrics: Metrics::new(env),
+            context: None,
+        }
+    }
+
+    /// Run `task` in the background: the response goes out without waiting
+    /// for it, and the worker stays alive until it's done. `task` logs its
+    /// own failures.
+    pub fn wait_until<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        match &self.context {
+            Some(context) => context.wait_until(task),
+            // Cron runs have no fetch context; the task may be cut short
+            None => worker::wasm_bindgen_futures::spawn_local(task),
+        }
+    }
+
+    /// Count a subscribe, unsubscribe, send, ... and whether it succeeded.
+    pub fn event(&self, name: &str, ok: bool) {
+        self.metrics.record("event", name, outcome(ok), 0);
+    }
+
+    /// Log the outcome of a call to an upstream service. `status` is the HTTP
+    /// status if a response came back at all; `error` is why the call failed.
+    pub fn upstream(
+        &self,
+        service: &str,
+        operation: &str,
+        started: u64,
+        status: Option<u16>,
+        error: Option<&str>,
+    ) {
+        let ok = error.is_none() && status.is_none_or(|s| s < 300);
+        let duration_ms = now_millis().saturating_sub(started);
+        self.metrics.record(
+            "upstream",
+            &format!("{}.{}", service, operation),
+            outcome(ok),
+            duration_ms,
+        );
+        self.emit(json!({
+            "event": "upstream",
+            "request_id": self.id,
+            "service": service,
+            "operation": operation,
+            "ok": ok,
+            "status": status,
+            "error": error,
+            "duration_ms": duration_ms,
+        }));
+    }
+
+    /// [`upstream`](Self::upstream) for a call that yields an HTTP status.
+    pub fn upstream_status<E: Display>(
+        &self,
+        service: &str,
+        operation: &str,
+        started: u64,
+        result: &std::result::Result<u16, E>,
+    ) {
+        match result {
+            Ok(status) => self.upstream(service, operation, started, Some(*status), None),
+            Err(e) => self.upstream(service, operation, started, None, Some(&e.to_string())),
+        }
+    }
+
+    /// Log the finished request and stamp its response with `X-Request-Id`.
+    /// `error` is why the handler failed, if it did.
+    pub fn finish(
+        &self,
+        method: &str,
+        path: &str,
+        mut resp: Response,
+        error: Option<&str>,
+    ) -> Result<Response> {
+        let duration_ms = now_millis().saturating_sub(self.started);
+        self.metrics.record(
+            "request",
+            &format!("{} {}", method, metrics::route_name(path)),
+            &format!("{}xx", resp.status_code() / 100),
+            duration_ms,
+        );
+        self.emit(json!({
+            "event": "request",
+            "request_id": self.id,
+            "method": method,
+            "path": path,
+            "status": resp.status_code(),
+            "error": error,
+            "duration_ms": duration_ms,
+        }));
+        resp.headers_mut().set("X-Request-Id", &self.id)?;
+        Ok(resp)
+    }
+
+    /// Log the finished cron run. `error` is why it failed, if it did.
+    pub fn finish_scheduled(&self, cron: &str, error: Option<&str>) {
+        let duration_ms = now_millis().saturating_sub(self.started);
+        self.metrics
+            .record("scheduled", cron, outcome(error.is_none()), duration_ms);
+        self.emit(json!({
+            "event": "scheduled",
+            "request_id": self.id,
+            "cron": cron,
+            "error": error,
+            "duration_ms": duration_ms,
+        }));
+    }
+
+    fn emit(&self, mut line: Value) {
+        // Drop nulls so lines only carry the fields that apply
+        if let Some(fields) = line.as_object_mut() {
+            fields.retain(|_, v| !v.is_null());
+        }
+        console_log!("{}", line);
+    }
+}
+
+/// Start time for [`RequestLog::upstream`].
+pub fn now_millis() -> u64 {
+    Date::now().as_millis()
+}
+
+fn outcome(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return format!("{:x}", now_millis());
+    }
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
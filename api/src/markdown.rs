@@ -0,0 +1,492 @@
+//! Markdown rendering for newsletter bodies.
+
+use std::collections::HashSet;
+
+use pulldown_cmark::{
+    html, BlockQuoteKind, CowStr, Event, HeadingLevel, Options, Parser, Tag, TagEnd,
+    TextMergeStream,
+};
+
+use crate::email::Lang;
+use crate::html::{escape, slugify};
+
+const SANS: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif";
+
+/// Tables with more columns than this can't fit a 600px column on mobile.
+const MAX_TABLE_COLUMNS: usize = 4;
+
+/// Per-issue rendering switches, mostly driven by frontmatter.
+#[derive(Default)]
+pub struct RenderOptions {
+    /// Convert tables wider than [`MAX_TABLE_COLUMNS`] into stacked
+    /// "header: value" lists (`stack_wide_tables: true`).
+    pub stack_wide_tables: bool,
+    /// URL template for rendering TeX to an image, with `{tex}` replaced by
+    /// the URL-encoded expression (MATH_IMAGE_URL). Without one, equations
+    /// become links to the post.
+    pub math_image_url: Option<String>,
+    /// Curly quotes, en/em dashes and ellipses, as Zola's `smart_punctuation`.
+    pub smart_punctuation: bool,
+    /// The post on the site, where math renders properly.
+    pub post_url: String,
+    /// Emit a linked table of contents before the body (`toc: true`).
+    pub toc: bool,
+    /// Hosted archive copy of the issue, which the contents links point into.
+    pub archive_url: String,
+    /// Cut the body after roughly this many words, at a block boundary, and
+    /// link to the rest (`email_truncate: N`).
+    pub truncate_words: Option<usize>,
+    /// Language for generated labels.
+    pub lang: Lang,
+}
+
+/// Render markdown to HTML using pulldown-cmark.
+pub fn render_markdown(md: &str, opts: &RenderOptions) -> String {
+    let mut parser_opts = Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_MATH
+        | Options::ENABLE_HEADING_ATTRIBUTES
+        | Options::ENABLE_GFM;
+    if opts.smart_punctuation {
+        parser_opts |= Options::ENABLE_SMART_PUNCTUATION;
+    }
+    // Merged so `:shortcodes:` aren't split across text events
+    let mut events: Vec<Event> = TextMergeStream::new(Parser::new_ext(md, parser_opts)).collect();
+    if let Some(max_words) = opts.truncate_words {
+        events = truncate(events, max_words, opts);
+    }
+
+    let mut events: Vec<Event> = events
+        .into_iter()
+        .map(|event| match event {
+            Event::Text(text) if text.contains(':') => Event::Text(emojify(&text).into()),
+            Event::InlineMath(tex) => Event::InlineHtml(render_math(&tex, false, opts).into()),
+            // Display math sits inside a paragraph, so it stays inline-level markup
+            Event::DisplayMath(tex) => Event::InlineHtml(render_math(&tex, true, opts).into()),
+            Event::Start(Tag::BlockQuote(Some(kind))) => Event::Html(callout_open(kind, opts).into()),
+            Event::End(TagEnd::BlockQuote(Some(_))) => Event::Html("</div>\n".into()),
+            other => other,
+        })
+        .collect();
+
+    events = collect_footnotes(events);
+    let headings = anchor_headings(&mut events);
+
+    if opts.stack_wide_tables {
+        events = stack_wide_tables(events);
+    }
+
+    let mut html_output = String::new();
+    if opts.toc {
+        html_output.push_str(&table_of_contents(&headings, opts));
+    }
+    html::push_html(&mut html_output, events.into_iter());
+    html_output
+}
+
+/// Replace GitHub-style `:sparkles:` shortcodes with the emoji. Unknown
+/// shortcodes (and times like 10:30:00) are left as written.
+pub fn emojify(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        // Not inside a word, so `a:b:c` stays as it is
+        let at_boundary = !out.ends_with(|c: char| c.is_alphanumeric());
+        let emoji = (at_boundary && name_len > 0 && after[name_len..].starts_with(':'))
+            .then(|| emojis::get_by_shortcode(&after[..name_len]))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                out.push_str(emoji.as_str());
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Keep top-level blocks until `max_words` words have been seen, then drop
+/// the rest (except footnote definitions) and add a "continue reading" button.
+fn truncate<'a>(events: Vec<Event<'a>>, max_words: usize, opts: &RenderOptions) -> Vec<Event<'a>> {
+    let mut words = 0;
+    let mut depth = 0usize;
+    let mut in_footnote = false;
+    let mut cut = None;
+
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(_)) => in_footnote = true,
+            Event::End(TagEnd::FootnoteDefinition) => in_footnote = false,
+            Event::Text(t) | Event::Code(t) if !in_footnote => {
+                words += t.split_whitespace().count()
+            }
+            _ => {}
+        }
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth == 0 && words >= max_words && !in_footnote {
+            cut = Some(i + 1);
+            break;
+        }
+    }
+
+    let Some(cut) = cut.filter(|&cut| cut < events.len()) else {
+        return events;
+    };
+    let mut events = events;
+    let rest = events.split_off(cut);
+
+    events.push(Event::Html(
+        format!(
+            "<p class=\"continue-reading\" style=\"text-align: center; margin: 24px 0;\"><a href=\"{href}\" class=\"email-button\" style=\"display: inline-block; padding: 12px 24px; background-color: #D4706A; color: #ffffff; border-radius: 6px; font-family: {SANS}; font-size: 16px; font-weight: 600; text-decoration: none;\">{label} &rarr;</a></p>\n",
+            href = escape(&opts.post_url),
+            label = opts.lang.continue_reading(),
+        )
+        .into(),
+    ));
+
+    // Footnotes referenced above may be defined below the cut
+    let mut in_footnote = false;
+    for event in rest {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(_)) => {
+                in_footnote = true;
+                events.push(event);
+            }
+            Event::End(TagEnd::FootnoteDefinition) => {
+                in_footnote = false;
+                events.push(event);
+            }
+            other if in_footnote => events.push(other),
+            _ => {}
+        }
+    }
+    events
+}
+
+/// Plain text of the first paragraph with any text in it, cut at a word
+/// boundary to at most `max_chars` characters (with an ellipsis if cut).
+pub fn excerpt(md: &str, max_chars: usize) -> String {
+    let mut text = String::new();
+    let mut in_paragraph = false;
+    // Alt text isn't prose, so image-only paragraphs are skipped
+    let mut in_image = false;
+    for event in Parser::new_ext(md, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH) {
+        match event {
+            Event::Start(Tag::Paragraph) => in_paragraph = true,
+            Event::Start(Tag::Image { .. }) => in_image = true,
+            Event::End(TagEnd::Image) => in_image = false,
+            Event::End(TagEnd::Paragraph) => {
+                in_paragraph = false;
+                if !text.trim().is_empty() {
+                    break;
+                }
+                text.clear();
+            }
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) if in_paragraph && !in_image => {
+                text.push_str(&t)
+            }
+            Event::SoftBreak | Event::HardBreak if in_paragraph => text.push(' '),
+            _ => {}
+        }
+    }
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let mut cut = String::new();
+    for word in text.split(' ') {
+        if cut.chars().count() + word.chars().count() + 1 > max_chars {
+            break;
+        }
+        if !cut.is_empty() {
+            cut.push(' ');
+        }
+        cut.push_str(word);
+    }
+    let cut = cut.trim_end_matches(|c: char| c.is_ascii_punctuation());
+    format!("{cut}…")
+}
+
+/// Render untrusted markdown (comments). Raw HTML shows as text, headings as
+/// plain paragraphs, images as links to them, and only http(s) and mailto
+/// links survive, marked `nofollow ugc`.
+pub fn render_comment(md: &str) -> String {
+    let safe = |url: &str| {
+        let url = url.trim().to_ascii_lowercase();
+        url.starts_with("https://") || url.starts_with("http://") || url.starts_with("mailto:")
+    };
+    // Links can't nest, so one flag tracks whether the open one was kept
+    let mut kept_link = false;
+
+    let events = Parser::new_ext(md, Options::ENABLE_STRIKETHROUGH).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Heading { .. }) => Event::Start(Tag::Paragraph),
+        Event::End(TagEnd::Heading(_)) => Event::End(TagEnd::Paragraph),
+        Event::Start(Tag::Link { dest_url, .. }) | Event::Start(Tag::Image { dest_url, .. }) => {
+            kept_link = safe(&dest_url);
+            if kept_link {
+                Event::InlineHtml(
+                    format!(r#"<a href="{}" rel="nofollow ugc noopener">"#, escape(&dest_url))
+                        .into(),
+                )
+            } else {
+                Event::Text("".into())
+            }
+        }
+        Event::End(TagEnd::Link) | Event::End(TagEnd::Image) => {
+            Event::InlineHtml(if kept_link { "</a>" } else { "" }.into())
+        }
+        other => other,
+    });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+/// A heading as listed in the table of contents.
+struct TocEntry {
+    level: HeadingLevel,
+    id: String,
+    text: String,
+}
+
+/// Give every heading a slugified id (keeping explicit `{#id}` attributes),
+/// de-duplicated with a numeric suffix the way Zola does, and return them in
+/// document order.
+fn anchor_headings(events: &mut [Event]) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+
+    for i in 0..events.len() {
+        let Event::Start(Tag::Heading { level, id, .. }) = &events[i] else {
+            continue;
+        };
+        let level = *level;
+        let explicit = id.as_ref().map(|id| id.to_string());
+
+        let mut text = String::new();
+        for event in &events[i + 1..] {
+            match event {
+                Event::End(TagEnd::Heading(_)) => break,
+                Event::Text(t) | Event::Code(t) => text.push_str(t),
+                _ => {}
+            }
+        }
+
+        let base = explicit.unwrap_or_else(|| slugify(&text));
+        let mut unique = base.clone();
+        let mut n = 1;
+        while !seen.insert(unique.clone()) {
+            unique = format!("{base}-{n}");
+            n += 1;
+        }
+
+        if let Event::Start(Tag::Heading { id, .. }) = &mut events[i] {
+            *id = Some(CowStr::from(unique.clone()));
+        }
+        entries.push(TocEntry {
+            level,
+            id: unique,
+            text,
+        });
+    }
+    entries
+}
+
+/// Contents list of the issue's h2/h3 headings, linking into the archive copy
+/// (in-email anchors are unreliable across clients).
+fn table_of_contents(headings: &[TocEntry], opts: &RenderOptions) -> String {
+    let items: String = headings
+        .iter()
+        .filter(|h| matches!(h.level, HeadingLevel::H2 | HeadingLevel::H3))
+        .map(|h| {
+            let indent = if h.level == HeadingLevel::H3 { "padding-left: 16px; " } else { "" };
+            format!(
+                "<li style=\"{indent}margin: 0 0 4px 0;\"><a href=\"{base}#{id}\">{text}</a></li>\n",
+                base = escape(&opts.archive_url),
+                id = escape(&h.id),
+                text = escape(&h.text),
+            )
+        })
+        .collect();
+    if items.is_empty() {
+        return String::new();
+    }
+
+    format!(
+        "<div class=\"toc email-callout\" style=\"margin: 0 0 24px 0; padding: 12px 16px; background-color: #F0EBE3; border-radius: 6px;\">\n<p><strong>{title}</strong></p>\n<ul class=\"toc-list\" style=\"list-style: none; padding-left: 0;\">\n{items}</ul>\n</div>\n",
+        title = opts.lang.contents(),
+    )
+}
+
+/// Opening markup for a `> [!NOTE]`-style callout: a tinted box with a
+/// colored left border and a title, instead of a plain blockquote.
+fn callout_open(kind: BlockQuoteKind, opts: &RenderOptions) -> String {
+    let [note, tip, important, warning, caution] = opts.lang.callout_titles();
+    let (class, title, color, background) = match kind {
+        BlockQuoteKind::Note => ("note", note, "#2A8F82", "#E6F2EF"),
+        BlockQuoteKind::Tip => ("tip", tip, "#3C8D5A", "#E8F3EA"),
+        BlockQuoteKind::Important => ("important", important, "#6B5CA5", "#EFECF6"),
+        BlockQuoteKind::Warning => ("warning", warning, "#B7791F", "#FAF1E1"),
+        BlockQuoteKind::Caution => ("caution", caution, "#D4706A", "#FBEDEC"),
+    };
+    format!(
+        "<div class=\"callout callout-{class} email-callout\" style=\"margin: 0 0 16px 0; padding: 12px 16px 0 16px; border-left: 4px solid {color}; background-color: {background}; border-radius: 0 6px 6px 0;\">\n<p style=\"margin: 0 0 8px 0; font-family: {SANS}; font-size: 14px; font-weight: 700; color: {color};\">{title}</p>\n"
+    )
+}
+
+/// Email clients can't run KaTeX, so `$...$` and `$$...$$` become images from
+/// the configured rendering service, or links to the post where they render.
+fn render_math(tex: &str, display: bool, opts: &RenderOptions) -> String {
+    let tex = tex.trim();
+    let alt = escape(tex);
+
+    if let Some(template) = opts.math_image_url.as_deref().filter(|t| !t.is_empty()) {
+        let src = escape(&template.replace("{tex}", &percent_encode(tex)));
+        return if display {
+            format!(
+                "<span class=\"math-display\" style=\"display: block; text-align: center;\"><img src=\"{src}\" alt=\"{alt}\" style=\"max-width: 100%; height: auto;\" /></span>"
+            )
+        } else {
+            format!(
+                "<img class=\"math-inline\" src=\"{src}\" alt=\"{alt}\" style=\"vertical-align: middle; height: auto;\" />"
+            )
+        };
+    }
+
+    let href = escape(&opts.post_url);
+    let label = opts.lang.view_equation();
+    if display {
+        format!(
+            "<span class=\"math-display email-callout\" style=\"display: block; text-align: center; padding: 10px 14px; background-color: #F0EBE3; border-radius: 6px;\"><a href=\"{href}\" title=\"{alt}\">{label} &rarr;</a></span>"
+        )
+    } else {
+        // Short inline expressions usually read fine as source
+        format!("<a href=\"{href}\" class=\"math-inline\" title=\"{label}\"><code>{alt}</code></a>")
+    }
+}
+
+/// Percent-encode everything but unreserved characters (RFC 3986).
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+/// Move footnote definitions to the end of the document, after a separator
+/// rule, wherever they were written in the source.
+fn collect_footnotes(events: Vec<Event>) -> Vec<Event> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut footnotes = Vec::new();
+    let mut in_footnote = false;
+    for event in events {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(_)) => {
+                in_footnote = true;
+                footnotes.push(event);
+            }
+            Event::End(TagEnd::FootnoteDefinition) => {
+                in_footnote = false;
+                footnotes.push(event);
+            }
+            other if in_footnote => footnotes.push(other),
+            other => out.push(other),
+        }
+    }
+
+    if !footnotes.is_empty() {
+        out.push(Event::Html("<div class=\"footnotes\">\n".into()));
+        out.push(Event::Rule);
+        out.extend(footnotes);
+        out.push(Event::Html("</div>\n".into()));
+    }
+    out
+}
+
+/// Replace every table wider than [`MAX_TABLE_COLUMNS`] with one block per
+/// row listing each cell under its column header.
+fn stack_wide_tables(events: Vec<Event>) -> Vec<Event> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut iter = events.into_iter();
+    while let Some(event) = iter.next() {
+        match event {
+            Event::Start(Tag::Table(alignments)) if alignments.len() > MAX_TABLE_COLUMNS => {
+                out.push(Event::Html(stack_table(&mut iter).into()));
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Consume events up to the end of the current table and render it stacked.
+fn stack_table<'a>(iter: &mut impl Iterator<Item = Event<'a>>) -> String {
+    let mut headers: Vec<String> = Vec::new();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut cell: Option<Vec<Event>> = None;
+    let mut in_head = false;
+
+    for event in iter.by_ref() {
+        match event {
+            Event::End(TagEnd::Table) => break,
+            Event::Start(Tag::TableHead) => in_head = true,
+            Event::End(TagEnd::TableHead) => in_head = false,
+            Event::Start(Tag::TableRow) => row.clear(),
+            Event::End(TagEnd::TableRow) => rows.push(std::mem::take(&mut row)),
+            Event::Start(Tag::TableCell) => cell = Some(Vec::new()),
+            Event::End(TagEnd::TableCell) => {
+                let mut rendered = String::new();
+                html::push_html(&mut rendered, cell.take().unwrap_or_default().into_iter());
+                if in_head {
+                    headers.push(rendered);
+                } else {
+                    row.push(rendered);
+                }
+            }
+            other => {
+                if let Some(cell) = cell.as_mut() {
+                    cell.push(other);
+                }
+            }
+        }
+    }
+
+    let mut out = String::from("<div class=\"stacked-table\">\n");
+    for row in rows {
+        out.push_str(
+            "<div class=\"email-callout\" style=\"margin: 0 0 12px 0; padding: 10px 14px; background-color: #F0EBE3; border-radius: 6px;\">\n",
+        );
+        for (i, value) in row.iter().enumerate() {
+            let header = headers.get(i).map(String::as_str).unwrap_or("");
+            out.push_str(&format!(
+                "<p style=\"margin: 0 0 4px 0;\"><strong>{header}:</strong> {value}</p>\n"
+            ));
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</div>\n");
+    out
+}
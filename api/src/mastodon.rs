@@ -0,0 +1,97 @@
+//! Announcing sent issues on Mastodon. With MASTODON_URL (the instance) and
+//! the MASTODON_TOKEN secret (an access token with `write:statuses`) set,
+//! each send posts the issue's title, description, link and tags as a public
+//! status, as a background job (see jobs.rs) so the send's response doesn't
+//! wait for it and a failed post is retried.
+//! `mastodon: false` in an issue's frontmatter skips it.
+
+use serde_json::json;
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Error, Fetch, Headers, Method, Request, RequestInit, Result};
+
+use crate::logging::{self, RequestLog};
+use crate::sendlog::SentIssue;
+
+/// Mastodon's default status length limit. Links count as 23 however long.
+const MAX_STATUS_CHARS: usize = 500;
+const LINK_CHARS: usize = 23;
+
+/// `#tag` for each tag, reduced to the letters and digits hashtags allow.
+fn hashtags(tags: &[String]) -> String {
+    tags.iter()
+        .map(|tag| {
+            tag.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+        })
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| format!("#{}", tag))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The status text; the description is left out if it doesn't fit.
+fn status_text(issue: &SentIssue) -> String {
+    let tags = hashtags(&issue.tags);
+    let mut parts = vec![issue.title.as_str()];
+    // Three blank-line separators, with the description
+    let fixed = issue.title.chars().count() + LINK_CHARS + tags.chars().count() + 6;
+    if !issue.description.is_empty()
+        && fixed + issue.description.chars().count() <= MAX_STATUS_CHARS
+    {
+        parts.push(&issue.description);
+    }
+    parts.push(&issue.post_url);
+    if !tags.is_empty() {
+        parts.push(&tags);
+    }
+    parts.join("\n\n")
+}
+
+/// Post `issue` to the configured account, if Mastodon is configured.
+pub async fn announce(env: &Env, log: &RequestLog, issue: &SentIssue) -> Result<()> {
+    let (Ok(instance), Ok(token)) = (env.var("MASTODON_URL"), env.secret("MASTODON_TOKEN")) else {
+        return Ok(());
+    };
+    let url = format!(
+        "{}/api/v1/statuses",
+        instance.to_string().trim_end_matches('/')
+    );
+
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("Authorization", &format!("Bearer {}", token))?;
+    // Resending an issue within the hour doesn't post it twice
+    headers.set("Idempotency-Key", &format!("issue-{}", issue.slug))?;
+    let body = json!({ "status": status_text(issue), "visibility": "public" });
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    init.with_headers(headers);
+    init.with_body(Some(JsValue::from_str(&body.to_string())));
+    let req = Request::new_with_init(&url, &init)?;
+
+    let started = logging::now_millis();
+    let mut resp = match Fetch::Request(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            log.upstream(
+                "mastodon",
+                "post_status",
+                started,
+                None,
+                Some(&e.to_string()),
+            );
+            return Err(e);
+        }
+    };
+    let status = resp.status_code();
+    log.upstream("mastodon", "post_status", started, Some(status), None);
+    if status != 200 {
+        let detail = resp.text().await.unwrap_or_default();
+        return Err(Error::RustError(format!(
+            "Posting the status failed (status {}): {}",
+            status, detail
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! API health metrics in Workers Analytics Engine.
+//!
+//! Every data point is one occurrence:
+//!
+//! ```text
+//! index1 / blob1  kind: "request", "upstream", "event" or "scheduled"
+//! blob2           name: "POST /api/subscribe", "stalwart.add_member", "subscribe",
+//!                 "*/5 * * * *", ...
+//! blob3           outcome: "ok" or "error" ("2xx", "4xx", ... for requests)
+//! double1         duration in milliseconds (0 for events)
+//! ```
+//!
+//! GET /api/admin/metrics reads them back through the Analytics Engine SQL
+//! API, which needs CF_ACCOUNT_ID and a CF_ANALYTICS_TOKEN secret with
+//! "Account Analytics: Read". Without the METRICS binding nothing is recorded.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::Value;
+use worker::{
+    AnalyticsEngineDataPointBuilder, AnalyticsEngineDataset, Env, Error, Fetch, Headers, Method,
+    Request, RequestInit, Response, Result, RouteContext,
+};
+
+use crate::logging::{self, RequestLog};
+use crate::json_response;
+
+/// Must match `dataset` of the METRICS binding in wrangler.toml.
+const DATASET: &str = "newsletter_metrics";
+
+/// Where data points go, if the METRICS binding is configured.
+#[derive(Clone)]
+pub struct Metrics(Option<AnalyticsEngineDataset>);
+
+impl Metrics {
+    pub fn new(env: &Env) -> Self {
+        Metrics(env.analytics_engine("METRICS").ok())
+    }
+
+    pub fn record(&self, kind: &str, name: &str, outcome: &str, duration_ms: u64) {
+        let Some(dataset) = &self.0 else {
+            return;
+        };
+        let point = AnalyticsEngineDataPointBuilder::new()
+            .indexes([kind])
+            .add_blob(kind)
+            .add_blob(name)
+            .add_blob(outcome)
+            .add_double(duration_ms as f64)
+            .build();
+        if let Err(e) = dataset.write_data_point(&point) {
+            worker::console_warn!("Failed to record {} metric: {}", kind, e);
+        }
+    }
+}
+
+/// The route a path belongs to, so versions, slugs, codes and site pages
+/// aggregate together.
+pub fn route_name(path: &str) -> String {
+    let path = match path.strip_prefix("/api/v1/") {
+        Some(rest) => format!("/api/{}", rest),
+        None => path.to_string(),
+    };
+    for (route, param) in [
+        ("/api/archive/", ":slug"),
+        ("/api/comments/", ":slug"),
+        ("/api/reactions/", ":slug"),
+        ("/api/related/", ":slug"),
+        ("/api/activitypub/posts/", ":slug"),
+        ("/api/bookmarks/", ":id"),
+        ("/api/premium/", ":slug"),
+        ("/api/views/", ":slug"),
+        ("/api/poll/", ":id/vote/:option"),
+        ("/api/admin/links/", ":code"),
+        ("/api/admin/send-status/", ":slug"),
+        ("/api/admin/uploads/", ":name"),
+        ("/api/uploads/", ":name"),
+        ("/s/", ":code"),
+    ] {
+        if path.starts_with(route) {
+            return format!("{}{}", route, param);
+        }
+    }
+    // Site pages passed through by the redirects fallback
+    if !path.starts_with("/api/")
+        && !matches!(path.as_str(), "/sitemap-newsletter.xml" | "/.well-known/webfinger")
+    {
+        return "/*path".into();
+    }
+    path
+}
+
+/// SQL for per-metric counts and average latency since `interval` ago.
+fn summary_query(interval: &str) -> String {
+    format!(
+        "SELECT blob1 AS kind, blob2 AS name, blob3 AS outcome, \
+         SUM(_sample_interval) AS count, \
+         SUM(_sample_interval * double1) / SUM(_sample_interval) AS avg_ms \
+         FROM {} WHERE timestamp > NOW() - INTERVAL {} \
+         GROUP BY kind, name, outcome ORDER BY kind, name, outcome FORMAT JSON",
+        DATASET, interval
+    )
+}
+
+/// SQL for how many times each of `events` succeeded since `interval` ago.
+fn event_count_query(events: &[&str], interval: &str) -> String {
+    let names: Vec<String> = events.iter().map(|e| format!("'{}'", e)).collect();
+    format!(
+        "SELECT blob2 AS name, SUM(_sample_interval) AS count \
+         FROM {} WHERE blob1 = 'event' AND blob3 = 'ok' AND blob2 IN ({}) \
+         AND timestamp > NOW() - INTERVAL {} \
+         GROUP BY name FORMAT JSON",
+        DATASET,
+        names.join(", "),
+        interval
+    )
+}
+
+/// How many times each of `events` succeeded since `interval` ago (e.g.
+/// `'7' DAY`); events that didn't happen are left out.
+pub async fn event_counts(
+    account_id: &str,
+    token: &str,
+    events: &[&str],
+    interval: &str,
+) -> Result<HashMap<String, u64>> {
+    let rows = query(account_id, token, &event_count_query(events, interval)).await?;
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let name = row["name"].as_str()?.to_string();
+            // 64-bit integers come back quoted
+            let count = match &row["count"] {
+                Value::String(s) => s.parse::<f64>().ok()?,
+                v => v.as_f64()?,
+            };
+            Some((name, count as u64))
+        })
+        .collect())
+}
+
+/// Run a query against the Analytics Engine SQL API; the result rows.
+pub async fn query(account_id: &str, token: &str, sql: &str) -> Result<Vec<Value>> {
+    let url = format!(
+        "https://api.cloudflare.com/client/v4/accounts/{}/analytics_engine/sql",
+        account_id
+    );
+
+    let headers = Headers::new();
+    headers.set("Authorization", &format!("Bearer {}", token))?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    init.with_headers(headers);
+    init.with_body(Some(worker::wasm_bindgen::JsValue::from_str(sql)));
+
+    let req = Request::new_with_init(&url, &init)?;
+    let mut resp = Fetch::Request(req).send().await?;
+    if resp.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "Analytics Engine SQL API returned {}",
+            resp.status_code()
+        )));
+    }
+
+    let mut result: Value = resp.json().await?;
+    match result.get_mut("data").map(Value::take) {
+        Some(Value::Array(rows)) => Ok(rows),
+        _ => Err(Error::RustError("Analytics Engine response has no data".into())),
+    }
+}
+
+/// GET /api/admin/metrics — admin: counts and latencies over the last 24
+/// hours and 7 days.
+pub async fn handle_metrics(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let account_id = ctx.env.var("CF_ACCOUNT_ID")?.to_string();
+    let token = ctx.env.secret("CF_ANALYTICS_TOKEN")?.to_string();
+
+    let mut windows = Vec::new();
+    for interval in ["'1' DAY", "'7' DAY"] {
+        let started = logging::now_millis();
+        let rows = query(&account_id, &token, &summary_query(interval)).await;
+        let error = rows.as_ref().err().map(|e| e.to_string());
+        ctx.data
+            .upstream("analytics_engine", "query", started, None, error.as_deref());
+        windows.push(rows?);
+    }
+
+    #[derive(Serialize)]
+    struct MetricsResponse {
+        last_24h: Vec<Value>,
+        last_7d: Vec<Value>,
+    }
+
+    let last_7d = windows.pop().unwrap_or_default();
+    let last_24h = windows.pop().unwrap_or_default();
+    json_response(
+        &MetricsResponse { last_24h, last_7d },
+        200,
+    )
+}
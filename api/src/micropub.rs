@@ -0,0 +1,420 @@
+//! Micropub (<https://www.w3.org/TR/micropub/>), for publishing from
+//! Micropub clients such as phone apps. An `h-entry` created here is
+//! committed to the site's repository as a Zola post (see github.rs) and
+//! goes live with the Pages build that triggers. Entries with a `name` are
+//! posts titled by it; without one they're notes, titled by their first
+//! words and filed under the `notes` category.
+//!
+//! Syndicating an entry to `newsletter` also commits it as a newsletter
+//! issue under static/newsletter/, so once the site is built it can be sent
+//! with `scripts/send-newsletter.sh <slug>`. Sending needs the built issue,
+//! so it isn't started from here.
+//!
+//! Clients authenticate with a token from the IndieAuth token endpoint (see
+//! indieauth.rs) as their bearer token (or, form-encoded, `access_token`):
+//! `create` scope to publish, or `draft` to create drafts only. Only creating
+//! is supported, from form-encoded or JSON requests; not updates, deletes or
+//! media.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+use worker::{Env, FormData, FormEntry, Request, Response, Result, RouteContext};
+
+use crate::github::Repo;
+use crate::logging::RequestLog;
+use crate::{dates, html, indieauth, json_response, sendlog};
+
+/// The syndication target that makes the entry a newsletter issue too.
+const NEWSLETTER_TARGET: &str = "newsletter";
+
+const MAX_SLUG_WORDS: usize = 6;
+const MAX_NOTE_TITLE_CHARS: usize = 60;
+
+/// The properties read from form-encoded requests.
+const FORM_PROPERTIES: [&str; 7] = [
+    "name",
+    "content",
+    "summary",
+    "category",
+    "post-status",
+    "mp-slug",
+    "mp-syndicate-to",
+];
+
+/// A request's properties, from either encoding, as lists of strings;
+/// `{"html": ...}` content is taken as its HTML, which Zola's markdown
+/// passes through.
+type Properties = HashMap<String, Vec<String>>;
+
+/// What's committed for a created entry.
+struct Entry {
+    name: Option<String>,
+    content: String,
+    summary: Option<String>,
+    tags: Vec<String>,
+    slug: Option<String>,
+    draft: bool,
+    syndicate_to: Vec<String>,
+}
+
+/// Micropub's error shape, which clients show to the user.
+fn error(status: u16, code: &str, description: &str) -> Result<Response> {
+    json_response(
+        &json!({ "error": code, "error_description": description }),
+        status,
+    )
+}
+
+/// The presented token, if IndieAuth issued it: otherwise the error response
+/// to send.
+async fn check_token(
+    env: &Env,
+    presented: Option<String>,
+) -> Result<std::result::Result<indieauth::Token, Response>> {
+    let Some(presented) = presented else {
+        return error(401, "unauthorized", "No access token").map(Err);
+    };
+    match indieauth::verify(env, &presented).await? {
+        Some(token) => Ok(Ok(token)),
+        None => error(403, "forbidden", "Unknown or revoked access token").map(Err),
+    }
+}
+
+fn fields(form: &FormData, name: &str) -> Vec<String> {
+    [name.to_string(), format!("{}[]", name)]
+        .iter()
+        .flat_map(|key| form.get_all(key).unwrap_or_default())
+        .filter_map(|entry| match entry {
+            FormEntry::Field(value) => Some(value),
+            FormEntry::File(_) => None,
+        })
+        .collect()
+}
+
+/// A form-encoded request's properties, after checking it's a create.
+fn form_properties(form: &FormData) -> std::result::Result<Properties, &'static str> {
+    if form.has("action") {
+        return Err("Only creating entries is supported");
+    }
+    if form.get_field("h").is_some_and(|h| h != "entry") {
+        return Err("Only h=entry is supported");
+    }
+    Ok(FORM_PROPERTIES
+        .iter()
+        .map(|name| (name.to_string(), fields(form, name)))
+        .collect())
+}
+
+/// A JSON request's properties, after checking it's a create.
+fn json_properties(body: &Value) -> std::result::Result<Properties, &'static str> {
+    if body.get("action").is_some() {
+        return Err("Only creating entries is supported");
+    }
+    if let Some(types) = body.get("type") {
+        if !types
+            .as_array()
+            .is_some_and(|t| t.iter().any(|t| t == "h-entry"))
+        {
+            return Err("Only h-entry is supported");
+        }
+    }
+    let Some(properties) = body.get("properties").and_then(Value::as_object) else {
+        return Err("Expected {\"type\": [\"h-entry\"], \"properties\": {...}}");
+    };
+    Ok(properties
+        .iter()
+        .map(|(name, values)| {
+            let values = values
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|value| match value {
+                    Value::String(s) => Some(s.clone()),
+                    Value::Object(o) => o
+                        .get("html")
+                        .or_else(|| o.get("value"))
+                        .and_then(Value::as_str)
+                        .map(String::from),
+                    _ => None,
+                })
+                .collect();
+            (name.clone(), values)
+        })
+        .collect())
+}
+
+fn entry(properties: &Properties) -> std::result::Result<Entry, &'static str> {
+    let first = |name: &str| {
+        properties
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let all = |name: &str| -> Vec<String> {
+        properties
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect()
+    };
+
+    let Some(content) = first("content") else {
+        return Err("content is required");
+    };
+    let slug = first("mp-slug");
+    if slug.as_deref().is_some_and(|s| !crate::is_valid_slug(s)) {
+        return Err("mp-slug may only have lowercase letters, digits and hyphens");
+    }
+    let mut tags: Vec<String> = Vec::new();
+    for tag in all("category") {
+        let tag = tag.to_lowercase();
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(Entry {
+        name: first("name"),
+        content,
+        summary: first("summary"),
+        tags,
+        slug,
+        draft: first("post-status").as_deref() == Some("draft"),
+        syndicate_to: all("mp-syndicate-to"),
+    })
+}
+
+/// A slug from the first words of `text`, ASCII only (Norwegian letters
+/// spelled out, other non-ASCII dropped); empty if nothing's left.
+fn slug_from(text: &str) -> String {
+    let words: Vec<&str> = text.split_whitespace().take(MAX_SLUG_WORDS).collect();
+    let text = words
+        .join(" ")
+        .to_lowercase()
+        .replace('æ', "ae")
+        .replace('ø', "o")
+        .replace('å', "a");
+    html::slugify(&text)
+        .split('-')
+        .map(|part| {
+            part.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+        })
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// A note's title: its first line, cut at a word boundary if long.
+fn note_title(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default().trim();
+    if line.chars().count() <= MAX_NOTE_TITLE_CHARS {
+        return line.to_string();
+    }
+    let mut title = String::new();
+    for word in line.split_whitespace() {
+        if title.chars().count() + word.chars().count() + 1 > MAX_NOTE_TITLE_CHARS {
+            break;
+        }
+        if !title.is_empty() {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    if title.is_empty() {
+        title = line.chars().take(MAX_NOTE_TITLE_CHARS).collect();
+    }
+    title.push('…');
+    title
+}
+
+/// `text` as a TOML basic string.
+fn toml_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `text` as a YAML (and JSON) double-quoted string.
+fn yaml_string(text: &str) -> String {
+    Value::from(text).to_string()
+}
+
+fn string_array(items: &[String], quote: fn(&str) -> String) -> String {
+    let items: Vec<String> = items.iter().map(|item| quote(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// The post's index.md, with Zola's TOML frontmatter.
+fn post_markdown(entry: &Entry, title: &str, published: u64) -> String {
+    let mut frontmatter = vec![
+        format!("title = {}", toml_string(title)),
+        format!("date = {}", dates::rfc3339(published)),
+    ];
+    if let Some(summary) = &entry.summary {
+        frontmatter.push(format!("description = {}", toml_string(summary)));
+    }
+    if entry.draft {
+        frontmatter.push("draft = true".into());
+    }
+    frontmatter.push("[taxonomies]".into());
+    frontmatter.push(format!("tags = {}", string_array(&entry.tags, toml_string)));
+    if entry.name.is_none() {
+        frontmatter.push("categories = [\"notes\"]".into());
+    }
+    format!(
+        "+++\n{}\n+++\n\n{}\n",
+        frontmatter.join("\n"),
+        entry.content
+    )
+}
+
+/// The newsletter issue, as `scripts/generate-newsletter.sh` would write it.
+fn issue_markdown(entry: &Entry, title: &str, published: u64, post_url: &str) -> String {
+    let mut frontmatter = vec![
+        format!("title: {}", yaml_string(title)),
+        format!("date: {}", yaml_string(&dates::iso_date(published))),
+    ];
+    if let Some(summary) = &entry.summary {
+        frontmatter.push(format!("description: {}", yaml_string(summary)));
+    }
+    frontmatter.push(format!("url: {}", yaml_string(post_url)));
+    if !entry.tags.is_empty() {
+        frontmatter.push(format!("tags: {}", string_array(&entry.tags, yaml_string)));
+    }
+    format!(
+        "---\n{}\n---\n\n{}\n",
+        frontmatter.join("\n"),
+        entry.content
+    )
+}
+
+fn syndication_targets() -> Value {
+    json!([{ "uid": NEWSLETTER_TARGET, "name": "Newsletter" }])
+}
+
+/// GET /api/micropub?q=config|syndicate-to — what clients ask before
+/// posting.
+pub async fn handle_query(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    if let Err(rejected) = check_token(&ctx.env, indieauth::bearer_token(&req)?).await? {
+        return Ok(rejected);
+    }
+    let params: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+    match params.get("q").map(String::as_str) {
+        Some("config" | "syndicate-to") => {
+            json_response(&json!({ "syndicate-to": syndication_targets() }), 200)
+        }
+        _ => error(
+            400,
+            "invalid_request",
+            "Supported queries: config, syndicate-to",
+        ),
+    }
+}
+
+/// POST /api/micropub — create an entry, form-encoded or JSON. 202 with
+/// the post's URL as Location: it's live once the site has been built.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    let (properties, presented) = if content_type.starts_with("application/json") {
+        let Ok(body) = req.json::<Value>().await else {
+            return error(400, "invalid_request", "Malformed JSON body");
+        };
+        (json_properties(&body), indieauth::bearer_token(&req)?)
+    } else {
+        let Ok(form) = req.form_data().await else {
+            return error(400, "invalid_request", "Malformed form body");
+        };
+        let presented = indieauth::bearer_token(&req)?.or_else(|| form.get_field("access_token"));
+        (form_properties(&form), presented)
+    };
+    let token = match check_token(&ctx.env, presented).await? {
+        Ok(token) => token,
+        Err(rejected) => return Ok(rejected),
+    };
+    let mut entry = match properties.as_ref().map_err(|e| *e).and_then(entry) {
+        Ok(entry) => entry,
+        Err(message) => return error(400, "invalid_request", message),
+    };
+    if !token.has_scope("create") {
+        if !token.has_scope("draft") {
+            return error(403, "insufficient_scope", "The token can't create posts");
+        }
+        entry.draft = true;
+    }
+    let Some(repo) = Repo::from_env(&ctx.env) else {
+        return error(503, "invalid_request", "Publishing isn't configured");
+    };
+
+    let published = sendlog::now_millis();
+    let title = entry
+        .name
+        .clone()
+        .unwrap_or_else(|| note_title(&entry.content));
+    let derived_slug = slug_from(entry.name.as_deref().unwrap_or(&entry.content));
+    let slug = match &entry.slug {
+        Some(slug) => slug.clone(),
+        None if derived_slug.is_empty() => format!("note-{}", published / 1000),
+        None => derived_slug,
+    };
+    let path = format!("content/blog/{}/index.md", slug);
+    if repo.exists(&ctx.data, &path).await? {
+        return error(
+            400,
+            "invalid_request",
+            &format!("There's already a post at {}; choose another mp-slug", slug),
+        );
+    }
+
+    let issue_path = (!entry.draft && entry.syndicate_to.iter().any(|t| t == NEWSLETTER_TARGET))
+        .then(|| format!("static/newsletter/{}.md", slug));
+    if let Some(issue_path) = &issue_path {
+        // Committing over an issue would rewrite one that may already be sent
+        if repo.exists(&ctx.data, issue_path).await? {
+            return error(
+                409,
+                "invalid_request",
+                &format!(
+                    "There's already a newsletter issue {}; choose another mp-slug",
+                    slug
+                ),
+            );
+        }
+    }
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let post_url = format!("{}/blog/{}/", site_url, slug);
+    let mut files = vec![(path, post_markdown(&entry, &title, published))];
+    if let Some(issue_path) = issue_path {
+        files.push((
+            issue_path,
+            issue_markdown(&entry, &title, published, &post_url),
+        ));
+    }
+    let kind = if entry.name.is_some() { "post" } else { "note" };
+    repo.commit(
+        &ctx.data,
+        &format!("Add {} {} via Micropub", kind, slug),
+        &files,
+    )
+    .await?;
+
+    let mut resp = Response::empty()?.with_status(202);
+    resp.headers_mut().set("Location", &post_url)?;
+    Ok(resp)
+}
@@ -0,0 +1,365 @@
+//! The layer around the router: every request is logged, answered with the
+//! same CORS headers, has its body checked, and gets a JSON error instead of a
+//! bare 500 if its handler fails. Unknown paths and methods get JSON 404s and
+//! 405s, except non-API paths when there's a [`Routes::fallback`]. Admin routes
+//! are wrapped in [`admin`] where they're registered.
+
+use std::future::Future;
+
+use serde_json::Value;
+use worker::{Env, Headers, Method, Request, Response, Result, RouteContext, Router};
+
+use crate::logging::RequestLog;
+use crate::{audit, auth, json_response, ApiResponse};
+
+/// Used when ALLOWED_ORIGINS is unset, and for disallowed origins.
+const DEFAULT_ALLOWED_ORIGIN: &str = "https://lindfors.no";
+
+/// Default for MAX_BODY_BYTES. Every JSON body the API takes is tiny.
+const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024;
+
+fn max_body_bytes(env: &Env) -> usize {
+    env.var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+/// What [`check_body`] lets through to a route.
+#[derive(Clone, Copy)]
+enum Body {
+    /// A JSON object (or a form) of at most MAX_BODY_BYTES.
+    Json,
+    /// Any content type, declared at most this many bytes long. The handler
+    /// reads the body itself and bounds what it actually gets.
+    Raw(usize),
+}
+
+/// A [`Router`] that also remembers which methods each pattern accepts, and
+/// what bodies, so unmatched requests can be answered before reaching it.
+pub struct Routes<'a> {
+    router: Router<'a, RequestLog>,
+    table: Vec<(String, Method, Body)>,
+    fallback: bool,
+}
+
+impl<'a> Routes<'a> {
+    pub fn new(log: RequestLog) -> Self {
+        Routes {
+            router: Router::with_data(log),
+            table: Vec::new(),
+            fallback: false,
+        }
+    }
+
+    pub fn get<T>(mut self, pattern: &str, func: fn(Request, RouteContext<RequestLog>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push((pattern.to_string(), Method::Get, Body::Json));
+        self.router = self.router.get_async(pattern, func);
+        self
+    }
+
+    pub fn post<T>(mut self, pattern: &str, func: fn(Request, RouteContext<RequestLog>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push((pattern.to_string(), Method::Post, Body::Json));
+        self.router = self.router.post_async(pattern, func);
+        self
+    }
+
+    pub fn put<T>(mut self, pattern: &str, func: fn(Request, RouteContext<RequestLog>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push((pattern.to_string(), Method::Put, Body::Json));
+        self.router = self.router.put_async(pattern, func);
+        self
+    }
+
+    pub fn delete<T>(
+        mut self,
+        pattern: &str,
+        func: fn(Request, RouteContext<RequestLog>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.table.push((pattern.to_string(), Method::Delete, Body::Json));
+        self.router = self.router.delete_async(pattern, func);
+        self
+    }
+
+    /// [`Routes::post`] for a handler that reads a raw body of up to
+    /// `max_bytes` itself, such as a webhook checking a signature over it.
+    pub fn post_raw<T>(
+        self,
+        pattern: &str,
+        max_bytes: usize,
+        func: fn(Request, RouteContext<RequestLog>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.post(pattern, func).raw(max_bytes)
+    }
+
+    /// [`Routes::put`] for a handler that reads a raw body of up to
+    /// `max_bytes` itself, such as a file upload.
+    pub fn put_raw<T>(
+        self,
+        pattern: &str,
+        max_bytes: usize,
+        func: fn(Request, RouteContext<RequestLog>) -> T,
+    ) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.put(pattern, func).raw(max_bytes)
+    }
+
+    /// Mark the route registered last as taking raw bodies.
+    fn raw(mut self, max_bytes: usize) -> Self {
+        if let Some((_, _, body)) = self.table.last_mut() {
+            *body = Body::Raw(max_bytes);
+        }
+        self
+    }
+
+    /// Handle requests outside /api/ that match no pattern with `func`,
+    /// whatever their method, rather than answering 404.
+    pub fn fallback<T>(mut self, func: fn(Request, RouteContext<RequestLog>) -> T) -> Self
+    where
+        T: Future<Output = Result<Response>> + 'a,
+    {
+        self.fallback = true;
+        self.router = self.router.on_async("/*path", func);
+        self
+    }
+
+    /// Whether an unmatched request goes to the fallback.
+    fn falls_back(&self, path: &str) -> bool {
+        self.fallback && path != "/api" && !path.starts_with("/api/")
+    }
+
+    /// Methods registered for the pattern matching `path`, if any does.
+    fn allowed_methods(&self, path: &str) -> Option<Vec<Method>> {
+        let methods: Vec<Method> = self
+            .table
+            .iter()
+            .filter(|(pattern, _, _)| pattern_matches(pattern, path))
+            .map(|(_, method, _)| method.clone())
+            .collect();
+        (!methods.is_empty()).then_some(methods)
+    }
+
+    /// The bodies the route for `method` on `path` takes.
+    fn body(&self, method: &Method, path: &str) -> Body {
+        self.table
+            .iter()
+            .find(|(pattern, m, _)| m == method && pattern_matches(pattern, path))
+            .map_or(Body::Json, |(_, _, body)| *body)
+    }
+}
+
+/// Whether `path` matches a router pattern, where `:name` stands for one
+/// segment and `*name` for the rest of the path.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+        if expected.starts_with('*') {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if expected.starts_with(':') => {
+                if segment.is_empty() {
+                    return false;
+                }
+            }
+            Some(segment) if segment == expected => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Route `req` with the checks above applied.
+pub async fn run(req: Request, env: Env, log: RequestLog, routes: Routes<'_>) -> Result<Response> {
+    let method = req.method().to_string();
+    let path = req.path();
+    let cors = cors_headers(&req, &env)?;
+
+    let allowed = routes.allowed_methods(&path);
+    let result = match allowed {
+        None if routes.falls_back(&path) => routes.router.run(req, env).await,
+        None => reject(404, format!("No such endpoint: {}", path)),
+        Some(_) if req.method() == Method::Options => preflight(),
+        Some(methods) if !methods.contains(&req.method()) => method_not_allowed(&methods),
+        Some(_) => match check_body(&req, &env, routes.body(&req.method(), &path)).await {
+            Ok(Some(rejected)) => Ok(rejected),
+            Ok(None) => routes.router.run(req, env).await,
+            Err(e) => Err(e),
+        },
+    };
+
+    let (mut resp, error) = match result {
+        Ok(resp) => (resp, None),
+        Err(e) => {
+            let message = format!("Internal error (request {})", log.id);
+            (reject(500, message)?, Some(e.to_string()))
+        }
+    };
+    for (key, val) in cors.entries() {
+        // Added to, as handlers may vary on other headers too
+        if key == "vary" {
+            resp.headers_mut().append(&key, &val)?;
+        } else {
+            resp.headers_mut().set(&key, &val)?;
+        }
+    }
+    log.finish(&method, &path, resp, error.as_deref())
+}
+
+/// Run `handler` only if the request is an authenticated admin request
+/// (see [`auth::require_admin`]), and record it in the audit log unless it's
+/// a read.
+pub async fn admin<F, Fut>(req: Request, ctx: RouteContext<RequestLog>, handler: F) -> Result<Response>
+where
+    F: FnOnce(Request, RouteContext<RequestLog>) -> Fut,
+    Fut: Future<Output = Result<Response>>,
+{
+    if let Some(unauthorized) = auth::require_admin(&req, &ctx.env).await? {
+        return Ok(unauthorized);
+    }
+
+    let Some(pending) = audit::Pending::capture(&req).await? else {
+        return handler(req, ctx).await;
+    };
+    let env = ctx.env.clone();
+    let request_id = ctx.data.id.clone();
+    let result = handler(req, ctx).await;
+    let status = result.as_ref().map_or(500, |resp| resp.status_code());
+    pending.record(&env, &request_id, status).await;
+    result
+}
+
+/// Origins allowed to call the API from a browser (ALLOWED_ORIGINS,
+/// comma-separated, matched exactly).
+fn allowed_origins(env: &Env) -> Vec<String> {
+    env.var("ALLOWED_ORIGINS")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| DEFAULT_ALLOWED_ORIGIN.to_string())
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect()
+}
+
+fn cors_headers(req: &Request, env: &Env) -> Result<Headers> {
+    let origin = req.headers().get("Origin")?.unwrap_or_default();
+    let origins = allowed_origins(env);
+    // Disallowed origins get the site's own, which the browser then rejects
+    let allowed = match origins.iter().find(|o| **o == origin) {
+        Some(o) => o.clone(),
+        None => origins
+            .first()
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_ALLOWED_ORIGIN.to_string()),
+    };
+
+    let headers = Headers::new();
+    headers.set("Access-Control-Allow-Origin", &allowed)?;
+    headers.set(
+        "Access-Control-Allow-Methods",
+        "POST, GET, PUT, DELETE, OPTIONS",
+    )?;
+    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization")?;
+    headers.set(
+        "Access-Control-Expose-Headers",
+        "X-Request-Id, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, Retry-After",
+    )?;
+    headers.set("Vary", "Origin")?;
+    Ok(headers)
+}
+
+fn preflight() -> Result<Response> {
+    let mut resp = Response::empty()?.with_status(204);
+    resp.headers_mut().set("Access-Control-Max-Age", "86400")?;
+    Ok(resp)
+}
+
+fn method_not_allowed(methods: &[Method]) -> Result<Response> {
+    let mut allow: Vec<String> = methods.iter().map(|m| m.to_string()).collect();
+    allow.push("OPTIONS".into());
+    let mut resp = reject(405, "Method not allowed".into())?;
+    resp.headers_mut().set("Allow", &allow.join(", "))?;
+    Ok(resp)
+}
+
+fn reject(status: u16, error: String) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(error),
+        },
+        status,
+    )
+}
+
+/// Bound and validate request bodies: at most MAX_BODY_BYTES (413), JSON only
+/// (415; ActivityPub's JSON-LD types count, and form-encoded bodies pass for
+/// Micropub clients), and a JSON object (400). Bodyless requests pass. Raw
+/// routes only have their declared length checked, against their own limit.
+/// Returns the response to send instead of running the handler, if any.
+async fn check_body(req: &Request, env: &Env, policy: Body) -> Result<Option<Response>> {
+    if !matches!(req.method(), Method::Post | Method::Put | Method::Patch) {
+        return Ok(None);
+    }
+
+    let limit = match policy {
+        Body::Json => max_body_bytes(env),
+        Body::Raw(max_bytes) => max_bytes,
+    };
+    let too_large = || format!("Request body too large (max {} bytes)", limit);
+    let declared = req
+        .headers()
+        .get("Content-Length")?
+        .and_then(|len| len.trim().parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return reject(413, too_large()).map(Some);
+    }
+    // Not read here: it may be large, and isn't ours to judge
+    if let Body::Raw(_) = policy {
+        return Ok(None);
+    }
+
+    // Content-Length may be absent (chunked) or wrong, so measure too. The
+    // clone leaves the body for the handler.
+    let body = req.clone()?.bytes().await?;
+    if body.len() > limit {
+        return reject(413, too_large()).map(Some);
+    }
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    let json_types = [
+        "application/json",
+        "application/activity+json",
+        "application/ld+json",
+    ];
+    if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return Ok(None);
+    }
+    if !json_types.iter().any(|t| media_type.eq_ignore_ascii_case(t)) {
+        return reject(415, "Content-Type must be application/json".into()).map(Some);
+    }
+    if !matches!(serde_json::from_slice(&body), Ok(Value::Object(_))) {
+        return reject(400, "Request body must be a JSON object".into()).map(Some);
+    }
+    Ok(None)
+}
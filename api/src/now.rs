@@ -0,0 +1,98 @@
+//! The /now page's status — what I'm doing, reading and listening to — kept
+//! as one small JSON document under `now` in the NEWSLETTER KV namespace, so
+//! a quick `scripts/update-now.sh` replaces it without rebuilding the site.
+
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{json_response, sendlog, ApiResponse};
+
+const NOW_KEY: &str = "now";
+
+const MAX_STATUS_CHARS: usize = 280;
+const MAX_ITEM_CHARS: usize = 200;
+
+#[derive(Deserialize)]
+struct NowRequest {
+    status: String,
+    #[serde(default)]
+    reading: Option<String>,
+    #[serde(default)]
+    listening: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Now {
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reading: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listening: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    updated_at: u64,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// Trimmed, with blank as unset.
+fn item(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// GET /api/now — the current status.
+pub async fn handle_get(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let Some(now) = kv.get(NOW_KEY).json::<Now>().await? else {
+        return error(404, "No status set yet");
+    };
+    let mut resp = json_response(&now, 200)?;
+    resp.headers_mut()
+        .set("Cache-Control", "public, max-age=60")?;
+    Ok(resp)
+}
+
+/// PUT /api/now — admin: replace the status:
+/// `{"status", "reading"?, "listening"?}`.
+pub async fn handle_put(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(body) = req.json::<NowRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"status\": \"...\", \"reading\"?, \"listening\"?}",
+        );
+    };
+    let status = body.status.trim();
+    if status.is_empty() || status.chars().count() > MAX_STATUS_CHARS {
+        return error(400, "status must be 1–280 characters");
+    }
+    let (reading, listening) = (item(body.reading), item(body.listening));
+    if [&reading, &listening].iter().any(|v| {
+        v.as_ref()
+            .is_some_and(|v| v.chars().count() > MAX_ITEM_CHARS)
+    }) {
+        return error(400, "reading and listening can be at most 200 characters");
+    }
+
+    let now = Now {
+        status: status.to_string(),
+        reading,
+        listening,
+        updated_at: sendlog::now_millis(),
+    };
+    ctx.env
+        .kv("NEWSLETTER")?
+        .put(NOW_KEY, &now)?
+        .execute()
+        .await?;
+    json_response(&now, 200)
+}
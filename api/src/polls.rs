@@ -0,0 +1,426 @@
+//! Reader polls in issues. A `poll:` frontmatter block renders as answer
+//! buttons at the end of the email:
+//!
+//! ```yaml
+//! poll:
+//!   id: next-topic          # optional, defaults to the issue's slug
+//!   question: What should I write about next?
+//!   options: [Embedded Rust, WebAssembly, Databases]
+//! ```
+//!
+//! Each button links to GET /api/poll/{id}/vote/{n} (options count from 1),
+//! a page that casts the vote with a POST once it loads, so link scanners in
+//! mail filters don't vote. Sending registers the poll as `poll:{id}` in the
+//! NEWSLETTER KV namespace, which also holds the counts.
+//!
+//! One vote counts per recipient. An issue with a poll goes to each subscriber
+//! on their own, its links carrying a token signed for them:
+//!
+//! ```text
+//! ?token=VOTER-hex(HMAC-SHA256(POLL_SIGNING_KEY, ID \n VOTER))[..32]
+//! VOTER = hex(HMAC-SHA256(POLL_SIGNING_KEY, "voter" \n EMAIL))[..16]
+//! ```
+//!
+//! so a token names the recipient without giving their address away, and
+//! can't be made up. Votes without a valid token (from the archive copy, or
+//! forged) aren't counted; the page shows the results anyway.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::frontmatter::Poll;
+use crate::logging::RequestLog;
+use crate::{auth, dates, html, is_valid_slug, json_response, ApiResponse};
+
+const SANS: &str = "-apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif";
+
+/// Long enough to outlast any poll's voting.
+const DEDUP_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+const MAX_OPTIONS: usize = 10;
+const MAX_TOKEN_CHARS: usize = 64;
+
+/// Stands in for the token in a rendered poll's links, until the send signs
+/// them for each recipient ([`sign_links`]) or leaves them out ([`unsigned`]).
+const TOKEN_PLACEHOLDER: &str = "?token=__poll_token__";
+
+#[derive(Serialize, Deserialize)]
+struct PollRecord {
+    question: String,
+    options: Vec<String>,
+    /// Votes per option, in `options` order.
+    votes: Vec<u64>,
+    /// Milliseconds since the Unix epoch of the first send.
+    created_at: u64,
+}
+
+#[derive(Deserialize, Default)]
+struct VoteRequest {
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OptionResult<'a> {
+    option: usize,
+    label: &'a str,
+    votes: u64,
+}
+
+#[derive(Serialize)]
+struct PollResults<'a> {
+    id: &'a str,
+    question: &'a str,
+    total: u64,
+    results: Vec<OptionResult<'a>>,
+    created_at: String,
+}
+
+impl PollRecord {
+    fn results<'a>(&'a self, id: &'a str) -> PollResults<'a> {
+        PollResults {
+            id,
+            question: &self.question,
+            total: self.votes.iter().sum(),
+            results: self
+                .options
+                .iter()
+                .zip(&self.votes)
+                .enumerate()
+                .map(|(i, (label, &votes))| OptionResult {
+                    option: i + 1,
+                    label,
+                    votes,
+                })
+                .collect(),
+            created_at: dates::rfc3339(self.created_at),
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn key(id: &str) -> String {
+    format!("poll:{}", id)
+}
+
+async fn load(env: &Env, id: &str) -> Result<Option<PollRecord>> {
+    let kv = env.kv("NEWSLETTER")?;
+    Ok(kv.get(&key(id)).json().await?)
+}
+
+/// The poll's id for an issue, or why the block can't be used.
+pub fn poll_id(poll: &Poll, slug: &str) -> std::result::Result<String, String> {
+    let id = poll.id.clone().unwrap_or_else(|| slug.to_string());
+    if !is_valid_slug(&id) {
+        return Err(format!("Poll id {} isn't a valid slug; poll left out", id));
+    }
+    if poll.options.len() < 2 || poll.options.len() > MAX_OPTIONS {
+        return Err(format!(
+            "Poll {} needs 2–{} options; poll left out",
+            id, MAX_OPTIONS
+        ));
+    }
+    Ok(id)
+}
+
+/// Email HTML for the poll: the question, then one button per option.
+pub fn render_block(poll: &Poll, id: &str, site_url: &str) -> String {
+    let buttons: String = poll
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            format!(
+                "<tr><td style=\"padding: 4px 0; border: none;\"><a href=\"{site_url}/api/poll/{id}/vote/{n}{TOKEN_PLACEHOLDER}\" \
+                 class=\"email-button\" style=\"display: block; padding: 10px 16px; \
+                 background-color: #D4706A; color: #ffffff; border-radius: 6px; \
+                 font-family: {SANS}; font-size: 15px; font-weight: 600; \
+                 text-decoration: none; text-align: center;\">{label}</a></td></tr>",
+                site_url = html::escape(site_url),
+                id = id,
+                n = i + 1,
+                label = html::escape(label),
+            )
+        })
+        .collect();
+    format!(
+        "<div class=\"poll\" style=\"margin: 32px 0;\">\
+         <p style=\"font-family: {SANS}; font-size: 17px; font-weight: 600;\">{}</p>\
+         <table role=\"presentation\" width=\"100%\" cellpadding=\"0\" cellspacing=\"0\">{}</table>\
+         </div>\n",
+        html::escape(&poll.question),
+        buttons
+    )
+}
+
+/// Whether rendered issue HTML has poll links to sign.
+pub fn has_links(html: &str) -> bool {
+    html.contains(TOKEN_PLACEHOLDER)
+}
+
+/// Rendered issue HTML with the poll links' tokens left out, for copies that
+/// aren't any one recipient's.
+pub fn unsigned(html: &str) -> String {
+    html.replace(TOKEN_PLACEHOLDER, "")
+}
+
+/// Rendered issue HTML with poll `id`'s links signed for `recipient`, or
+/// `None` if `key` can't be used.
+pub fn sign_links(html: &str, key: &str, id: &str, recipient: &str) -> Option<String> {
+    let voter = hmac_hex(key, &format!("voter\n{}", recipient.to_lowercase()), 8)?;
+    let sig = hmac_hex(key, &format!("{id}\n{voter}"), 16)?;
+    Some(html.replace(TOKEN_PLACEHOLDER, &format!("?token={voter}-{sig}")))
+}
+
+/// The voter `token` was signed for in poll `id`, if it was.
+fn verified_voter<'a>(key: &str, id: &str, token: &'a str) -> Option<&'a str> {
+    let (voter, sig) = token.split_once('-')?;
+    let expected = hmac_hex(key, &format!("{id}\n{voter}"), 16)?;
+    auth::constant_time_eq(expected.as_bytes(), sig.as_bytes()).then_some(voter)
+}
+
+/// Hex of the first `bytes` of HMAC-SHA256(`key`, `message`).
+fn hmac_hex(key: &str, message: &str, bytes: usize) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(message.as_bytes());
+    Some(
+        mac.finalize().into_bytes()[..bytes]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+    )
+}
+
+/// Record the poll at send time. A resend keeps the votes; options added or
+/// removed since start or stop counting.
+pub async fn register(env: &Env, id: &str, poll: &Poll) -> Result<()> {
+    let kv = env.kv("NEWSLETTER")?;
+    let record = match load(env, id).await? {
+        Some(mut existing) => {
+            existing.question = poll.question.clone();
+            existing.options = poll.options.clone();
+            existing.votes.resize(poll.options.len(), 0);
+            existing
+        }
+        None => PollRecord {
+            question: poll.question.clone(),
+            options: poll.options.clone(),
+            votes: vec![0; poll.options.len()],
+            created_at: worker::Date::now().as_millis(),
+        },
+    };
+    kv.put(&key(id), &record)?.execute().await?;
+    Ok(())
+}
+
+/// The route's poll id and 0-based option, if both exist.
+async fn route_poll(ctx: &RouteContext<RequestLog>) -> Result<Option<(String, usize, PollRecord)>> {
+    let id = ctx.param("id").cloned().unwrap_or_default();
+    let option = ctx.param("option").and_then(|o| o.parse::<usize>().ok());
+    if !is_valid_slug(&id) {
+        return Ok(None);
+    }
+    let Some(record) = load(&ctx.env, &id).await? else {
+        return Ok(None);
+    };
+    match option {
+        Some(n) if n >= 1 && n <= record.options.len() => Ok(Some((id, n - 1, record))),
+        _ => Ok(None),
+    }
+}
+
+fn valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_TOKEN_CHARS
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// GET /api/poll/{id}/vote/{n} — the page an answer button opens; it casts
+/// the vote and shows the results.
+pub async fn handle_vote_page(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Some((_, option, record)) = route_poll(&ctx).await? else {
+        return error(404, "No such poll or option");
+    };
+    let token = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .filter(|t| valid_token(t));
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let mut resp = Response::from_html(vote_page(&site_url, option, &record, token))?;
+    resp.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(resp)
+}
+
+/// POST /api/poll/{id}/vote/{n} — cast a vote: `{"token"?}`. Answers with the
+/// results and whether this vote counted, which without a token it doesn't.
+pub async fn handle_vote(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Some((id, option, mut record)) = route_poll(&ctx).await? else {
+        return error(404, "No such poll or option");
+    };
+    let body: VoteRequest = req.json().await.unwrap_or_default();
+    let Ok(signing_key) = ctx.env.secret("POLL_SIGNING_KEY") else {
+        return error(503, "Polls aren't configured");
+    };
+    let voter = match body.token.as_deref() {
+        Some(token) => match verified_voter(&signing_key.to_string(), &id, token) {
+            Some(voter) => Some(voter.to_string()),
+            None => return error(403, "Invalid token"),
+        },
+        None => None,
+    };
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let mut counted = false;
+    if let Some(voter) = voter {
+        let marker = format!("voted:{}:{}", id, voter);
+        counted = kv.get(&marker).text().await?.is_none();
+        if counted {
+            record.votes[option] += 1;
+            kv.put(&key(&id), &record)?.execute().await?;
+            kv.put(&marker, option.to_string())?
+                .expiration_ttl(DEDUP_TTL_SECS)
+                .execute()
+                .await?;
+        }
+    }
+
+    #[derive(Serialize)]
+    struct VoteResponse<'a> {
+        counted: bool,
+        #[serde(flatten)]
+        poll: PollResults<'a>,
+    }
+
+    json_response(
+        &VoteResponse {
+            counted,
+            poll: record.results(&id),
+        },
+        200,
+    )
+}
+
+/// GET /api/admin/polls — admin: every poll's results, newest first.
+pub async fn handle_results(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let mut polls = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix("poll:".into());
+        if let Some(c) = cursor {
+            list = list.cursor(c);
+        }
+        let page = list.execute().await?;
+        for listed in page.keys {
+            let id = listed.name.trim_start_matches("poll:").to_string();
+            if let Some(record) = load(&ctx.env, &id).await? {
+                polls.push((id, record));
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    polls.sort_by_key(|(_, record)| std::cmp::Reverse(record.created_at));
+
+    #[derive(Serialize)]
+    struct ResultsResponse<'a> {
+        polls: Vec<PollResults<'a>>,
+    }
+
+    let polls = polls
+        .iter()
+        .map(|(id, record)| record.results(id))
+        .collect();
+    json_response(&ResultsResponse { polls }, 200)
+}
+
+fn vote_page(site_url: &str, option: usize, record: &PollRecord, token: Option<String>) -> String {
+    let (body, not_counted) = match token {
+        Some(token) => (
+            format!("{{\"token\": \"{}\"}}", token),
+            "'You already voted in this poll.'",
+        ),
+        None => (
+            "{}".into(),
+            "'Only votes from the links in the email count. The results so far:'",
+        ),
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="robots" content="noindex">
+    <title>Poll - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 480px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        p {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+        button {{ padding: 10px 18px; background: #D4706A; color: #F0EAE0; border: none; border-radius: 6px; font-size: 14px; font-weight: 600; cursor: pointer; font-family: -apple-system, sans-serif; }}
+        .result {{ font-family: -apple-system, sans-serif; font-size: 14px; margin: 12px 0; }}
+        .bar {{ height: 8px; background: #D4706A; border-radius: 4px; margin-top: 4px; }}
+        .mine {{ font-weight: 600; }}
+    </style>
+</head>
+<body>
+    <h1>{question}</h1>
+    <p id="status">Your answer: <strong>{answer}</strong>. Counting your vote&hellip;</p>
+    <noscript><p>Voting needs JavaScript, sorry.</p></noscript>
+    <div id="results"></div>
+    <p style="margin-top: 32px;"><a href="{site_url}">Back to lindfors.no</a></p>
+    <script>
+    (function() {{
+        var status = document.getElementById('status');
+        fetch(location.pathname, {{
+            method: 'POST',
+            headers: {{ 'Content-Type': 'application/json' }},
+            body: '{body}'
+        }}).then(function(r) {{ return r.json(); }}).then(function(data) {{
+            if (!data.results) throw new Error(data.error);
+            status.textContent = data.counted ? 'Thanks, your vote is in!' : {not_counted};
+            var results = document.getElementById('results');
+            data.results.forEach(function(r) {{
+                var pct = data.total ? Math.round(100 * r.votes / data.total) : 0;
+                var row = document.createElement('div');
+                row.className = 'result' + (r.option === {option} ? ' mine' : '');
+                row.textContent = r.label + ' — ' + pct + '% (' + r.votes + ')';
+                var bar = document.createElement('div');
+                bar.className = 'bar';
+                bar.style.width = Math.max(pct, 1) + '%';
+                row.appendChild(bar);
+                results.appendChild(row);
+            }});
+        }}).catch(function() {{
+            status.textContent = 'Something went wrong counting your vote. Please try again.';
+        }});
+    }})();
+    </script>
+</body>
+</html>"#,
+        question = html::escape(&record.question),
+        answer = html::escape(&record.options[option]),
+        site_url = html::escape(site_url),
+        body = body,
+        not_counted = not_counted,
+        option = option + 1,
+    )
+}
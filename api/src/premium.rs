@@ -0,0 +1,320 @@
+//! Supporter-only posts. Each supporter gets a token when their Stripe
+//! subscription starts (see supporters.rs), emailed to them as a link to
+//! GET /api/premium, which lists the posts; the token opens them at
+//! GET /api/premium/{slug}. The first visit swaps the link's `?token=` for
+//! a cookie, so the token isn't left in the address bar, and the pages send
+//! no Referer to the sites they link to.
+//!
+//! Posts are newsletter-style markdown kept out of the public site, under
+//! `premium:post:{slug}` in the NEWSLETTER KV namespace, with the title as
+//! the key's metadata (`scripts/upload-premium.sh` puts them there). Tokens
+//! are stored hashed under `premium:token:`; one only works while its
+//! supporter is still a supporter and hasn't been issued a newer one, so a
+//! lapsed subscription revokes it.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{Env, Error, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::supporters::{self, Supporter};
+use crate::{
+    email, frontmatter, html, json_response, markdown, sendlog, shortcodes, ApiResponse,
+    RenderConfig,
+};
+
+const POST_PREFIX: &str = "premium:post:";
+
+/// Holds the token once a link has been opened. `__Host-` keeps it to this
+/// host, over https.
+const COOKIE: &str = "__Host-premium_token";
+
+/// The token is revoked server-side, so the cookie can outlast it.
+const COOKIE_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    customer: String,
+    /// Milliseconds since the Unix epoch.
+    issued_at: u64,
+}
+
+#[derive(Deserialize)]
+struct PostMetadata {
+    title: String,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn token_key(hash: &str) -> String {
+    format!("premium:token:{}", hash)
+}
+
+/// A new token for `customer`: the token, to give them, and its hash, to
+/// keep in their supporter record.
+pub async fn issue_token(env: &Env, customer: &str) -> Result<(String, String)> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    let hash = token_hash(&token);
+    let stored = StoredToken {
+        customer: customer.to_string(),
+        issued_at: sendlog::now_millis(),
+    };
+    env.kv("NEWSLETTER")?
+        .put(&token_key(&hash), &stored)?
+        .execute()
+        .await?;
+    Ok((token, hash))
+}
+
+/// Whether `token` belongs to a current supporter. A stale token's record
+/// is deleted on the way.
+async fn is_valid(env: &Env, token: &str) -> Result<bool> {
+    let kv = env.kv("NEWSLETTER")?;
+    let hash = token_hash(token);
+    let Some(stored) = kv.get(&token_key(&hash)).json::<StoredToken>().await? else {
+        return Ok(false);
+    };
+    let current = kv
+        .get(&supporters::supporter_key(&stored.customer))
+        .json::<Supporter>()
+        .await?
+        .is_some_and(|s| s.token_hash.as_deref() == Some(hash.as_str()));
+    if !current {
+        kv.delete(&token_key(&hash)).await?;
+    }
+    Ok(current)
+}
+
+fn cookie_token(req: &Request) -> Option<String> {
+    let cookies = req.headers().get("Cookie").ok().flatten()?;
+    cookies.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        (name == COOKIE).then(|| value.to_string())
+    })
+}
+
+/// Whether the request's token, from `?token=` or the cookie, opens
+/// supporter-only posts: `Err` is the response to send instead. That's an
+/// error, or for a good `?token=`, a redirect to the same page without it
+/// that sets the cookie.
+async fn check_token(req: &Request, env: &Env) -> Result<std::result::Result<(), Response>> {
+    let mut url = req.url()?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let from_query = params.get("token").filter(|t| !t.is_empty()).cloned();
+    let Some(token) = from_query.clone().or_else(|| cookie_token(req)) else {
+        return error(401, "A supporter token is required").map(Err);
+    };
+    if !is_valid(env, &token).await? {
+        return error(403, "This supporter token is no longer valid").map(Err);
+    }
+    if from_query.is_none() {
+        return Ok(Ok(()));
+    }
+
+    let rest: Vec<(String, String)> = params.into_iter().filter(|(k, _)| k != "token").collect();
+    url.set_query(None);
+    if !rest.is_empty() {
+        url.query_pairs_mut().extend_pairs(rest);
+    }
+    let mut resp = Response::empty()?.with_status(303);
+    let headers = resp.headers_mut();
+    headers.set("Location", url.as_str())?;
+    headers.set(
+        "Set-Cookie",
+        &format!(
+            "{COOKIE}={token}; Max-Age={COOKIE_MAX_AGE_SECS}; Path=/; Secure; HttpOnly; SameSite=Lax"
+        ),
+    )?;
+    headers.set("Cache-Control", "private, no-store")?;
+    headers.set("Referrer-Policy", "no-referrer")?;
+    Ok(Err(resp))
+}
+
+fn private_html(page: String) -> Result<Response> {
+    let mut resp = Response::from_html(page)?;
+    resp.headers_mut()
+        .set("Cache-Control", "private, no-store")?;
+    resp.headers_mut().set("X-Robots-Tag", "noindex")?;
+    // Pages link off-site, and a Referer could carry a ?token= along
+    resp.headers_mut().set("Referrer-Policy", "no-referrer")?;
+    Ok(resp)
+}
+
+/// GET /api/premium?token=... — the supporter-only posts.
+pub async fn handle_index(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    if let Err(rejected) = check_token(&req, &ctx.env).await? {
+        return Ok(rejected);
+    }
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let mut posts = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(POST_PREFIX.into());
+        if let Some(c) = cursor {
+            list = list.cursor(c);
+        }
+        let page = list.execute().await?;
+        for listed in page.keys {
+            let slug = listed.name.trim_start_matches(POST_PREFIX).to_string();
+            let title = listed
+                .metadata
+                .and_then(|m| serde_json::from_value::<PostMetadata>(m).ok())
+                .map(|m| m.title)
+                .unwrap_or_else(|| slug.clone());
+            posts.push((slug, title));
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    private_html(index_page(&site_url, &posts))
+}
+
+/// GET /api/premium/{slug}?token=... — a supporter-only post, rendered in
+/// full as a web page: none of the email's view-in-browser link, tracking
+/// or unsubscribe footer.
+pub async fn handle_post(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !crate::is_valid_slug(&slug) {
+        return error(404, "No such post");
+    }
+    if let Err(rejected) = check_token(&req, &ctx.env).await? {
+        return Ok(rejected);
+    }
+
+    let key = format!("{}{}", POST_PREFIX, slug);
+    let Some(source) = ctx.env.kv("NEWSLETTER")?.get(&key).text().await? else {
+        return error(404, "No such post");
+    };
+    let (meta, md_body) = match frontmatter::parse(&source) {
+        Ok(parsed) => parsed,
+        Err(e) => return error(500, &format!("Invalid frontmatter in {}: {}", slug, e)),
+    };
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let config = RenderConfig::from_env(&ctx.env);
+    // Math, contents and shortcode links point back at this page
+    let page_url = format!("{}/api/premium/{}", site_url, slug);
+    let opts = markdown::RenderOptions {
+        stack_wide_tables: meta.stack_wide_tables,
+        math_image_url: config.math_image_url,
+        smart_punctuation: meta.smart_punctuation.unwrap_or(config.smart_punctuation),
+        post_url: page_url.clone(),
+        toc: meta.toc,
+        archive_url: page_url.clone(),
+        truncate_words: None,
+        lang: meta
+            .lang
+            .as_deref()
+            .map(email::Lang::from_code)
+            .unwrap_or(email::Lang::En),
+    };
+    let body = markdown::render_markdown(&shortcodes::expand(md_body, &page_url), &opts);
+    let title = markdown::emojify(meta.title.as_deref().unwrap_or(&slug));
+    private_html(post_page(&title, &body))
+}
+
+fn post_page(title: &str, body: &str) -> String {
+    let title = html::escape(title);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="robots" content="noindex">
+    <title>{title} - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 680px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1, h2, h3 {{ font-family: -apple-system, sans-serif; }}
+        p, li {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+        img {{ max-width: 100%; height: auto; }}
+        pre {{ overflow-x: auto; }}
+    </style>
+</head>
+<body>
+    <h1>{title}</h1>
+{body}
+    <p style="margin-top: 32px;"><a href="/api/premium">All supporter posts</a></p>
+</body>
+</html>"#
+    )
+}
+
+fn index_page(site_url: &str, posts: &[(String, String)]) -> String {
+    let items: String = if posts.is_empty() {
+        "    <p>Nothing here yet &mdash; the first one is on its way.</p>\n".into()
+    } else {
+        let links: String = posts
+            .iter()
+            .map(|(slug, title)| {
+                format!(
+                    "        <li><a href=\"/api/premium/{}\">{}</a></li>\n",
+                    html::escape(slug),
+                    html::escape(title)
+                )
+            })
+            .collect();
+        format!("    <ul>\n{}    </ul>\n", links)
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta name="robots" content="noindex">
+    <title>For supporters - lindfors.no</title>
+    <style>
+        body {{ font-family: Georgia, serif; max-width: 480px; margin: 80px auto; padding: 0 24px; color: #1C3240; background: #F0EAE0; }}
+        h1 {{ font-family: -apple-system, sans-serif; font-size: 1.5rem; }}
+        p, li {{ line-height: 1.6; }}
+        a {{ color: #D4706A; }}
+    </style>
+</head>
+<body>
+    <h1>For supporters</h1>
+    <p>Thank you for supporting the newsletter. These posts are just for you; please keep this page's link to yourself.</p>
+{items}    <p style="margin-top: 32px;"><a href="{site_url}">Back to lindfors.no</a></p>
+</body>
+</html>"#
+    )
+}
+
+/// The email that gives a new supporter their link.
+pub fn welcome_email(site_url: &str, token: &str) -> String {
+    let link = format!("{}/api/premium?token={}", site_url, token);
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<body style="font-family: Georgia, serif; color: #1C3240; line-height: 1.6;">
+    <p>Thank you for becoming a supporter!</p>
+    <p>Supporter-only posts are at <a href="{link}">{link}</a>. The link is yours alone, so please don't share it; it stops working if the subscription ends.</p>
+    <p>&mdash; Emil</p>
+</body>
+</html>"#
+    )
+}
@@ -0,0 +1,163 @@
+//! Per-client rate limiting for the public endpoints, as fixed windows counted
+//! by a Durable Object per route and client (the RATE_LIMITER binding).
+//! Limited routes report their budget on every response:
+//!
+//! ```text
+//! X-RateLimit-Limit: 10
+//! X-RateLimit-Remaining: 7
+//! X-RateLimit-Reset: <seconds until the window ends>
+//! Retry-After: <same, only on 429s>
+//! ```
+//!
+//! A Durable Object handles one request's storage reads and writes at a
+//! time, so concurrent requests each get their own count. A count that fails
+//! answers 429 as well: every limited route writes something.
+
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+// The Durable Object macro's generated code expects `wasm_bindgen` by name
+use worker::wasm_bindgen;
+use worker::{
+    durable_object, DurableObject, Env, Method, Request, RequestInit, Response, Result,
+    RouteContext, State,
+};
+
+use crate::logging::RequestLog;
+use crate::metrics::route_name;
+use crate::{json_response, ApiResponse};
+
+/// Length of a rate-limit window.
+const WINDOW_SECS: u64 = 60;
+
+/// Default for RATE_LIMIT_PER_MINUTE.
+const DEFAULT_LIMIT: u64 = 10;
+
+fn limit(env: &Env) -> u64 {
+    env.var("RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+}
+
+/// Where the request came from: the client IP as seen by Cloudflare.
+fn client(req: &Request) -> String {
+    req.headers()
+        .get("CF-Connecting-IP")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Run `handler` if the client is within its budget for the route, otherwise
+/// answer 429.
+pub async fn limited<F, Fut>(
+    req: Request,
+    ctx: RouteContext<RequestLog>,
+    handler: F,
+) -> Result<Response>
+where
+    F: FnOnce(Request, RouteContext<RequestLog>) -> Fut,
+    Fut: Future<Output = Result<Response>>,
+{
+    let limit = limit(&ctx.env);
+    let now = worker::Date::now().as_millis() / 1000;
+    let window = now / WINDOW_SECS;
+    let reset = (window + 1) * WINDOW_SECS - now;
+    let name = format!("{}:{}", route_name(&req.path()), client(&req));
+
+    let used = match count(&ctx.env, &name, window).await {
+        Ok(used) => used,
+        Err(e) => {
+            worker::console_warn!("Rate limit check failed for {}: {}", name, e);
+            limit
+        }
+    };
+
+    let mut resp = if used >= limit {
+        let mut resp = json_response(
+            &ApiResponse {
+                success: false,
+                error: Some("Too many requests — try again shortly".into()),
+            },
+            429,
+        )?;
+        resp.headers_mut().set("Retry-After", &reset.to_string())?;
+        resp
+    } else {
+        handler(req, ctx).await?
+    };
+
+    let remaining = limit.saturating_sub(used + 1);
+    let headers = resp.headers_mut();
+    headers.set("X-RateLimit-Limit", &limit.to_string())?;
+    headers.set("X-RateLimit-Remaining", &remaining.to_string())?;
+    headers.set("X-RateLimit-Reset", &reset.to_string())?;
+    Ok(resp)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Count {
+    window: u64,
+    used: u64,
+}
+
+/// Counts one route's requests from one client.
+#[durable_object(alarm)]
+pub struct RateLimiter {
+    state: State,
+}
+
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        RateLimiter { state }
+    }
+
+    /// POST /{window}: count a request in `window`, answering how many came
+    /// before it.
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let Ok(window) = req.path().trim_start_matches('/').parse::<u64>() else {
+            return Response::error("Not found", 404);
+        };
+        let storage = self.state.storage();
+        let used = match storage.get::<Count>("window").await? {
+            Some(current) if current.window == window => current.used,
+            _ => 0,
+        };
+        let count = Count {
+            window,
+            used: used + 1,
+        };
+        storage.put("window", &count).await?;
+        if used == 0 {
+            // Forget the client once this window is well over
+            storage.set_alarm((WINDOW_SECS * 2 * 1000) as i64).await?;
+        }
+        Response::from_json(&used)
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+/// Count this request in `window`; how many came before it. The host is a
+/// placeholder, only the path reaches the object.
+async fn count(env: &Env, name: &str, window: u64) -> Result<u64> {
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    let req = Request::new_with_init(&format!("https://ratelimiter/{}", window), &init)?;
+    let mut resp = env
+        .durable_object("RATE_LIMITER")?
+        .get_by_name(name)?
+        .fetch_with_request(req)
+        .await?;
+    if resp.status_code() != 200 {
+        return Err(worker::Error::RustError(format!(
+            "Rate limiter answered {}",
+            resp.status_code()
+        )));
+    }
+    resp.json().await
+}
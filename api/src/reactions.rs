@@ -0,0 +1,129 @@
+//! Per-post emoji reactions, counted in the NEWSLETTER KV namespace:
+//! `reactions:{slug}` holds the counts, and `reacted:{slug}:{reaction}:{client}`
+//! marks that a client (see [`client_fingerprint`]) already reacted.
+//!
+//! Counts are read-modify-write in eventually consistent KV, so simultaneous
+//! reactions can lose a count now and then.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{client_fingerprint, is_valid_slug, json_response, ApiResponse};
+
+/// The reactions on offer, by the name the API uses.
+const REACTIONS: &[(&str, &str)] = &[
+    ("like", "👍"),
+    ("love", "❤️"),
+    ("tada", "🎉"),
+    ("insightful", "💡"),
+    ("rocket", "🚀"),
+];
+
+/// How long a client's reaction is remembered for de-duplication.
+const DEDUP_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+struct ReactRequest {
+    reaction: String,
+}
+
+#[derive(Serialize)]
+struct Reaction {
+    name: &'static str,
+    emoji: &'static str,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct CountsResponse {
+    /// Whether this request added a reaction (false for repeats). Absent on GET.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counted: Option<bool>,
+    reactions: Vec<Reaction>,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+async fn load(env: &Env, slug: &str) -> Result<BTreeMap<String, u64>> {
+    let kv = env.kv("NEWSLETTER")?;
+    Ok(kv
+        .get(&format!("reactions:{}", slug))
+        .json()
+        .await?
+        .unwrap_or_default())
+}
+
+fn counts_response(counts: &BTreeMap<String, u64>, counted: Option<bool>) -> Result<Response> {
+    let reactions = REACTIONS
+        .iter()
+        .map(|&(name, emoji)| Reaction {
+            name,
+            emoji,
+            count: counts.get(name).copied().unwrap_or(0),
+        })
+        .collect();
+    json_response(&CountsResponse { counted, reactions }, 200)
+}
+
+/// GET /api/reactions/{slug} — counts for every reaction on offer.
+pub async fn handle_counts(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+    counts_response(&load(&ctx.env, &slug).await?, None)
+}
+
+/// POST /api/reactions/{slug} — `{"reaction": "like"}` (a name or its emoji).
+/// Reacting twice the same way counts once.
+pub async fn handle_react(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+    let Ok(body) = req.json::<ReactRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"reaction\": \"...\"}",
+        );
+    };
+    let Some(&(name, _)) = REACTIONS
+        .iter()
+        .find(|(name, emoji)| body.reaction == *name || body.reaction == *emoji)
+    else {
+        return error(400, "Unknown reaction");
+    };
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let marker = format!(
+        "reacted:{}:{}:{}",
+        slug,
+        name,
+        client_fingerprint(&req, "reactions")
+    );
+    let mut counts = load(&ctx.env, &slug).await?;
+    if kv.get(&marker).text().await?.is_some() {
+        return counts_response(&counts, Some(false));
+    }
+
+    *counts.entry(name.to_string()).or_insert(0) += 1;
+    kv.put(&format!("reactions:{}", slug), &counts)?
+        .execute()
+        .await?;
+    kv.put(&marker, "1")?
+        .expiration_ttl(DEDUP_TTL_SECS)
+        .execute()
+        .await?;
+    counts_response(&counts, Some(true))
+}
@@ -0,0 +1,223 @@
+//! Redirects for moved pages, so links in already-sent issues keep working
+//! after a post is renamed. The map (old path → new URL) is one JSON object
+//! under `redirects` in the NEWSLETTER KV namespace.
+//!
+//! The worker is routed on the site paths that may hold moved pages (see
+//! `routes` in wrangler.toml). Requests there that aren't API routes land in
+//! [`handle_fallback`], whatever their method: GETs and HEADs of mapped paths
+//! get a 301, everything else is passed through to the site.
+//!
+//! ```text
+//! GET    /api/admin/redirects
+//! PUT    /api/admin/redirects            {"from": "/blog/old/", "to": "/blog/new/"}
+//! DELETE /api/admin/redirects?from=/blog/old/
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, Fetch, Method, Request, Response, Result, RouteContext, Url};
+
+use crate::logging::RequestLog;
+use crate::{dates, json_response, ApiResponse};
+
+const REDIRECTS_KEY: &str = "redirects";
+
+/// Seconds edge locations may cache the map; edits take up to this long to
+/// apply everywhere.
+const CACHE_TTL_SECS: u64 = 300;
+
+#[derive(Serialize, Deserialize)]
+struct Redirect {
+    /// A site path (`/blog/new/`) or an absolute http(s) URL.
+    to: String,
+    /// Unix milliseconds of the last change.
+    updated_at: u64,
+}
+
+type RedirectMap = BTreeMap<String, Redirect>;
+
+#[derive(Deserialize)]
+struct RedirectRequest {
+    from: String,
+    to: String,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+/// The map key for a path: without query, fragment or trailing slash, so
+/// `/blog/old` and `/blog/old/` redirect alike.
+fn normalize(path: &str) -> Option<String> {
+    let path = path.split(['?', '#']).next().unwrap_or("").trim();
+    if !path.starts_with('/') || path.starts_with("//") {
+        return None;
+    }
+    let trimmed = path.trim_end_matches('/');
+    Some(if trimmed.is_empty() { "/" } else { trimmed }.to_string())
+}
+
+async fn load(env: &Env, cached: bool) -> Result<RedirectMap> {
+    let kv = env.kv("NEWSLETTER")?;
+    let mut get = kv.get(REDIRECTS_KEY);
+    if cached {
+        get = get.cache_ttl(CACHE_TTL_SECS);
+    }
+    Ok(get.json().await?.unwrap_or_default())
+}
+
+async fn store(env: &Env, map: &RedirectMap) -> Result<()> {
+    let kv = env.kv("NEWSLETTER")?;
+    kv.put(REDIRECTS_KEY, map)?.execute().await?;
+    Ok(())
+}
+
+/// Where `to` points, resolved against the site for paths.
+fn target(env: &Env, to: &str) -> Option<Url> {
+    if to.starts_with('/') {
+        let site_url = env.var("SITE_URL").ok()?.to_string();
+        return Url::parse(&site_url).ok()?.join(to).ok();
+    }
+    Url::parse(to)
+        .ok()
+        .filter(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+/// Any request on a non-API path the worker is routed on — for a GET or
+/// HEAD, 301 to the new location if the path has moved; otherwise the site's
+/// own response.
+pub async fn handle_fallback(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let url = req.url()?;
+    let redirect = match normalize(url.path()) {
+        Some(path) if matches!(req.method(), Method::Get | Method::Head) => {
+            load(&ctx.env, true).await?.remove(&path)
+        }
+        _ => None,
+    };
+
+    if let Some(redirect) = redirect {
+        if let Some(mut location) = target(&ctx.env, &redirect.to) {
+            if location.query().is_none() {
+                location.set_query(url.query());
+            }
+            return Response::redirect_with_status(location, 301);
+        }
+        worker::console_warn!("Redirect for {} has an invalid target", url.path());
+    }
+
+    // Fetched responses have immutable headers; copy them so the middleware
+    // can add its own
+    let resp = Fetch::Request(req).send().await?;
+    let headers = resp.headers().clone();
+    Ok(resp.with_headers(headers))
+}
+
+#[derive(Serialize)]
+struct RedirectInfo {
+    from: String,
+    to: String,
+    updated_at: String,
+}
+
+/// GET /api/admin/redirects — admin: the whole map.
+pub async fn handle_list(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let redirects: Vec<RedirectInfo> = load(&ctx.env, false)
+        .await?
+        .into_iter()
+        .map(|(from, r)| RedirectInfo {
+            from,
+            to: r.to,
+            updated_at: dates::rfc3339(r.updated_at),
+        })
+        .collect();
+
+    #[derive(Serialize)]
+    struct RedirectsResponse {
+        redirects: Vec<RedirectInfo>,
+    }
+
+    json_response(&RedirectsResponse { redirects }, 200)
+}
+
+/// PUT /api/admin/redirects — admin: add or replace a redirect. 201 if `from`
+/// is new, 200 if it was replaced.
+pub async fn handle_put(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(body) = req.json::<RedirectRequest>().await else {
+        return error(
+            400,
+            "Invalid request body — expected {\"from\": \"/old/\", \"to\": \"/new/\"}",
+        );
+    };
+    let Some(from) = normalize(&body.from) else {
+        return error(400, "from must be a site path starting with /");
+    };
+    if from == "/api" || from.starts_with("/api/") || from.starts_with("/s/") {
+        return error(400, "from can't be an API or short link path");
+    }
+    let to = body.to.trim().to_string();
+    let Some(location) = target(&ctx.env, &to) else {
+        return error(400, "to must be a site path or an http(s) URL");
+    };
+
+    let mut map = load(&ctx.env, false).await?;
+    // A target that redirects itself would chain, or loop if it's `from`
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let same_site = Url::parse(&site_url).is_ok_and(|site| site.origin() == location.origin());
+    let redirects = |path: String| path == from || map.contains_key(&path);
+    if same_site && normalize(location.path()).is_some_and(redirects) {
+        return error(400, "to points at a path that redirects");
+    }
+
+    let updated_at = worker::Date::now().as_millis();
+    let replaced = map
+        .insert(
+            from.clone(),
+            Redirect {
+                to: to.clone(),
+                updated_at,
+            },
+        )
+        .is_some();
+    store(&ctx.env, &map).await?;
+
+    json_response(
+        &RedirectInfo {
+            from,
+            to,
+            updated_at: dates::rfc3339(updated_at),
+        },
+        if replaced { 200 } else { 201 },
+    )
+}
+
+/// DELETE /api/admin/redirects?from=/blog/old/ — admin: remove a redirect.
+pub async fn handle_delete(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let url = req.url()?;
+    let from = url
+        .query_pairs()
+        .find(|(k, _)| k == "from")
+        .and_then(|(_, v)| normalize(&v));
+    let Some(from) = from else {
+        return error(400, "Missing ?from=/path/");
+    };
+
+    let mut map = load(&ctx.env, false).await?;
+    if map.remove(&from).is_none() {
+        return error(404, "No redirect for that path");
+    }
+    store(&ctx.env, &map).await?;
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        200,
+    )
+}
@@ -0,0 +1,139 @@
+//! "You might also like" posts. The posts' tags and categories are uploaded
+//! to `related:index` in the NEWSLETTER KV namespace by
+//! `scripts/upload-related-index.sh`; similarity is computed per request,
+//! which is cheap at this site's size.
+//!
+//! A post scores two points per shared tag and one per shared category, and
+//! ties go to the newer post. Posts sharing nothing aren't related.
+
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{is_valid_slug, json_response, ApiResponse};
+
+const INDEX_KEY: &str = "related:index";
+
+/// Edge-cache the index this long; it only changes on deploy.
+const INDEX_CACHE_SECS: u64 = 300;
+
+const DEFAULT_LIMIT: usize = 3;
+const MAX_LIMIT: usize = 10;
+
+const TAG_WEIGHT: u32 = 2;
+const CATEGORY_WEIGHT: u32 = 1;
+
+#[derive(Deserialize)]
+struct Post {
+    slug: String,
+    url: String,
+    title: String,
+    #[serde(default)]
+    description: String,
+    /// `YYYY-MM-DD`, so it sorts as a string.
+    #[serde(default)]
+    date: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RelatedPost<'a> {
+    slug: &'a str,
+    url: &'a str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    description: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    date: &'a str,
+    /// Tags and categories shared with the requested post.
+    shared: Vec<&'a str>,
+    score: u32,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn related<'a>(of: &Post, candidate: &'a Post) -> RelatedPost<'a> {
+    let shared_tags = candidate.tags.iter().filter(|t| of.tags.contains(t));
+    let shared_categories = candidate
+        .categories
+        .iter()
+        .filter(|c| of.categories.contains(c));
+    let score = shared_tags.clone().count() as u32 * TAG_WEIGHT
+        + shared_categories.clone().count() as u32 * CATEGORY_WEIGHT;
+    RelatedPost {
+        slug: &candidate.slug,
+        url: &candidate.url,
+        title: &candidate.title,
+        description: &candidate.description,
+        date: &candidate.date,
+        shared: shared_tags
+            .chain(shared_categories)
+            .map(String::as_str)
+            .collect(),
+        score,
+    }
+}
+
+/// GET /api/related/{slug}?limit=N — the posts most like `slug`, best first
+/// (default 3, at most 10).
+pub async fn handle_related(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+    let limit = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "limit")
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let posts: Vec<Post> = kv
+        .get(INDEX_KEY)
+        .cache_ttl(INDEX_CACHE_SECS)
+        .json()
+        .await?
+        .unwrap_or_default();
+    let Some(post) = posts.iter().find(|p| p.slug == slug) else {
+        return error(404, "Post not in the related-posts index");
+    };
+
+    let mut related: Vec<RelatedPost> = posts
+        .iter()
+        .filter(|p| p.slug != slug)
+        .map(|p| related(post, p))
+        .filter(|r| r.score > 0)
+        .collect();
+    related.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| b.date.cmp(a.date)));
+    related.truncate(limit);
+
+    #[derive(Serialize)]
+    struct RelatedResponse<'a> {
+        slug: &'a str,
+        related: Vec<RelatedPost<'a>>,
+    }
+
+    let mut resp = json_response(
+        &RelatedResponse {
+            slug: &slug,
+            related,
+        },
+        200,
+    )?;
+    resp.headers_mut()
+        .set("Cache-Control", "public, max-age=300")?;
+    Ok(resp)
+}
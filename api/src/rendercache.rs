@@ -0,0 +1,74 @@
+//! Rendered issues, cached in the NEWSLETTER KV namespace under
+//! `render:{slug}:{hash}`, where the hash covers everything the render
+//! depends on: the markdown as fetched, the footer, the "Previously" issues,
+//! the render settings and the code doing the render (the build's commit and
+//! [`TEMPLATE_VERSION`]). Sending an issue again (after a failed JMAP
+//! call, say) reuses the render instead of redoing it, image probes and
+//! all, and what's archived is byte for byte what was sent.
+//!
+//! Entries expire after a week; a changed post just gets a new key.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use worker::{Env, Result};
+
+use crate::email::{FooterConfig, TEMPLATE_VERSION};
+use crate::sendlog::SentIssue;
+use crate::{RenderConfig, RenderedIssue};
+
+const TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+fn key(slug: &str, hash: &str) -> String {
+    format!("render:{}:{}", slug, hash)
+}
+
+/// Hash of a render's inputs.
+pub fn inputs_hash(
+    md_source: &str,
+    site_url: &str,
+    footer: &FooterConfig,
+    previously: &[&SentIssue],
+    config: &RenderConfig,
+) -> String {
+    #[derive(Serialize)]
+    struct Inputs<'a> {
+        build: &'a str,
+        template_version: u32,
+        md_source: &'a str,
+        site_url: &'a str,
+        footer: &'a FooterConfig,
+        previously: &'a [&'a SentIssue],
+        config: &'a RenderConfig,
+    }
+
+    let inputs = serde_json::to_vec(&Inputs {
+        build: env!("GIT_SHA"),
+        template_version: TEMPLATE_VERSION,
+        md_source,
+        site_url,
+        footer,
+        previously,
+        config,
+    })
+    .unwrap_or_default();
+    Sha256::digest(&inputs)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The cached render of `slug` from inputs hashing to `hash`, if any.
+/// Unreadable entries count as missing.
+pub async fn load(env: &Env, slug: &str, hash: &str) -> Option<RenderedIssue> {
+    let kv = env.kv("NEWSLETTER").ok()?;
+    kv.get(&key(slug, hash)).json().await.ok().flatten()
+}
+
+pub async fn store(env: &Env, slug: &str, hash: &str, issue: &RenderedIssue) -> Result<()> {
+    env.kv("NEWSLETTER")?
+        .put(&key(slug, hash), issue)?
+        .expiration_ttl(TTL_SECS)
+        .execute()
+        .await?;
+    Ok(())
+}
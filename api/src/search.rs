@@ -0,0 +1,182 @@
+//! Full-text search over the site, for the search box. The documents come
+//! from Zola's search index, uploaded to `search:index` in the NEWSLETTER KV
+//! namespace by `scripts/upload-search-index.sh`.
+//!
+//! Every query word must match (as a word prefix) in the title, description
+//! or body; matches in the title count most, then the description, then how
+//! often the body mentions them.
+
+use serde::{Deserialize, Serialize};
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::html::escape;
+use crate::logging::RequestLog;
+use crate::{json_response, ApiResponse};
+
+const INDEX_KEY: &str = "search:index";
+
+/// Edge-cache the index this long; it only changes on deploy.
+const INDEX_CACHE_SECS: u64 = 300;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+const MAX_TERMS: usize = 8;
+
+/// Words of context around the first match in a snippet.
+const SNIPPET_BEFORE: usize = 10;
+const SNIPPET_AFTER: usize = 20;
+
+#[derive(Deserialize)]
+struct Document {
+    url: String,
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    url: String,
+    title: String,
+    /// HTML: escaped text with matches in `<mark>`.
+    snippet: String,
+    score: u32,
+}
+
+/// Lowercase alphanumeric form of a word, for matching.
+fn normalize(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase()
+}
+
+fn query_terms(q: &str) -> Vec<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for term in q
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+    {
+        if term.chars().count() >= 2 && !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms.truncate(MAX_TERMS);
+    terms
+}
+
+fn matches(word: &str, terms: &[String]) -> bool {
+    let word = normalize(word);
+    !word.is_empty() && terms.iter().any(|t| word.starts_with(t.as_str()))
+}
+
+/// How many words of `text` start with `term`.
+fn hits(text: &str, term: &str) -> u32 {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.to_lowercase().starts_with(term))
+        .count() as u32
+}
+
+/// Score a document, or `None` if some term doesn't match it at all.
+fn score(doc: &Document, terms: &[String]) -> Option<u32> {
+    let mut total = 0;
+    for term in terms {
+        let title = hits(&doc.title, term);
+        let description = hits(&doc.description, term);
+        let body = hits(&doc.body, term);
+        if title + description + body == 0 {
+            return None;
+        }
+        total += title * 10 + description * 3 + body.min(10);
+    }
+    Some(total)
+}
+
+/// Words around the first match in the body, or the start of the
+/// description if the body doesn't match.
+fn snippet(doc: &Document, terms: &[String]) -> String {
+    let words: Vec<&str> = doc.body.split_whitespace().collect();
+    let (source, first) = match words.iter().position(|w| matches(w, terms)) {
+        Some(i) => (words, i),
+        None => (doc.description.split_whitespace().collect(), 0),
+    };
+    let start = first.saturating_sub(SNIPPET_BEFORE);
+    let end = (first + SNIPPET_AFTER).min(source.len());
+
+    let mut out = String::new();
+    if start > 0 {
+        out.push('…');
+    }
+    for (i, word) in source[start..end].iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        if matches(word, terms) {
+            out.push_str(&format!("<mark>{}</mark>", escape(word)));
+        } else {
+            out.push_str(&escape(word));
+        }
+    }
+    if end < source.len() {
+        out.push('…');
+    }
+    out
+}
+
+/// GET /api/search?q=...&limit=N — ranked matches with snippets.
+pub async fn handle_search(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let params: std::collections::HashMap<String, String> =
+        req.url()?.query_pairs().into_owned().collect();
+    let terms = query_terms(params.get("q").map(String::as_str).unwrap_or(""));
+    if terms.is_empty() {
+        return json_response(
+            &ApiResponse {
+                success: false,
+                error: Some("Query must contain a word of at least 2 characters".into()),
+            },
+            400,
+        );
+    }
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .min(MAX_LIMIT);
+
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let documents: Vec<Document> = kv
+        .get(INDEX_KEY)
+        .cache_ttl(INDEX_CACHE_SECS)
+        .json()
+        .await?
+        .unwrap_or_default();
+
+    let mut scored: Vec<(u32, &Document)> = documents
+        .iter()
+        .filter_map(|doc| score(doc, &terms).map(|s| (s, doc)))
+        .collect();
+    scored.sort_by_key(|&(s, _)| std::cmp::Reverse(s));
+
+    #[derive(Serialize)]
+    struct SearchResponse {
+        total: usize,
+        results: Vec<SearchResult>,
+    }
+
+    let total = scored.len();
+    let results = scored
+        .into_iter()
+        .take(limit)
+        .map(|(score, doc)| SearchResult {
+            url: doc.url.clone(),
+            title: doc.title.clone(),
+            snippet: snippet(doc, &terms),
+            score,
+        })
+        .collect();
+
+    let mut resp = json_response(&SearchResponse { total, results }, 200)?;
+    resp.headers_mut()
+        .set("Cache-Control", "public, max-age=300")?;
+    Ok(resp)
+}
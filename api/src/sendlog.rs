@@ -0,0 +1,75 @@
+//! Log of sent newsletter issues, kept as a JSON array (newest first) under
+//! the `sendlog` key in the NEWSLETTER KV namespace.
+
+use serde::{Deserialize, Serialize};
+use worker::{Date, Env, Result};
+
+const SENDLOG_KEY: &str = "sendlog";
+
+/// Keep the log bounded; older issues stay on the site, just not in the footer.
+const MAX_ENTRIES: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SentIssue {
+    pub slug: String,
+    pub title: String,
+    /// Plain text.
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub date: String,
+    pub post_url: String,
+    /// Milliseconds since the Unix epoch.
+    pub sent_at: u64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Problems noticed while rendering the issue.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// The `at://` URI of the issue's Bluesky announcement, if posted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bluesky_uri: Option<String>,
+    /// Hex SHA-256 of the markdown the issue was rendered from (see
+    /// sourcecache.rs). Not recorded before that existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+}
+
+/// Read the send log. A missing binding or key is an empty log.
+pub async fn load(env: &Env) -> Vec<SentIssue> {
+    let Ok(kv) = env.kv("NEWSLETTER") else {
+        return Vec::new();
+    };
+    kv.get(SENDLOG_KEY)
+        .json::<Vec<SentIssue>>()
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Prepend an issue to the send log, replacing any earlier entry for the slug.
+pub async fn record(env: &Env, issue: SentIssue) -> Result<()> {
+    let kv = env.kv("NEWSLETTER")?;
+    let mut entries = load(env).await;
+    entries.retain(|e| e.slug != issue.slug);
+    entries.insert(0, issue);
+    entries.truncate(MAX_ENTRIES);
+    kv.put(SENDLOG_KEY, &entries)?.execute().await?;
+    Ok(())
+}
+
+/// The most recent `n` issues other than `exclude_slug`.
+pub fn recent<'a>(entries: &'a [SentIssue], exclude_slug: &str, n: usize) -> Vec<&'a SentIssue> {
+    entries
+        .iter()
+        .filter(|e| e.slug != exclude_slug)
+        .take(n)
+        .collect()
+}
+
+pub fn now_millis() -> u64 {
+    Date::now().as_millis()
+}
@@ -0,0 +1,456 @@
+//! Shortcode pre-processing for newsletter markdown.
+//!
+//! Posts are written for the site and use Zola shortcodes
+//! (`{{ figure(src="a.webp") }}`, `{% katex(block=true) %}...{% end %}`) and
+//! occasionally Hugo-style ones (`{{< youtube id >}}`). Known shortcodes are
+//! turned into email-safe HTML or markdown; unknown ones are dropped, keeping
+//! the body of body shortcodes. Code blocks and code spans are left alone.
+
+use crate::html::escape;
+
+/// Marks a stashed code block or span while shortcodes are expanded.
+const CODE_MARKER: char = '\u{1A}';
+
+/// Expand shortcodes in `md`. `post_url` is the post's URL on the site, for
+/// shortcodes that link back to it.
+pub fn expand(md: &str, post_url: &str) -> String {
+    let (prose, code) = stash_code(md);
+    let expanded = expand_prose(&prose, post_url);
+    restore_code(&expanded, &code)
+}
+
+// ---------------------------------------------------------------------------
+// Code protection
+// ---------------------------------------------------------------------------
+
+/// Replace fenced code blocks and inline code spans with numbered markers so
+/// shortcode examples inside them survive verbatim.
+fn stash_code(md: &str) -> (String, Vec<String>) {
+    let mut prose = String::with_capacity(md.len());
+    let mut code = Vec::new();
+    let mut fence: Option<(char, usize, String)> = None;
+
+    for line in md.split_inclusive('\n') {
+        if let Some((ch, len, block)) = fence.as_mut() {
+            block.push_str(line);
+            let trimmed = line.trim();
+            if trimmed.len() >= *len && trimmed.chars().all(|c| c == *ch) {
+                prose.push_str(&marker(code.len()));
+                code.push(std::mem::take(block));
+                fence = None;
+            }
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        let ch = trimmed.chars().next().unwrap_or(' ');
+        let len = trimmed.chars().take_while(|&c| c == ch).count();
+        if (ch == '`' || ch == '~') && len >= 3 {
+            fence = Some((ch, len, line.to_string()));
+        } else {
+            stash_code_spans(line, &mut prose, &mut code);
+        }
+    }
+    // An unterminated fence runs to the end of the document
+    if let Some((_, _, block)) = fence {
+        prose.push_str(&marker(code.len()));
+        code.push(block);
+    }
+    (prose, code)
+}
+
+/// Stash `` `code` `` spans (matching backtick runs) within one line.
+fn stash_code_spans(line: &str, prose: &mut String, code: &mut Vec<String>) {
+    let mut rest = line;
+    while let Some(start) = rest.find('`') {
+        let run = rest[start..].chars().take_while(|&c| c == '`').count();
+        let ticks = &rest[start..start + run];
+        let after = &rest[start + run..];
+        let Some(end) = find_closing_run(after, run) else {
+            break;
+        };
+        prose.push_str(&rest[..start]);
+        prose.push_str(&marker(code.len()));
+        code.push(format!("{ticks}{}{ticks}", &after[..end]));
+        rest = &after[end + run..];
+    }
+    prose.push_str(rest);
+}
+
+/// Offset of the next run of exactly `run` backticks in `s`.
+fn find_closing_run(s: &str, run: usize) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'`' {
+            let len = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+            if len == run {
+                return Some(i);
+            }
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+fn marker(index: usize) -> String {
+    format!("{CODE_MARKER}{index}{CODE_MARKER}")
+}
+
+fn restore_code(text: &str, code: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut parts = text.split(CODE_MARKER);
+    if let Some(first) = parts.next() {
+        out.push_str(first);
+    }
+    // Markers alternate: index, then the text up to the next marker
+    while let (Some(index), Some(text)) = (parts.next(), parts.next()) {
+        if let Some(block) = index.parse::<usize>().ok().and_then(|i| code.get(i)) {
+            out.push_str(block);
+        }
+        out.push_str(text);
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// Parsing
+// ---------------------------------------------------------------------------
+
+/// A parsed shortcode invocation.
+struct Shortcode {
+    name: String,
+    /// Named arguments, plus positional ones (Hugo) keyed by their index.
+    args: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+impl Shortcode {
+    fn arg(&self, key: &str) -> Option<&str> {
+        self.args
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Named argument, falling back to the positional one at `index`.
+    fn arg_or(&self, key: &str, index: usize) -> Option<&str> {
+        self.arg(key).or_else(|| self.arg(&index.to_string()))
+    }
+}
+
+fn expand_prose(text: &str, post_url: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        match parse_shortcode(candidate) {
+            Some((shortcode, consumed)) => {
+                out.push_str(&render(&shortcode, post_url));
+                rest = &candidate[consumed..];
+            }
+            None => {
+                out.push('{');
+                rest = &candidate[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parse a shortcode at the start of `s`, returning it and the bytes consumed.
+fn parse_shortcode(s: &str) -> Option<(Shortcode, usize)> {
+    if let Some(inner) = s.strip_prefix("{{<").or_else(|| s.strip_prefix("{{%")) {
+        let close = if s.as_bytes()[2] == b'<' { ">}}" } else { "%}}" };
+        return parse_hugo(s, inner, close);
+    }
+    if let Some(inner) = s.strip_prefix("{{") {
+        let (shortcode, len) = parse_zola_call(inner)?;
+        let after = inner[len..].trim_start().strip_prefix("}}")?;
+        return Some((shortcode, s.len() - after.len()));
+    }
+    if let Some(inner) = s.strip_prefix("{%") {
+        let (mut shortcode, len) = parse_zola_call(inner)?;
+        let after = inner[len..].trim_start().strip_prefix("%}")?;
+        let (body, tail) = split_zola_body(after)?;
+        shortcode.body = Some(body.to_string());
+        return Some((shortcode, s.len() - tail.len()));
+    }
+    None
+}
+
+/// Parse ` name(key=value, ...)` and return it with the length consumed.
+/// Template expressions like `{{ page.title }}` don't match.
+fn parse_zola_call(s: &str) -> Option<(Shortcode, usize)> {
+    let trimmed = s.trim_start();
+    let lead = s.len() - trimmed.len();
+    let name_len = trimmed
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(trimmed.len());
+    if name_len == 0 || !trimmed[name_len..].starts_with('(') {
+        return None;
+    }
+    let name = trimmed[..name_len].to_string();
+    let args_start = name_len + 1;
+    let args_len = find_unquoted(&trimmed[args_start..], ")")?;
+    let args = parse_args(&trimmed[args_start..args_start + args_len], ',');
+    let shortcode = Shortcode {
+        name,
+        args,
+        body: None,
+    };
+    Some((shortcode, lead + args_start + args_len + 1))
+}
+
+/// Split a Zola body shortcode at its `{% end %}`.
+fn split_zola_body(s: &str) -> Option<(&str, &str)> {
+    let mut offset = 0;
+    while let Some(i) = s[offset..].find("{%") {
+        let start = offset + i;
+        let inner = s[start + 2..].trim_start();
+        if let Some(after) = inner.strip_prefix("end") {
+            if let Some(tail) = after.trim_start().strip_prefix("%}") {
+                return Some((&s[..start], tail));
+            }
+        }
+        offset = start + 2;
+    }
+    None
+}
+
+/// Parse a Hugo shortcode `{{< name args >}}`, with its body if a matching
+/// `{{< /name >}}` follows.
+fn parse_hugo<'a>(s: &'a str, inner: &'a str, close: &str) -> Option<(Shortcode, usize)> {
+    let end = find_unquoted(inner, close)?;
+    let mut tokens = inner[..end].trim();
+    if tokens.starts_with('/') {
+        // A stray closing tag
+        return Some((
+            Shortcode {
+                name: String::new(),
+                args: Vec::new(),
+                body: None,
+            },
+            s.len() - inner.len() + end + close.len(),
+        ));
+    }
+    let name_len = tokens.find(char::is_whitespace).unwrap_or(tokens.len());
+    let name = tokens[..name_len].to_string();
+    tokens = &tokens[name_len..];
+    let args = parse_args(tokens, ' ');
+
+    let after = &inner[end + close.len()..];
+    let open = &s[..3];
+    let closing = format!("{open} /{name} {close}");
+    let closing_tight = format!("{open}/{name}{close}");
+    let body_end = after
+        .find(&closing)
+        .map(|i| (i, closing.len()))
+        .or_else(|| after.find(&closing_tight).map(|i| (i, closing_tight.len())));
+
+    let (body, tail) = match body_end {
+        Some((i, len)) => (Some(after[..i].to_string()), &after[i + len..]),
+        None => (None, after),
+    };
+    Some((Shortcode { name, args, body }, s.len() - tail.len()))
+}
+
+/// Parse `key="value"` and positional arguments separated by `sep` (and
+/// whitespace). Values may be double-, single- or backtick-quoted, or bare.
+fn parse_args(s: &str, sep: char) -> Vec<(String, String)> {
+    let mut args = Vec::new();
+    let mut position = 0;
+    let mut i = 0;
+
+    loop {
+        let rest = s[i..].trim_start_matches(|c: char| c == sep || c.is_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+        let start = s.len() - rest.len();
+
+        let key_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(rest.len());
+        let after_key = rest[key_len..].trim_start();
+        let (key, value_start) = match after_key.strip_prefix('=') {
+            Some(value) if key_len > 0 => {
+                (Some(rest[..key_len].to_string()), s.len() - value.trim_start().len())
+            }
+            _ => (None, start),
+        };
+
+        let (value, end) = read_value(s, value_start, sep);
+        match key {
+            Some(key) => args.push((key, value)),
+            None => {
+                args.push((position.to_string(), value));
+                position += 1;
+            }
+        }
+        i = end.max(start + 1).min(s.len());
+    }
+    args
+}
+
+/// Read a quoted or bare value starting at `start`; returns it and its end.
+fn read_value(s: &str, start: usize, sep: char) -> (String, usize) {
+    let rest = &s[start..];
+    let Some(quote @ ('"' | '\'' | '`')) = rest.chars().next() else {
+        let end = rest
+            .find(|c: char| c == sep || c.is_whitespace())
+            .unwrap_or(rest.len());
+        return (rest[..end].to_string(), start + end);
+    };
+
+    let mut value = String::new();
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            c if c == quote => return (value, start + i + 1),
+            c => value.push(c),
+        }
+    }
+    (value, s.len())
+}
+
+/// Index of the first occurrence of `target` outside quoted strings.
+fn find_unquoted(s: &str, target: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' || c == '`' => quote = Some(c),
+            None if s[i..].starts_with(target) => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+// ---------------------------------------------------------------------------
+// Rendering
+// ---------------------------------------------------------------------------
+
+fn render(sc: &Shortcode, post_url: &str) -> String {
+    match sc.name.as_str() {
+        "figure" => render_figure(sc),
+        "youtube" => sc
+            .arg_or("id", 0)
+            .map(|id| {
+                let id = escape(id);
+                video_thumbnail(
+                    &format!("https://www.youtube.com/watch?v={id}"),
+                    &format!("https://img.youtube.com/vi/{id}/hqdefault.jpg"),
+                    "Watch on YouTube",
+                )
+            })
+            .unwrap_or_default(),
+        "vimeo" => sc
+            .arg_or("id", 0)
+            .map(|id| {
+                format!(
+                    "\n\n<p><a href=\"https://vimeo.com/{id}\">&#9654; Watch on Vimeo</a></p>\n\n",
+                    id = escape(id)
+                )
+            })
+            .unwrap_or_default(),
+        "note" | "tip" | "important" | "warning" | "caution" | "info" | "danger" => {
+            callout(&sc.name, sc.body.as_deref().unwrap_or(""))
+        }
+        "callout" | "admonition" => callout(
+            sc.arg_or("type", 0).unwrap_or("note"),
+            sc.body.as_deref().unwrap_or(""),
+        ),
+        // Left as TeX for the markdown math pass
+        "katex" => {
+            let body = sc.body.as_deref().unwrap_or("").trim();
+            if matches!(sc.arg("block"), Some("true")) {
+                format!("\n\n$${body}$$\n\n")
+            } else {
+                format!("${body}$")
+            }
+        }
+        "reference" => sc
+            .arg_or("key", 0)
+            .map(|key| {
+                let label = sc.arg("num").unwrap_or(key);
+                format!(
+                    "<a href=\"{post}#ref-{key}\" class=\"citation\">[{label}]</a>",
+                    post = escape(post_url),
+                    key = escape(key),
+                    label = escape(label),
+                )
+            })
+            .unwrap_or_default(),
+        // Unknown: drop the tag, keep any body text
+        _ => sc.body.clone().unwrap_or_default(),
+    }
+}
+
+/// Callout shortcodes become GFM `> [!NOTE]` blockquotes, which the markdown
+/// renderer styles.
+fn callout(kind: &str, body: &str) -> String {
+    let kind = match kind.to_lowercase().as_str() {
+        "tip" | "hint" | "success" => "TIP",
+        "important" => "IMPORTANT",
+        "warning" | "warn" => "WARNING",
+        "caution" | "danger" | "error" => "CAUTION",
+        _ => "NOTE",
+    };
+    let mut out = format!("\n\n> [!{kind}]\n");
+    for line in body.trim().lines() {
+        out.push_str("> ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+    out
+}
+
+fn render_figure(sc: &Shortcode) -> String {
+    let Some(src) = sc.arg_or("src", 0) else {
+        return String::new();
+    };
+    let alt = sc.arg("alt").unwrap_or("");
+    let width = sc
+        .arg("width")
+        .map(|w| format!(" width=\"{}\"", escape(w)))
+        .unwrap_or_default();
+    let img = format!(
+        "<img src=\"{}\" alt=\"{}\"{width} />",
+        escape(src),
+        escape(alt)
+    );
+    let img = match sc.arg("link") {
+        Some(link) => format!("<a href=\"{}\">{img}</a>", escape(link)),
+        None => img,
+    };
+    let caption = sc
+        .arg("caption")
+        .or(sc.arg("title"))
+        .map(|c| format!("<figcaption>{}</figcaption>", escape(c)))
+        .unwrap_or_default();
+    format!("\n\n<figure>{img}{caption}</figure>\n\n")
+}
+
+/// Email can't embed video, so link a thumbnail to the player.
+fn video_thumbnail(href: &str, thumbnail: &str, label: &str) -> String {
+    format!(
+        "\n\n<p><a href=\"{href}\"><img src=\"{thumbnail}\" alt=\"{label}\" /></a><br /><a href=\"{href}\">&#9654; {label}</a></p>\n\n"
+    )
+}
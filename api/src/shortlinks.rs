@@ -0,0 +1,241 @@
+//! Short links: `https://lindfors.no/s/{code}` redirects to a post (or
+//! anywhere), for sharing in the newsletter and on social media. Each link is
+//! `link:{code}` in the NEWSLETTER KV namespace, holding its target and click
+//! count. Links with an expiry are also given a KV expiration, so they clean
+//! themselves up.
+//!
+//! Managed through the admin API:
+//!
+//! ```text
+//! POST   /api/admin/links          {"url", "code"?, "expires_in_days"?}
+//! GET    /api/admin/links
+//! DELETE /api/admin/links/{code}
+//! ```
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, Error, Request, Response, Result, RouteContext, Url};
+
+use crate::logging::RequestLog;
+use crate::{dates, is_valid_slug, json_response, ApiResponse};
+
+/// Characters of generated codes: lowercase and digits without the
+/// look-alikes 0/o and 1/l. 32 of them, so random bytes map evenly.
+const CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Length of generated codes.
+const CODE_LEN: usize = 6;
+
+/// Longest custom code accepted.
+const MAX_CODE_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+struct ShortLink {
+    url: String,
+    /// Unix milliseconds.
+    created_at: u64,
+    /// Unix seconds, as KV expirations are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    clicks: u64,
+}
+
+impl ShortLink {
+    fn expired(&self, now_millis: u64) -> bool {
+        self.expires_at.is_some_and(|at| at * 1000 <= now_millis)
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateLinkRequest {
+    url: String,
+    code: Option<String>,
+    expires_in_days: Option<u64>,
+}
+
+/// A link as the admin API shows it.
+#[derive(Serialize)]
+struct LinkInfo {
+    code: String,
+    short_url: String,
+    url: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    clicks: u64,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn key(code: &str) -> String {
+    format!("link:{}", code)
+}
+
+async fn load(env: &Env, code: &str) -> Result<Option<ShortLink>> {
+    let kv = env.kv("NEWSLETTER")?;
+    Ok(kv.get(&key(code)).json().await?)
+}
+
+async fn store(env: &Env, code: &str, link: &ShortLink) -> Result<()> {
+    let kv = env.kv("NEWSLETTER")?;
+    let mut put = kv.put(&key(code), link)?;
+    if let Some(at) = link.expires_at {
+        put = put.expiration(at);
+    }
+    put.execute().await?;
+    Ok(())
+}
+
+fn info(env: &Env, code: String, link: ShortLink) -> LinkInfo {
+    let site_url = env
+        .var("SITE_URL")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "https://lindfors.no".into());
+    LinkInfo {
+        short_url: format!("{}/s/{}", site_url.trim_end_matches('/'), code),
+        code,
+        url: link.url,
+        created_at: dates::rfc3339(link.created_at),
+        expires_at: link.expires_at.map(|at| dates::rfc3339(at * 1000)),
+        clicks: link.clicks,
+    }
+}
+
+fn generate_code() -> Result<String> {
+    let mut bytes = [0u8; CODE_LEN];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    Ok(bytes
+        .iter()
+        .map(|b| CODE_ALPHABET[*b as usize % CODE_ALPHABET.len()] as char)
+        .collect())
+}
+
+/// GET /s/{code} — redirect to the link's target and count the click.
+pub async fn handle_redirect(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let code = ctx.param("code").cloned().unwrap_or_default();
+    let now = worker::Date::now().as_millis();
+    let Some(mut link) = load(&ctx.env, &code).await? else {
+        return error(404, "No such link");
+    };
+    // KV expirations can lag a little behind
+    if link.expired(now) {
+        return error(404, "No such link");
+    }
+    let Ok(target) = Url::parse(&link.url) else {
+        return error(500, "Link has an invalid target");
+    };
+
+    link.clicks += 1;
+    if let Err(e) = store(&ctx.env, &code, &link).await {
+        worker::console_warn!("Failed to count click on {}: {}", code, e);
+    }
+    // Not 301: browsers would cache it and later clicks would go uncounted
+    Response::redirect_with_status(target, 302)
+}
+
+/// POST /api/admin/links — admin: create a short link, with a random code
+/// unless one is given.
+pub async fn handle_create(mut req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let Ok(body) = req.json::<CreateLinkRequest>().await else {
+        return error(400, "Invalid request body — expected {\"url\": \"...\"}");
+    };
+    let url = body.url.trim().to_string();
+    if !Url::parse(&url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")) {
+        return error(400, "url must be an http(s) URL");
+    }
+    if body.expires_in_days == Some(0) {
+        return error(400, "expires_in_days must be at least 1");
+    }
+
+    let code = match body.code.map(|c| c.trim().to_string()) {
+        Some(code) => {
+            if !is_valid_slug(&code) || code.chars().count() > MAX_CODE_CHARS {
+                return error(
+                    400,
+                    "code may only contain a-z, 0-9 and '-' (at most 64 characters)",
+                );
+            }
+            if load(&ctx.env, &code).await?.is_some() {
+                return error(409, "A link with that code already exists");
+            }
+            code
+        }
+        None => loop {
+            let code = generate_code()?;
+            if load(&ctx.env, &code).await?.is_none() {
+                break code;
+            }
+        },
+    };
+
+    let now = worker::Date::now().as_millis();
+    let link = ShortLink {
+        url,
+        created_at: now,
+        expires_at: body
+            .expires_in_days
+            .map(|days| now / 1000 + days * 24 * 60 * 60),
+        clicks: 0,
+    };
+    store(&ctx.env, &code, &link).await?;
+    json_response(&info(&ctx.env, code, link), 201)
+}
+
+/// GET /api/admin/links — admin: every live link, most clicked first.
+pub async fn handle_list(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let now = worker::Date::now().as_millis();
+    let mut links = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix("link:".into());
+        if let Some(c) = cursor {
+            list = list.cursor(c);
+        }
+        let page = list.execute().await?;
+        for listed in page.keys {
+            let code = listed.name.trim_start_matches("link:").to_string();
+            if let Some(link) = load(&ctx.env, &code).await? {
+                if !link.expired(now) {
+                    links.push(info(&ctx.env, code, link));
+                }
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    links.sort_by_key(|l| std::cmp::Reverse(l.clicks));
+
+    #[derive(Serialize)]
+    struct LinksResponse {
+        links: Vec<LinkInfo>,
+    }
+
+    json_response(&LinksResponse { links }, 200)
+}
+
+/// DELETE /api/admin/links/{code} — admin: remove a short link.
+pub async fn handle_delete(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let code = ctx.param("code").cloned().unwrap_or_default();
+    if load(&ctx.env, &code).await?.is_none() {
+        return error(404, "No such link");
+    }
+    ctx.env.kv("NEWSLETTER")?.delete(&key(&code)).await?;
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        200,
+    )
+}
@@ -0,0 +1,47 @@
+//! A sitemap of the pages the worker serves, for search engines. The static
+//! site's own sitemap.xml can't list them, so robots.txt points at this one
+//! too.
+
+use worker::{Request, Response, Result, RouteContext};
+
+use crate::html::escape;
+use crate::logging::RequestLog;
+use crate::{archive, dates, sendlog};
+
+/// Changes only when an issue is sent.
+const CACHE_CONTROL: &str = "public, max-age=3600";
+
+fn url_entry(loc: &str, lastmod_millis: u64) -> String {
+    format!(
+        "  <url>\n    <loc>{}</loc>\n    <lastmod>{}</lastmod>\n  </url>\n",
+        escape(loc),
+        dates::iso_date(lastmod_millis)
+    )
+}
+
+/// GET /sitemap-newsletter.xml — the archive index and every archived issue,
+/// last modified when they were (last) sent.
+pub async fn handle_sitemap(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let site_url = ctx.env.var("SITE_URL")?.to_string();
+    let issues = sendlog::load(&ctx.env).await;
+
+    let mut urls = String::new();
+    if let Some(latest) = issues.iter().map(|issue| issue.sent_at).max() {
+        urls.push_str(&url_entry(&format!("{}/api/archive", site_url), latest));
+    }
+    for issue in &issues {
+        let loc = archive::archive_url(&site_url, &issue.slug);
+        urls.push_str(&url_entry(&loc, issue.sent_at));
+    }
+
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{}</urlset>\n",
+        urls
+    );
+    let mut resp = Response::ok(xml)?;
+    let headers = resp.headers_mut();
+    headers.set("Content-Type", "application/xml; charset=utf-8")?;
+    headers.set("Cache-Control", CACHE_CONTROL)?;
+    Ok(resp)
+}
@@ -0,0 +1,99 @@
+//! The newsletter markdown of each post, as last fetched from the site, kept
+//! under `source:{slug}` in the NEWSLETTER KV namespace with the response's
+//! ETag and Last-Modified. Fetching it again sends those back
+//! (If-None-Match / If-Modified-Since), so an unchanged post answers 304 and
+//! the cached copy is used.
+//!
+//! Each copy carries the SHA-256 of its text, which the send log records
+//! (`content_hash`), so which revision of a post was emailed can be checked
+//! against the post's history.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{Env, Fetch, Headers, Method, Request, RequestInit, Result};
+
+use crate::logging::{self, RequestLog};
+
+#[derive(Serialize, Deserialize)]
+pub struct Source {
+    pub markdown: String,
+    /// Hex SHA-256 of `markdown`.
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+fn key(slug: &str) -> String {
+    format!("source:{}", slug)
+}
+
+fn hash(markdown: &str) -> String {
+    Sha256::digest(markdown.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+async fn cached(env: &Env, slug: &str) -> Option<Source> {
+    let kv = env.kv("NEWSLETTER").ok()?;
+    kv.get(&key(slug)).json().await.ok().flatten()
+}
+
+/// The markdown for `slug` at `url`, or the status the site answered with
+/// instead.
+pub async fn fetch(
+    env: &Env,
+    log: &RequestLog,
+    slug: &str,
+    url: &str,
+) -> Result<std::result::Result<Source, u16>> {
+    let cached = cached(env, slug).await;
+
+    let headers = Headers::new();
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            headers.set("If-None-Match", etag)?;
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            headers.set("If-Modified-Since", last_modified)?;
+        }
+    }
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get);
+    init.with_headers(headers);
+    let req = Request::new_with_init(url, &init)?;
+
+    let started = logging::now_millis();
+    let fetched = Fetch::Request(req).send().await;
+    let status = fetched.as_ref().map(|resp| resp.status_code());
+    log.upstream_status("site", "fetch_markdown", started, &status);
+    let mut resp = fetched?;
+
+    match (resp.status_code(), cached) {
+        (304, Some(cached)) => Ok(Ok(cached)),
+        (200, _) => {
+            let markdown = resp.text().await?;
+            let source = Source {
+                hash: hash(&markdown),
+                markdown,
+                etag: resp.headers().get("ETag")?,
+                last_modified: resp.headers().get("Last-Modified")?,
+            };
+            if let Err(e) = store(env, slug, &source).await {
+                worker::console_warn!("Failed to cache the markdown of {}: {}", slug, e);
+            }
+            Ok(Ok(source))
+        }
+        (status, _) => Ok(Err(status)),
+    }
+}
+
+async fn store(env: &Env, slug: &str, source: &Source) -> Result<()> {
+    env.kv("NEWSLETTER")?
+        .put(&key(slug), source)?
+        .execute()
+        .await?;
+    Ok(())
+}
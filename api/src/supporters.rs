@@ -0,0 +1,299 @@
+//! Paid supporters, through Stripe subscriptions. Stripe's webhook tells the
+//! worker when a subscription starts or ends, and the customer's email is
+//! added to or removed from a separate Stalwart mailing list,
+//! SUPPORTERS_LIST_ID, for supporter-only issues.
+//!
+//! Subscription events only name the customer, so who each customer is is
+//! kept under `supporter:{customer id}` in the NEWSLETTER KV namespace while
+//! they're a supporter. New supporters are also emailed a token for the
+//! supporter-only posts (see premium.rs), which the record keeps current.
+//!
+//! In the Stripe dashboard, point a webhook endpoint at
+//! /api/webhooks/stripe with the `checkout.session.completed`,
+//! `customer.subscription.updated` and `customer.subscription.deleted`
+//! events, and set its signing secret as STRIPE_WEBHOOK_SECRET.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::logging::{self, RequestLog};
+use crate::{auth, breaker, jobs, json_response, premium, sendlog, ApiResponse, StalwartPatchOp};
+
+/// How old a signed event may be, as Stripe's own libraries allow.
+const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+
+/// Largest webhook body taken. Stripe's events are mostly a few kilobytes,
+/// but a checkout session with its line items can be well over the
+/// default body limit.
+pub const MAX_WEBHOOK_BYTES: usize = 1024 * 1024;
+
+/// Subscription statuses after which the customer is no longer a supporter.
+const ENDED_STATUSES: [&str; 3] = ["canceled", "unpaid", "incomplete_expired"];
+
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    data: EventData,
+}
+
+#[derive(Deserialize)]
+struct EventData {
+    object: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Supporter {
+    pub email: String,
+    /// Milliseconds since the Unix epoch.
+    pub since: u64,
+    /// Their current premium.rs token's hash.
+    #[serde(default)]
+    pub token_hash: Option<String>,
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+pub fn supporter_key(customer: &str) -> String {
+    format!("supporter:{}", customer)
+}
+
+/// Whether `header` (`Stripe-Signature: t=...,v1=...`) signs `body` with
+/// `secret`, recently enough. Any of several `v1` signatures may match, as
+/// during a secret rotation.
+fn signature_valid(secret: &str, header: &str, body: &[u8], now_secs: u64) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for item in header.split(',') {
+        match item.trim().split_once('=') {
+            Some(("t", t)) => timestamp = t.parse::<u64>().ok(),
+            Some(("v1", signature)) => signatures.push(signature),
+            _ => {}
+        }
+    }
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    if now_secs.abs_diff(timestamp) > MAX_SIGNATURE_AGE_SECS {
+        return false;
+    }
+
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let expected: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    signatures
+        .iter()
+        .any(|signature| auth::constant_time_eq(signature.as_bytes(), expected.as_bytes()))
+}
+
+/// Add `email` to or remove it from the supporters list.
+async fn update_list(
+    env: &Env,
+    log: &RequestLog,
+    action: &'static str,
+    email: &str,
+) -> Result<bool> {
+    let api_url = env.var("STALWART_API_URL")?.to_string();
+    let api_key = env.secret("STALWART_API_KEY")?.to_string();
+    let list_id = env.var("SUPPORTERS_LIST_ID")?.to_string();
+    // Failing has Stripe retry the event later
+    if breaker::check(crate::STALWART).is_err() {
+        return Ok(false);
+    }
+    let ops = [StalwartPatchOp {
+        action,
+        field: "externalMembers",
+        value: email.to_string(),
+    }];
+
+    let started = logging::now_millis();
+    let result = crate::stalwart_patch(&api_url, &api_key, &list_id, &ops).await;
+    let operation = if action == "addItem" {
+        "add_supporter"
+    } else {
+        "remove_supporter"
+    };
+    log.upstream_status("stalwart", operation, started, &result);
+    Ok(matches!(result, Ok(status) if status < 300))
+}
+
+/// A completed checkout for a subscription: the customer becomes a
+/// supporter.
+async fn checkout_completed(env: &Env, log: &RequestLog, session: &Value) -> Result<bool> {
+    if session["mode"] != "subscription" {
+        return Ok(true);
+    }
+    let Some(customer) = session["customer"].as_str() else {
+        return Ok(true);
+    };
+    let email = session["customer_details"]["email"]
+        .as_str()
+        .or_else(|| session["customer_email"].as_str())
+        .map(|e| e.trim().to_lowercase());
+    let Some(email) = email.filter(|e| crate::is_valid_email(e)) else {
+        worker::console_warn!("Stripe checkout for {} has no usable email", customer);
+        return Ok(true);
+    };
+
+    let added = update_list(env, log, "addItem", &email).await?;
+    log.event("supporter_join", added);
+    if !added {
+        return Ok(false);
+    }
+    // A retried event issues a new token, which replaces the old one
+    let (token, token_hash) = premium::issue_token(env, customer).await?;
+    let supporter = Supporter {
+        email,
+        since: sendlog::now_millis(),
+        token_hash: Some(token_hash),
+    };
+    env.kv("NEWSLETTER")?
+        .put(&supporter_key(customer), &supporter)?
+        .execute()
+        .await?;
+    send_welcome(env, &supporter.email, &token).await
+}
+
+/// Email a new supporter their link to the supporter-only posts, as a
+/// background job (see jobs.rs), so the webhook answers without waiting on
+/// JMAP. Only failing to queue it has Stripe retry the event.
+async fn send_welcome(env: &Env, email: &str, token: &str) -> Result<bool> {
+    let site_url = env.var("SITE_URL")?.to_string();
+    let job = jobs::Job::Email {
+        to: email.to_string(),
+        subject: "Thank you for supporting lindfors.no".into(),
+        html: premium::welcome_email(&site_url, token),
+        operation: "send_welcome".into(),
+    };
+    match jobs::enqueue(env, &job).await {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            worker::console_error!("Failed to queue the welcome email: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// A subscription that has ended: the customer stops being a supporter.
+async fn subscription_ended(env: &Env, log: &RequestLog, subscription: &Value) -> Result<bool> {
+    let Some(customer) = subscription["customer"].as_str() else {
+        return Ok(true);
+    };
+    let kv = env.kv("NEWSLETTER")?;
+    let key = supporter_key(customer);
+    let Some(supporter) = kv.get(&key).json::<Supporter>().await? else {
+        // Not a supporter, or already removed
+        return Ok(true);
+    };
+
+    let removed = update_list(env, log, "removeItem", &supporter.email).await?;
+    log.event("supporter_leave", removed);
+    if removed {
+        // Without the record their token stops working
+        kv.delete(&key).await?;
+    }
+    Ok(removed)
+}
+
+/// POST /api/webhooks/stripe — Stripe's webhook. Answers 502 when the list
+/// couldn't be updated, so Stripe retries the event.
+pub async fn handle_stripe_webhook(
+    mut req: Request,
+    ctx: RouteContext<RequestLog>,
+) -> Result<Response> {
+    let Ok(secret) = ctx.env.secret("STRIPE_WEBHOOK_SECRET") else {
+        return error(503, "Stripe webhooks aren't configured");
+    };
+    let signature = req.headers().get("Stripe-Signature")?.unwrap_or_default();
+    let body = req.bytes().await?;
+    if body.len() > MAX_WEBHOOK_BYTES {
+        return error(413, "Event too large");
+    }
+    let now_secs = logging::now_millis() / 1000;
+    if !signature_valid(&secret.to_string(), &signature, &body, now_secs) {
+        return error(400, "Invalid Stripe-Signature");
+    }
+    let Ok(event) = serde_json::from_slice::<Event>(&body) else {
+        return error(400, "Malformed event");
+    };
+
+    let object = &event.data.object;
+    let handled = match event.kind.as_str() {
+        "checkout.session.completed" => checkout_completed(&ctx.env, &ctx.data, object).await?,
+        "customer.subscription.deleted" => subscription_ended(&ctx.env, &ctx.data, object).await?,
+        "customer.subscription.updated"
+            if object["status"]
+                .as_str()
+                .is_some_and(|s| ENDED_STATUSES.contains(&s)) =>
+        {
+            subscription_ended(&ctx.env, &ctx.data, object).await?
+        }
+        _ => true,
+    };
+    if !handled {
+        return error(502, "Updating the supporters list failed");
+    }
+    json_response(
+        &ApiResponse {
+            success: true,
+            error: None,
+        },
+        200,
+    )
+}
+
+/// GET /api/admin/supporters — admin: list the supporters list's members.
+pub async fn handle_list(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let api_url = ctx.env.var("STALWART_API_URL")?.to_string();
+    let api_key = ctx.env.secret("STALWART_API_KEY")?.to_string();
+    let list_id = ctx.env.var("SUPPORTERS_LIST_ID")?.to_string();
+    if let Err(retry_after) = breaker::check(crate::STALWART) {
+        return breaker::unavailable(retry_after);
+    }
+
+    let started = logging::now_millis();
+    let result = crate::stalwart_get_members(&api_url, &api_key, &list_id).await;
+    let error = result.as_ref().err().map(|e| e.to_string());
+    ctx.data.upstream(
+        "stalwart",
+        "list_supporters",
+        started,
+        None,
+        error.as_deref(),
+    );
+    let members = result?;
+
+    #[derive(Serialize)]
+    struct ListResponse {
+        total: usize,
+        members: Vec<String>,
+    }
+
+    json_response(
+        &ListResponse {
+            total: members.len(),
+            members,
+        },
+        200,
+    )
+}
@@ -0,0 +1,174 @@
+//! Page view counts, without cookies or stored personal data. The site
+//! beacons POST /api/views/{slug} (with an empty body) on each post view;
+//! `views:{slug}` in the NEWSLETTER KV namespace holds the total and per-day
+//! counts.
+//!
+//! A client counts once per post per day. Its fingerprint (see
+//! [`client_fingerprint`]) includes the date, so it can't be linked across
+//! days, and the marker expires with the day.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{Env, Request, Response, Result, RouteContext};
+
+use crate::logging::RequestLog;
+use crate::{client_fingerprint, dates, is_valid_slug, json_response, ApiResponse};
+
+/// Days of per-day counts kept per post.
+const KEEP_DAYS: usize = 90;
+
+/// Slightly over a day, since a client's day and the worker's may straddle.
+const DEDUP_TTL_SECS: u64 = 2 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Default)]
+struct ViewCounts {
+    total: u64,
+    /// `YYYY-MM-DD` (UTC) to that day's views, the latest [`KEEP_DAYS`] days.
+    #[serde(default)]
+    days: BTreeMap<String, u64>,
+}
+
+impl ViewCounts {
+    /// Views over the last `days` days that have counts, including today.
+    fn recent(&self, days: usize) -> u64 {
+        self.days.values().rev().take(days).sum()
+    }
+}
+
+fn error(status: u16, message: &str) -> Result<Response> {
+    json_response(
+        &ApiResponse {
+            success: false,
+            error: Some(message.into()),
+        },
+        status,
+    )
+}
+
+fn key(slug: &str) -> String {
+    format!("views:{}", slug)
+}
+
+async fn load(env: &Env, slug: &str) -> Result<ViewCounts> {
+    let kv = env.kv("NEWSLETTER")?;
+    Ok(kv.get(&key(slug)).json().await?.unwrap_or_default())
+}
+
+#[derive(Serialize)]
+struct ViewsResponse {
+    slug: String,
+    total: u64,
+    last_7_days: u64,
+}
+
+fn views_response(slug: String, counts: &ViewCounts) -> Result<Response> {
+    json_response(
+        &ViewsResponse {
+            slug,
+            total: counts.total,
+            last_7_days: counts.recent(7),
+        },
+        200,
+    )
+}
+
+/// GET /api/views/{slug} — the post's view count.
+pub async fn handle_count(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+    let counts = load(&ctx.env, &slug).await?;
+    views_response(slug, &counts)
+}
+
+/// POST /api/views/{slug} — count a view.
+pub async fn handle_view(req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let slug = ctx.param("slug").cloned().unwrap_or_default();
+    if !is_valid_slug(&slug) {
+        return error(400, "Invalid slug");
+    }
+
+    let today = dates::iso_date(worker::Date::now().as_millis());
+    let kv = ctx.env.kv("NEWSLETTER")?;
+    let marker = format!(
+        "viewed:{}:{}",
+        slug,
+        client_fingerprint(&req, &format!("views:{}", today))
+    );
+    let mut counts = load(&ctx.env, &slug).await?;
+    if kv.get(&marker).text().await?.is_some() {
+        return views_response(slug, &counts);
+    }
+
+    counts.total += 1;
+    *counts.days.entry(today).or_insert(0) += 1;
+    while counts.days.len() > KEEP_DAYS {
+        counts.days.pop_first();
+    }
+    kv.put(&key(&slug), &counts)?.execute().await?;
+    kv.put(&marker, "1")?
+        .expiration_ttl(DEDUP_TTL_SECS)
+        .execute()
+        .await?;
+    views_response(slug, &counts)
+}
+
+/// Every post's counts, in no particular order.
+async fn all_counts(env: &Env) -> Result<Vec<(String, ViewCounts)>> {
+    let kv = env.kv("NEWSLETTER")?;
+    let mut posts = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix("views:".into());
+        if let Some(c) = cursor {
+            list = list.cursor(c);
+        }
+        let page = list.execute().await?;
+        for listed in page.keys {
+            let slug = listed.name.trim_start_matches("views:").to_string();
+            let counts = load(env, &slug).await?;
+            posts.push((slug, counts));
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    Ok(posts)
+}
+
+/// The `limit` posts most viewed over the last 7 days, with those views.
+pub async fn top_last_7_days(env: &Env, limit: usize) -> Result<Vec<(String, u64)>> {
+    let mut posts: Vec<(String, u64)> = all_counts(env)
+        .await?
+        .into_iter()
+        .map(|(slug, counts)| (slug, counts.recent(7)))
+        .filter(|(_, views)| *views > 0)
+        .collect();
+    posts.sort_by_key(|(_, views)| std::cmp::Reverse(*views));
+    posts.truncate(limit);
+    Ok(posts)
+}
+
+/// GET /api/admin/views — admin: every post's counts, most viewed first.
+pub async fn handle_popular(_req: Request, ctx: RouteContext<RequestLog>) -> Result<Response> {
+    let mut posts: Vec<ViewsResponse> = all_counts(&ctx.env)
+        .await?
+        .into_iter()
+        .map(|(slug, counts)| ViewsResponse {
+            slug,
+            total: counts.total,
+            last_7_days: counts.recent(7),
+        })
+        .collect();
+    posts.sort_by_key(|p| std::cmp::Reverse(p.total));
+
+    #[derive(Serialize)]
+    struct PopularResponse {
+        posts: Vec<ViewsResponse>,
+    }
+
+    json_response(&PopularResponse { posts }, 200)
+}
@@ -17,22 +17,30 @@ if [ -z "$ADMIN_KEY" ]; then
   exit 1
 fi
 
+FORCE=false
+if [ "${1:-}" = "--force" ]; then
+  FORCE=true
+  shift
+fi
+
 SLUG="${1:-}"
 if [ -z "$SLUG" ]; then
-  echo "Usage: $0 <slug> [subject]"
+  echo "Usage: $0 [--force] <slug> [subject]"
   echo "Example: $0 aquaculture-innovation"
+  echo "  --force  send even if the issue is marked draft: true"
   exit 1
 fi
 
 SUBJECT="${2:-}"
 if [ -n "$SUBJECT" ]; then
-  BODY=$(printf '{"slug":"%s","subject":"%s"}' "$SLUG" "$SUBJECT")
+  BODY=$(printf '{"slug":"%s","subject":"%s","force":%s}' "$SLUG" "$SUBJECT" "$FORCE")
 else
-  BODY=$(printf '{"slug":"%s"}' "$SLUG")
+  BODY=$(printf '{"slug":"%s","force":%s}' "$SLUG" "$FORCE")
 fi
 
 echo "Newsletter: $SLUG"
 [ -n "$SUBJECT" ] && echo "Subject override: $SUBJECT"
+[ "$FORCE" = true ] && echo "Forcing send of a draft"
 echo ""
 read -rp "Send to all subscribers? [y/N] " confirm
 if [ "$confirm" != "y" ] && [ "$confirm" != "Y" ]; then
@@ -40,7 +48,17 @@ if [ "$confirm" != "y" ] && [ "$confirm" != "Y" ]; then
   exit 0
 fi
 
+# Sign the request (see api/src/auth.rs): HMAC-SHA256 over
+# method, path, timestamp and body, each on its own line
+API_PATH="/api/v1/send-newsletter"
+TIMESTAMP=$(date +%s)
+SIGNATURE=$(printf 'POST\n%s\n%s\n%s' "$API_PATH" "$TIMESTAMP" "$BODY" \
+  | openssl dgst -sha256 -hmac "$ADMIN_KEY" -hex | sed 's/^.*= //')
+
 echo "Sending..."
-curl -s -X POST "https://lindfors.no/api/send-newsletter?key=$ADMIN_KEY" \
+curl -s -X POST "https://lindfors.no${API_PATH}" \
+  -H "Authorization: Bearer $ADMIN_KEY" \
+  -H "X-Signature-Timestamp: $TIMESTAMP" \
+  -H "X-Signature: $SIGNATURE" \
   -H 'Content-Type: application/json' \
   -d "$BODY" | python3 -m json.tool
@@ -0,0 +1,56 @@
+#!/usr/bin/env bash
+#
+# Update the /now page's status (api/src/now.rs) without rebuilding the site.
+#
+# Usage: ./scripts/update-now.sh "<status>" ["<reading>"] ["<listening>"]
+# Example: ./scripts/update-now.sh "Writing up the sensor results" "Piranesi"
+#
+# Leaving out reading or listening clears it. Needs ADMIN_KEY in .env.
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "$0")" && pwd)"
+ROOT_DIR="$(dirname "$SCRIPT_DIR")"
+ENV_FILE="$ROOT_DIR/.env"
+
+if [ ! -f "$ENV_FILE" ]; then
+  echo "Error: .env file not found at $ENV_FILE"
+  exit 1
+fi
+
+ADMIN_KEY=$(grep -E '^ADMIN_KEY=' "$ENV_FILE" | cut -d'=' -f2-)
+
+if [ -z "$ADMIN_KEY" ]; then
+  echo "Error: ADMIN_KEY not set in .env"
+  exit 1
+fi
+
+if [ -z "${1:-}" ]; then
+  echo "Usage: $0 \"<status>\" [\"<reading>\"] [\"<listening>\"]"
+  exit 1
+fi
+
+# python3 for the JSON quoting
+BODY=$(python3 -c '
+import json, sys
+args = sys.argv[1:]
+body = {"status": args[0]}
+for key, value in zip(["reading", "listening"], args[1:]):
+    if value:
+        body[key] = value
+print(json.dumps(body, ensure_ascii=False), end="")
+' "$@")
+
+# Sign the request (see api/src/auth.rs): HMAC-SHA256 over
+# method, path, timestamp and body, each on its own line
+API_PATH="/api/v1/now"
+TIMESTAMP=$(date +%s)
+SIGNATURE=$(printf 'PUT\n%s\n%s\n%s' "$API_PATH" "$TIMESTAMP" "$BODY" \
+  | openssl dgst -sha256 -hmac "$ADMIN_KEY" -hex | sed 's/^.*= //')
+
+curl -s -X PUT "https://lindfors.no${API_PATH}" \
+  -H "Authorization: Bearer $ADMIN_KEY" \
+  -H "X-Signature-Timestamp: $TIMESTAMP" \
+  -H "X-Signature: $SIGNATURE" \
+  -H 'Content-Type: application/json' \
+  -d "$BODY" | python3 -m json.tool
@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+#
+# Upload a supporter-only post for GET /api/premium/{slug}
+# (api/src/premium.rs).
+#
+# Usage: ./scripts/upload-premium.sh path/to/<slug>.md
+#
+# The file is newsletter markdown, with YAML (---) or TOML (+++)
+# frontmatter, like the output of generate-newsletter.sh, but kept out of
+# static/ so it isn't on the public site. It's stored under
+# `premium:post:<slug>` in the NEWSLETTER KV namespace, with its title as
+# the key's metadata for the supporters' index.
+
+set -euo pipefail
+
+if [ $# -lt 1 ]; then
+    echo "Usage: $0 <path-to-markdown>"
+    exit 1
+fi
+
+INPUT="$1"
+
+if [ ! -f "$INPUT" ]; then
+    echo "Error: File not found: $INPUT"
+    exit 1
+fi
+
+SLUG=$(basename "$INPUT" .md)
+if ! [[ "$SLUG" =~ ^[a-z0-9-]+$ ]]; then
+    echo "Error: slug must be lowercase letters, digits and hyphens: $SLUG"
+    exit 1
+fi
+
+TITLE=$(awk '/^(---|\+\+\+)$/{n++; next} n==1{print}' "$INPUT" | grep '^title' | head -1 \
+    | sed 's/^title *[:=] *//; s/^"//; s/"$//')
+METADATA=$(python3 -c 'import json, sys; print(json.dumps({"title": sys.argv[1] or sys.argv[2]}))' "$TITLE" "$SLUG")
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+INPUT="$(cd "$(dirname "$INPUT")" && pwd)/$(basename "$INPUT")"
+cd "$SCRIPT_DIR/../api"
+npx wrangler kv key put --binding NEWSLETTER --remote "premium:post:${SLUG}" \
+    --path "$INPUT" --metadata "$METADATA"
+
+echo "Uploaded: ${SLUG} (${TITLE:-untitled})"
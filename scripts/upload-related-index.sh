@@ -0,0 +1,50 @@
+#!/usr/bin/env bash
+#
+# Upload the posts' tags and categories for GET /api/related/{slug}
+# (api/src/related.rs).
+#
+# Usage: ./scripts/upload-related-index.sh
+#
+# Reads the TOML frontmatter of content/blog/*/index.md (drafts left out)
+# and stores [{slug, url, title, description, date, tags, categories}]
+# under `related:index` in the NEWSLETTER KV namespace. Needs Python 3.11+
+# for tomllib.
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+ROOT_DIR="$(dirname "$SCRIPT_DIR")"
+OUT="$(mktemp)"
+trap 'rm -f "$OUT"' EXIT
+
+python3 - "$ROOT_DIR" > "$OUT" <<'PY'
+import json, pathlib, sys, tomllib
+
+root = pathlib.Path(sys.argv[1])
+base_url = tomllib.loads((root / "zola.toml").read_text())["base_url"].rstrip("/")
+
+posts = []
+for path in sorted(root.glob("content/blog/*/index.md")):
+    text = path.read_text()
+    if not text.startswith("+++"):
+        continue
+    front = tomllib.loads(text.split("+++", 2)[1])
+    if front.get("draft", False):
+        continue
+    taxonomies = front.get("taxonomies", {})
+    slug = path.parent.name
+    posts.append({
+        "slug": slug,
+        "url": f"{base_url}/blog/{slug}/",
+        "title": front.get("title", slug),
+        "description": front.get("description", ""),
+        "date": str(front.get("date", "")),
+        "tags": taxonomies.get("tags", []),
+        "categories": taxonomies.get("categories", []),
+    })
+json.dump(posts, sys.stdout)
+PY
+
+echo "Uploading $(jq length "$OUT") posts..."
+cd "$ROOT_DIR/api"
+npx wrangler kv key put --binding NEWSLETTER --remote related:index --path "$OUT"
@@ -0,0 +1,35 @@
+#!/usr/bin/env bash
+#
+# Upload the site's search documents for GET /api/search (api/src/search.rs).
+#
+# Usage: zola build && ./scripts/upload-search-index.sh
+#
+# Reads the elasticlunr index Zola writes to public/search_index.en.js
+# (build_search_index = true) and stores its documents, as
+# [{url, title, description, body}], under `search:index` in the
+# NEWSLETTER KV namespace.
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+INDEX="$SCRIPT_DIR/../public/search_index.en.js"
+OUT="$(mktemp)"
+trap 'rm -f "$OUT"' EXIT
+
+if [ ! -f "$INDEX" ]; then
+    echo "Error: $INDEX not found — run 'zola build' first"
+    exit 1
+fi
+
+# Strip the `window.searchIndex = ...;` wrapper to get plain JSON
+sed -e '1s/^[^{]*//' -e '$s/;[[:space:]]*$//' "$INDEX" \
+    | jq '[.documentStore.docs | to_entries[] | {
+            url: .key,
+            title: (.value.title // ""),
+            description: (.value.description // ""),
+            body: (.value.body // "")
+        }]' > "$OUT"
+
+echo "Uploading $(jq length "$OUT") documents..."
+cd "$SCRIPT_DIR/../api"
+npx wrangler kv key put --binding NEWSLETTER --remote search:index --path "$OUT"
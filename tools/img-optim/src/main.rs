@@ -1,10 +1,56 @@
-use image::GenericImageView;
+use image::{GenericImageView, ImageDecoder};
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
 use std::{env, fs, process};
 
 const THUMB_WIDTH: u32 = 600;
 const THUMB_QUALITY: f32 = 75.0;
 const THUMB_SUFFIX: &str = "-thumb";
+/// How long a watched file must go unchanged before it's converted, so a
+/// file still being copied in isn't read half-written.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+struct Options {
+    max_width: u32,
+    quality: f32,
+    /// `--target-size`: lower the quality until the main output fits.
+    target_size: Option<u64>,
+    /// `--metric ssim`: score each main output against its source.
+    metric: bool,
+    /// Raise the quality until the score is at least this.
+    min_score: Option<f64>,
+    thumbnails: bool,
+    crop: Option<Crop>,
+    watermark: Option<Watermark>,
+    compression: Compression,
+    exif: ExifMode,
+    manifest: Option<PathBuf>,
+    /// `--update-md`: the directory of markdown to point at the outputs.
+    update_md: Option<PathBuf>,
+    /// Rewrite references as `<picture>` elements rather than plain links.
+    md_picture: bool,
+    /// What becomes of a source once its outputs check out.
+    originals: Originals,
+    /// `--minify-svg`: rewrite SVGs in place. Otherwise they're left alone.
+    minify_svg: bool,
+    /// Convert files even when their outputs are newer than they are.
+    force: bool,
+    /// Encode in memory only, to report what a run would do.
+    dry_run: bool,
+    verbosity: Verbosity,
+}
+
+impl Options {
+    /// The manifest to update, none on a dry run.
+    fn manifest(&self) -> Option<&Path> {
+        self.manifest.as_deref().filter(|_| !self.dry_run)
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -12,7 +58,26 @@ fn main() {
     let mut paths = Vec::new();
     let mut max_width: u32 = 1200;
     let mut quality: f32 = 80.0;
+    let mut target_size = None;
+    let mut metric = false;
+    let mut min_score = None;
     let mut thumbnails = false;
+    let mut watch_mode = false;
+    let mut crop_ratio = None;
+    let mut focus = None;
+    let mut watermark = None;
+    let mut position = None;
+    let mut opacity = None;
+    let mut compression = Compression::Auto;
+    let mut exif = ExifMode::Strip;
+    let mut manifest = None;
+    let mut update_md = None;
+    let mut md_picture = false;
+    let mut originals = Originals::Keep;
+    let mut minify_svg = false;
+    let mut force = false;
+    let mut dry_run = false;
+    let mut verbosity = Verbosity::Normal;
 
     let mut i = 1;
     while i < args.len() {
@@ -25,9 +90,101 @@ fn main() {
                 i += 1;
                 quality = args[i].parse().expect("invalid quality (0-100)");
             }
+            "--target-size" => {
+                i += 1;
+                target_size = Some(parse_size(&args[i]).expect("invalid target size, e.g. 200KB"));
+            }
+            "--metric" => {
+                i += 1;
+                if args[i] != "ssim" {
+                    eprintln!("Unsupported metric {} (supported: ssim)", args[i]);
+                    process::exit(1);
+                }
+                metric = true;
+            }
+            "--min-quality-score" => {
+                i += 1;
+                min_score = Some(args[i].parse().expect("invalid score (0-1)"));
+                metric = true;
+            }
             "-t" | "--thumbnails" => {
                 thumbnails = true;
             }
+            "--crop" => {
+                i += 1;
+                crop_ratio = Some(parse_ratio(&args[i]).expect("invalid crop ratio, e.g. 16:9"));
+            }
+            "--focus" => {
+                i += 1;
+                focus = Some(parse_focus(&args[i]).expect("invalid focus point, e.g. 0.5,0.3"));
+            }
+            "--watermark" => {
+                i += 1;
+                watermark = Some(PathBuf::from(&args[i]));
+            }
+            "--position" => {
+                i += 1;
+                position = Some(Position::parse(&args[i]).expect(
+                    "invalid position (top-left, top-right, bottom-left, bottom-right, center)",
+                ));
+            }
+            "--opacity" => {
+                i += 1;
+                opacity = Some(parse_opacity(&args[i]).expect("invalid opacity (0-1)"));
+            }
+            "--lossy" => {
+                compression = Compression::Lossy;
+            }
+            "--lossless" => {
+                compression = Compression::Lossless;
+            }
+            "--auto" => {
+                compression = Compression::Auto;
+            }
+            "--strip-exif" => {
+                exif = ExifMode::Strip;
+            }
+            "--keep-exif" => {
+                exif = ExifMode::Keep;
+            }
+            "--keep-copyright" => {
+                exif = ExifMode::KeepCopyright;
+            }
+            "--manifest" => {
+                i += 1;
+                manifest = Some(PathBuf::from(args.get(i).expect("missing manifest path")));
+            }
+            "--update-md" => {
+                i += 1;
+                update_md = Some(PathBuf::from(&args[i]));
+            }
+            "--md-picture" => {
+                md_picture = true;
+            }
+            "--minify-svg" => {
+                minify_svg = true;
+            }
+            "--delete-originals" => {
+                originals = Originals::Delete;
+            }
+            "--move-originals" => {
+                originals = Originals::Move;
+            }
+            "--force" => {
+                force = true;
+            }
+            "-n" | "--dry-run" => {
+                dry_run = true;
+            }
+            "--quiet" => {
+                verbosity = Verbosity::Quiet;
+            }
+            "-v" | "--verbose" => {
+                verbosity = Verbosity::Verbose;
+            }
+            "--watch" => {
+                watch_mode = true;
+            }
             "-h" | "--help" => {
                 print_usage();
                 return;
@@ -45,57 +202,216 @@ fn main() {
         print_usage();
         process::exit(1);
     }
+    if md_picture && update_md.is_none() {
+        eprintln!("--md-picture only applies with --update-md");
+        process::exit(1);
+    }
+    if focus.is_some() && crop_ratio.is_none() {
+        eprintln!("--focus only applies with --crop");
+        process::exit(1);
+    }
+    if (position.is_some() || opacity.is_some()) && watermark.is_none() {
+        eprintln!("--position and --opacity only apply with --watermark");
+        process::exit(1);
+    }
+    if dry_run && watch_mode {
+        eprintln!("--dry-run and --watch can't be combined");
+        process::exit(1);
+    }
+
+    let opts = Options {
+        max_width,
+        quality,
+        target_size,
+        metric,
+        min_score,
+        thumbnails,
+        crop: crop_ratio.map(|ratio| Crop {
+            ratio,
+            focus: focus.unwrap_or((0.5, 0.5)),
+        }),
+        watermark: watermark.map(|path| {
+            let logo = open_upright(&path).unwrap_or_else(|e| {
+                eprintln!("Can't read watermark {}: {e}", path.display());
+                process::exit(1);
+            });
+            Watermark {
+                logo: logo.to_rgba8(),
+                position: position.unwrap_or(Position::BottomRight),
+                opacity: opacity.unwrap_or(1.0),
+            }
+        }),
+        compression,
+        exif,
+        manifest,
+        update_md,
+        md_picture,
+        originals,
+        minify_svg,
+        force,
+        dry_run,
+        verbosity,
+    };
 
-    let files = collect_files(&paths);
-    if files.is_empty() {
-        eprintln!("No convertible images found (jpg, jpeg, png, gif, bmp, tiff)");
+    let files: Vec<PathBuf> = collect_files(&paths)
+        .into_iter()
+        .filter(|file| opts.minify_svg || !is_svg(file))
+        .collect();
+    if files.is_empty() && !watch_mode {
+        eprintln!(
+            "No convertible images found (jpg, jpeg, png, gif, bmp, tiff; svg with --minify-svg)"
+        );
         process::exit(1);
     }
+    process_files(&files, &opts);
 
+    if watch_mode {
+        if let Err(e) = watch(&paths, &opts) {
+            eprintln!("Watch failed: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn process_files(files: &[PathBuf], opts: &Options) {
     let mut total_before: u64 = 0;
     let mut total_after: u64 = 0;
+    let mut entries = Vec::new();
+    // Each source with outputs, and those outputs
+    let mut converted = Vec::new();
+    let mut skipped = 0;
+
+    if opts.dry_run {
+        println!("  Dry run: nothing is written, sizes are what the outputs would be");
+    }
+    let progress = Progress::new(files.len(), opts.verbosity);
+    for file in files {
+        progress.start(file);
+        // SVGs are minified in place, so have no output to be up to date
+        // and no entry in the manifest
+        if is_svg(file) {
+            match minify_svg_file(file, opts.dry_run) {
+                Ok((before, after)) => {
+                    let saved = 100.0 - (after as f64 / before as f64 * 100.0);
+                    progress.file(format!(
+                        "  {} ({} -> {}, -{:.0}%, minified)",
+                        file.display(),
+                        fmt_size(before),
+                        fmt_size(after),
+                        saved,
+                    ));
+                    total_before += before;
+                    total_after += after;
+                }
+                Err(e) => progress.error(format!("  ERROR {}: {e}", file.display())),
+            }
+            continue;
+        }
+        if !opts.force && is_up_to_date(file, opts) {
+            skipped += 1;
+            progress.detail(format!("  {} is up to date", file.display()));
+            if let Some(manifest) = opts.manifest() {
+                match manifest_entry(manifest, file, &expected_outputs(file, opts), opts.crop) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => progress.error(format!("  MANIFEST ERROR {}: {e}", file.display())),
+                }
+            }
+            converted.push((file.clone(), expected_outputs(file, opts)));
+            continue;
+        }
 
-    for file in &files {
         let result = if is_animated_gif(file) {
-            optimize_animated_gif(file, max_width, quality)
+            let choice = opts.compression.choose_animated();
+            optimize_animated_gif(
+                file,
+                opts.max_width,
+                opts.crop,
+                opts.watermark.as_ref(),
+                opts.quality,
+                choice,
+                opts.dry_run,
+            )
         } else {
-            optimize(file, max_width, quality)
+            optimize(file, opts)
         };
 
         match result {
-            Ok((before, after, out)) => {
+            Ok((before, after, out, choice)) => {
                 let saved = 100.0 - (after as f64 / before as f64 * 100.0);
-                println!(
-                    "  {} -> {} ({} -> {}, -{:.0}%)",
+                progress.file(format!(
+                    "  {} -> {} ({} -> {}, -{:.0}%, {})",
                     file.display(),
                     out.file_name().unwrap().to_string_lossy(),
                     fmt_size(before),
                     fmt_size(after),
                     saved,
-                );
+                    choice,
+                ));
+                progress.detail(settings(
+                    file,
+                    opts.crop,
+                    opts.max_width,
+                    choice.quality.unwrap_or(opts.quality),
+                    choice,
+                ));
+                if let (Some(min), Some(score)) = (opts.min_score, choice.score) {
+                    if score < min {
+                        progress.error(format!(
+                            "  WARNING {}: SSIM {score:.4} is under {min} at the highest quality tried",
+                            file.display()
+                        ));
+                    }
+                }
+                if opts.target_size.is_some_and(|target| after > target) {
+                    progress.error(format!(
+                        "  WARNING {}: over the target size even at quality {TARGET_MIN_QUALITY}",
+                        file.display()
+                    ));
+                }
                 total_before += before;
                 total_after += after;
+                // Each output with the width it was fitted to
+                let mut outputs = vec![(out, opts.max_width)];
 
-                if thumbnails && !is_animated_gif(file) {
-                    match thumbnail(file, THUMB_WIDTH, THUMB_QUALITY) {
+                if opts.thumbnails && !is_animated_gif(file) {
+                    match thumbnail(file, THUMB_WIDTH, THUMB_QUALITY, choice.lossless, opts) {
                         Ok((sz, thumb_path)) => {
-                            println!(
+                            progress.file(format!(
                                 "  {} -> {} ({})",
                                 file.display(),
                                 thumb_path.file_name().unwrap().to_string_lossy(),
                                 fmt_size(sz),
-                            );
+                            ));
+                            progress.detail(settings(
+                                file,
+                                opts.crop,
+                                THUMB_WIDTH,
+                                THUMB_QUALITY,
+                                choice,
+                            ));
                             total_after += sz;
+                            outputs.push((thumb_path, THUMB_WIDTH));
+                        }
+                        Err(e) => progress.error(format!("  THUMB ERROR {}: {e}", file.display())),
+                    }
+                }
+
+                if let Some(manifest) = opts.manifest() {
+                    match manifest_entry(manifest, file, &outputs, opts.crop) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => {
+                            progress.error(format!("  MANIFEST ERROR {}: {e}", file.display()))
                         }
-                        Err(e) => eprintln!("  THUMB ERROR {}: {e}", file.display()),
                     }
                 }
+                converted.push((file.clone(), outputs));
             }
-            Err(e) => eprintln!("  ERROR {}: {e}", file.display()),
+            Err(e) => progress.error(format!("  ERROR {}: {e}", file.display())),
         }
     }
+    progress.finish();
 
-    if files.len() > 1 {
+    if files.len() - skipped > 1 {
         let saved = 100.0 - (total_after as f64 / total_before as f64 * 100.0);
         println!(
             "\n  Total: {} -> {} (-{:.0}%)",
@@ -104,179 +420,1977 @@ fn main() {
             saved,
         );
     }
+    if skipped > 0 {
+        println!("  Skipped {skipped} up-to-date file(s), --force to convert them anyway");
+    }
+
+    if let Some(manifest) = opts.manifest() {
+        if let Err(e) = update_manifest(manifest, entries) {
+            eprintln!("  MANIFEST ERROR {}: {e}", manifest.display());
+        }
+    }
+    if let Some(dir) = &opts.update_md {
+        if let Err(e) = update_markdown(dir, &converted, opts) {
+            eprintln!("  MARKDOWN ERROR {}: {e}", dir.display());
+        }
+    }
+    // Last, as the manifest and markdown read the sources
+    if opts.originals != Originals::Keep {
+        remove_originals(&converted, opts);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Progress and verbosity
+// ---------------------------------------------------------------------------
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Verbosity {
+    /// Only errors and the totals.
+    Quiet,
+    /// A line per output.
+    Normal,
+    /// Also dimensions and encoder settings.
+    Verbose,
+}
+
+/// Output for one run over a list of files: a progress bar naming the
+/// file being converted (unless quiet, or for a single file), with the
+/// per-file lines printed above it.
+struct Progress {
+    bar: indicatif::ProgressBar,
+    verbosity: Verbosity,
+}
+
+impl Progress {
+    fn new(files: usize, verbosity: Verbosity) -> Self {
+        let bar = if verbosity == Verbosity::Quiet || files < 2 {
+            indicatif::ProgressBar::hidden()
+        } else {
+            let style =
+                indicatif::ProgressStyle::with_template("  {bar:30} {pos}/{len} {wide_msg}")
+                    .expect("valid progress template");
+            indicatif::ProgressBar::new(files as u64).with_style(style)
+        };
+        Progress { bar, verbosity }
+    }
+
+    /// Show `file` as the one being worked on, counting the one before it
+    /// as done.
+    fn start(&self, file: &Path) {
+        if !self.bar.message().is_empty() {
+            self.bar.inc(1);
+        }
+        self.bar.set_message(file.display().to_string());
+    }
+
+    fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    /// A line about a file's outputs, unless quiet.
+    fn file(&self, line: String) {
+        if self.verbosity >= Verbosity::Normal {
+            self.bar.suspend(|| println!("{line}"));
+        }
+    }
+
+    /// A line of detail, only when verbose.
+    fn detail(&self, line: String) {
+        if self.verbosity >= Verbosity::Verbose {
+            self.bar.suspend(|| println!("{line}"));
+        }
+    }
+
+    fn error(&self, line: String) {
+        self.bar.suspend(|| eprintln!("{line}"));
+    }
+}
+
+/// The `--verbose` line for an output of `file` fitted to `max_width`.
+fn settings(
+    file: &Path,
+    crop: Option<Crop>,
+    max_width: u32,
+    quality: f32,
+    choice: Choice,
+) -> String {
+    let encoding = if choice.lossless {
+        "lossless".to_string()
+    } else {
+        format!("quality {quality}")
+    };
+    let Ok(source) = upright_dimensions(file) else {
+        return format!("    {encoding}");
+    };
+    let (w, h) = output_size(source, crop, max_width);
+    format!("    {}x{} -> {w}x{h}, {encoding}", source.0, source.1)
 }
 
 // ---------------------------------------------------------------------------
 // Static image optimization
 // ---------------------------------------------------------------------------
 
+/// Decode `path` the way it's meant to be seen: its EXIF orientation is
+/// applied to the pixels, since the tag itself isn't carried over.
+fn open_upright(path: &Path) -> image::ImageResult<image::DynamicImage> {
+    let mut decoder = image::ImageReader::open(path)?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = image::DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// The size of `path` once [`open_upright`] has turned it, without decoding it.
+fn upright_dimensions(path: &Path) -> image::ImageResult<(u32, u32)> {
+    use image::metadata::Orientation;
+
+    let mut decoder = image::ImageReader::open(path)?.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    Ok(match decoder.orientation()? {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    })
+}
+
 fn optimize(
     path: &Path,
-    max_width: u32,
-    quality: f32,
-) -> Result<(u64, u64, PathBuf), Box<dyn std::error::Error>> {
+    opts: &Options,
+) -> Result<(u64, u64, PathBuf, Choice), Box<dyn std::error::Error>> {
     let before = fs::metadata(path)?.len();
-    let img = image::open(path)?;
-    let img = resize_to_width(img, max_width);
+    let img = crop_to(open_upright(path)?, opts.crop);
+    let mut choice = opts.compression.choose(&img);
+    let img = resize_to_width(img, opts.max_width);
+    let img = watermark(img, opts.watermark.as_ref());
 
     let out_path = path.with_extension("webp");
-    encode_webp(&img, &out_path, quality)?;
+    let exif = exif_to_keep(path, opts.exif);
+    let mut data = encode_webp(&img, opts.quality, choice.lossless, exif.as_deref())?;
+    if let Some(target) = opts.target_size {
+        if choice.lossless && data.len() as u64 > target {
+            choice.lossless = false;
+            choice.reason = Some("lossless is over the target size");
+        }
+        if !choice.lossless {
+            let quality;
+            (data, quality) = fit_to_size(&img, opts.quality, target, exif.as_deref())?;
+            choice.quality = Some(quality);
+        }
+    }
+    if opts.metric {
+        let mut score = ssim(&img, &decode_webp(&data)?);
+        if let Some(min) = opts.min_score.filter(|_| !choice.lossless) {
+            let mut quality = choice.quality.unwrap_or(opts.quality);
+            while score < min && quality < 100.0 {
+                let raised = (quality + SCORE_QUALITY_STEP).min(100.0);
+                let encoded = encode_webp(&img, raised, false, exif.as_deref())?;
+                // The size budget wins over the score
+                if opts
+                    .target_size
+                    .is_some_and(|target| encoded.len() as u64 > target)
+                {
+                    break;
+                }
+                score = ssim(&img, &decode_webp(&encoded)?);
+                (data, quality) = (encoded, raised);
+            }
+            if quality != opts.quality {
+                choice.quality = Some(quality);
+            }
+        }
+        choice.score = Some(score);
+    }
 
-    let after = fs::metadata(&out_path)?.len();
-    Ok((before, after, out_path))
+    let after = save(&out_path, &data, opts.dry_run)?;
+    Ok((before, after, out_path, choice))
 }
 
 fn thumbnail(
     path: &Path,
     width: u32,
     quality: f32,
+    lossless: bool,
+    opts: &Options,
 ) -> Result<(u64, PathBuf), Box<dyn std::error::Error>> {
-    let img = image::open(path)?;
+    let img = crop_to(open_upright(path)?, opts.crop);
     let img = resize_to_width(img, width);
+    let img = watermark(img, opts.watermark.as_ref());
 
-    let stem = path.file_stem().unwrap().to_string_lossy();
-    let out_path = path.with_file_name(format!("{stem}{THUMB_SUFFIX}.webp"));
-    encode_webp(&img, &out_path, quality)?;
+    let out_path = thumb_path(path);
+    let exif = exif_to_keep(path, opts.exif);
+    let data = encode_webp(&img, quality, lossless, exif.as_deref())?;
 
-    let size = fs::metadata(&out_path)?.len();
+    let size = save(&out_path, &data, opts.dry_run)?;
     Ok((size, out_path))
 }
 
-fn resize_to_width(img: image::DynamicImage, max_width: u32) -> image::DynamicImage {
-    let (w, h) = img.dimensions();
+fn thumb_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    path.with_file_name(format!("{stem}{THUMB_SUFFIX}.webp"))
+}
+
+/// The size a `source`-sized image comes out at, cropped and fitted to
+/// `max_width`.
+fn output_size(source: (u32, u32), crop: Option<Crop>, max_width: u32) -> (u32, u32) {
+    let cropped = match crop {
+        Some(crop) => {
+            let (_, _, w, h) = crop.rect(source);
+            (w, h)
+        }
+        None => source,
+    };
+    fit_width(cropped, max_width)
+}
+
+/// The size an image of `(w, h)` comes out at when fitted to `max_width`.
+fn fit_width((w, h): (u32, u32), max_width: u32) -> (u32, u32) {
     if w > max_width {
-        let new_h = (max_width as f64 / w as f64 * h as f64) as u32;
-        img.resize_exact(max_width, new_h, image::imageops::FilterType::Lanczos3)
+        (max_width, (max_width as f64 / w as f64 * h as f64) as u32)
+    } else {
+        (w, h)
+    }
+}
+
+fn resize_to_width(img: image::DynamicImage, max_width: u32) -> image::DynamicImage {
+    let (w, h) = fit_width(img.dimensions(), max_width);
+    if w != img.width() {
+        img.resize_exact(w, h, image::imageops::FilterType::Lanczos3)
     } else {
         img
     }
 }
 
+/// `quality` only applies to lossy encoding.
 fn encode_webp(
     img: &image::DynamicImage,
-    path: &Path,
     quality: f32,
-) -> Result<(), Box<dyn std::error::Error>> {
+    lossless: bool,
+    exif: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let encoder = webp::Encoder::from_image(img).map_err(|e| format!("webp encode: {e}"))?;
-    let data = encoder.encode(quality);
-    fs::write(path, &*data)?;
-    Ok(())
+    let data = if lossless {
+        encoder.encode_lossless()
+    } else {
+        encoder.encode(quality)
+    };
+    match exif {
+        Some(exif) => Ok(with_exif(&data, exif, img.dimensions())?),
+        None => Ok(data.to_vec()),
+    }
 }
 
-// ---------------------------------------------------------------------------
-// Animated GIF -> animated WebP
-// ---------------------------------------------------------------------------
+/// The lowest quality `--target-size` goes down to.
+const TARGET_MIN_QUALITY: f32 = 5.0;
 
-fn is_animated_gif(path: &Path) -> bool {
-    let ext = path
-        .extension()
-        .and_then(|e| e.to_str())
-        .map(|e| e.to_lowercase());
-    if ext.as_deref() != Some("gif") {
-        return false;
+/// How far under the target size is close enough to stop searching, as a
+/// share of it.
+const TARGET_TOLERANCE: f64 = 0.05;
+
+/// `img` encoded lossy at the highest quality up to `max_quality` that fits
+/// in `target` bytes, found by binary search over whole qualities, and that
+/// quality. If nothing fits, the encoding at [`TARGET_MIN_QUALITY`].
+fn fit_to_size(
+    img: &image::DynamicImage,
+    max_quality: f32,
+    target: u64,
+    exif: Option<&[u8]>,
+) -> Result<(Vec<u8>, f32), Box<dyn std::error::Error>> {
+    let encode = |quality| encode_webp(img, quality, false, exif);
+    let fits = |data: &Vec<u8>| data.len() as u64 <= target;
+
+    let data = encode(max_quality)?;
+    if fits(&data) {
+        return Ok((data, max_quality));
     }
-    // Check if GIF has more than one frame
-    let Ok(file) = fs::File::open(path) else {
-        return false;
+    // The best fit so far; `high` is known too big
+    let mut best = None;
+    let (mut low, mut high) = (TARGET_MIN_QUALITY as u32, max_quality as u32);
+    while low < high {
+        let quality = (low + high) / 2;
+        let data = encode(quality as f32)?;
+        if !fits(&data) {
+            high = quality;
+            continue;
+        }
+        let close_enough = data.len() as f64 >= target as f64 * (1.0 - TARGET_TOLERANCE);
+        best = Some((data, quality as f32));
+        if close_enough {
+            break;
+        }
+        low = quality + 1;
+    }
+    match best {
+        Some(best) => Ok(best),
+        None => Ok((encode(TARGET_MIN_QUALITY)?, TARGET_MIN_QUALITY)),
+    }
+}
+
+/// `200KB` (or `200k`, `1.5MB`, `50000`) in bytes, with KB = 1024 bytes as
+/// in the report.
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_lowercase();
+    let (number, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
     };
-    let mut decoder = gif::DecodeOptions::new();
-    decoder.set_color_output(gif::ColorOutput::RGBA);
-    let Ok(mut reader) = decoder.read_info(file) else {
-        return false;
+    let unit = match unit {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1024.0,
+        "m" | "mb" => 1024.0 * 1024.0,
+        _ => return None,
     };
-    // Read first frame
-    if reader.read_next_frame().ok().flatten().is_none() {
-        return false;
+    let n: f64 = number.trim().parse().ok()?;
+    (n > 0.0).then_some((n * unit) as u64)
+}
+
+/// Write `data` to `path`, unless it's a dry run. Returns its size.
+fn save(path: &Path, data: &[u8], dry_run: bool) -> io::Result<u64> {
+    if !dry_run {
+        fs::write(path, data)?;
     }
-    // If there's a second frame, it's animated
-    reader.read_next_frame().ok().flatten().is_some()
+    Ok(data.len() as u64)
 }
 
-fn optimize_animated_gif(
-    path: &Path,
-    max_width: u32,
-    quality: f32,
-) -> Result<(u64, u64, PathBuf), Box<dyn std::error::Error>> {
-    let before = fs::metadata(path)?.len();
+// ---------------------------------------------------------------------------
+// Quality metric
+// ---------------------------------------------------------------------------
 
-    let file = fs::File::open(path)?;
-    let mut decoder = gif::DecodeOptions::new();
-    decoder.set_color_output(gif::ColorOutput::RGBA);
-    let mut reader = decoder.read_info(file)?;
+/// How much `--min-quality-score` raises the quality per try.
+const SCORE_QUALITY_STEP: f32 = 5.0;
 
-    let src_width = reader.width() as u32;
-    let src_height = reader.height() as u32;
+/// SSIM windows are this many pixels square, and start every half window.
+const SSIM_WINDOW: u32 = 8;
 
-    // Determine output dimensions
-    let (out_w, out_h) = if src_width > max_width {
-        let scale = max_width as f64 / src_width as f64;
-        (max_width, (src_height as f64 * scale) as u32)
-    } else {
-        (src_width, src_height)
-    };
+fn decode_webp(data: &[u8]) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+    let decoded = webp::Decoder::new(data)
+        .decode()
+        .ok_or("webp decode failed")?;
+    Ok(decoded.to_image())
+}
 
-    let config = webp_animation::EncodingConfig::new_lossy(quality);
-    let mut options = webp_animation::EncoderOptions::default();
-    options.encoding_config = Some(config);
-    options.minimize_size = true;
+/// The structural similarity of two images of the same size, on their
+/// luma: 1 for identical, lower the more they differ. The mean over
+/// overlapping [`SSIM_WINDOW`]-pixel windows, without the usual Gaussian
+/// weighting.
+fn ssim(source: &image::DynamicImage, output: &image::DynamicImage) -> f64 {
+    let (a, b) = (source.to_luma8(), output.to_luma8());
+    if a.dimensions() != b.dimensions() {
+        return 0.0;
+    }
+    let (width, height) = a.dimensions();
+    let window = SSIM_WINDOW.min(width).min(height);
+    let step = (window / 2).max(1);
 
-    let mut encoder = webp_animation::Encoder::new_with_options((out_w, out_h), options)?;
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    for y in (0..=height - window).step_by(step as usize) {
+        for x in (0..=width - window).step_by(step as usize) {
+            total += window_ssim(&a, &b, (x, y), window);
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
 
-    let mut timestamp_ms: i32 = 0;
-    let needs_resize = src_width > max_width;
+fn window_ssim(a: &image::GrayImage, b: &image::GrayImage, (x, y): (u32, u32), size: u32) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
 
-    while let Some(frame) = reader.read_next_frame()? {
-        let delay_ms = frame.delay as i32 * 10; // GIF delay is in centiseconds
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for py in y..y + size {
+        for px in x..x + size {
+            let pa = a.get_pixel(px, py).0[0] as f64;
+            let pb = b.get_pixel(px, py).0[0] as f64;
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+    let n = (size * size) as f64;
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covar = sum_ab / n - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
 
-        let frame_rgba = if needs_resize {
-            let img = image::RgbaImage::from_raw(src_width, src_height, frame.buffer.to_vec())
-                .ok_or("invalid frame dimensions")?;
-            let resized = image::imageops::resize(
-                &img,
-                out_w,
-                out_h,
-                image::imageops::FilterType::Lanczos3,
-            );
-            resized.into_raw()
+// ---------------------------------------------------------------------------
+// Cropping
+// ---------------------------------------------------------------------------
+
+/// `--crop`: cut images to an aspect ratio before resizing.
+#[derive(Clone, Copy)]
+struct Crop {
+    /// Width to height, e.g. `(16, 9)`.
+    ratio: (u32, u32),
+    /// The point to keep in view, as fractions of the width and height from
+    /// the top left. The centre unless `--focus` says otherwise.
+    focus: (f64, f64),
+}
+
+impl Crop {
+    /// The part of a `(w, h)` image to keep, as `(x, y, width, height)`: the
+    /// largest rectangle of the ratio, centred on the focus point as far as
+    /// the edges allow.
+    fn rect(self, (w, h): (u32, u32)) -> (u32, u32, u32, u32) {
+        let (rw, rh) = (self.ratio.0 as u64, self.ratio.1 as u64);
+        let (cw, ch) = if w as u64 * rh > h as u64 * rw {
+            (((h as u64 * rw / rh) as u32).max(1), h)
         } else {
-            frame.buffer.to_vec()
+            (w, ((w as u64 * rh / rw) as u32).max(1))
         };
-
-        encoder.add_frame(&frame_rgba, timestamp_ms)?;
-        timestamp_ms += delay_ms.max(20); // Floor at 20ms (50fps) to avoid 0-delay GIFs
+        let offset = |size: u32, kept: u32, focus: f64| {
+            (size as f64 * focus - kept as f64 / 2.0).clamp(0.0, (size - kept) as f64) as u32
+        };
+        (
+            offset(w, cw, self.focus.0),
+            offset(h, ch, self.focus.1),
+            cw,
+            ch,
+        )
     }
+}
 
-    let webp_data = encoder.finalize(timestamp_ms)?;
+fn crop_to(img: image::DynamicImage, crop: Option<Crop>) -> image::DynamicImage {
+    match crop {
+        Some(crop) => {
+            let (x, y, w, h) = crop.rect(img.dimensions());
+            img.crop_imm(x, y, w, h)
+        }
+        None => img,
+    }
+}
 
-    let out_path = path.with_extension("webp");
-    fs::write(&out_path, &webp_data)?;
+/// `16:9` as `(16, 9)`.
+fn parse_ratio(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = s.split_once(':')?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    (w > 0 && h > 0).then_some((w, h))
+}
 
-    let after = fs::metadata(&out_path)?.len();
-    Ok((before, after, out_path))
+/// `0.5,0.3` as `(0.5, 0.3)`, each between 0 and 1.
+fn parse_focus(s: &str) -> Option<(f64, f64)> {
+    let (x, y) = s.split_once(',')?;
+    let (x, y): (f64, f64) = (x.trim().parse().ok()?, y.trim().parse().ok()?);
+    ((0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y)).then_some((x, y))
 }
 
 // ---------------------------------------------------------------------------
-// File collection
+// Watermark
 // ---------------------------------------------------------------------------
 
-fn collect_files(paths: &[PathBuf]) -> Vec<PathBuf> {
-    let mut files = Vec::new();
-    for path in paths {
-        if path.is_dir() {
-            if let Ok(entries) = fs::read_dir(path) {
-                for entry in entries.flatten() {
-                    let p = entry.path();
-                    if is_convertible(&p) {
-                        files.push(p);
-                    }
-                }
-            }
-        } else if is_convertible(path) {
-            files.push(path.clone());
+/// How wide the watermark is drawn, as a share of the output's width, so
+/// it takes the same part of the main output and the thumbnail.
+const WATERMARK_WIDTH: f64 = 0.2;
+
+/// The gap between the watermark and the edges, as a share of the
+/// output's width.
+const WATERMARK_MARGIN: f64 = 0.03;
+
+/// `--watermark`: a logo drawn onto every output, after resizing.
+struct Watermark {
+    logo: image::RgbaImage,
+    position: Position,
+    /// 0-1, multiplying the logo's own alpha.
+    opacity: f32,
+}
+
+#[derive(Clone, Copy)]
+enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Position {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "top-left" => Some(Position::TopLeft),
+            "top-right" => Some(Position::TopRight),
+            "bottom-left" => Some(Position::BottomLeft),
+            "bottom-right" => Some(Position::BottomRight),
+            "center" | "centre" => Some(Position::Center),
+            _ => None,
         }
     }
-    files.sort();
-    files
+}
+
+impl Watermark {
+    /// The logo scaled and faded for a `(w, h)` output, and where its top
+    /// left corner goes.
+    fn overlay(&self, (w, h): (u32, u32)) -> (image::RgbaImage, i64, i64) {
+        let (logo_w, logo_h) = self.logo.dimensions();
+        let scaled_w = ((w as f64 * WATERMARK_WIDTH) as u32).max(1);
+        let scaled_h = ((scaled_w as f64 / logo_w as f64 * logo_h as f64) as u32).max(1);
+        let mut logo = image::imageops::resize(
+            &self.logo,
+            scaled_w,
+            scaled_h,
+            image::imageops::FilterType::Lanczos3,
+        );
+        for pixel in logo.pixels_mut() {
+            pixel.0[3] = (pixel.0[3] as f32 * self.opacity).round() as u8;
+        }
+
+        let margin = (w as f64 * WATERMARK_MARGIN) as i64;
+        let (w, h, scaled_w, scaled_h) = (w as i64, h as i64, scaled_w as i64, scaled_h as i64);
+        let (x, y) = match self.position {
+            Position::TopLeft => (margin, margin),
+            Position::TopRight => (w - scaled_w - margin, margin),
+            Position::BottomLeft => (margin, h - scaled_h - margin),
+            Position::BottomRight => (w - scaled_w - margin, h - scaled_h - margin),
+            Position::Center => ((w - scaled_w) / 2, (h - scaled_h) / 2),
+        };
+        (logo, x, y)
+    }
+}
+
+/// `img` with the watermark drawn on, if there is one.
+fn watermark(img: image::DynamicImage, watermark: Option<&Watermark>) -> image::DynamicImage {
+    let Some(watermark) = watermark else {
+        return img;
+    };
+    let (logo, x, y) = watermark.overlay(img.dimensions());
+    let has_alpha = img.color().has_alpha();
+    let mut rgba = img.to_rgba8();
+    image::imageops::overlay(&mut rgba, &logo, x, y);
+    // An opaque image stays without an alpha channel to encode
+    if has_alpha {
+        image::DynamicImage::ImageRgba8(rgba)
+    } else {
+        image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(rgba).to_rgb8())
+    }
+}
+
+/// `0.4` as `0.4`, between 0 and 1.
+fn parse_opacity(s: &str) -> Option<f32> {
+    let opacity: f32 = s.trim().parse().ok()?;
+    (0.0..=1.0).contains(&opacity).then_some(opacity)
+}
+
+// ---------------------------------------------------------------------------
+// Lossy vs lossless
+// ---------------------------------------------------------------------------
+
+/// Images with at most this many distinct colours are taken for
+/// screenshots or diagrams under `--auto`. Photos have far more.
+const AUTO_MAX_COLORS: usize = 4096;
+
+/// The share of neighbouring pixels that are identical above which an image
+/// has the flat areas of a screenshot or plot. In photos, noise makes
+/// nearly every pair differ.
+const AUTO_MIN_FLAT: f64 = 0.5;
+
+/// Of the neighbouring pixels that differ, the share that differ sharply
+/// (text, lines, UI borders) above which the flat areas count as drawn.
+const AUTO_MIN_HARD_EDGES: f64 = 0.15;
+
+/// A channel difference summed over RGB at least this large is a sharp edge.
+const HARD_EDGE: u32 = 96;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Compression {
+    Lossy,
+    Lossless,
+    /// Decide per image: lossless for what looks like a screenshot or
+    /// diagram, lossy for photos. Animated GIFs stay lossy.
+    Auto,
+}
+
+/// How an image gets encoded, and why, for the report.
+#[derive(Clone, Copy, Default)]
+struct Choice {
+    lossless: bool,
+    reason: Option<&'static str>,
+    /// The quality used where it isn't `--quality`: what `--target-size` or
+    /// `--min-quality-score` settled on.
+    quality: Option<f32>,
+    /// With `--metric`, the output's SSIM against the resized source.
+    score: Option<f64>,
+}
+
+impl std::fmt::Display for Choice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(if self.lossless { "lossless" } else { "lossy" })?;
+        if let Some(reason) = self.reason {
+            write!(f, ": {reason}")?;
+        }
+        if let Some(quality) = self.quality {
+            write!(f, ", quality {quality}")?;
+        }
+        match self.score {
+            Some(score) => write!(f, ", SSIM {score:.4}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Compression {
+    /// The encoding for `img`, the source: resizing blends in new colours
+    /// and softens edges, so it's judged before that.
+    fn choose(self, img: &image::DynamicImage) -> Choice {
+        let fixed = |lossless| Choice {
+            lossless,
+            ..Choice::default()
+        };
+        match self {
+            Compression::Lossy => fixed(false),
+            Compression::Lossless => fixed(true),
+            Compression::Auto => looks_drawn(&img.to_rgba8()),
+        }
+    }
+
+    fn choose_animated(self) -> Choice {
+        match self {
+            Compression::Auto => Choice {
+                lossless: false,
+                reason: Some("animated"),
+                ..Choice::default()
+            },
+            _ => Choice {
+                lossless: self == Compression::Lossless,
+                ..Choice::default()
+            },
+        }
+    }
+}
+
+/// The `--auto` heuristic. Screenshots, plots and diagrams have few
+/// colours, or large flat areas with sharp edges between them; logos and
+/// icons add transparency. Photos have none of that.
+fn looks_drawn(img: &image::RgbaImage) -> Choice {
+    let choice = |lossless, reason| Choice {
+        lossless,
+        reason: Some(reason),
+        ..Choice::default()
+    };
+    if has_few_colors(img, AUTO_MAX_COLORS) {
+        return choice(true, "few colours");
+    }
+    let (flat, hard_edges) = edge_stats(img);
+    if flat >= AUTO_MIN_FLAT && hard_edges >= AUTO_MIN_HARD_EDGES {
+        return choice(true, "flat areas, sharp edges");
+    }
+    let transparent = img.pixels().any(|pixel| pixel.0[3] < u8::MAX);
+    if transparent && flat >= AUTO_MIN_FLAT {
+        return choice(true, "flat areas, transparency");
+    }
+    choice(false, "photo")
+}
+
+fn has_few_colors(img: &image::RgbaImage, max: usize) -> bool {
+    let mut colors = std::collections::HashSet::new();
+    for pixel in img.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > max {
+            return false;
+        }
+    }
+    true
+}
+
+/// Over horizontally neighbouring pixels: the share that are identical, and
+/// of the rest, the share that differ by at least [`HARD_EDGE`]. Large
+/// images are sampled every few rows.
+fn edge_stats(img: &image::RgbaImage) -> (f64, f64) {
+    let step = (img.height() / 512).max(1) as usize;
+    let (mut pairs, mut flat, mut hard) = (0u64, 0u64, 0u64);
+    for row in img.rows().step_by(step) {
+        let row: Vec<_> = row.collect();
+        for pair in row.windows(2) {
+            let (a, b) = (pair[0].0, pair[1].0);
+            pairs += 1;
+            if a == b {
+                flat += 1;
+            } else if (0..3).map(|c| a[c].abs_diff(b[c]) as u32).sum::<u32>() >= HARD_EDGE {
+                hard += 1;
+            }
+        }
+    }
+    let changed = pairs - flat;
+    let share = |n: u64, of: u64| if of == 0 { 0.0 } else { n as f64 / of as f64 };
+    (share(flat, pairs), share(hard, changed))
+}
+
+// ---------------------------------------------------------------------------
+// EXIF metadata
+// ---------------------------------------------------------------------------
+
+/// Which of the source's EXIF fields end up in the WebP. Re-encoding drops
+/// everything unless they're copied over.
+#[derive(Clone, Copy, PartialEq)]
+enum ExifMode {
+    Strip,
+    KeepCopyright,
+    /// Copyright and capture date, but nothing else (no GPS, camera
+    /// serials, ...). Orientation is applied on decode instead.
+    Keep,
+}
+
+impl ExifMode {
+    fn tags(self) -> &'static [exif::Tag] {
+        match self {
+            ExifMode::Strip => &[],
+            ExifMode::KeepCopyright => &[exif::Tag::Copyright],
+            ExifMode::Keep => &[exif::Tag::Copyright, exif::Tag::DateTimeOriginal],
+        }
+    }
+}
+
+/// The EXIF (a TIFF structure) to write for `path` under `mode`: the
+/// source's fields that `mode` keeps, if it has any of them.
+fn exif_to_keep(path: &Path, mode: ExifMode) -> Option<Vec<u8>> {
+    if mode.tags().is_empty() {
+        return None;
+    }
+    let file = fs::File::open(path).ok()?;
+    let source = exif::Reader::new()
+        .read_from_container(&mut io::BufReader::new(file))
+        .ok()?;
+    let fields: Vec<&exif::Field> = mode
+        .tags()
+        .iter()
+        .filter_map(|tag| source.get_field(*tag, exif::In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut out = io::Cursor::new(Vec::new());
+    writer.write(&mut out, source.little_endian()).ok()?;
+    Some(out.into_inner())
+}
+
+/// `webp` (as libwebp writes it) with an EXIF chunk added. That takes the
+/// extended format, so a simple file gets a VP8X header chunk first.
+fn with_exif(webp: &[u8], exif: &[u8], (width, height): (u32, u32)) -> Result<Vec<u8>, String> {
+    const EXIF_FLAG: u8 = 0x08;
+    const ALPHA_FLAG: u8 = 0x10;
+
+    if webp.len() < 20 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err("not a WebP file".into());
+    }
+    let mut chunks = webp[12..].to_vec();
+    match &chunks[0..4] {
+        b"VP8X" => chunks[8] |= EXIF_FLAG,
+        kind @ (b"VP8 " | b"VP8L") => {
+            // A lossless bitstream says whether it uses alpha, bit 28 after
+            // its signature byte
+            let alpha = kind == b"VP8L"
+                && chunks
+                    .get(9..13)
+                    .is_some_and(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) & (1 << 28) != 0);
+            let mut vp8x = b"VP8X".to_vec();
+            vp8x.extend_from_slice(&10u32.to_le_bytes());
+            vp8x.push(EXIF_FLAG | if alpha { ALPHA_FLAG } else { 0 });
+            vp8x.extend_from_slice(&[0, 0, 0]);
+            vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            chunks.splice(0..0, vp8x);
+        }
+        _ => return Err("unexpected WebP chunk layout".into()),
+    }
+
+    // EXIF goes after the image data
+    chunks.extend_from_slice(b"EXIF");
+    chunks.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+    chunks.extend_from_slice(exif);
+    if exif.len() % 2 == 1 {
+        chunks.push(0);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&chunks);
+    Ok(out)
+}
+
+// ---------------------------------------------------------------------------
+// Animated GIF -> animated WebP
+// ---------------------------------------------------------------------------
+
+fn is_animated_gif(path: &Path) -> bool {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    if ext.as_deref() != Some("gif") {
+        return false;
+    }
+    // Check if GIF has more than one frame
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::RGBA);
+    let Ok(mut reader) = decoder.read_info(file) else {
+        return false;
+    };
+    // Read first frame
+    if reader.read_next_frame().ok().flatten().is_none() {
+        return false;
+    }
+    // If there's a second frame, it's animated
+    reader.read_next_frame().ok().flatten().is_some()
+}
+
+fn optimize_animated_gif(
+    path: &Path,
+    max_width: u32,
+    crop: Option<Crop>,
+    watermark: Option<&Watermark>,
+    quality: f32,
+    choice: Choice,
+    dry_run: bool,
+) -> Result<(u64, u64, PathBuf, Choice), Box<dyn std::error::Error>> {
+    let before = fs::metadata(path)?.len();
+
+    let file = fs::File::open(path)?;
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info(file)?;
+
+    let src_width = reader.width() as u32;
+    let src_height = reader.height() as u32;
+
+    let (crop_x, crop_y, crop_w, crop_h) = match crop {
+        Some(crop) => crop.rect((src_width, src_height)),
+        None => (0, 0, src_width, src_height),
+    };
+    let (out_w, out_h) = fit_width((crop_w, crop_h), max_width);
+
+    let config = if choice.lossless {
+        webp_animation::EncodingConfig {
+            encoding_type: webp_animation::EncodingType::Lossless,
+            ..Default::default()
+        }
+    } else {
+        webp_animation::EncodingConfig::new_lossy(quality)
+    };
+    let options = webp_animation::EncoderOptions {
+        encoding_config: Some(config),
+        minimize_size: true,
+        ..Default::default()
+    };
+
+    let mut encoder = webp_animation::Encoder::new_with_options((out_w, out_h), options)?;
+
+    let mut timestamp_ms: i32 = 0;
+    let needs_resize = (out_w, out_h) != (src_width, src_height);
+    // The same for every frame, so scaled once
+    let overlay = watermark.map(|watermark| watermark.overlay((out_w, out_h)));
+
+    // Frames may cover only part of the screen, so each is drawn onto the
+    // full canvas, which is what gets encoded
+    let mut canvas = image::RgbaImage::new(src_width, src_height);
+
+    while let Some(frame) = reader.read_next_frame()? {
+        let delay_ms = frame.delay as i32 * 10; // GIF delay is in centiseconds
+
+        let patch = image::RgbaImage::from_raw(
+            frame.width as u32,
+            frame.height as u32,
+            frame.buffer.to_vec(),
+        )
+        .ok_or("invalid frame dimensions")?;
+        let previous = (frame.dispose == gif::DisposalMethod::Previous).then(|| canvas.clone());
+        image::imageops::overlay(&mut canvas, &patch, frame.left as i64, frame.top as i64);
+
+        let mut frame_rgba = if needs_resize {
+            let img = image::imageops::crop_imm(&canvas, crop_x, crop_y, crop_w, crop_h).to_image();
+            let resized = image::imageops::resize(
+                &img,
+                out_w,
+                out_h,
+                image::imageops::FilterType::Lanczos3,
+            );
+            resized.into_raw()
+        } else {
+            canvas.to_vec()
+        };
+        if let Some((logo, x, y)) = &overlay {
+            let mut img = image::RgbaImage::from_raw(out_w, out_h, frame_rgba)
+                .ok_or("invalid frame dimensions")?;
+            image::imageops::overlay(&mut img, logo, *x, *y);
+            frame_rgba = img.into_raw();
+        }
+
+        encoder.add_frame(&frame_rgba, timestamp_ms)?;
+        timestamp_ms += delay_ms.max(20); // Floor at 20ms (50fps) to avoid 0-delay GIFs
+
+        // What the next frame is drawn over
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                let blank = image::RgbaImage::new(patch.width(), patch.height());
+                image::imageops::replace(&mut canvas, &blank, frame.left as i64, frame.top as i64);
+            }
+            gif::DisposalMethod::Previous => canvas = previous.unwrap_or(canvas),
+            _ => {}
+        }
+    }
+
+    let webp_data = encoder.finalize(timestamp_ms)?;
+
+    let out_path = path.with_extension("webp");
+    let after = save(&out_path, &webp_data, dry_run)?;
+    Ok((before, after, out_path, choice))
+}
+
+// ---------------------------------------------------------------------------
+// Incremental runs
+// ---------------------------------------------------------------------------
+
+/// The files converting `file` writes, each with the width it's fitted to.
+fn expected_outputs(file: &Path, opts: &Options) -> Vec<(PathBuf, u32)> {
+    let mut outputs = vec![(file.with_extension("webp"), opts.max_width)];
+    if opts.thumbnails && !is_animated_gif(file) {
+        outputs.push((thumb_path(file), THUMB_WIDTH));
+    }
+    outputs
+}
+
+/// Whether every output of `file` exists and was written after `file` last
+/// changed. Only modification times count, so rerunning with a different
+/// quality or width needs `--force`.
+fn is_up_to_date(file: &Path, opts: &Options) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+    let Ok(source) = modified(file) else {
+        return false;
+    };
+    expected_outputs(file, opts)
+        .iter()
+        .all(|(out, _)| modified(out).is_ok_and(|out| out >= source))
+}
+
+// ---------------------------------------------------------------------------
+// Manifest
+// ---------------------------------------------------------------------------
+
+/// One source image in the `--manifest` JSON, for the site templates to
+/// fill in width/height and srcset from. Paths are relative to the
+/// manifest's directory.
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    source: String,
+    width: u32,
+    height: u32,
+    bytes: u64,
+    /// `#rrggbb`, or null for a fully transparent image.
+    dominant_color: Option<String>,
+    /// BlurHash of the image, to show while it loads.
+    placeholder: String,
+    outputs: Vec<ManifestOutput>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestOutput {
+    path: String,
+    width: u32,
+    height: u32,
+    bytes: u64,
+}
+
+fn manifest_entry(
+    manifest: &Path,
+    source: &Path,
+    outputs: &[(PathBuf, u32)],
+    crop: Option<Crop>,
+) -> Result<ManifestEntry, Box<dyn std::error::Error>> {
+    let img = open_upright(source)?;
+    let dimensions = img.dimensions();
+    // The placeholder and colour are for the outputs, so of the crop
+    let img = crop_to(img, crop);
+    let small = img.thumbnail(64, 64).to_rgba8();
+    let placeholder = blurhash::encode(4, 3, small.width(), small.height(), small.as_raw())
+        .map_err(|e| format!("blurhash: {e}"))?;
+
+    let outputs = outputs
+        .iter()
+        .map(|(path, max_width)| {
+            let (width, height) = fit_width(img.dimensions(), *max_width);
+            Ok(ManifestOutput {
+                path: manifest_path(manifest, path),
+                width,
+                height,
+                bytes: fs::metadata(path)?.len(),
+            })
+        })
+        .collect::<io::Result<_>>()?;
+
+    Ok(ManifestEntry {
+        source: manifest_path(manifest, source),
+        width: dimensions.0,
+        height: dimensions.1,
+        bytes: fs::metadata(source)?.len(),
+        dominant_color: dominant_color(&small),
+        placeholder,
+        outputs,
+    })
+}
+
+/// `path` relative to the manifest's directory where it's inside it, so
+/// the same file gets the same entry however it was named on the command
+/// line (watch mode sees absolute paths).
+fn manifest_path(manifest: &Path, path: &Path) -> String {
+    let base = manifest
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .canonicalize()
+        .ok();
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let relative = base
+        .and_then(|base| path.strip_prefix(base).ok().map(Path::to_path_buf))
+        .unwrap_or(path);
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+/// The most common colour, counting pixels in 16 levels per channel and
+/// averaging the winning bucket. Mostly transparent pixels don't count.
+fn dominant_color(img: &image::RgbaImage) -> Option<String> {
+    let mut buckets: std::collections::HashMap<[u8; 3], (u32, [u32; 3])> =
+        std::collections::HashMap::new();
+    for pixel in img.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let (count, sum) = buckets.entry([r >> 4, g >> 4, b >> 4]).or_default();
+        *count += 1;
+        sum[0] += r as u32;
+        sum[1] += g as u32;
+        sum[2] += b as u32;
+    }
+    let (count, sum) = buckets.into_values().max_by_key(|(count, _)| *count)?;
+    Some(format!(
+        "#{:02x}{:02x}{:02x}",
+        sum[0] / count,
+        sum[1] / count,
+        sum[2] / count
+    ))
+}
+
+/// Write `entries` to the manifest, keeping the entries already in it for
+/// other sources, sorted by source.
+fn update_manifest(
+    manifest: &Path,
+    entries: Vec<ManifestEntry>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut all: Vec<ManifestEntry> = match fs::read(manifest) {
+        Ok(existing) => serde_json::from_slice(&existing)
+            .map_err(|e| format!("existing manifest isn't one this tool wrote: {e}"))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    all.retain(|old| !entries.iter().any(|new| new.source == old.source));
+    all.extend(entries);
+    all.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let mut json = serde_json::to_string_pretty(&all)?;
+    json.push('\n');
+    fs::write(manifest, json)?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Markdown references
+// ---------------------------------------------------------------------------
+
+/// Point the `![alt](image)` references in the `.md` files directly in
+/// `dir` at the WebP outputs of `converted`: the link itself, or with
+/// `--md-picture` a `<picture>` that keeps the original as the fallback
+/// (the main output instead, when originals are removed).
+/// A changed file's original is kept next to it as `.md.bak`, unless an
+/// earlier run left one already.
+fn update_markdown(
+    dir: &Path,
+    converted: &[(PathBuf, Vec<(PathBuf, u32)>)],
+    opts: &Options,
+) -> io::Result<()> {
+    // By canonical source path, as references are resolved to that
+    let converted: Vec<_> = converted
+        .iter()
+        .filter_map(|(source, outputs)| Some((source.canonicalize().ok()?, source, outputs)))
+        .collect();
+
+    let mut posts: Vec<PathBuf> = fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "md"))
+        .collect();
+    posts.sort();
+
+    for post in posts {
+        let text = fs::read_to_string(&post)?;
+        let base = post.parent().unwrap_or(Path::new("."));
+        let (rewritten, count) = rewrite_image_refs(&text, |alt, target, rest| {
+            if target.contains("://") || target.starts_with('/') {
+                return None;
+            }
+            let resolved = base.join(target).canonicalize().ok()?;
+            let (_, source, outputs) = converted.iter().find(|(c, _, _)| *c == resolved)?;
+            Some(image_ref(alt, target, rest, source, outputs, opts))
+        });
+        if count == 0 {
+            continue;
+        }
+        if !opts.dry_run {
+            let backup = post.with_extension("md.bak");
+            if !backup.exists() {
+                fs::copy(&post, &backup)?;
+            }
+            fs::write(&post, rewritten)?;
+        }
+        println!("  {}: {count} image reference(s) updated", post.display());
+    }
+    Ok(())
+}
+
+/// The replacement for `![alt](target rest)`, `target` being `source`
+/// which was converted into `outputs` (the main one first).
+fn image_ref(
+    alt: &str,
+    target: &str,
+    rest: &str,
+    source: &Path,
+    outputs: &[(PathBuf, u32)],
+    opts: &Options,
+) -> String {
+    // Outputs sit next to their source, so only the file name changes
+    let dir = target.rfind('/').map_or("", |i| &target[..=i]);
+    let link = |output: &Path| {
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        format!("{dir}{name}")
+    };
+    if !opts.md_picture {
+        return format!("![{alt}]({}{rest})", link(&outputs[0].0));
+    }
+
+    let mut sized: Option<Vec<(String, (u32, u32))>> =
+        upright_dimensions(source).ok().map(|size| {
+            outputs
+                .iter()
+                .map(|(path, max_width)| (link(path), output_size(size, opts.crop, *max_width)))
+                .collect()
+        });
+    // An image narrower than the thumbnail width comes out the same size
+    // twice; srcset takes one candidate per width
+    if let Some(sized) = &mut sized {
+        sized.dedup_by_key(|(_, (w, _))| *w);
+    }
+    let dimensions = |(w, h)| format!(" width=\"{w}\" height=\"{h}\"");
+    let (srcset, sizes, dimensions) = match sized.as_deref() {
+        Some(all @ [(_, (w, h)), _, ..]) => {
+            let srcset: Vec<String> = all
+                .iter()
+                .rev()
+                .map(|(link, (w, _))| format!("{link} {w}w"))
+                .collect();
+            (
+                srcset.join(", "),
+                format!(" sizes=\"(max-width: {w}px) 100vw, {w}px\""),
+                dimensions((*w, *h)),
+            )
+        }
+        Some([(main, size)]) => (main.clone(), String::new(), dimensions(*size)),
+        _ => (link(&outputs[0].0), String::new(), String::new()),
+    };
+    let fallback = match opts.originals {
+        Originals::Keep => target.to_string(),
+        Originals::Delete | Originals::Move => link(&outputs[0].0),
+    };
+    let alt = escape_attribute(alt);
+    format!(
+        "<picture><source type=\"image/webp\" srcset=\"{srcset}\"{sizes}><img src=\"{fallback}\" alt=\"{alt}\"{dimensions} loading=\"lazy\"></picture>"
+    )
+}
+
+/// `text` escaped for a double-quoted HTML attribute.
+fn escape_attribute(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Byte ranges of `text`'s fenced code blocks and inline code spans, in
+/// which `![..](..)` is only text.
+fn code_ranges(text: &str) -> Vec<(usize, usize)> {
+    /// The fence `line` opens or closes with: its character and length.
+    fn fence(line: &str) -> Option<(char, usize)> {
+        let trimmed = line.trim_start_matches(' ');
+        if line.len() - trimmed.len() > 3 {
+            return None;
+        }
+        let c = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+        let len = trimmed.len() - trimmed.trim_start_matches(c).len();
+        // A backtick fence's info string can't hold backticks
+        let info_ok = c == '~' || !trimmed[len..].contains('`');
+        (len >= 3 && info_ok).then_some((c, len))
+    }
+
+    let mut ranges = Vec::new();
+    // Text outside fences, where inline spans are looked for
+    let mut prose = Vec::new();
+    let mut open: Option<(char, usize, usize)> = None;
+    let mut prose_start = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let end = offset + line.len();
+        match (open, fence(line)) {
+            (None, Some((c, len))) => {
+                prose.push((prose_start, offset));
+                open = Some((c, len, offset));
+            }
+            (Some((c, len, start)), Some((close, close_len)))
+                if close == c
+                    && close_len >= len
+                    && line.trim().trim_start_matches(c).is_empty() =>
+            {
+                ranges.push((start, end));
+                open = None;
+                prose_start = end;
+            }
+            _ => {}
+        }
+        offset = end;
+    }
+    match open {
+        // An unclosed fence runs to the end
+        Some((_, _, start)) => ranges.push((start, text.len())),
+        None => prose.push((prose_start, text.len())),
+    }
+
+    for (start, end) in prose {
+        let bytes = &text.as_bytes()[start..end];
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'`' || (i > 0 && bytes[i - 1] == b'\\') {
+                i += 1;
+                continue;
+            }
+            let run = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+            // Closed by a run of exactly as many backticks
+            let mut j = i + run;
+            let mut close = None;
+            while j < bytes.len() {
+                let len = bytes[j..].iter().take_while(|&&b| b == b'`').count();
+                if len == run {
+                    close = Some(j + len);
+                    break;
+                }
+                j += len.max(1);
+            }
+            match close {
+                Some(close) => {
+                    ranges.push((start + i, start + close));
+                    i = close;
+                }
+                None => i += run,
+            }
+        }
+    }
+    ranges.sort_unstable();
+    ranges
+}
+
+/// `text` with each `![alt](target rest)` rewritten to what `replace` makes
+/// of it, where it makes anything, and how many were. References in code
+/// are left alone.
+fn rewrite_image_refs(
+    text: &str,
+    replace: impl Fn(&str, &str, &str) -> Option<String>,
+) -> (String, usize) {
+    let code = code_ranges(text);
+    let mut out = String::with_capacity(text.len());
+    let mut count = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find("![") {
+        let at = text.len() - rest.len() + start;
+        if let Some(&(_, end)) = code.iter().find(|(from, to)| (*from..*to).contains(&at)) {
+            out.push_str(&text[text.len() - rest.len()..end]);
+            rest = &text[end..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let image = &rest[start..];
+        let replaced = image.find("](").and_then(|alt_end| {
+            let alt = &image[2..alt_end];
+            let link_start = alt_end + 2;
+            let link_end = link_start + image[link_start..].find(')')?;
+            let link = image[link_start..link_end].trim_start();
+            let target = link.split_whitespace().next()?;
+            if alt.contains('\n') {
+                return None;
+            }
+            let replacement = replace(alt, target, &link[target.len()..])?;
+            Some((replacement, link_end + 1))
+        });
+        match replaced {
+            Some((replacement, end)) => {
+                out.push_str(&replacement);
+                count += 1;
+                rest = &image[end..];
+            }
+            None => {
+                out.push_str("![");
+                rest = &image[2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    (out, count)
+}
+
+// ---------------------------------------------------------------------------
+// Removing originals
+// ---------------------------------------------------------------------------
+
+/// Where moved originals go, next to the outputs. Directories are read
+/// without recursing, so nothing in it is converted again.
+const ORIGINALS_DIR: &str = ".originals";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Originals {
+    Keep,
+    /// `--delete-originals`
+    Delete,
+    /// `--move-originals`: into [`ORIGINALS_DIR`].
+    Move,
+}
+
+/// Delete or move away each source in `converted` whose outputs check out,
+/// keeping the others with a note of why.
+fn remove_originals(converted: &[(PathBuf, Vec<(PathBuf, u32)>)], opts: &Options) {
+    if opts.dry_run {
+        println!("  Originals are kept on a dry run");
+        return;
+    }
+    for (source, _) in converted {
+        let removed = check_outputs(source, opts).and_then(|()| match opts.originals {
+            Originals::Keep => Ok(None),
+            Originals::Delete => fs::remove_file(source)
+                .map(|()| None)
+                .map_err(|e| e.to_string()),
+            Originals::Move => move_original(source).map(Some),
+        });
+        match removed {
+            Ok(moved) if opts.verbosity > Verbosity::Quiet => match moved {
+                Some(to) => println!("  {} moved to {}", source.display(), to.display()),
+                None => println!("  {} deleted", source.display()),
+            },
+            Ok(_) => {}
+            Err(e) => eprintln!("  KEPT {}: {e}", source.display()),
+        }
+    }
+}
+
+/// Whether `source` is safe to remove: every output it should have exists
+/// and decodes, and the main one is smaller than it.
+fn check_outputs(source: &Path, opts: &Options) -> Result<(), String> {
+    let source_size = fs::metadata(source).map_err(|e| e.to_string())?.len();
+    for (i, (output, _)) in expected_outputs(source, opts).iter().enumerate() {
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        let data = fs::read(output).map_err(|e| format!("{name}: {e}"))?;
+        // The animation decoder reads still WebPs too
+        let decodes = webp_animation::Decoder::new(&data)
+            .is_ok_and(|decoder| decoder.into_iter().count() > 0);
+        if !decodes {
+            return Err(format!("{name} doesn't decode"));
+        }
+        if i == 0 && data.len() as u64 >= source_size {
+            return Err(format!("{name} isn't smaller than the original"));
+        }
+    }
+    Ok(())
+}
+
+/// Move `source` into [`ORIGINALS_DIR`] beside it, never over a file
+/// already there.
+fn move_original(source: &Path) -> Result<PathBuf, String> {
+    let dir = source
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(ORIGINALS_DIR);
+    let to = dir.join(source.file_name().unwrap_or_default());
+    if to.exists() {
+        return Err(format!("{} already exists", to.display()));
+    }
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    fs::rename(source, &to).map_err(|e| e.to_string())?;
+    Ok(to)
+}
+
+// ---------------------------------------------------------------------------
+// SVG minification
+// ---------------------------------------------------------------------------
+
+/// Decimal places numbers in SVG geometry are rounded to.
+const SVG_PRECISION: usize = 3;
+
+/// Namespace prefixes of editor-only data, dropped with the elements and
+/// attributes that use them.
+const SVG_EDITOR_PREFIXES: &[&str] = &["inkscape", "sodipodi", "sketch", "serif"];
+
+/// Whether the attribute `name` holds numbers (or lists of them) that can
+/// be rounded. Paths' `d` is rounded by [`round_path`] instead.
+fn is_numeric_attribute(name: &str) -> bool {
+    matches!(
+        name,
+        "points"
+            | "transform"
+            | "viewBox"
+            | "x"
+            | "y"
+            | "x1"
+            | "y1"
+            | "x2"
+            | "y2"
+            | "cx"
+            | "cy"
+            | "r"
+            | "rx"
+            | "ry"
+            | "dx"
+            | "dy"
+            | "width"
+            | "height"
+            | "offset"
+            | "opacity"
+            | "fill-opacity"
+            | "stroke-opacity"
+            | "stroke-width"
+            | "font-size"
+    )
+}
+
+fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("svg"))
+}
+
+/// Minify the SVG at `path` in place, if that makes it smaller. Returns
+/// the sizes before and after.
+fn minify_svg_file(path: &Path, dry_run: bool) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let svg = fs::read_to_string(path)?;
+    let minified = minify_svg(&svg)?;
+    if minified.len() >= svg.len() {
+        return Ok((svg.len() as u64, svg.len() as u64));
+    }
+    let after = save(path, minified.as_bytes(), dry_run)?;
+    Ok((svg.len() as u64, after))
+}
+
+/// An element's start tag, held until all its attributes are read.
+struct SvgElement<'a> {
+    /// Where its start tag is in the document.
+    start: usize,
+    prefix: &'a str,
+    local: &'a str,
+    /// Prefix, local name and (still escaped) value.
+    attributes: Vec<(&'a str, &'a str, &'a str)>,
+}
+
+/// `svg` with what doesn't affect rendering taken out, in the spirit of
+/// SVGO's defaults: the XML declaration, comments, `<metadata>`, editor
+/// data, unreferenced `<defs>`, whitespace between tags, and digits past
+/// [`SVG_PRECISION`].
+fn minify_svg(svg: &str) -> Result<String, xmlparser::Error> {
+    use xmlparser::{ElementEnd, Token};
+
+    let tokens = xmlparser::Tokenizer::from(svg).collect::<Result<Vec<_>, _>>()?;
+    let used = used_definitions(&tokens, &referenced_ids(&tokens));
+
+    let mut out = String::with_capacity(svg.len());
+    // Local names of the elements we're inside, innermost last
+    let mut open: Vec<&str> = Vec::new();
+    let mut pending: Option<SvgElement> = None;
+    // How many levels deep we are inside an element being dropped
+    let mut dropping = 0usize;
+    // Where the DOCTYPE started, and whether it declares entities, which
+    // the document may use and so can't go
+    let mut dtd: Option<(usize, bool)> = None;
+
+    for token in tokens {
+        match token {
+            Token::Declaration { .. }
+            | Token::ProcessingInstruction { .. }
+            | Token::Comment { .. }
+            | Token::EmptyDtd { .. } => {}
+            Token::DtdStart { span, .. } => dtd = Some((span.start(), false)),
+            Token::EntityDeclaration { .. } => {
+                if let Some((_, entities)) = &mut dtd {
+                    *entities = true;
+                }
+            }
+            Token::DtdEnd { span } => {
+                if let Some((start, true)) = dtd.take() {
+                    out.push_str(&svg[start..span.end()]);
+                }
+            }
+            Token::ElementStart {
+                prefix,
+                local,
+                span,
+            } => {
+                pending = Some(SvgElement {
+                    start: span.start(),
+                    prefix: prefix.as_str(),
+                    local: local.as_str(),
+                    attributes: Vec::new(),
+                });
+            }
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } => {
+                if let Some(element) = &mut pending {
+                    element
+                        .attributes
+                        .push((prefix.as_str(), local.as_str(), value.as_str()));
+                }
+            }
+            Token::ElementEnd {
+                end: end @ (ElementEnd::Open | ElementEnd::Empty),
+                ..
+            } => {
+                let Some(element) = pending.take() else {
+                    continue;
+                };
+                let is_open = matches!(end, ElementEnd::Open);
+                if dropping > 0 || is_droppable(&element, open.last().copied(), &used) {
+                    if is_open {
+                        dropping += 1;
+                    }
+                    continue;
+                }
+                write_svg_start(&mut out, &element, !is_open);
+                if is_open {
+                    open.push(element.local);
+                }
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Close(prefix, local),
+                ..
+            } => {
+                if dropping > 0 {
+                    dropping -= 1;
+                    continue;
+                }
+                open.pop();
+                out.push_str("</");
+                push_qualified(&mut out, prefix.as_str(), local.as_str());
+                out.push('>');
+            }
+            Token::Text { text } => {
+                let in_text = open
+                    .last()
+                    .is_some_and(|e| matches!(*e, "text" | "tspan" | "textPath"));
+                if dropping == 0 && (in_text || !text.as_str().trim().is_empty()) {
+                    out.push_str(text.as_str());
+                }
+            }
+            Token::Cdata { span, .. } => {
+                if dropping == 0 {
+                    out.push_str(span.as_str());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The ids something in the document points at, with `url(#id)` or an
+/// `href="#id"`.
+fn referenced_ids<'a>(tokens: &[xmlparser::Token<'a>]) -> std::collections::HashSet<&'a str> {
+    let mut ids = std::collections::HashSet::new();
+    for token in tokens {
+        let text = match token {
+            xmlparser::Token::Attribute { local, value, .. } => {
+                if local.as_str() == "href" {
+                    if let Some(id) = value.as_str().strip_prefix('#') {
+                        ids.insert(id);
+                    }
+                }
+                value.as_str()
+            }
+            xmlparser::Token::Text { text } | xmlparser::Token::Cdata { text, .. } => text.as_str(),
+            _ => continue,
+        };
+        for rest in text.split("url(").skip(1) {
+            let rest = rest.trim_start_matches(['\'', '"']);
+            if let Some(id) = rest.strip_prefix('#') {
+                let end = id.find([')', '\'', '"']).unwrap_or(id.len());
+                ids.insert(&id[..end]);
+            }
+        }
+    }
+    ids
+}
+
+/// Where the `<defs>` children start that something refers to: to them, or
+/// to an element anywhere inside them.
+fn used_definitions(
+    tokens: &[xmlparser::Token],
+    referenced: &std::collections::HashSet<&str>,
+) -> std::collections::HashSet<usize> {
+    use xmlparser::{ElementEnd, Token};
+
+    let mut used = std::collections::HashSet::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut pending = "";
+    // The start of the definition we're in, and how many elements enclose it
+    let mut definition: Option<(usize, usize)> = None;
+    for token in tokens {
+        match token {
+            Token::ElementStart { local, span, .. } => {
+                if definition.is_none() && open.last() == Some(&"defs") {
+                    definition = Some((span.start(), open.len()));
+                }
+                pending = local.as_str();
+            }
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } => {
+                let referenced_id = prefix.is_empty()
+                    && local.as_str() == "id"
+                    && referenced.contains(value.as_str());
+                if let (true, Some((start, _))) = (referenced_id, definition) {
+                    used.insert(start);
+                }
+            }
+            Token::ElementEnd { end, .. } => {
+                match end {
+                    ElementEnd::Open => open.push(pending),
+                    ElementEnd::Close(..) => {
+                        open.pop();
+                    }
+                    ElementEnd::Empty => {}
+                }
+                if definition.is_some_and(|(_, depth)| depth == open.len()) {
+                    definition = None;
+                }
+            }
+            _ => {}
+        }
+    }
+    used
+}
+
+/// Whether `element`, inside an element named `parent`, goes: editor data,
+/// `<metadata>`, and definitions nothing refers to (see [`used_definitions`]).
+fn is_droppable(
+    element: &SvgElement,
+    parent: Option<&str>,
+    used: &std::collections::HashSet<usize>,
+) -> bool {
+    if SVG_EDITOR_PREFIXES.contains(&element.prefix) {
+        return true;
+    }
+    if element.prefix.is_empty() && element.local == "metadata" {
+        return true;
+    }
+    if parent == Some("defs") && element.local != "style" {
+        return !used.contains(&element.start);
+    }
+    false
+}
+
+fn write_svg_start(out: &mut String, element: &SvgElement, empty: bool) {
+    out.push('<');
+    push_qualified(out, element.prefix, element.local);
+    for &(prefix, local, value) in &element.attributes {
+        let editor = SVG_EDITOR_PREFIXES.contains(&prefix)
+            || (prefix == "xmlns" && SVG_EDITOR_PREFIXES.contains(&local));
+        if editor {
+            continue;
+        }
+        let value = if prefix.is_empty() && local == "d" {
+            round_path(value)
+        } else if prefix.is_empty() && is_numeric_attribute(local) {
+            round_numbers(value)
+        } else {
+            value.to_string()
+        };
+        out.push(' ');
+        push_qualified(out, prefix, local);
+        // Values come through as written, so one with a double quote was
+        // single-quoted
+        let quote = if value.contains('"') { '\'' } else { '"' };
+        out.push('=');
+        out.push(quote);
+        out.push_str(&value);
+        out.push(quote);
+    }
+    out.push_str(if empty { "/>" } else { ">" });
+}
+
+fn push_qualified(out: &mut String, prefix: &str, local: &str) {
+    if !prefix.is_empty() {
+        out.push_str(prefix);
+        out.push(':');
+    }
+    out.push_str(local);
+}
+
+/// `value` with each decimal number in it rounded to [`SVG_PRECISION`]
+/// places and written as briefly as it can be. Integers are left alone.
+fn round_numbers(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        match number_end(value, i) {
+            Some(end) => {
+                push_rounded(&mut out, &value[i..end], value[end..].starts_with('.'));
+                i = end;
+            }
+            None => i += push_char(&mut out, &value[i..]),
+        }
+    }
+    out
+}
+
+/// A path's `d` with its numbers rounded as [`round_numbers`] does, read a
+/// command at a time: an arc's two flags are one character each and may run
+/// into what follows (`a1 1 0 01.5.5`), so they're copied as they are.
+fn round_path(d: &str) -> String {
+    let mut out = String::with_capacity(d.len());
+    let mut command = b' ';
+    // Arguments read since the command letter
+    let mut argument = 0;
+    let mut i = 0;
+    while i < d.len() {
+        let c = d.as_bytes()[i];
+        if c.is_ascii_alphabetic() && !matches!(c, b'e' | b'E') {
+            (command, argument) = (c, 0);
+            out.push(c as char);
+            i += 1;
+            continue;
+        }
+        let is_flag = matches!(command, b'a' | b'A') && matches!(argument % 7, 3 | 4);
+        if is_flag && matches!(c, b'0' | b'1') {
+            out.push(c as char);
+            argument += 1;
+            i += 1;
+            continue;
+        }
+        match number_end(d, i) {
+            Some(end) => {
+                push_rounded(&mut out, &d[i..end], d[end..].starts_with('.'));
+                argument += 1;
+                i = end;
+            }
+            None => i += push_char(&mut out, &d[i..]),
+        }
+    }
+    out
+}
+
+/// Copy the first character of `rest` to `out`, returning its length.
+fn push_char(out: &mut String, rest: &str) -> usize {
+    let c = rest.chars().next().unwrap();
+    out.push(c);
+    c.len_utf8()
+}
+
+/// Where the number starting at `start` in `value` ends, if one does.
+fn number_end(value: &str, start: usize) -> Option<usize> {
+    let bytes = value.as_bytes();
+    let digit_at = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+    let mut i = start;
+    if matches!(bytes[i], b'-' | b'+') {
+        i += 1;
+    }
+    if !(digit_at(i) || (bytes.get(i) == Some(&b'.') && digit_at(i + 1))) {
+        return None;
+    }
+    while digit_at(i) {
+        i += 1;
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        while digit_at(i) {
+            i += 1;
+        }
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(i + 1), Some(b'-' | b'+')));
+        if digit_at(i + 1 + sign) {
+            i += 1 + sign;
+            while digit_at(i) {
+                i += 1;
+            }
+        }
+    }
+    Some(i)
+}
+
+/// Push `number`, rounded if it has decimals. `1.0001.5` is two numbers;
+/// rounded to `1`, the second needs a separator to stay separate, which
+/// `dot_follows` says it does.
+fn push_rounded(out: &mut String, number: &str, dot_follows: bool) {
+    if !number.contains(['.', 'e', 'E']) {
+        out.push_str(number);
+        return;
+    }
+    let rounded = shortest_number(number);
+    out.push_str(&rounded);
+    if !rounded.contains('.') && dot_follows {
+        out.push(' ');
+    }
+}
+
+fn shortest_number(number: &str) -> String {
+    let Ok(n) = number.parse::<f64>() else {
+        return number.to_string();
+    };
+    let mut s = format!("{n:.SVG_PRECISION$}");
+    if s.contains('.') {
+        s = s.trim_end_matches('0').trim_end_matches('.').to_string();
+    }
+    if s == "-0" {
+        s = "0".into();
+    }
+    if let Some(fraction) = s.strip_prefix("0.") {
+        s = format!(".{fraction}");
+    } else if let Some(fraction) = s.strip_prefix("-0.") {
+        s = format!("-.{fraction}");
+    }
+    if s.len() < number.len() {
+        s
+    } else {
+        number.to_string()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Watch mode
+// ---------------------------------------------------------------------------
+
+fn watch(paths: &[PathBuf], opts: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // Watch a file's directory rather than the file: editors and image tools
+    // often replace a file instead of writing to it
+    for path in paths {
+        let dir = if path.is_dir() {
+            path.as_path()
+        } else {
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+        };
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    }
+    println!("\n  Watching for new or changed images (Ctrl-C to stop)...");
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut next = rx.recv()?;
+        // Collect events until the files have been quiet for a while
+        loop {
+            collect_changed(next, paths, &mut changed);
+            match rx.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(event) => next = event,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+
+        let files: Vec<PathBuf> = changed
+            .into_iter()
+            .filter(|p| p.is_file() && (opts.minify_svg || !is_svg(p)))
+            .collect();
+        if !files.is_empty() {
+            process_files(&files, opts);
+        }
+    }
+}
+
+/// Add the convertible files a watch event created or changed, and that
+/// the command line asked for, to `changed`.
+fn collect_changed(
+    event: notify::Result<notify::Event>,
+    paths: &[PathBuf],
+    changed: &mut BTreeSet<PathBuf>,
+) {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            eprintln!("  WATCH ERROR: {e}");
+            return;
+        }
+    };
+    if !matches!(
+        event.kind,
+        notify::EventKind::Create(_) | notify::EventKind::Modify(_)
+    ) {
+        return;
+    }
+    for file in event.paths {
+        if is_convertible(&file) && is_watched(&file, paths) {
+            changed.insert(file);
+        }
+    }
+}
+
+/// Whether `file` is one of `paths` or directly inside one of them.
+fn is_watched(file: &Path, paths: &[PathBuf]) -> bool {
+    paths.iter().any(|path| {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        if path.is_dir() {
+            file.parent()
+                .and_then(|parent| parent.canonicalize().ok())
+                .is_some_and(|parent| parent == path)
+        } else {
+            file.canonicalize().is_ok_and(|file| file == path)
+        }
+    })
+}
+
+// ---------------------------------------------------------------------------
+// File collection
+// ---------------------------------------------------------------------------
+
+fn collect_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    let p = entry.path();
+                    if is_convertible(&p) {
+                        files.push(p);
+                    }
+                }
+            }
+        } else if is_convertible(path) {
+            files.push(path.clone());
+        }
+    }
+    files.sort();
+    files
 }
 
 fn is_convertible(path: &Path) -> bool {
@@ -285,7 +2399,7 @@ fn is_convertible(path: &Path) -> bool {
             .and_then(|e| e.to_str())
             .map(|e| e.to_lowercase())
             .as_deref(),
-        Some("jpg" | "jpeg" | "png" | "gif" | "bmp" | "tiff" | "tif")
+        Some("jpg" | "jpeg" | "png" | "gif" | "bmp" | "tiff" | "tif" | "svg")
     )
 }
 
@@ -310,16 +2424,207 @@ fn print_usage() {
     eprintln!("Options:");
     eprintln!("  -w, --max-width <PX>   Max width in pixels (default: 1200)");
     eprintln!("  -q, --quality <0-100>   WebP quality (default: 80)");
+    eprintln!("      --target-size <SIZE> Lower the quality until each image fits, e.g. 200KB");
+    eprintln!("      --metric ssim       Print each image's SSIM against its source");
+    eprintln!("      --min-quality-score <0-1> Raise the quality until the SSIM is at least this");
     eprintln!("  -t, --thumbnails        Also generate *-thumb.webp (600px, q75)");
+    eprintln!("      --crop <W:H>        Crop to an aspect ratio before resizing, e.g. 16:9");
+    eprintln!("      --focus <X,Y>       Point to keep when cropping, 0-1 (default: 0.5,0.5)");
+    eprintln!("      --watermark <FILE>  Draw a logo onto every output");
+    eprintln!("      --position <POS>    ...at top-left, top-right, bottom-left, bottom-right");
+    eprintln!("                          or center (default: bottom-right)");
+    eprintln!("      --opacity <0-1>     ...at this opacity (default: 1)");
+    eprintln!("      --auto              Lossless for screenshots, lossy for photos (default)");
+    eprintln!("      --lossy             Always encode lossy");
+    eprintln!("      --lossless          Always encode lossless");
+    eprintln!("      --strip-exif        Drop all EXIF metadata (default)");
+    eprintln!("      --keep-exif         Keep copyright and capture date");
+    eprintln!("      --keep-copyright    Keep only the copyright notice");
+    eprintln!("      --manifest <FILE>   Write sizes, colours and placeholders to a JSON file");
+    eprintln!("      --update-md <DIR>   Point image links in DIR/*.md at the WebP outputs");
+    eprintln!("      --md-picture        ...as <picture> elements, with srcset and fallback");
+    eprintln!("      --minify-svg        Minify SVGs in place (they're skipped otherwise)");
+    eprintln!("      --delete-originals  Delete sources once their outputs decode and are smaller");
+    eprintln!("      --move-originals    ...or move them into .originals/ beside them");
+    eprintln!("      --force             Convert files whose outputs are already up to date");
+    eprintln!("  -n, --dry-run           Show what would be converted, without writing anything");
+    eprintln!("      --watch             Keep converting images as they're added or changed");
+    eprintln!("      --quiet             Only print errors and totals");
+    eprintln!("  -v, --verbose           Also print dimensions and encoder settings");
     eprintln!("  -h, --help              Show this help");
     eprintln!();
     eprintln!("Supported formats:");
     eprintln!("  Static:   jpg, jpeg, png, bmp, tiff -> WebP");
     eprintln!("  Animated: gif (multi-frame) -> animated WebP");
+    eprintln!("  Vector:   svg -> minified in place, with --minify-svg");
     eprintln!();
     eprintln!("Examples:");
     eprintln!("  img-optim content/blog/my-post/");
     eprintln!("  img-optim -t content/blog/my-post/hero.jpg");
     eprintln!("  img-optim content/blog/my-post/demo.gif");
     eprintln!("  img-optim -q 90 -w 1600 photo.png");
+    eprintln!("  img-optim --watch -t content/blog/my-post/");
+    eprintln!("  img-optim --target-size 200KB content/blog/my-post/hero.jpg");
+    eprintln!("  img-optim --crop 16:9 --focus 0.5,0.3 content/blog/my-post/hero.jpg");
+    eprintln!("  img-optim --watermark static/logo.png --opacity 0.4 content/blog/my-post/");
+    eprintln!("  img-optim -t --update-md content/blog/my-post/ content/blog/my-post/");
+    eprintln!("  img-optim -t --manifest static/images.json static/images/");
+    eprintln!("  img-optim --move-originals content/blog/my-post/");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(md_picture: bool) -> Options {
+        Options {
+            max_width: 1200,
+            quality: 80.0,
+            target_size: None,
+            metric: false,
+            min_score: None,
+            thumbnails: false,
+            crop: None,
+            watermark: None,
+            compression: Compression::Auto,
+            exif: ExifMode::Strip,
+            manifest: None,
+            update_md: None,
+            md_picture,
+            originals: Originals::Keep,
+            minify_svg: false,
+            force: false,
+            dry_run: true,
+            verbosity: Verbosity::Quiet,
+        }
+    }
+
+    #[test]
+    fn watermarks_gif_frames_covering_part_of_the_screen() {
+        let dir = std::env::temp_dir().join(format!("img-optim-gif-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("anim.gif");
+
+        // A red screen, then a blue square drawn over part of it
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 40, 30, &[]).unwrap();
+            let mut red = [255, 0, 0, 255].repeat(40 * 30);
+            let screen = gif::Frame::from_rgba(40, 30, &mut red);
+            encoder.write_frame(&screen).unwrap();
+            let mut blue = [0, 0, 255, 255].repeat(10 * 10);
+            let mut square = gif::Frame::from_rgba(10, 10, &mut blue);
+            (square.left, square.top) = (20, 15);
+            encoder.write_frame(&square).unwrap();
+        }
+        fs::write(&path, gif).unwrap();
+
+        let watermark = Watermark {
+            logo: image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 255, 0, 255])),
+            position: Position::Center,
+            opacity: 1.0,
+        };
+        let choice = Choice {
+            lossless: true,
+            ..Default::default()
+        };
+        let (_, _, out, _) =
+            optimize_animated_gif(&path, 1200, None, Some(&watermark), 80.0, choice, false)
+                .unwrap();
+
+        let webp = fs::read(&out).unwrap();
+        let frames: Vec<_> = webp_animation::Decoder::new(&webp)
+            .unwrap()
+            .into_iter()
+            .map(|frame| {
+                let (width, height) = frame.dimensions();
+                image::RgbaImage::from_raw(width, height, frame.data().to_vec()).unwrap()
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames.len(), 2);
+        let last = &frames[1];
+        assert_eq!(last.dimensions(), (40, 30));
+        assert_eq!(last.get_pixel(20, 15).0, [0, 255, 0, 255]);
+        assert_eq!(last.get_pixel(27, 22).0, [0, 0, 255, 255]);
+        assert_eq!(last.get_pixel(5, 5).0, [255, 0, 0, 255]);
+    }
+
+    fn rewrite(text: &str) -> (String, usize) {
+        rewrite_image_refs(text, |alt, target, _| Some(format!("[{alt}|{target}]")))
+    }
+
+    #[test]
+    fn rewrites_image_refs_in_prose() {
+        assert_eq!(
+            rewrite("A ![cat](cat.png \"Cat\") and ![dog](dog.jpg)."),
+            ("A [cat|cat.png] and [dog|dog.jpg].".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn leaves_image_refs_in_code_alone() {
+        let text = "Write `![alt](a.png)` or ``![x](`b`.png)``:\n\
+                    \n\
+                    ```markdown\n\
+                    ![alt](c.png)\n\
+                    ```\n\
+                    \n\
+                    ~~~~\n\
+                    ![alt](d.png)\n\
+                    ```\n\
+                    ~~~~\n\
+                    ![real](e.png)\n";
+        let (rewritten, count) = rewrite(text);
+        assert_eq!(count, 1);
+        assert_eq!(rewritten, text.replace("![real](e.png)", "[real|e.png]"));
+    }
+
+    #[test]
+    fn unclosed_fences_and_backticks() {
+        assert_eq!(rewrite("``` ![a](a.png) `").1, 1);
+        assert_eq!(rewrite("\\`![a](a.png)` \n").1, 1);
+        assert_eq!(rewrite("```\n![a](a.png)\n").1, 0);
+    }
+
+    #[test]
+    fn escapes_picture_alt_text() {
+        let outputs = [(PathBuf::from("a.webp"), 1200)];
+        let picture = image_ref(
+            r#"Tom & "Jerry" <3"#,
+            "a.png",
+            "",
+            Path::new("missing.png"),
+            &outputs,
+            &options(true),
+        );
+        assert!(picture.contains(r#"alt="Tom &amp; &quot;Jerry&quot; &lt;3""#));
+        let plain = image_ref(
+            "a",
+            "img/a.png",
+            "",
+            Path::new("a.png"),
+            &outputs,
+            &options(false),
+        );
+        assert_eq!(plain, "![a](img/a.webp)");
+    }
+
+    #[test]
+    fn keeps_definitions_referenced_inside() {
+        let svg = r##"<svg><defs><g><path id="a" d="M0 0h1"/></g><g><path id="b"/></g></defs><use href="#a"/></svg>"##;
+        assert_eq!(
+            minify_svg(svg).unwrap(),
+            r##"<svg><defs><g><path id="a" d="M0 0h1"/></g></defs><use href="#a"/></svg>"##
+        );
+    }
+
+    #[test]
+    fn drops_unreferenced_definitions() {
+        let svg = r##"<svg><defs><linearGradient id="g"><stop offset="0"/></linearGradient><clipPath id="c"/></defs><rect fill="url(#g)"/></svg>"##;
+        assert_eq!(
+            minify_svg(svg).unwrap(),
+            r##"<svg><defs><linearGradient id="g"><stop offset="0"/></linearGradient></defs><rect fill="url(#g)"/></svg>"##
+        );
+    }
 }